@@ -0,0 +1,10 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // The gRPC schema is compiled by protox, in Rust, so building doesn't need `protoc` installed
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/paymentprocessor.proto");
+        let descriptors = protox::compile(["paymentprocessor.proto"], ["proto"]).expect("Invalid gRPC schema");
+        tonic_prost_build::configure().compile_fds(descriptors).expect("Failed to generate the gRPC service");
+    }
+}
@@ -0,0 +1,135 @@
+use crate::engine::log_refusal;
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::invariants;
+use crate::metrics::{Metrics, Tally};
+use crate::rules::{self, Rules};
+use crate::structures::{ClientAccount, Transaction};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Default number of messages a client's mailbox buffers before senders wait.
+pub const DEFAULT_MAILBOX_CAPACITY: usize = 64;
+
+/// Messages understood by a client actor.
+#[derive(Debug)]
+pub enum ClientMessage {
+    /// Apply a transaction (including dispute, resolve, and chargeback) to the actor's account.
+    Apply(Transaction),
+}
+
+/// Handle to a running client actor.
+struct ClientHandle {
+    mailbox: mpsc::Sender<ClientMessage>,
+    task: JoinHandle<ClientAccount>,
+}
+
+/// Routes messages to one lightweight task per active client.
+/// Each actor owns its `ClientAccount` outright, so transactions can be fed in stream order without
+/// pre-partitioning the input, while different clients still progress in parallel.
+pub struct ActorRouter {
+    mailbox_capacity: usize,
+    budget: Option<MemoryBudget>,
+    metrics: Option<Arc<Metrics>>,
+    rules: Option<Arc<Rules>>,
+    actors: HashMap<u32, ClientHandle>,
+}
+
+impl Default for ActorRouter {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAILBOX_CAPACITY)
+    }
+}
+
+impl ActorRouter {
+    pub fn new(mailbox_capacity: usize) -> Self {
+        Self {
+            mailbox_capacity: mailbox_capacity.max(1),
+            budget: None,
+            metrics: None,
+            rules: None,
+            actors: HashMap::new(),
+        }
+    }
+
+    /// Have every actor's account history count against (and possibly spill under) `budget`.
+    pub fn with_budget(mut self, budget: Option<MemoryBudget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Have every actor add its rejected transactions and time spent applying to `metrics` when it exits.
+    pub fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Have every actor apply `rules` instead of the installed ones, when given.
+    pub fn with_rules(mut self, rules: Option<Arc<Rules>>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Deliver a transaction to its client's actor, spawning the actor on first contact.
+    /// Waits if the actor's mailbox is full. Must be called from within a tokio runtime.
+    pub async fn send(&mut self, transaction: Transaction) -> Result<(), KrakenError> {
+        let capacity = self.mailbox_capacity;
+        let (budget, metrics, rules) = (&self.budget, &self.metrics, &self.rules);
+        let handle = self.actors.entry(transaction.client).or_insert_with(|| {
+            spawn_client(capacity, ClientAccount::new(budget.as_ref()), metrics.clone(), rules.clone())
+        });
+
+        handle
+            .mailbox
+            .send(ClientMessage::Apply(transaction))
+            .await
+            .map_err(|_| KrakenError::Error)
+    }
+
+    /// Close every mailbox and wait for the actors to drain them, returning the final accounts.
+    pub async fn shutdown(self) -> Result<HashMap<u32, ClientAccount>> {
+        let mut accounts = HashMap::with_capacity(self.actors.len());
+        for (client_id, handle) in self.actors {
+            drop(handle.mailbox);
+            accounts.insert(client_id, handle.task.await?);
+        }
+        Ok(accounts)
+    }
+}
+
+fn spawn_client(
+    capacity: usize,
+    mut account: ClientAccount,
+    metrics: Option<Arc<Metrics>>,
+    rules: Option<Arc<Rules>>,
+) -> ClientHandle {
+    let (mailbox, mut inbox) = mpsc::channel(capacity);
+    let task = tokio::spawn(async move {
+        let (mut tally, mut applying) = (Tally::default(), Duration::ZERO);
+        while let Some(message) = inbox.recv().await {
+            match message {
+                ClientMessage::Apply(mut transaction) => {
+                    // Refusals are logged, like the engine's, and results counted for the metrics
+                    let (client, tx, kind, memo) = (transaction.client, transaction.tx, transaction.kind.clone(), transaction.memo.take());
+                    let start = Instant::now();
+                    let result = invariants::apply_checked(&mut account, transaction, rules.as_deref().unwrap_or_else(|| rules::current()));
+                    let elapsed = start.elapsed();
+                    log_refusal(&result, client, tx, &kind, memo.as_deref());
+                    tally.record(kind, &result, elapsed);
+                    applying += elapsed;
+                }
+            }
+        }
+        if let Some(metrics) = metrics {
+            metrics.add_tally(&tally);
+            metrics.add_apply(applying);
+        }
+        account
+    });
+
+    ClientHandle { mailbox, task }
+}
@@ -0,0 +1,145 @@
+use crate::dates::format_timestamp;
+use crate::engine::{replay, Outcome};
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::InputSource;
+use crate::structures::TransactionType;
+use std::collections::HashMap;
+use std::io::Write;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+const HEADER: &str = "client, tx, held, opened_row, age_rows, opened_at, age_days";
+
+/// A dispute still open at the end of the input, and how long it has been.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenDispute {
+    pub client: u32,
+    /// The disputed transaction.
+    pub tx: u32,
+    /// How much the dispute holds.
+    pub held: f64,
+    /// Row of the input the dispute was opened at, counting from 1 across every input.
+    pub opened_row: u64,
+    /// Rows read after it.
+    pub age_rows: u64,
+    /// Time the dispute was opened, if its row had one.
+    pub opened_at: Option<i64>,
+    /// Whole days from then to the time of the latest transaction of the input.
+    pub age_days: Option<u64>,
+}
+
+/// Replay `source` serially, then write the disputes left open to `writer` as `DisputeAging` does. Returns the
+/// disputes written.
+pub fn write_dispute_aging<S: InputSource, W: Write>(
+    source: S,
+    writer: W,
+    budget: Option<MemoryBudget>,
+) -> Result<Vec<OpenDispute>, KrakenError> {
+    let mut aging = DisputeAging::default();
+    replay(source, budget, |outcome| {
+        aging.record(outcome);
+        Ok(())
+    })?;
+    aging.finish(writer)
+}
+
+/// The disputes open so far, and how long they've been open.
+#[derive(Debug, Default)]
+pub struct DisputeAging {
+    /// By the tx id of the transaction disputed, which resolves and chargebacks name too.
+    open: HashMap<u32, OpenDispute>,
+    rows: u64,
+    /// Time of the latest transaction.
+    latest: Option<i64>,
+}
+
+impl DisputeAging {
+    pub fn record(&mut self, outcome: &Outcome) {
+        self.rows += 1;
+        self.latest = self.latest.max(outcome.timestamp);
+        if outcome.result.is_err() {
+            return;
+        }
+        match outcome.kind {
+            TransactionType::Dispute => {
+                let dispute = OpenDispute {
+                    client: outcome.client,
+                    tx: outcome.tx,
+                    held: outcome.held_change,
+                    opened_row: self.rows,
+                    age_rows: 0,
+                    opened_at: outcome.timestamp,
+                    age_days: None,
+                };
+                self.open.insert(outcome.tx, dispute);
+            }
+            TransactionType::Resolve | TransactionType::Chargeback => {
+                self.open.remove(&outcome.tx);
+            }
+            _ => {}
+        }
+    }
+
+    /// Write every dispute left open as a `client, tx, held, opened_row, age_rows, opened_at, age_days` row to
+    /// `writer`, oldest first, so stale disputes can be chased. Age is counted in rows, and in days when the dispute
+    /// and the input have times, up to the latest of them. Returns the disputes written.
+    pub fn finish<W: Write>(self, mut writer: W) -> Result<Vec<OpenDispute>, KrakenError> {
+        let mut disputes: Vec<OpenDispute> = self.open.into_values().collect();
+        disputes.sort_by_key(|dispute| dispute.opened_row);
+        writeln!(writer, "{HEADER}").map_err(|_| KrakenError::IO)?;
+        for dispute in &mut disputes {
+            dispute.age_rows = self.rows - dispute.opened_row;
+            let age = dispute.opened_at.zip(self.latest).map(|(opened, latest)| latest - opened);
+            dispute.age_days = age.map(|age| (age / SECONDS_PER_DAY) as u64);
+            writeln!(
+                writer,
+                "{}, {}, {:.4}, {}, {}, {}, {}",
+                dispute.client,
+                dispute.tx,
+                dispute.held,
+                dispute.opened_row,
+                dispute.age_rows,
+                dispute.opened_at.map(format_timestamp).unwrap_or_default(),
+                dispute.age_days.map(|days| days.to_string()).unwrap_or_default()
+            )
+            .map_err(|_| KrakenError::IO)?;
+        }
+        writer.flush().map_err(|_| KrakenError::IO)?;
+        Ok(disputes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aging::write_dispute_aging;
+    use crate::input::JsonLinesSource;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_dispute_aging() {
+        let rows = [
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 5.0, "timestamp": "2024-01-01"}"#,
+            r#"{"type": "deposit", "client": 1, "tx": 2, "amount": 2.0, "timestamp": "2024-01-02"}"#,
+            r#"{"type": "deposit", "client": 2, "tx": 3, "amount": 1.0}"#,
+            r#"{"type": "dispute", "client": 1, "tx": 1, "timestamp": "2024-01-03"}"#,
+            r#"{"type": "dispute", "client": 1, "tx": 2, "timestamp": "2024-01-04"}"#,
+            r#"{"type": "dispute", "client": 2, "tx": 3}"#,
+            // Refused, as it's open already
+            r#"{"type": "dispute", "client": 1, "tx": 1, "timestamp": "2024-01-20"}"#,
+            r#"{"type": "resolve", "client": 1, "tx": 2, "timestamp": "2024-01-10"}"#,
+            r#"{"type": "deposit", "client": 3, "tx": 4, "amount": 1.0, "timestamp": "2024-02-02T12:00:00Z"}"#,
+        ];
+        let source = JsonLinesSource::from_reader(Cursor::new(rows.join("\n")));
+        let mut report = Vec::new();
+        let disputes = write_dispute_aging(source, &mut report, None).unwrap();
+        let ages: Vec<(u32, u64, u64)> = disputes.iter().map(|d| (d.tx, d.opened_row, d.age_rows)).collect();
+        assert_eq!(vec![(1, 4, 5), (3, 6, 3)], ages);
+
+        let report = String::from_utf8(report).unwrap();
+        let expected = "client, tx, held, opened_row, age_rows, opened_at, age_days\n\
+                        1, 1, 5.0000, 4, 5, 2024-01-03T00:00:00Z, 30\n\
+                        2, 3, 1.0000, 6, 3, , \n";
+        assert_eq!(expected, report);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::engine::EngineObserver;
+use crate::errors::KrakenError;
+use crate::redact::{Amount, Client};
+use crate::structures::Transaction;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::{error, warn};
+
+/// Something wrong enough with an account for someone to look at it, written as JSON with its `alert` name.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "alert", rename_all = "snake_case")]
+pub enum Alert {
+    /// Chargeback `tx` locked the account.
+    AccountLocked { client: u32, tx: u32 },
+    /// Transaction `tx` left the available funds below zero, as a dispute of funds already withdrawn does.
+    NegativeBalance { client: u32, tx: u32, available: f64 },
+    /// The engine refused transaction `tx` for `reason`, such as a withdrawal exceeding the funds.
+    RuleViolation {
+        client: u32,
+        tx: u32,
+        #[serde(rename = "type")]
+        kind: String,
+        reason: String,
+    },
+}
+
+impl Alert {
+    /// The alert's name, as in its JSON and the `code` of its log record.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Alert::AccountLocked { .. } => "account_locked",
+            Alert::NegativeBalance { .. } => "negative_balance",
+            Alert::RuleViolation { .. } => "rule_violation",
+        }
+    }
+
+    pub fn client(&self) -> u32 {
+        match self {
+            Alert::AccountLocked { client, .. }
+            | Alert::NegativeBalance { client, .. }
+            | Alert::RuleViolation { client, .. } => *client,
+        }
+    }
+
+    pub fn tx(&self) -> u32 {
+        match self {
+            Alert::AccountLocked { tx, .. } | Alert::NegativeBalance { tx, .. } | Alert::RuleViolation { tx, .. } => *tx,
+        }
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Alert::AccountLocked { client, tx } => write!(f, "Account {} locked by chargeback {tx}", Client(*client)),
+            Alert::NegativeBalance { client, tx, available } => {
+                write!(f, "Account {} available {:.4} after tx {tx}", Client(*client), Amount(*available))
+            }
+            Alert::RuleViolation { client, tx, kind, reason } => {
+                write!(f, "Refused {kind} tx {tx} of client {}: {reason}", Client(*client))
+            }
+        }
+    }
+}
+
+/// Somewhere alerts go, such as a chat channel or a pager. Sinks are called on the thread applying the
+/// transaction, so any that block, such as on the network, should hand the alert off instead.
+pub trait AlertSink: Send {
+    fn alert(&mut self, alert: &Alert);
+}
+
+/// Logs each alert as a warning, which goes to stderr with every other log record, in the `--diagnostics` format.
+#[derive(Debug, Default)]
+pub struct StderrSink;
+
+impl AlertSink for StderrSink {
+    fn alert(&mut self, alert: &Alert) {
+        warn!(code = alert.code(), client = alert.client(), tx = alert.tx(), "{alert}");
+    }
+}
+
+/// Appends each alert to a file as a line of JSON, flushed at once so a tail of the file sees it.
+#[derive(Debug)]
+pub struct FileSink {
+    writer: BufWriter<File>,
+}
+
+impl FileSink {
+    /// Append to the file at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self, KrakenError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|_| KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO)))?;
+        Ok(Self { writer: BufWriter::new(file) })
+    }
+}
+
+impl AlertSink for FileSink {
+    fn alert(&mut self, alert: &Alert) {
+        // An alert always serializes
+        let line = serde_json::to_string(alert).unwrap_or_default();
+        if let Err(e) = writeln!(self.writer, "{line}").and_then(|_| self.writer.flush()) {
+            error!(code = "io", error = %e, "Failed to write an alert: {line}");
+        }
+    }
+}
+
+/// Posts each alert as JSON to webhooks, with the retries and backoff of `webhook::deliver`.
+#[cfg(feature = "server")]
+pub struct WebhookSink {
+    sender: tokio::sync::mpsc::UnboundedSender<String>,
+}
+
+#[cfg(feature = "server")]
+impl WebhookSink {
+    /// Start delivering to `config.urls` on the current tokio runtime, which this must be called from.
+    pub fn spawn(config: crate::webhook::WebhookConfig) -> Self {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(crate::webhook::deliver(config, receiver));
+        Self { sender }
+    }
+}
+
+#[cfg(feature = "server")]
+impl AlertSink for WebhookSink {
+    fn alert(&mut self, alert: &Alert) {
+        // An alert always serializes, and sending only fails once the runtime has shut down
+        let _ = self.sender.send(serde_json::to_string(alert).unwrap_or_default());
+    }
+}
+
+/// Observer raising alerts to its sinks: when a chargeback locks an account, when an account's available funds
+/// fall below zero, and whenever a transaction is refused. A negative balance is raised once, when it turns
+/// negative, and again only once it has recovered and turned negative anew.
+#[derive(Default)]
+pub struct Alerts {
+    sinks: Vec<Box<dyn AlertSink>>,
+}
+
+impl Alerts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also raise every alert to `sink`, after the sinks added before.
+    pub fn with_sink(mut self, sink: impl AlertSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    fn raise(&mut self, alert: Alert) {
+        for sink in &mut self.sinks {
+            sink.alert(&alert);
+        }
+    }
+}
+
+impl EngineObserver for Alerts {
+    fn on_rejected(&mut self, transaction: &Transaction, error: &KrakenError) {
+        self.raise(Alert::RuleViolation {
+            client: transaction.client,
+            tx: transaction.tx,
+            kind: transaction.kind.name().to_string(),
+            reason: error.to_string(),
+        });
+    }
+
+    fn on_account_locked(&mut self, client: u32, tx: u32) {
+        self.raise(Alert::AccountLocked { client, tx });
+    }
+
+    fn on_negative_balance(&mut self, client: u32, tx: u32, available: f64) {
+        self.raise(Alert::NegativeBalance { client, tx, available });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alerts::{Alert, AlertSink, Alerts, FileSink};
+    use crate::engine::Engine;
+    use crate::structures::Transaction;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps the alerts, as an integrator's own sink would send them on.
+    struct Collect(Arc<Mutex<Vec<Alert>>>);
+
+    impl AlertSink for Collect {
+        fn alert(&mut self, alert: &Alert) {
+            self.0.lock().unwrap().push(alert.clone());
+        }
+    }
+
+    #[test]
+    fn test_alerts() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("alerts.jsonl");
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let alerts = Alerts::new().with_sink(Collect(collected.clone())).with_sink(FileSink::open(&path).unwrap());
+        let mut engine = Engine::new().with_observer(alerts);
+        let rows = [
+            "deposit, 1, 1, 5.0",
+            "withdrawal, 1, 2, 4.0",
+            "withdrawal, 1, 3, 4.0",
+            "dispute, 1, 1, ",
+            "deposit, 2, 4, 1.0",
+            "chargeback, 1, 1, ",
+        ];
+        engine.process(rows.iter().map(|row| Transaction::try_from(*row).unwrap()));
+
+        let reason = String::from("Insufficient Funds for account: 1");
+        let expected = vec![
+            Alert::RuleViolation { client: 1, tx: 3, kind: String::from("withdrawal"), reason },
+            Alert::NegativeBalance { client: 1, tx: 1, available: -4.0 },
+            Alert::AccountLocked { client: 1, tx: 1 },
+        ];
+        assert_eq!(expected, *collected.lock().unwrap());
+        let file = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(3, file.lines().count());
+        assert_eq!(r#"{"alert":"negative_balance","client":1,"tx":1,"available":-4.0}"#, file.lines().nth(1).unwrap());
+    }
+}
@@ -0,0 +1,104 @@
+use crate::errors::KrakenError;
+use crate::input::csv_error;
+use crate::redact::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Client ids declared to be the same entity as another client, the canonical one, whose id they are reported under.
+/// Inputs have their client ids replaced as they're read, so every processing mode routes an alias's transactions to
+/// the canonical account, and `Snapshot::alias` merges the accounts of a snapshot saved before.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Aliases {
+    canonical: HashMap<u32, u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AliasRow {
+    alias: u32,
+    client: u32,
+}
+
+impl Aliases {
+    /// Aliases from `(alias, client)` pairs. A client can't be an alias of itself, of two clients, or of a client that
+    /// is itself an alias, as the canonical id would be ambiguous.
+    pub fn new(pairs: impl IntoIterator<Item = (u32, u32)>) -> Result<Self, KrakenError> {
+        let invalid = |message: String| Err(KrakenError::InvalidArgument(message));
+        let mut canonical = HashMap::new();
+        for (alias, client) in pairs {
+            if alias == client {
+                return invalid(format!("Client {} is declared an alias of itself", Client(alias)));
+            }
+            if let Some(other) = canonical.insert(alias, client)
+                && other != client
+            {
+                let (alias, other, client) = (Client(alias), Client(other), Client(client));
+                return invalid(format!("Client {alias} is declared an alias of both {other} and {client}"));
+            }
+        }
+        if let Some((alias, client)) = canonical.iter().find(|(_, client)| canonical.contains_key(client)) {
+            return invalid(format!("Client {} is an alias of {}, which is itself an alias", Client(*alias), Client(*client)));
+        }
+        Ok(Self { canonical })
+    }
+
+    /// Read the aliases from the CSV file at `path`, of `alias, client` rows under a header, each declaring `alias`
+    /// to be the same entity as `client`.
+    pub fn load(path: &Path) -> Result<Self, KrakenError> {
+        let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(path).map_err(|e| in_file(csv_error(e)))?;
+        let mut pairs = Vec::new();
+        for row in reader.deserialize::<AliasRow>() {
+            let row = row.map_err(|e| in_file(csv_error(e)))?;
+            pairs.push((row.alias, row.client));
+        }
+        Self::new(pairs).map_err(in_file)
+    }
+
+    /// The id `client` is reported under: the client it's an alias of, or its own.
+    pub fn canonical(&self, client: u32) -> u32 {
+        self.canonical.get(&client).copied().unwrap_or(client)
+    }
+
+    pub fn len(&self) -> usize {
+        self.canonical.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.canonical.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Aliases;
+    use crate::engine::Engine;
+    use crate::input::{rows, InputOptions, MultiSource};
+    use crate::processor::tests::TEST_DIR;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_aliases() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("aliases.csv");
+        std::fs::write(&path, "alias, client\n2, 1\n7, 1\n").unwrap();
+        let aliases = Aliases::load(&path).unwrap();
+        assert_eq!((1, 1, 1, 3), (aliases.canonical(2), aliases.canonical(7), aliases.canonical(1), aliases.canonical(3)));
+
+        // Ambiguous declarations are refused
+        assert!(Aliases::new([(1, 1)]).is_err());
+        assert!(Aliases::new([(2, 1), (2, 3)]).unwrap_err().to_string().contains("alias of both 1 and 3"));
+        assert!(Aliases::new([(3, 2), (2, 1)]).unwrap_err().to_string().contains("itself an alias"));
+        assert_eq!(1, Aliases::new([(2, 1), (2, 1)]).unwrap().len());
+        std::fs::write(&path, "alias, client\n2, x\n").unwrap();
+        assert_eq!("parse", Aliases::load(&path).unwrap_err().code());
+
+        // Client 2's transactions are applied to client 1's account, which can now cover its withdrawal
+        let input = InputOptions { aliases: Some(Arc::new(aliases)), ..Default::default() };
+        let mut source = MultiSource::new(&[String::from(TEST_DIR) + "0-trivial.csv"], input);
+        let mut engine = Engine::new();
+        engine.process(rows(&mut source).map(Result::unwrap));
+        assert_eq!(1, engine.accounts().len());
+        assert_eq!("1, 0.5000, 0.0000, 0.5000, false", engine.accounts()[&1].to_str_row(1).unwrap());
+    }
+}
@@ -0,0 +1,187 @@
+use crate::compression::Compression;
+use crate::engine::Engine;
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::resolve_delimiter;
+use crate::structures::{ClientAccount, Transaction};
+use anyhow::Result;
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Default number of items buffered between two pipeline stages.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Asynchronous variant of the engine.
+/// Input is read, deserialized, and applied by three tasks connected with bounded channels, so a slow
+/// stage applies backpressure to the ones before it instead of the whole input being buffered.
+#[derive(Debug)]
+pub struct AsyncEngine {
+    capacity: usize,
+    budget: Option<MemoryBudget>,
+    delimiter: Option<u8>,
+}
+
+impl Default for AsyncEngine {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHANNEL_CAPACITY)
+    }
+}
+
+impl AsyncEngine {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            budget: None,
+            delimiter: None,
+        }
+    }
+
+    /// Have account histories count against (and possibly spill under) `budget`.
+    pub fn with_budget(mut self, budget: Option<MemoryBudget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Split rows on `delimiter`, or pick one per file with `resolve_delimiter` when `None`.
+    pub fn with_delimiter(mut self, delimiter: Option<u8>) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Run the pipeline over a file, transparently decompressing gzip or zstd input.
+    pub async fn process_file(&self, path: impl AsRef<Path>) -> Result<HashMap<u32, ClientAccount>> {
+        self.process_files(&[path]).await
+    }
+
+    /// Run the pipeline over several files, in order, as one logical stream.
+    /// Each file is decompressed as needed and has its own header skipped, and read errors name the file.
+    pub async fn process_files<P: AsRef<Path>>(&self, paths: &[P]) -> Result<HashMap<u32, ClientAccount>> {
+        let paths: Vec<PathBuf> = paths.iter().map(|path| path.as_ref().to_path_buf()).collect();
+        let (line_sink, line_source) = mpsc::channel(self.capacity);
+        let delimiter = self.delimiter;
+
+        let read = tokio::spawn(async move {
+            for path in paths {
+                read_file(&path, resolve_delimiter(delimiter, &path), line_sink.clone())
+                    .await
+                    .map_err(|e| e.context(path.display().to_string()))?;
+            }
+            Ok(())
+        });
+        self.run(read, line_source).await
+    }
+
+    /// Run the pipeline over any buffered async reader yielding a headed `type, client, tx, amount` CSV.
+    pub async fn process_reader<R>(&self, reader: R) -> Result<HashMap<u32, ClientAccount>>
+    where
+        R: AsyncBufRead + Unpin + Send + 'static,
+    {
+        let (line_sink, line_source) = mpsc::channel(self.capacity);
+        let delimiter = self.delimiter.unwrap_or(b',');
+        self.run(tokio::spawn(read_lines(reader, delimiter, line_sink)), line_source).await
+    }
+
+    /// Join a spawned read stage to the deserialize and apply stages.
+    async fn run(
+        &self,
+        read: JoinHandle<Result<()>>,
+        line_source: mpsc::Receiver<Line>,
+    ) -> Result<HashMap<u32, ClientAccount>> {
+        let (transaction_sink, transaction_source) = mpsc::channel(self.capacity);
+
+        let (read, deserialized, applied) = tokio::join!(
+            read,
+            tokio::spawn(deserialize(line_source, transaction_sink)),
+            tokio::spawn(apply(transaction_source, self.budget.clone())),
+        );
+
+        // Surface the earliest stage's error first, since it is the likely cause of any later one
+        read??;
+        deserialized??;
+        Ok(applied?)
+    }
+}
+
+/// A raw row along with the delimiter of the file it came from.
+type Line = (String, u8);
+
+async fn read_file(path: &Path, delimiter: u8, sink: mpsc::Sender<Line>) -> Result<()> {
+    let compression = Compression::detect(path)?;
+    let file = BufReader::new(tokio::fs::File::open(path).await?);
+
+    match compression {
+        Compression::None => read_lines(file, delimiter, sink).await,
+        Compression::Gzip => {
+            let mut decoder = GzipDecoder::new(file);
+            decoder.multiple_members(true);
+            read_lines(BufReader::new(decoder), delimiter, sink).await
+        }
+        Compression::Zstd => read_lines(BufReader::new(ZstdDecoder::new(file)), delimiter, sink).await,
+    }
+}
+
+async fn read_lines<R>(reader: R, delimiter: u8, sink: mpsc::Sender<Line>) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut lines = reader.lines();
+
+    // Skip the header
+    lines.next_line().await?;
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // A closed channel means a downstream stage bailed out; it reports the error
+        if sink.send((line, delimiter)).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn deserialize(
+    mut source: mpsc::Receiver<Line>,
+    sink: mpsc::Sender<Transaction>,
+) -> Result<(), KrakenError> {
+    while let Some((line, delimiter)) = source.recv().await {
+        let transaction = Transaction::parse_delimited(line.as_bytes(), delimiter)?;
+        if sink.send(transaction).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn apply(
+    mut source: mpsc::Receiver<Transaction>,
+    budget: Option<MemoryBudget>,
+) -> HashMap<u32, ClientAccount> {
+    let mut engine = Engine::with_budget(budget);
+    while let Some(transaction) = source.recv().await {
+        // Swallow results since we aren't tracking them
+        let _ = engine.apply(transaction);
+    }
+    engine.into_accounts()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::async_engine::AsyncEngine;
+    use crate::processor::tests::{TEST_CASES, TEST_DIR};
+
+    #[tokio::test]
+    async fn test_csv_async() {
+        let engine = AsyncEngine::new(2);
+        for (file_name, expected) in TEST_CASES {
+            let totals = engine.process_file(String::from(TEST_DIR) + file_name).await.unwrap();
+            assert_eq!(String::from(expected), totals.get(&1).expect("").to_str_row(1).unwrap())
+        }
+    }
+}
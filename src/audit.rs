@@ -0,0 +1,256 @@
+use crate::engine::{replay, Outcome};
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::InputSource;
+use crate::provenance::{InputProvenance, Provenance};
+use crate::redact::{self, Amount};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// `prev` of the first record of a log.
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// What happened to one transaction, and the account right after it. Amounts are written to four places, as
+/// strings, so a record hashes the same once read back.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Position of the record in the log, counting from 1 across every run appending to it.
+    pub seq: u64,
+    pub tx: u32,
+    pub client: AuditClient,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub amount: Option<String>,
+    /// `applied` or `rejected`.
+    pub status: String,
+    /// Why the transaction was rejected.
+    pub reason: Option<String>,
+    /// The transaction's memo. Left out when there is none, so records written before memos hash the same.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: bool,
+    /// Hash of the record before, or `GENESIS`.
+    pub prev: String,
+}
+
+/// The client of a record: its id, or its pseudonym in records written while `redact` was enabled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AuditClient {
+    Id(u32),
+    Pseudonym(String),
+}
+
+/// A line of the log: the entry, and the SHA-256 of its JSON text, which `prev` chains to the record before.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+    pub hash: String,
+}
+
+impl AuditRecord {
+    fn seal(entry: AuditEntry) -> Result<Self, KrakenError> {
+        let hash = hash(&entry)?;
+        Ok(Self { entry, hash })
+    }
+}
+
+/// The inputs the records of a run were read from, appended to the log after them, so they can be traced back to
+/// the exact files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputsEntry {
+    pub seq: u64,
+    pub inputs: Vec<InputProvenance>,
+    pub prev: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputsRecord {
+    #[serde(flatten)]
+    pub entry: InputsEntry,
+    pub hash: String,
+}
+
+/// Any line of the log, each chained to the one before in the same way.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AuditLine {
+    Transaction(AuditRecord),
+    Inputs(InputsRecord),
+}
+
+/// Where an unbroken log ends: how many records it holds, and the hash of the last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditHead {
+    pub records: u64,
+    pub hash: String,
+}
+
+/// Replay `source` serially, appending a record of every transaction to the audit log at `path` as `AuditWriter`
+/// does. Returns the head of the log once the records are synced to disk.
+pub fn write_audit_log<S: InputSource>(
+    source: S,
+    path: &Path,
+    budget: Option<MemoryBudget>,
+    provenance: Option<&Provenance>,
+) -> Result<AuditHead, KrakenError> {
+    let mut log = AuditWriter::open(path)?;
+    replay(source, budget, |outcome| log.record(outcome))?;
+    log.finish(provenance)
+}
+
+/// Appends a record of every transaction to an audit log: whether it was applied or why it was rejected, and the
+/// client's balances right after it.
+///
+/// The log is JSON Lines, only ever appended to, and hash chained: each record holds the hash of the one before,
+/// so editing, removing, or reordering any record breaks every hash after it. The chain of an existing log is
+/// checked before it's extended. Records are staged in a file beside the log, and only appended to it by `finish`.
+pub struct AuditWriter {
+    path: PathBuf,
+    staged: BufWriter<NamedTempFile>,
+    head: AuditHead,
+}
+
+impl AuditWriter {
+    pub fn open(path: &Path) -> Result<Self, KrakenError> {
+        let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+        let head = match path.exists() {
+            true => verify_audit_log(path)?,
+            false => AuditHead { records: 0, hash: String::from(GENESIS) },
+        };
+        let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let staged = NamedTempFile::new_in(directory).map_err(|_| in_file(KrakenError::IO))?;
+        Ok(Self { path: path.to_path_buf(), staged: BufWriter::new(staged), head })
+    }
+
+    pub fn record(&mut self, outcome: &Outcome) -> Result<(), KrakenError> {
+        let account = outcome.account;
+        let (status, reason) = match outcome.result {
+            Ok(()) => ("applied", None),
+            Err(e) => ("rejected", Some(e.to_string())),
+        };
+        let record = AuditRecord::seal(AuditEntry {
+            seq: self.head.records + 1,
+            tx: outcome.tx,
+            client: match redact::enabled() {
+                true => AuditClient::Pseudonym(redact::client(u64::from(outcome.client))),
+                false => AuditClient::Id(outcome.client),
+            },
+            kind: outcome.kind.name().to_string(),
+            amount: outcome.amount.map(|amount| format!("{:.4}", Amount(amount))),
+            status: String::from(status),
+            reason,
+            // Memos are free text, which may say anything about the client
+            memo: outcome.memo.filter(|_| !redact::enabled()).map(String::from),
+            available: format!("{:.4}", Amount(account.available)),
+            held: format!("{:.4}", Amount(account.held)),
+            total: format!("{:.4}", Amount(account.total()?)),
+            locked: account.locked,
+            prev: self.head.hash.clone(),
+        })?;
+        serde_json::to_writer(&mut self.staged, &record).map_err(|_| KrakenError::IO)?;
+        writeln!(self.staged).map_err(|_| KrakenError::IO)?;
+        self.head = AuditHead { records: record.entry.seq, hash: record.hash };
+        Ok(())
+    }
+
+    /// Append the records to the log, followed, with the `provenance` of the input, by one of the inputs they were
+    /// read from. Returns the head of the log once the records are synced to disk.
+    pub fn finish(mut self, provenance: Option<&Provenance>) -> Result<AuditHead, KrakenError> {
+        let in_file = |e| KrakenError::InFile(self.path.display().to_string(), Box::new(e));
+        if let Some(provenance) = provenance {
+            let head = &self.head;
+            let entry = InputsEntry { seq: head.records + 1, inputs: provenance.inputs()?, prev: head.hash.clone() };
+            let record = InputsRecord { hash: hash(&entry)?, entry };
+            serde_json::to_writer(&mut self.staged, &record).map_err(|_| KrakenError::IO)?;
+            writeln!(self.staged).map_err(|_| KrakenError::IO)?;
+            self.head = AuditHead { records: record.entry.seq, hash: record.hash };
+        }
+
+        let staged = self.staged.into_inner().map_err(|_| in_file(KrakenError::IO))?;
+        let mut records = staged.reopen().map_err(|_| in_file(KrakenError::IO))?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).map_err(|_| in_file(KrakenError::IO))?;
+        std::io::copy(&mut records, &mut file).map_err(|_| in_file(KrakenError::IO))?;
+        file.sync_all().map_err(|_| in_file(KrakenError::IO))?;
+        Ok(self.head)
+    }
+}
+
+/// Check the hash chain of the audit log at `path`, from its first record to its last. Removing records from the
+/// end leaves the chain unbroken, so the head returned is worth keeping elsewhere to compare against.
+pub fn verify_audit_log(path: &Path) -> Result<AuditHead, KrakenError> {
+    let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+    let file = File::open(path).map_err(|_| in_file(KrakenError::IO))?;
+    let mut head = AuditHead { records: 0, hash: String::from(GENESIS) };
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|_| in_file(KrakenError::IO))?;
+        let seq = head.records + 1;
+        let broken = |reason: &str| in_file(KrakenError::AuditChain(seq, reason.to_string()));
+        let line: AuditLine = serde_json::from_str(&line).map_err(|e| broken(&e.to_string()))?;
+        let (numbered, prev, expected, record_hash) = match line {
+            AuditLine::Transaction(record) => (record.entry.seq, record.entry.prev.clone(), hash(&record.entry)?, record.hash),
+            AuditLine::Inputs(record) => (record.entry.seq, record.entry.prev.clone(), hash(&record.entry)?, record.hash),
+        };
+        if numbered != seq {
+            return Err(broken(&format!("numbered {numbered}")));
+        }
+        if prev != head.hash {
+            return Err(broken("doesn't follow the record before"));
+        }
+        if expected != record_hash {
+            return Err(broken("doesn't match its hash"));
+        }
+        head = AuditHead { records: seq, hash: record_hash };
+    }
+    Ok(head)
+}
+
+fn hash(entry: &impl Serialize) -> Result<String, KrakenError> {
+    let text = serde_json::to_vec(entry).map_err(|e| KrakenError::Parse(e.to_string()))?;
+    Ok(Sha256::digest(&text).iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::{verify_audit_log, write_audit_log, AuditLine};
+    use crate::input::CsvSource;
+    use crate::processor::tests::TEST_DIR;
+    use crate::provenance::Provenance;
+
+    #[test]
+    fn test_audit_log() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("audit.jsonl");
+        let open = || CsvSource::open(String::from(TEST_DIR) + "1-dispute-after-withdraw.csv", b',').unwrap();
+        let first = write_audit_log(open(), &path, None, None).unwrap();
+        // A second run extends the chain
+        let second = write_audit_log(open(), &path, None, None).unwrap();
+        assert_eq!(2 * first.records, second.records);
+        assert_eq!(second, verify_audit_log(&path).unwrap());
+        // A run knowing its inputs ends with a record of them, chained like the others
+        let provenance = Provenance::new(&[String::from(TEST_DIR) + "1-dispute-after-withdraw.csv"]);
+        let third = write_audit_log(open(), &path, None, Some(&provenance)).unwrap();
+        assert_eq!(second.records + first.records + 1, third.records);
+        assert_eq!(third, verify_audit_log(&path).unwrap());
+        let last: AuditLine = serde_json::from_str(std::fs::read_to_string(&path).unwrap().lines().last().unwrap()).unwrap();
+        assert!(matches!(last, AuditLine::Inputs(record) if record.entry.inputs[0].sha256.is_some()));
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let first_line = log.lines().next().unwrap();
+        assert!(first_line.contains(r#""status":"applied","reason":null,"available":"10.0000""#), "{first_line}");
+
+        // Editing a balance breaks the chain at that record
+        std::fs::write(&path, log.replacen(r#""available":"10.0000""#, r#""available":"99.0000""#, 1)).unwrap();
+        let error = verify_audit_log(&path).unwrap_err().to_string();
+        assert!(error.ends_with("Audit log broken at record 1: doesn't match its hash"), "{error}");
+        assert!(write_audit_log(open(), &path, None, None).is_err());
+    }
+}
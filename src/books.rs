@@ -0,0 +1,274 @@
+use crate::output::csv_row;
+use crate::snapshot::Snapshot;
+use crate::structures::{DisputeState, TransactionType};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+pub const HEADER: &str = "check,client,tx,detail";
+
+/// Amounts are posted and reported to four places, so anything smaller is what summing them left over.
+const TOLERANCE: f64 = 0.00005;
+
+/// An invariant the books break, with the client and tx it was found at, where there's one.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BookViolation {
+    /// What was checked, such as `held_mismatch`.
+    pub check: &'static str,
+    pub client: Option<u32>,
+    pub tx: Option<u32>,
+    pub detail: String,
+}
+
+impl fmt::Display for BookViolation {
+    /// A `check, client, tx, detail` CSV row, with the client and tx empty when there's none, and the detail quoted
+    /// when it holds a comma.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let client = self.client.map(|client| client.to_string()).unwrap_or_default();
+        let tx = self.tx.map(|tx| tx.to_string()).unwrap_or_default();
+        write!(f, "{}", csv_row(&[self.check, &client, &tx, &self.detail]))
+    }
+}
+
+fn violation(check: &'static str, client: Option<u32>, tx: Option<u32>, detail: String) -> BookViolation {
+    BookViolation { check, client, tx, detail }
+}
+
+/// What a client's accounts in a journal add up to, as the balances they stand for: liabilities are posted as
+/// negative amounts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JournalBalances {
+    pub available: f64,
+    pub held: f64,
+    pub pending: f64,
+}
+
+/// The balances of a journal written by `write_journal`, as it posts them to each client's accounts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JournalTotals {
+    pub clients: BTreeMap<u32, JournalBalances>,
+    /// What every client account adds up to, those of purged clients included.
+    pub total: f64,
+    pub entries: usize,
+}
+
+/// Check the accounts of `snapshot`, each on its own:
+///
+/// - `non_finite_balance`: every balance is a finite number
+/// - `negative_held`: held funds are never below zero
+/// - `held_mismatch`: held funds are what the open disputes, the authorizations, and the deposits and withdrawals
+///   held by a lock add up to
+/// - `orphaned_state`: only deposits and withdrawals with an amount are disputed, and only authorizations authorized,
+///   captured, or expired
+/// - `orphaned_pending`: pending deposits are deposits of the account's history, disputed only while marked so
+/// - `orphaned_hold`: what a lock holds is a deposit or withdrawal of the account's history, and the account is
+///   locked
+/// - `duplicate_tx`: a tx id is in the history of a single account, once
+pub fn check_snapshot(snapshot: &Snapshot) -> Vec<BookViolation> {
+    let mut violations = Vec::new();
+    let mut txs: HashMap<u32, u32> = HashMap::new();
+    for account in &snapshot.accounts {
+        let client = Some(account.client);
+        let (available, held, pending) = (account.available, account.held, account.pending());
+        if ![available, held, pending].iter().all(|amount| amount.is_finite()) {
+            let detail = format!("available {available}, held {held}, pending {pending}");
+            violations.push(violation("non_finite_balance", client, None, detail));
+            continue;
+        }
+        if held < -TOLERANCE {
+            violations.push(violation("negative_held", client, None, format!("held {held:.4}")));
+        }
+
+        let mut expected_held = account.holds.iter().map(|hold| hold.amount).sum::<f64>();
+        let mut entries = HashMap::with_capacity(account.history.len());
+        for entry in &account.history {
+            if let Some(other) = txs.insert(entry.tx, account.client) {
+                let detail = match other == account.client {
+                    true => String::from("listed twice in the history"),
+                    false => format!("also in the history of client {other}"),
+                };
+                violations.push(violation("duplicate_tx", client, Some(entry.tx), detail));
+            }
+            entries.insert(entry.tx, entry);
+
+            let Some(state) = entry.state else { continue };
+            let disputable = matches!(entry.kind, TransactionType::Deposit | TransactionType::Withdrawal);
+            let orphaned = match state {
+                DisputeState::Open | DisputeState::Resolved | DisputeState::ChargedBack | DisputeState::Reversed => {
+                    !disputable
+                }
+                DisputeState::Authorized | DisputeState::Captured | DisputeState::Expired => {
+                    entry.kind != TransactionType::Authorize
+                }
+            };
+            if orphaned || entry.amount.is_none() {
+                let detail = format!("{} in state {state:?}, amount {:?}", entry.kind.name(), entry.amount);
+                violations.push(violation("orphaned_state", client, Some(entry.tx), detail));
+            } else if matches!(state, DisputeState::Open | DisputeState::Authorized) {
+                expected_held += entry.amount.unwrap_or_default();
+            }
+        }
+        if (held - expected_held).abs() > TOLERANCE {
+            let detail = format!("held {held:.4}, while open disputes and authorizations hold {expected_held:.4}");
+            violations.push(violation("held_mismatch", client, None, detail));
+        }
+
+        for pending in &account.pending {
+            let deposit = entries.get(&pending.tx);
+            let state = match pending.disputed {
+                true => |state| state == Some(DisputeState::Open),
+                false => |state| matches!(state, None | Some(DisputeState::Resolved)),
+            };
+            if !deposit.is_some_and(|entry| entry.kind == TransactionType::Deposit && state(entry.state)) {
+                let detail = format!("pending {:.4} without a deposit in that state", pending.amount);
+                violations.push(violation("orphaned_pending", client, Some(pending.tx), detail));
+            }
+        }
+        for hold in &account.holds {
+            let held = entries.get(&hold.tx);
+            if !held.is_some_and(|entry| entry.kind == hold.kind) || !account.locked {
+                let detail = match account.locked {
+                    true => format!("{} of {:.4} held by a lock, not in the history", hold.kind.name(), hold.amount),
+                    false => format!("{} of {:.4} held by a lock of an unlocked account", hold.kind.name(), hold.amount),
+                };
+                violations.push(violation("orphaned_hold", client, Some(hold.tx), detail));
+            }
+        }
+    }
+    violations
+}
+
+/// Add up `journal`, as written by `write_journal` in either format, checking that every posting has an account
+/// and an amount (`malformed_posting`), and every entry balances (`unbalanced_entry`).
+pub fn check_journal(journal: &str) -> (JournalTotals, Vec<BookViolation>) {
+    let mut totals = JournalTotals::default();
+    let mut violations = Vec::new();
+    let mut lines = journal.lines().peekable();
+    while let Some(line) = lines.next() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [_, "*", description @ ..] = fields.as_slice() else { continue };
+        totals.entries += 1;
+        let description = description.join(" ");
+        let description = description.trim_matches('"');
+        let mut sum = 0.0;
+        while let Some(posting) = lines.next_if(|line| line.starts_with(' ')) {
+            let fields: Vec<&str> = posting.split_whitespace().collect();
+            let amount = fields.get(1).and_then(|amount| amount.parse::<f64>().ok()).filter(|amount| amount.is_finite());
+            let (Some(account), Some(amount)) = (fields.first(), amount) else {
+                let detail = format!("posting of {description} without an account and amount: {}", posting.trim());
+                violations.push(violation("malformed_posting", None, None, detail));
+                continue;
+            };
+            sum += amount;
+            let Some(rest) = account.strip_prefix("Liabilities:Clients:") else { continue };
+            totals.total -= amount;
+            // Purged clients only count in the total
+            let Some((client, balance)) = rest.split_once(':') else { continue };
+            let Ok(client) = client.parse() else { continue };
+            let balances = totals.clients.entry(client).or_default();
+            match balance {
+                "Available" => balances.available -= amount,
+                "Held" => balances.held -= amount,
+                _ => balances.pending -= amount,
+            }
+        }
+        if sum.abs() > TOLERANCE {
+            let detail = format!("postings of {description} sum to {sum:.4}");
+            violations.push(violation("unbalanced_entry", None, None, detail));
+        }
+    }
+    (totals, violations)
+}
+
+/// Compare what a journal posted with the balances of `snapshot`, for a journal covering every transaction the
+/// snapshot holds: each client's available, held, and pending funds (`journal_mismatch`), and the sum of every
+/// balance, tombstones included, with that of every client account (`totals_mismatch`).
+pub fn compare(snapshot: &Snapshot, totals: &JournalTotals) -> Vec<BookViolation> {
+    let mut violations = Vec::new();
+    let mut clients: BTreeMap<u32, (JournalBalances, JournalBalances)> = BTreeMap::new();
+    for account in &snapshot.accounts {
+        let balances = JournalBalances { available: account.available, held: account.held, pending: account.pending() };
+        clients.entry(account.client).or_default().0 = balances;
+    }
+    for (client, balances) in &totals.clients {
+        clients.entry(*client).or_default().1 = balances.clone();
+    }
+    for (client, (balances, posted)) in &clients {
+        let fields = [
+            ("available", balances.available, posted.available),
+            ("held", balances.held, posted.held),
+            ("pending", balances.pending, posted.pending),
+        ];
+        let differences: Vec<String> = fields
+            .iter()
+            .filter(|(_, balance, posted)| (balance - posted).abs() > TOLERANCE)
+            .map(|(field, balance, posted)| format!("{field} {balance:.4} but posted {posted:.4}"))
+            .collect();
+        if !differences.is_empty() {
+            violations.push(violation("journal_mismatch", Some(*client), None, differences.join(", ")));
+        }
+    }
+
+    let accounts = snapshot.accounts.iter().map(|account| account.available + account.held + account.pending());
+    let tombstones = snapshot.tombstones.iter().map(|tombstone| tombstone.available + tombstone.held + tombstone.pending);
+    let balances: f64 = accounts.chain(tombstones).sum();
+    if (balances - totals.total).abs() > TOLERANCE {
+        let detail = format!("balances add up to {balances:.4}, the journal's client accounts to {:.4}", totals.total);
+        violations.push(violation("totals_mismatch", None, None, detail));
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::books::{check_journal, check_snapshot, compare, HEADER};
+    use crate::input::CsvSource;
+    use crate::journal::{write_journal, JournalFormat, JournalOptions};
+    use crate::processor::tests::TEST_DIR;
+    use crate::processor::{compute_account_totals, ProcessorConfig};
+    use crate::snapshot::Snapshot;
+    use crate::structures::DisputeState;
+
+    #[test]
+    fn test_check_books() {
+        let path = String::from(TEST_DIR) + "2-chargeback-after-withdraw.csv";
+        let accounts = compute_account_totals(&path, &ProcessorConfig::default()).unwrap();
+        let mut snapshot = Snapshot::capture(&accounts).unwrap();
+        assert!(check_snapshot(&snapshot).is_empty());
+        let mut journal = String::new();
+        for format in [JournalFormat::Ledger, JournalFormat::Beancount] {
+            let options = JournalOptions { format, date: String::from("2024-01-31"), commodity: String::from("USD") };
+            let mut written = Vec::new();
+            write_journal(CsvSource::open(path.clone(), b',').unwrap(), &mut written, &options, None).unwrap();
+            journal = String::from_utf8(written).unwrap();
+            let (totals, violations) = check_journal(&journal);
+            assert!(totals.entries == 5 && violations.is_empty(), "{violations:?}");
+            assert!(compare(&snapshot, &totals).is_empty());
+        }
+
+        // Held funds no dispute explains, a state a deposit can't be in, and an entry that doesn't balance
+        snapshot.accounts[0].held += 1.0;
+        let entry = snapshot.accounts[0].history.iter_mut().find(|entry| entry.state.is_none()).unwrap();
+        entry.state = Some(DisputeState::Captured);
+        let checks: Vec<_> = check_snapshot(&snapshot).into_iter().map(|violation| violation.check).collect();
+        assert_eq!(vec!["orphaned_state", "held_mismatch"], checks);
+        let (totals, violations) = check_journal(&journal.replacen(" -10.0000", " -12.0000", 1));
+        assert_eq!(1, violations.len());
+        let expected = "unbalanced_entry,,,postings of deposit tx 0 for client 1 sum to -2.0000";
+        assert_eq!(expected, violations[0].to_string());
+        snapshot.accounts[0].available -= 1.0;
+        let mismatches = compare(&snapshot, &totals);
+        let checks: Vec<_> = mismatches.iter().map(|violation| violation.check).collect();
+        assert_eq!(vec!["journal_mismatch", "totals_mismatch"], checks);
+        // A detail listing several differences keeps its commas, quoted
+        let row = format!("{HEADER}\n{}", mismatches[0]);
+        let record = csv::Reader::from_reader(row.as_bytes()).records().next().unwrap().unwrap();
+        assert!(record[3].contains(", ") && mismatches[0].detail == record[3], "{row}");
+
+        // An amount that isn't a number is reported, not read as zero
+        let (_, violations) = check_journal(&journal.replacen(" 10.0000", " 1O.0000", 1));
+        let checks: Vec<_> = violations.iter().map(|violation| violation.check).collect();
+        assert_eq!(vec!["malformed_posting", "unbalanced_entry"], checks);
+        assert!(violations[0].detail.contains("deposit tx 0 for client 1") && violations[0].detail.contains("1O.0000"));
+    }
+}
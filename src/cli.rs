@@ -0,0 +1,1593 @@
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use paymentprocessor::aliases::Aliases;
+use paymentprocessor::config::{ConfigFile, InputConfig, CONFIG_VAR, ENV_PREFIX};
+use paymentprocessor::dates::parse_timestamp;
+use paymentprocessor::encryption::{EncryptionKey, KEY_VAR};
+use paymentprocessor::errors::{FailOn, KrakenError};
+use paymentprocessor::errors::KrakenError::InvalidArgument;
+use paymentprocessor::follow::DEFAULT_FLUSH_INTERVAL;
+use paymentprocessor::generate::{AmountDistribution, ClientDistribution, GeneratorConfig};
+use paymentprocessor::ids::IdKind;
+use paymentprocessor::input::{InputFormat, InputOptions, ReaderKind, TypeFilter, STDIN};
+use paymentprocessor::journal::JournalFormat;
+use paymentprocessor::logging::{Diagnostics, LogConfig, LogLevel};
+use paymentprocessor::mapping::SchemaMapping;
+use paymentprocessor::minor_units::AmountBackend;
+use paymentprocessor::range::{Bound, Range};
+use paymentprocessor::ranking::RankBy;
+use paymentprocessor::settlement::Cycle;
+use paymentprocessor::rules::Rules;
+use paymentprocessor::output::OutputFormat;
+use paymentprocessor::signature::sidecar;
+use paymentprocessor::structures::TransactionType;
+use paymentprocessor::time_travel::AsOf;
+#[cfg(feature = "queue")]
+use paymentprocessor::queue::{Broker, ConsumeConfig, DEFAULT_BROKER, DEFAULT_CHECKPOINT_INTERVAL};
+use paymentprocessor::processor::{ParallelMode, ProcessorConfig};
+#[cfg(feature = "server")]
+use paymentprocessor::server::ServerConfig;
+#[cfg(feature = "server")]
+use paymentprocessor::webhook::WebhookConfig;
+use std::ffi::OsString;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Apply a stream of deposits, withdrawals, disputes, resolves, and chargebacks, and report every client's
+/// final balances.
+#[derive(Debug, Parser)]
+#[command(name = "paymentprocessor", version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Subcommands>,
+    /// Processing without a subcommand is the same as `process`.
+    #[command(flatten)]
+    process: ProcessArgs,
+    /// Least severe level logged to stderr: error, warn, info, debug (also each refused transaction), or trace.
+    /// Overrides RUST_LOG. Defaults to info.
+    #[arg(long, global = true, value_name = "LEVEL", value_parser = choice::<LogLevel>)]
+    log_level: Option<LogLevel>,
+    /// Log as text (the default), or as one JSON record per line, with a code, tx, client, and line where they
+    /// apply.
+    #[arg(long, global = true, value_name = "FORMAT", value_parser = choice::<Diagnostics>)]
+    diagnostics: Option<Diagnostics>,
+    /// Replace client ids with pseudonyms, and amounts with their order of magnitude, in logs, errors, and the
+    /// audit log, and leave memos out of them.
+    #[arg(long, global = true)]
+    redact: bool,
+    /// TOML file of defaults for the options, which PAYPROC_* variables and the options given on the command line
+    /// override. PAYPROC_CONFIG by default.
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// File holding the AES-256 key, as 64 hex digits, that state snapshots and journals are encrypted with, and
+    /// encrypted ones decrypted with. PAYPROC_ENCRYPTION_KEY, holding the key itself, by default.
+    #[arg(long, global = true, value_name = "PATH")]
+    encryption_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Subcommands {
+    /// Apply transactions and report the final balances. The default when no subcommand is given.
+    Process(Box<ProcessArgs>),
+    /// Check inputs for malformed rows and dangling references without applying them. Exits with an error if
+    /// any problem is found.
+    Validate(ValidateArgs),
+    /// Profile inputs without applying them.
+    Stats(StatsArgs),
+    /// Apply inputs on top of a saved state snapshot, then save the result back to it.
+    Replay(ReplayArgs),
+    /// Merge state snapshots of disjoint work, such as those of sharded workers, into one.
+    Merge(MergeArgs),
+    /// Compare two reports or state snapshots, printing how each client's balances and lock state changed.
+    Diff(DiffArgs),
+    /// List the accounts of a state snapshot with no activity for a number of days, with their balances.
+    Dormant(DormantArgs),
+    /// Check that the hash chain of an `--audit-log` is unbroken, printing its record count and last hash.
+    VerifyAudit(VerifyAuditArgs),
+    /// Check that a report matches the signature written by `--signing-key`. Exits with an error if it doesn't.
+    VerifyReport(VerifyReportArgs),
+    /// Decrypt a state snapshot or journal encrypted with `--encryption-key`, printing it.
+    Decrypt(DecryptArgs),
+    /// Remove a client's history from state snapshots and journals for a deletion request, leaving tombstones that
+    /// keep their totals.
+    PurgeClient(PurgeClientArgs),
+    /// Check the accounting invariants of a state snapshot, a journal, or both against each other, printing every
+    /// violation. Exits with an error if any is found.
+    CheckBooks(CheckBooksArgs),
+    /// Print the balances as of an earlier point of an event stream written by `--events`, replaying it up to there.
+    AsOf(AsOfArgs),
+    /// Write synthetic transactions as CSV, for load tests and reproducing bug reports without real data.
+    Generate(GenerateArgs),
+    /// Run every directory holding an `input.csv` and an `expected.csv` under a directory as a test case,
+    /// printing how each went. Exits with an error if any case fails.
+    Test(TestArgs),
+    /// Apply transactions from a message broker, checkpointing to a state snapshot.
+    #[cfg(feature = "queue")]
+    Consume(ConsumeArgs),
+    /// Run as a service, applying transactions posted to an HTTP REST API and answering balance queries.
+    #[cfg(feature = "server")]
+    Serve(ServeArgs),
+}
+
+/// Options shared by every command reading input files.
+#[derive(Debug, Args)]
+struct InputArgs {
+    /// Input format, detected from each file's extension by default: csv, jsonl, parquet, ipc, xlsx, iso20022,
+    /// ofx, or qif.
+    #[arg(long, value_name = "FORMAT", value_parser = choice::<InputFormat>)]
+    format: Option<InputFormat>,
+    /// CSV field delimiter: a single character, or `tab`. Detected from `.tsv` and `.psv` extensions by default.
+    #[arg(long, value_name = "CHAR", value_parser = parse_delimiter)]
+    delimiter: Option<u8>,
+    /// Worksheet of workbook input, the first one by default.
+    #[arg(long, value_name = "NAME")]
+    sheet: Option<String>,
+    /// Layout of CSV input, only given by the config file.
+    #[arg(skip)]
+    mapping: Option<Arc<SchemaMapping>>,
+}
+
+/// The part of the input to apply, for partial replays.
+#[derive(Debug, Args)]
+struct RangeArgs {
+    /// Skip the input before this row:N (counting from 1 across every input), tx:N, or date or time. Disputes,
+    /// resolves, and chargebacks are placed by the tx they refer to, and a time bound refuses rows without a time.
+    #[arg(long, value_name = "BOUND", value_parser = choice::<Bound>)]
+    from: Option<Bound>,
+    /// Skip the input after this row:N, tx:N, or date or time, included. A date includes the whole day.
+    #[arg(long, value_name = "BOUND", value_parser = choice::<Bound>)]
+    to: Option<Bound>,
+}
+
+impl RangeArgs {
+    fn into_range(self) -> Option<Range> {
+        (self.from.is_some() || self.to.is_some()).then_some(Range { from: self.from, to: self.to })
+    }
+}
+
+impl InputArgs {
+    /// Fill in every option not given on the command line from `config`.
+    fn with_config(self, config: &InputConfig, mapping: Option<&SchemaMapping>) -> Result<Self, KrakenError> {
+        Ok(Self {
+            format: or_config(self.format, &config.format, choice)?,
+            delimiter: or_config(self.delimiter, &config.delimiter, parse_delimiter)?,
+            sheet: self.sheet.or_else(|| config.sheet.clone()),
+            mapping: match mapping {
+                Some(mapping) => {
+                    mapping.check()?;
+                    Some(Arc::new(mapping.clone()))
+                }
+                None => None,
+            },
+        })
+    }
+
+    fn into_options(self, reader: Option<ReaderKind>) -> InputOptions {
+        InputOptions {
+            reader: reader.unwrap_or_default(),
+            format: self.format,
+            delimiter: self.delimiter,
+            sheet: self.sheet,
+            mapping: self.mapping,
+            ids: None,
+            aliases: None,
+            clients: None,
+            range: None,
+            types: None,
+            skip_malformed: false,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct ProcessArgs {
+    /// Input files or quoted glob patterns, processed in order as one stream. `-` reads stdin.
+    #[arg(value_name = "PATH")]
+    paths: Vec<String>,
+    /// Process the files matching PATTERN as the separate book of tenant NAME, instead of PATH. Given once per
+    /// pattern, with the tenants processed in the order first named.
+    #[arg(long, value_name = "NAME=PATTERN", value_parser = parse_tenant)]
+    tenant: Vec<(String, String)>,
+    #[command(flatten)]
+    input: InputArgs,
+    /// Parallel strategy: serial, threads, rayon, or actors.
+    #[arg(long, value_name = "MODE", value_parser = choice::<ParallelMode>)]
+    parallel: Option<ParallelMode>,
+    /// Worker threads, all available cores by default.
+    #[arg(long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+    /// Memory budget for transaction histories, such as `512M` or `2G`. Histories spill to disk beyond it.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// CSV reader: polars, fast, or csv.
+    #[arg(long, value_name = "READER", value_parser = choice::<ReaderKind>)]
+    reader: Option<ReaderKind>,
+    /// How the client and tx ids of CSV input are written: numeric (the default), or string, for UUIDs and other
+    /// textual ids.
+    #[arg(long, value_name = "KIND", value_parser = choice::<IdKind>)]
+    ids: Option<IdKind>,
+    /// CSV file of `alias, client` rows, each declaring client `alias` the same entity as `client`, under whose id
+    /// its transactions are applied and reported.
+    #[arg(long, value_name = "PATH")]
+    aliases: Option<PathBuf>,
+    /// Only process and report these clients, comma separated, skipping the rows of every other client as they're
+    /// read.
+    #[arg(long, value_name = "CLIENT", value_delimiter = ',')]
+    client: Vec<u32>,
+    #[command(flatten)]
+    range: RangeArgs,
+    /// Only apply transactions of these types, comma separated, such as deposit,withdrawal.
+    #[arg(long, value_name = "TYPE", value_parser = choice::<TransactionType>, value_delimiter = ',')]
+    only: Vec<TransactionType>,
+    /// Apply every transaction but those of these types, comma separated, such as chargeback, to see what the
+    /// balances would be without them.
+    #[arg(long, value_name = "TYPE", value_parser = choice::<TransactionType>, value_delimiter = ',')]
+    #[arg(conflicts_with = "only")]
+    ignore: Vec<TransactionType>,
+    /// Read an http(s):// or s3:// URL, as if it were given as a path.
+    #[cfg(feature = "remote")]
+    #[arg(long, value_name = "URL", value_parser = parse_url)]
+    input_url: Vec<String>,
+    /// Run the tokio pipeline instead of the partitioned one.
+    #[arg(long = "async")]
+    asynchronous: bool,
+    /// Also run the input serially and fail unless the final balances match.
+    #[arg(long)]
+    verify: bool,
+    /// Add up and compare amounts as `f64` (`float`, the default), or as whole ten-thousandths (`minor`), with
+    /// checked arithmetic, so no transaction is decided by rounding.
+    #[arg(long, value_name = "BACKEND", value_parser = choice::<AmountBackend>)]
+    amounts: Option<AmountBackend>,
+    /// Check the balances after every transaction, aborting with a dump of the account on the first that breaks an
+    /// invariant. Slows processing down.
+    #[arg(long)]
+    check_invariants: bool,
+    /// Add a pending column to the report, after held, with the deposits not yet available under the deposit delay
+    /// rules. Not for reports of several tenants or of string ids.
+    #[arg(long)]
+    show_pending: bool,
+    /// Keep the file open and apply rows as they are appended, reprinting the report as it changes.
+    #[arg(long)]
+    follow: bool,
+    /// Minimum seconds between two reports in follow mode, 1 by default.
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    flush_interval: Option<Duration>,
+    /// Report format: csv, json, jsonl, table, or parquet.
+    #[arg(long, value_name = "FORMAT", value_parser = choice::<OutputFormat>)]
+    output_format: Option<OutputFormat>,
+    /// Write the report to a file instead of stdout.
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Sign the report with the HMAC-SHA256 key held in this file, writing the signature next to it, with `.sig`
+    /// appended to its name.
+    #[arg(long, value_name = "PATH")]
+    signing_key: Option<PathBuf>,
+    /// Also write one statement per client into this directory.
+    #[arg(long, value_name = "DIR")]
+    statements: Option<PathBuf>,
+    /// Also append a hash-chained record of every transaction, its outcome, and the resulting balances to this
+    /// JSON Lines file.
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+    /// Also write a change event of every applied transaction, with its client's balances before and after it, to
+    /// this JSON Lines file.
+    #[arg(long, value_name = "PATH")]
+    events: Option<PathBuf>,
+    /// Also write every dispute left open, with the amount it holds and how long it has been open, to this CSV file.
+    #[arg(long, value_name = "PATH")]
+    dispute_aging: Option<PathBuf>,
+    /// Also write each client's balances and what it deposited and withdrew at the end of every settlement cycle to
+    /// this CSV file.
+    #[arg(long, value_name = "PATH")]
+    settlement: Option<PathBuf>,
+    /// Settlement cycle: day, by the time of the transactions (the default), or a number of rows.
+    #[arg(long, value_name = "CYCLE", value_parser = choice::<Cycle>, requires = "settlement")]
+    settle_every: Option<Cycle>,
+    /// Also save the final accounts as a state snapshot, which `replay --state` continues from, and which resumes
+    /// an interrupted run.
+    #[arg(long, value_name = "PATH")]
+    snapshot: Option<PathBuf>,
+    /// Also write a double-entry journal of every applied transaction.
+    #[arg(long, value_name = "PATH")]
+    journal: Option<PathBuf>,
+    /// Journal format, ledger or beancount, detected from the journal's extension by default.
+    #[arg(long, value_name = "FORMAT", value_parser = choice::<JournalFormat>, requires = "journal")]
+    journal_format: Option<JournalFormat>,
+    /// Date of every journal entry, today by default.
+    #[arg(long, value_name = "YYYY-MM-DD", value_parser = parse_iso_date, requires = "journal")]
+    journal_date: Option<String>,
+    /// Commodity every journal amount is denominated in, USD by default.
+    #[arg(long, value_name = "NAME", value_parser = parse_commodity)]
+    journal_commodity: Option<String>,
+    /// Print throughput, stage timings, and peak memory to stderr at the end of the run.
+    #[arg(long)]
+    metrics: bool,
+    /// Write the same metrics to a file, as JSON.
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+    /// Push the metrics to a Prometheus Pushgateway at an http:// URL, such as
+    /// http://localhost:9091/metrics/job/paymentprocessor.
+    #[arg(long, value_name = "URL", value_parser = parse_push_url)]
+    metrics_push: Option<String>,
+    /// Print the N largest accounts, ranked by --by, to stderr at the end of the run.
+    #[arg(long, value_name = "N")]
+    top_n: Option<usize>,
+    /// What --top-n ranks accounts by: total (the default), held, or volume, the amount deposited and withdrawn.
+    #[arg(long, value_name = "MEASURE", value_parser = choice::<RankBy>, requires = "top_n")]
+    by: Option<RankBy>,
+    /// Print the accounts with a reserve under the [rules] whose available funds are no more than MARGIN above it,
+    /// or below it, to stderr at the end of the run.
+    #[arg(long, value_name = "MARGIN", allow_negative_numbers = true)]
+    near_reserve: Option<f64>,
+    /// Print every account whose available funds went negative during the run, with how far, to stderr at the end
+    /// of the run.
+    #[arg(long)]
+    negative_balances: bool,
+    /// Never draw a progress bar, even when stderr is a terminal.
+    #[arg(long)]
+    no_progress: bool,
+    /// CSV file of the balances each client is expected to end with, laid out like the report. Every client
+    /// whose balances differ is reported, and the run fails unless the books balance.
+    #[arg(long, value_name = "PATH")]
+    reconcile: Option<PathBuf>,
+    /// Conditions failing the run, comma separated: parse-error (the default), rejected-tx, locked-account,
+    /// assertion, or never. Without parse-error, malformed rows are skipped with a warning, by the reports and sinks
+    /// alike.
+    #[arg(long, value_name = "CONDITION", value_parser = choice::<FailOn>, value_delimiter = ',')]
+    fail_on: Vec<FailOn>,
+    /// Also upsert the final balances into a `sqlite://` or `postgres://` database.
+    #[cfg(feature = "database")]
+    #[arg(long, value_name = "URL")]
+    database: Option<String>,
+    /// Table the balances are upserted into, `accounts` by default.
+    #[cfg(feature = "database")]
+    #[arg(long, value_name = "NAME", value_parser = parse_identifier)]
+    database_table: Option<String>,
+}
+
+impl ProcessArgs {
+    /// Fill in every option not given on the command line from `config`.
+    fn with_config(mut self, config: &ConfigFile) -> Result<Self, KrakenError> {
+        self.input = self.input.with_config(&config.input, config.mapping.as_ref())?;
+        self.reader = or_config(self.reader, &config.input.reader, choice)?;
+        self.ids = or_config(self.ids, &config.input.ids, choice)?;
+        self.aliases = self.aliases.or_else(|| config.input.aliases.clone());
+        if self.client.is_empty() {
+            self.client = config.input.clients.clone().unwrap_or_default();
+        }
+        self.parallel = or_config(self.parallel, &config.processing.parallel, choice)?;
+        self.threads = self.threads.or(config.processing.threads);
+        self.amounts = or_config(self.amounts, &config.processing.amounts, choice)?;
+        self.max_memory = or_config(self.max_memory, &config.limits.max_memory, parse_size)?;
+        self.reconcile = self.reconcile.or_else(|| config.processing.reconcile.clone());
+        // Inputs given on the command line replace the configured tenants, as they replace each other
+        #[allow(unused_mut)]
+        let mut inputs = !self.paths.is_empty() || !self.tenant.is_empty();
+        #[cfg(feature = "remote")]
+        {
+            inputs |= !self.input_url.is_empty();
+        }
+        if !inputs {
+            for (name, patterns) in &config.tenants {
+                let name = parse_tenant_name(name)?;
+                self.tenant.extend(patterns.iter().map(|pattern| (name.clone(), pattern.clone())));
+            }
+        }
+        if self.fail_on.is_empty() {
+            self.fail_on = config.processing.fail_on.iter().flatten().map(|c| choice(c)).collect::<Result<_, _>>()?;
+        }
+
+        let output = &config.output;
+        self.output_format = or_config(self.output_format, &output.format, choice)?;
+        self.output = self.output.or_else(|| output.path.clone());
+        self.signing_key = self.signing_key.or_else(|| output.signing_key.clone());
+        self.statements = self.statements.or_else(|| output.statements.clone());
+        self.audit_log = self.audit_log.or_else(|| output.audit_log.clone());
+        self.events = self.events.or_else(|| output.events.clone());
+        self.dispute_aging = self.dispute_aging.or_else(|| output.dispute_aging.clone());
+        self.settlement = self.settlement.or_else(|| output.settlement.clone());
+        self.settle_every = or_config(self.settle_every, &output.settle_every, choice)?;
+        self.snapshot = self.snapshot.or_else(|| output.snapshot.clone());
+        self.journal = self.journal.or_else(|| output.journal.clone());
+        self.journal_format = or_config(self.journal_format, &output.journal_format, choice)?;
+        self.journal_commodity = or_config(self.journal_commodity, &output.journal_commodity, parse_commodity)?;
+        self.metrics_file = self.metrics_file.or_else(|| output.metrics_file.clone());
+        self.top_n = self.top_n.or(output.top_n);
+        self.by = or_config(self.by, &output.top_n_by, choice)?;
+        self.near_reserve = self.near_reserve.or(output.near_reserve);
+        self.metrics_push = or_config(self.metrics_push, &output.metrics_push, parse_push_url)?;
+        #[cfg(feature = "database")]
+        {
+            self.database = self.database.or_else(|| output.database.clone());
+            self.database_table = or_config(self.database_table, &output.database_table, parse_identifier)?;
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Args)]
+struct StatsArgs {
+    /// Input files or quoted glob patterns. `-` reads stdin.
+    #[arg(value_name = "PATH", required = true)]
+    paths: Vec<String>,
+    #[command(flatten)]
+    input: InputArgs,
+    /// Print the summary as a JSON object instead of aligned text.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct ValidateArgs {
+    /// Input files or quoted glob patterns, checked in order as one stream. `-` reads stdin.
+    #[arg(value_name = "PATH", required = true)]
+    paths: Vec<String>,
+    #[command(flatten)]
+    input: InputArgs,
+    /// Print each problem as a JSON object, one per line, instead of as text.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct MergeArgs {
+    /// State snapshots to merge.
+    #[arg(value_name = "SNAPSHOT", required = true)]
+    snapshots: Vec<PathBuf>,
+    /// Snapshot to save the result to. It may be one of those merged.
+    #[arg(long, value_name = "PATH")]
+    output: PathBuf,
+    /// CSV file of `alias, client` rows, each declaring client `alias` the same entity as `client`, whose account
+    /// its own is merged into.
+    #[arg(long, value_name = "PATH")]
+    aliases: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct DiffArgs {
+    /// Earlier report, as CSV, JSON, or JSON Lines, or state snapshot (`.json`).
+    #[arg(value_name = "BEFORE")]
+    before: PathBuf,
+    /// Later report or state snapshot.
+    #[arg(value_name = "AFTER")]
+    after: PathBuf,
+    /// Print each change as a JSON object, one per line, instead of as CSV.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct DormantArgs {
+    /// State snapshot of the accounts.
+    #[arg(value_name = "SNAPSHOT")]
+    snapshot: PathBuf,
+    /// Days without activity that make an account dormant.
+    #[arg(long, value_name = "N")]
+    days: u64,
+    /// Date (`YYYY-MM-DD`) or RFC 3339 time the report is as of, now by default.
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    as_of: Option<i64>,
+    /// Print each account as a JSON object, one per line, instead of as CSV.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct TestArgs {
+    /// Directory of test cases, searched recursively.
+    #[arg(value_name = "DIR")]
+    directory: PathBuf,
+    /// Parallel strategy to run every case with: serial, threads, rayon, or actors.
+    #[arg(long, value_name = "MODE", value_parser = choice::<ParallelMode>)]
+    parallel: Option<ParallelMode>,
+    /// Worker threads, all available cores by default.
+    #[arg(long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+    /// CSV reader: polars, fast, or csv.
+    #[arg(long, value_name = "READER", value_parser = choice::<ReaderKind>)]
+    reader: Option<ReaderKind>,
+}
+
+#[derive(Debug, Args)]
+struct VerifyAuditArgs {
+    /// Audit log written by `--audit-log`.
+    #[arg(value_name = "PATH")]
+    path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct VerifyReportArgs {
+    /// Report written with `--signing-key`.
+    #[arg(value_name = "PATH")]
+    report: PathBuf,
+    /// File holding the key the report was signed with.
+    #[arg(long, value_name = "PATH")]
+    signing_key: PathBuf,
+    /// Signature of the report, the report's path with `.sig` appended by default.
+    #[arg(long, value_name = "PATH")]
+    signature: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct DecryptArgs {
+    /// Encrypted snapshot or journal.
+    #[arg(value_name = "PATH")]
+    path: PathBuf,
+    /// Write the decrypted file here instead of to stdout.
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct PurgeClientArgs {
+    /// Client to purge.
+    #[arg(value_name = "CLIENT")]
+    client: u32,
+    /// State snapshot to purge the client from, in place. May be given more than once.
+    #[arg(long = "state", value_name = "PATH")]
+    snapshots: Vec<PathBuf>,
+    /// Journal to purge the client from, in place. May be given more than once.
+    #[arg(long = "journal", value_name = "PATH")]
+    journals: Vec<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct CheckBooksArgs {
+    /// State snapshot to check.
+    #[arg(long = "state", value_name = "PATH")]
+    snapshot: Option<PathBuf>,
+    /// Journal to check, and, with --state, to compare with the snapshot's balances.
+    #[arg(long, value_name = "PATH")]
+    journal: Option<PathBuf>,
+    /// Print each violation as a JSON object, one per line, instead of as CSV.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct AsOfArgs {
+    /// Point to print the balances at: `seq:N`, right after event N, or `tx:N`, right after the transaction bringing
+    /// tx id N.
+    #[arg(value_name = "POINT", value_parser = choice::<AsOf>)]
+    point: AsOf,
+    /// Event stream written by `--events`.
+    #[arg(value_name = "EVENTS")]
+    events: PathBuf,
+    /// Only print the balances of this client.
+    #[arg(long, value_name = "CLIENT")]
+    client: Option<u32>,
+    /// Checkpoints of the event stream to replay from, written first if the file doesn't exist.
+    #[arg(long, value_name = "PATH")]
+    checkpoints: Option<PathBuf>,
+    /// Events between checkpoints, when writing them.
+    #[arg(long, value_name = "N", default_value_t = 10_000, value_parser = clap::value_parser!(u64).range(1..))]
+    checkpoint_every: u64,
+    /// Print each client's balances as a JSON object, one per line, instead of as CSV.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct ReplayArgs {
+    /// Input files or quoted glob patterns, applied in order. `-` reads stdin.
+    #[arg(value_name = "PATH", required = true)]
+    paths: Vec<String>,
+    #[command(flatten)]
+    input: InputArgs,
+    /// Snapshot to start from, if it exists, and to save the result to.
+    #[arg(long, value_name = "PATH")]
+    state: PathBuf,
+    /// Skip every transaction the state has already seen, by the idempotency key of a mapped input, or else by its
+    /// type and tx id, and keep their keys in the state for later replays.
+    #[arg(long)]
+    idempotent: bool,
+    /// Index of the tx ids every client has used, loaded if it exists and saved back: a deposit, withdrawal, or
+    /// other transaction reusing one of its client's is refused as a duplicate, whichever file used it first.
+    #[arg(long, value_name = "PATH")]
+    tx_index: Option<PathBuf>,
+    /// CSV file of `alias, client` rows, each declaring client `alias` the same entity as `client`, whose account
+    /// in the state its own is merged into.
+    #[arg(long, value_name = "PATH")]
+    aliases: Option<PathBuf>,
+    #[command(flatten)]
+    range: RangeArgs,
+    /// Memory budget for transaction histories, such as `512M` or `2G`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// Report format: csv, json, jsonl, table, or parquet.
+    #[arg(long, value_name = "FORMAT", value_parser = choice::<OutputFormat>)]
+    output_format: Option<OutputFormat>,
+    /// Write the report to a file instead of stdout.
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct GenerateArgs {
+    /// Rows to write.
+    #[arg(long, default_value_t = 1000)]
+    rows: u64,
+    /// Clients to spread the rows across, numbered from 1.
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+    clients: u32,
+    /// Share of new transactions that are withdrawals, between 0 and 1.
+    #[arg(long, value_name = "RATIO", default_value_t = 0.3, value_parser = parse_ratio)]
+    withdrawal_ratio: f64,
+    /// Share of deposits that are later disputed, between 0 and 1.
+    #[arg(long, value_name = "RATIO", default_value_t = 0.01, value_parser = parse_ratio)]
+    dispute_ratio: f64,
+    /// Share of disputes that end in a chargeback rather than a resolve, between 0 and 1.
+    #[arg(long, value_name = "RATIO", default_value_t = 0.2, value_parser = parse_ratio)]
+    chargeback_ratio: f64,
+    /// Amount distribution: `fixed:AMOUNT`, `uniform:MIN:MAX`, or `lognormal:MEDIAN:SPREAD`. `lognormal:50:1`
+    /// by default.
+    #[arg(long, value_name = "DISTRIBUTION", value_parser = choice::<AmountDistribution>)]
+    amounts: Option<AmountDistribution>,
+    /// How rows are spread across clients: `uniform` (the default), `zipf:EXPONENT` for a few busy clients and a
+    /// long tail, or `hot:CLIENTS:TRAFFIC` for the first CLIENTS share of the clients taking the TRAFFIC share of
+    /// the rows, such as `hot:0.01:0.9`.
+    #[arg(long, value_name = "DISTRIBUTION", value_parser = choice::<ClientDistribution>)]
+    client_distribution: Option<ClientDistribution>,
+    /// Seed of the random numbers, to generate the same rows again. Taken from the clock by default.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Write to a file instead of stdout.
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+#[cfg(feature = "queue")]
+#[derive(Debug, Args)]
+struct ConsumeArgs {
+    /// Broker to read from: kafka, amqp, or nats.
+    #[arg(long, value_name = "BROKER", value_parser = choice::<Broker>)]
+    broker: Option<Broker>,
+    /// Broker addresses, comma separated.
+    #[arg(long, value_name = "HOST:PORT", value_delimiter = ',', required = true)]
+    brokers: Vec<String>,
+    /// Topic, queue, or stream to read.
+    #[arg(long)]
+    topic: String,
+    /// Consumer group, or durable consumer name.
+    #[arg(long)]
+    group: String,
+    /// Snapshot the balances and positions are checkpointed to, and resumed from on restart.
+    #[arg(long, value_name = "PATH")]
+    state: PathBuf,
+    /// Seconds between checkpoints, 5 by default.
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    checkpoint_interval: Option<Duration>,
+    /// Memory budget for transaction histories, such as `512M` or `2G`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// Address to listen on.
+    #[arg(long, value_name = "HOST:PORT", default_value = "127.0.0.1:8080")]
+    listen: std::net::SocketAddr,
+    /// Serve the gRPC API on this address as well.
+    #[cfg(feature = "grpc")]
+    #[arg(long, value_name = "HOST:PORT")]
+    grpc: Option<std::net::SocketAddr>,
+    /// Also accept transactions over plain TCP on this address, one CSV row or JSON object per line.
+    #[arg(long, value_name = "HOST:PORT")]
+    tcp: Option<std::net::SocketAddr>,
+    /// Snapshot the balances are restored from on startup, if it exists, and saved to on shutdown.
+    #[arg(long, value_name = "PATH")]
+    state: Option<PathBuf>,
+    /// Memory budget for transaction histories, such as `512M` or `2G`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+    /// POST a JSON notification to this http:// URL whenever a dispute opens or a chargeback locks an account.
+    /// Repeat for several endpoints.
+    #[arg(long, value_name = "URL", value_parser = parse_push_url)]
+    webhook: Vec<String>,
+    /// Retries of a failed webhook delivery, waiting 1s, then twice as long each time up to a minute. 5 by default.
+    #[arg(long, value_name = "N", requires = "webhook")]
+    webhook_retries: Option<u32>,
+}
+
+/// What the command line asks for: process files (the default), or a subcommand.
+#[derive(Debug)]
+pub enum Command {
+    Process(Box<Options>),
+    Validate(ValidateOptions),
+    Stats(StatsOptions),
+    Replay(ReplayOptions),
+    Merge(MergeOptions),
+    Diff(DiffOptions),
+    Dormant(DormantOptions),
+    VerifyAudit(PathBuf),
+    VerifyReport(VerifyReportOptions),
+    Decrypt(DecryptOptions),
+    PurgeClient(PurgeClientOptions),
+    CheckBooks(CheckBooksOptions),
+    AsOf(AsOfOptions),
+    Generate(GenerateOptions),
+    Test(TestOptions),
+    #[cfg(feature = "queue")]
+    Consume(ConsumeOptions),
+    #[cfg(feature = "server")]
+    Serve(ServerConfig),
+}
+
+impl Command {
+    /// Parse `argv` (including the program name), along with how it asks for logs to be written, the rules, and the
+    /// encryption key, read from its file. Invalid
+    /// arguments, `--help`, and `--version` are returned as errors, for the caller to print with
+    /// `clap::Error::exit`.
+    pub fn try_parse_from<I, T>(args: I) -> Result<(Command, LogConfig, Rules, Option<EncryptionKey>), clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let cli = Cli::try_parse_from(args)?;
+        let invalid = |e: KrakenError| {
+            let message = match e {
+                InvalidArgument(message) => message,
+                e => e.to_string(),
+            };
+            Cli::command().error(ErrorKind::ArgumentConflict, message)
+        };
+        // Variables override the file, as flags override both
+        let vars: Vec<(String, String)> = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != CONFIG_VAR && name != KEY_VAR)
+            .collect();
+        let path = cli.config.clone().or_else(|| std::env::var_os(CONFIG_VAR).map(PathBuf::from));
+        let config = match &path {
+            Some(path) => ConfigFile::load(path).map_err(invalid)?,
+            None => ConfigFile::default(),
+        };
+        let config = config.with_env(vars.iter().cloned()).map_err(invalid)?;
+        // Bad values are reported against where they could have come from
+        let source = match (&path, vars.is_empty()) {
+            (Some(path), true) => path.display().to_string(),
+            (Some(path), false) => format!("{} or {ENV_PREFIX}* variables", path.display()),
+            (None, _) => format!("{ENV_PREFIX}* variables"),
+        };
+        let in_config = |e: KrakenError| invalid(KrakenError::InFile(source.clone(), Box::new(e)));
+        let input = |args: InputArgs| args.with_config(&config.input, config.mapping.as_ref()).map_err(in_config);
+        let max_memory = |max_memory| or_config(max_memory, &config.limits.max_memory, parse_size).map_err(in_config);
+
+        let command = match cli.command {
+            None => {
+                let args = cli.process.with_config(&config).map_err(in_config)?;
+                Command::Process(Box::new(Options::try_from(args).map_err(invalid)?))
+            }
+            Some(Subcommands::Process(args)) => {
+                let args = args.with_config(&config).map_err(in_config)?;
+                Command::Process(Box::new(Options::try_from(args).map_err(invalid)?))
+            }
+            Some(Subcommands::Validate(args)) => Command::Validate(ValidateOptions {
+                paths: expand_paths(&args.paths).map_err(invalid)?,
+                input: input(args.input)?.into_options(None),
+                json: args.json,
+            }),
+            Some(Subcommands::Stats(args)) => Command::Stats(StatsOptions {
+                paths: expand_paths(&args.paths).map_err(invalid)?,
+                input: input(args.input)?.into_options(None),
+                json: args.json,
+            }),
+            Some(Subcommands::Replay(args)) => Command::Replay(ReplayOptions {
+                paths: expand_paths(&args.paths).map_err(invalid)?,
+                input: InputOptions {
+                    aliases: load_aliases(args.aliases.or_else(|| config.input.aliases.clone())).map_err(in_config)?,
+                    range: args.range.into_range(),
+                    ..input(args.input)?.into_options(None)
+                },
+                state: args.state,
+                idempotent: args.idempotent,
+                tx_index: args.tx_index,
+                max_memory: max_memory(args.max_memory)?,
+                output_format: or_config(args.output_format, &config.output.format, choice).map_err(in_config)?.unwrap_or_default(),
+                output: args.output.or_else(|| config.output.path.clone()),
+            }),
+            Some(Subcommands::Merge(args)) => Command::Merge(MergeOptions {
+                snapshots: args.snapshots,
+                output: args.output,
+                aliases: load_aliases(args.aliases).map_err(invalid)?,
+            }),
+            Some(Subcommands::Diff(args)) => Command::Diff(DiffOptions { before: args.before, after: args.after, json: args.json }),
+            Some(Subcommands::Dormant(args)) => Command::Dormant(DormantOptions {
+                snapshot: args.snapshot,
+                days: args.days,
+                as_of: args.as_of.unwrap_or_else(|| {
+                    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64)
+                }),
+                json: args.json,
+            }),
+            Some(Subcommands::VerifyAudit(args)) => Command::VerifyAudit(args.path),
+            Some(Subcommands::Decrypt(args)) => Command::Decrypt(DecryptOptions { path: args.path, output: args.output }),
+            Some(Subcommands::PurgeClient(args)) => {
+                if args.snapshots.is_empty() && args.journals.is_empty() {
+                    return Err(invalid(InvalidArgument(String::from("purge-client requires --state or --journal"))));
+                }
+                Command::PurgeClient(PurgeClientOptions { client: args.client, snapshots: args.snapshots, journals: args.journals })
+            }
+            Some(Subcommands::CheckBooks(args)) => {
+                if args.snapshot.is_none() && args.journal.is_none() {
+                    return Err(invalid(InvalidArgument(String::from("check-books requires --state or --journal"))));
+                }
+                Command::CheckBooks(CheckBooksOptions { snapshot: args.snapshot, journal: args.journal, json: args.json })
+            }
+            Some(Subcommands::AsOf(args)) => Command::AsOf(AsOfOptions {
+                events: args.events,
+                point: args.point,
+                client: args.client,
+                checkpoints: args.checkpoints,
+                checkpoint_every: args.checkpoint_every,
+                json: args.json,
+            }),
+            Some(Subcommands::VerifyReport(args)) => Command::VerifyReport(VerifyReportOptions {
+                signature: args.signature.unwrap_or_else(|| sidecar(&args.report)),
+                report: args.report,
+                signing_key: args.signing_key,
+            }),
+            Some(Subcommands::Test(args)) => {
+                let defaults = ProcessorConfig::default();
+                Command::Test(TestOptions {
+                    directory: args.directory,
+                    processor: ProcessorConfig {
+                        parallel: args.parallel.unwrap_or(defaults.parallel),
+                        threads: args.threads.map_or(defaults.threads, NonZeroUsize::get),
+                        input: InputOptions { reader: args.reader.unwrap_or_default(), ..Default::default() },
+                        ..defaults
+                    },
+                })
+            }
+            Some(Subcommands::Generate(args)) => Command::Generate(GenerateOptions {
+                config: GeneratorConfig {
+                    rows: args.rows,
+                    clients: args.clients,
+                    withdrawal_ratio: args.withdrawal_ratio,
+                    dispute_ratio: args.dispute_ratio,
+                    chargeback_ratio: args.chargeback_ratio,
+                    amounts: args.amounts.unwrap_or_default(),
+                    distribution: args.client_distribution.unwrap_or_default(),
+                    seed: args.seed,
+                },
+                output: args.output,
+            }),
+            #[cfg(feature = "queue")]
+            Some(Subcommands::Consume(args)) => Command::Consume(ConsumeOptions {
+                broker: args
+                    .broker
+                    .or(DEFAULT_BROKER)
+                    .ok_or_else(|| invalid(InvalidArgument(String::from("consume requires --broker"))))?,
+                config: ConsumeConfig {
+                    brokers: args.brokers.iter().map(|b| b.trim()).filter(|b| !b.is_empty()).map(String::from).collect(),
+                    topic: args.topic,
+                    group: args.group,
+                    state: args.state,
+                    checkpoint_interval: args.checkpoint_interval.unwrap_or(DEFAULT_CHECKPOINT_INTERVAL),
+                },
+                max_memory: max_memory(args.max_memory)?,
+            }),
+            #[cfg(feature = "server")]
+            Some(Subcommands::Serve(args)) => Command::Serve(ServerConfig {
+                listen: args.listen,
+                #[cfg(feature = "grpc")]
+                grpc: args.grpc,
+                tcp: args.tcp,
+                state: args.state,
+                max_memory: max_memory(args.max_memory)?,
+                webhooks: WebhookConfig {
+                    urls: args.webhook,
+                    retries: args.webhook_retries.unwrap_or(WebhookConfig::default().retries),
+                    ..WebhookConfig::default()
+                },
+            }),
+        };
+        let log = LogConfig {
+            level: or_config(cli.log_level, &config.log.level, choice).map_err(in_config)?,
+            diagnostics: or_config(cli.diagnostics, &config.log.diagnostics, choice).map_err(in_config)?.unwrap_or_default(),
+            redact: cli.redact || config.log.redact.unwrap_or_default(),
+        };
+        let encryption_key = match (cli.encryption_key.or_else(|| config.output.encryption_key.clone()), std::env::var(KEY_VAR)) {
+            (Some(path), _) => Some(EncryptionKey::load(&path).map_err(invalid)?),
+            (None, Ok(key)) => {
+                let key = EncryptionKey::from_hex(&key).map_err(|e| KrakenError::InFile(String::from(KEY_VAR), Box::new(e)));
+                Some(key.map_err(invalid)?)
+            }
+            (None, Err(_)) => None,
+        };
+        let mut rules = config.rules;
+        if let Command::Process(options) = &command {
+            rules.amounts = options.amounts;
+        }
+        Ok((command, log, rules, encryption_key))
+    }
+}
+
+/// Placeholder for the tenant's name in the paths of the sinks written per tenant.
+pub const TENANT: &str = "{tenant}";
+
+/// Options collected from the command line.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Input files and URLs, in processing order, with any glob patterns already expanded. With `tenants`, those
+    /// of every tenant.
+    pub paths: Vec<String>,
+    /// Every tenant, in processing order, with its own input files.
+    pub tenants: Vec<(String, Vec<String>)>,
+    pub processor: ProcessorConfig,
+    /// Run the tokio pipeline (`AsyncEngine`) instead of the partitioned Polars path.
+    pub asynchronous: bool,
+    /// Re-run the input single-threaded and fail unless the final balances match.
+    pub verify: bool,
+    /// How the engine adds up and compares amounts, as the rules installed for the run have it.
+    pub amounts: AmountBackend,
+    /// Check the invariants of the accounts after every transaction, aborting on the first violation.
+    pub check_invariants: bool,
+    /// Report the pending deposits of every account in a column of their own.
+    pub show_pending: bool,
+    /// Tail the input as it grows, reprinting the balances at most once per `flush_interval`.
+    pub follow: bool,
+    pub flush_interval: Duration,
+    pub output_format: OutputFormat,
+    /// File the report is written to, replacing it on every flush in follow mode. Printed to stdout when `None`.
+    pub output: Option<PathBuf>,
+    /// File holding the key the report is signed with.
+    pub signing_key: Option<PathBuf>,
+    /// Directory to write one statement per client into, as the input is applied.
+    pub statements: Option<PathBuf>,
+    /// Audit log to append a record of every transaction to, as the input is applied.
+    pub audit_log: Option<PathBuf>,
+    /// File to write a change event of every applied transaction to, as the input is applied.
+    pub events: Option<PathBuf>,
+    /// File to write the disputes left open to, with their age, as the input is applied.
+    pub dispute_aging: Option<PathBuf>,
+    /// File to write the balances at the end of every settlement cycle to, as the input is applied.
+    pub settlement: Option<PathBuf>,
+    pub settle_every: Cycle,
+    /// File to save the final accounts to as a state snapshot.
+    pub snapshot: Option<PathBuf>,
+    /// File to write a double-entry journal of the input to, as the input is applied.
+    pub journal: Option<PathBuf>,
+    /// Journal format, detected from the journal's extension when `None`.
+    pub journal_format: Option<JournalFormat>,
+    /// Date of every journal entry, today when `None`.
+    pub journal_date: Option<String>,
+    pub journal_commodity: String,
+    /// Print throughput, stage timings, and peak memory to stderr at the end of the run.
+    pub metrics: bool,
+    /// File to write the same metrics to, as JSON.
+    pub metrics_file: Option<PathBuf>,
+    /// Pushgateway URL to push the metrics to.
+    pub metrics_push: Option<String>,
+    /// How many of the largest accounts to print at the end of the run, and what they are ranked by.
+    pub top_n: Option<(usize, RankBy)>,
+    /// Margin above their reserve within which accounts are printed at the end of the run.
+    pub near_reserve: Option<f64>,
+    /// Print the accounts whose available funds went negative at the end of the run.
+    pub negative_balances: bool,
+    /// Never draw a progress bar, even when stderr is a terminal.
+    pub no_progress: bool,
+    /// File of the balances the accounts are expected to end with, checked after the report is written.
+    pub reconcile: Option<PathBuf>,
+    /// Conditions besides failing to read or write that fail the run, after the report is written.
+    pub fail_on: Vec<FailOn>,
+    /// Database the final balances are upserted into, as a `sqlite://` or `postgres://` URL.
+    #[cfg(feature = "database")]
+    pub database: Option<String>,
+    #[cfg(feature = "database")]
+    pub database_table: String,
+}
+
+impl TryFrom<ProcessArgs> for Options {
+    type Error = KrakenError;
+
+    /// Expand the paths and check the combination of flags, which clap can't express on its own.
+    fn try_from(args: ProcessArgs) -> Result<Options, KrakenError> {
+        #[allow(unused_mut)]
+        let mut paths = expand_paths(&args.paths)?;
+        #[cfg(feature = "remote")]
+        paths.extend(args.input_url);
+        if !args.tenant.is_empty() && !paths.is_empty() {
+            return Err(InvalidArgument(String::from("Paths cannot be given along with --tenant, which lists the inputs")));
+        }
+        let mut tenants: Vec<(String, Vec<String>)> = Vec::new();
+        for (name, pattern) in &args.tenant {
+            let expanded = expand_path(pattern)?;
+            match tenants.iter_mut().find(|(tenant, _)| tenant == name) {
+                Some((_, paths)) => paths.extend(expanded),
+                None => tenants.push((name.clone(), expanded)),
+            }
+        }
+        paths.extend(tenants.iter().flat_map(|(_, paths)| paths.iter().cloned()));
+        if args.show_pending && (!tenants.is_empty() || args.ids == Some(IdKind::String)) {
+            return Err(InvalidArgument(String::from("--show-pending cannot be used with --tenant or --ids string")));
+        }
+
+        let fail_on = match args.fail_on.as_slice() {
+            [] => vec![FailOn::ParseError],
+            [FailOn::Never] => Vec::new(),
+            conditions if conditions.contains(&FailOn::Never) => {
+                return Err(InvalidArgument(String::from("--fail-on never cannot be combined with other conditions")));
+            }
+            conditions => conditions.to_vec(),
+        };
+        let defaults = ProcessorConfig::default();
+        let mut input = args.input.into_options(args.reader);
+        if args.ids == Some(IdKind::String) {
+            input.ids = Some(Arc::default());
+        }
+        input.aliases = load_aliases(args.aliases)?;
+        input.clients = (!args.client.is_empty()).then(|| Arc::new(args.client.into_iter().collect()));
+        input.range = args.range.into_range();
+        input.skip_malformed = !fail_on.contains(&FailOn::ParseError);
+        input.types = match (args.only, args.ignore) {
+            (only, _) if !only.is_empty() => Some(TypeFilter::Only(only)),
+            (_, ignore) if !ignore.is_empty() => Some(TypeFilter::Ignore(ignore)),
+            _ => None,
+        };
+        let options = Options {
+            paths,
+            tenants,
+            processor: ProcessorConfig {
+                parallel: args.parallel.unwrap_or(defaults.parallel),
+                threads: args.threads.map_or(defaults.threads, NonZeroUsize::get),
+                max_memory: args.max_memory,
+                input,
+                // Only kept when asked for, as timing every stage isn't free. Rejections are counted by them too.
+                metrics: (args.metrics
+                    || args.metrics_file.is_some()
+                    || args.metrics_push.is_some()
+                    || fail_on.contains(&FailOn::RejectedTx)
+                    || fail_on.contains(&FailOn::Assertion))
+                .then(Arc::default),
+                ..defaults
+            },
+            asynchronous: args.asynchronous,
+            verify: args.verify,
+            amounts: args.amounts.unwrap_or_default(),
+            check_invariants: args.check_invariants,
+            show_pending: args.show_pending,
+            follow: args.follow,
+            flush_interval: args.flush_interval.unwrap_or(DEFAULT_FLUSH_INTERVAL),
+            output_format: args.output_format.unwrap_or_default(),
+            output: args.output,
+            signing_key: args.signing_key,
+            statements: args.statements,
+            audit_log: args.audit_log,
+            events: args.events,
+            dispute_aging: args.dispute_aging,
+            settlement: args.settlement,
+            settle_every: args.settle_every.unwrap_or_default(),
+            snapshot: args.snapshot,
+            journal: args.journal,
+            journal_format: args.journal_format,
+            journal_date: args.journal_date,
+            journal_commodity: args.journal_commodity.unwrap_or_else(|| String::from("USD")),
+            metrics: args.metrics,
+            metrics_file: args.metrics_file,
+            metrics_push: args.metrics_push,
+            top_n: args.top_n.map(|n| (n, args.by.unwrap_or_default())),
+            near_reserve: args.near_reserve,
+            negative_balances: args.negative_balances,
+            no_progress: args.no_progress,
+            reconcile: args.reconcile,
+            fail_on,
+            #[cfg(feature = "database")]
+            database: args.database,
+            #[cfg(feature = "database")]
+            database_table: args.database_table.unwrap_or_else(|| String::from(paymentprocessor::database::DEFAULT_TABLE)),
+        };
+
+        if options.paths.is_empty() {
+            return Err(InvalidArgument(String::from("Must supply path to data csv")));
+        }
+        #[cfg(feature = "remote")]
+        if options.asynchronous && options.paths.iter().any(|path| paymentprocessor::remote::is_url(path)) {
+            return Err(InvalidArgument(String::from("--input-url cannot be combined with --async")));
+        }
+        if (options.asynchronous || options.follow) && options.paths.iter().any(|path| path == STDIN) {
+            return Err(InvalidArgument(String::from("--async and --follow cannot read from stdin")));
+        }
+        let sinks = options.statements.is_some()
+            || options.journal.is_some()
+            || options.audit_log.is_some()
+            || options.events.is_some()
+            || options.dispute_aging.is_some()
+            || options.settlement.is_some();
+        if sinks && options.follow {
+            return Err(InvalidArgument(String::from(
+                "--statements, --journal, --audit-log, --events, --dispute-aging, and --settlement cannot be combined with \
+                 --follow",
+            )));
+        }
+        #[cfg(feature = "polars")]
+        if options.follow && options.output.is_none() && options.output_format == OutputFormat::Parquet {
+            return Err(InvalidArgument(String::from("--follow with --output-format parquet requires --output")));
+        }
+        if options.metrics_requested() && (options.asynchronous || options.follow) {
+            return Err(InvalidArgument(String::from(
+                "--metrics, --metrics-file, and --metrics-push cannot be combined with --async or --follow",
+            )));
+        }
+        // The tokio pipeline neither ends its input early nor counts rejections, and following never ends
+        let counted = [FailOn::RejectedTx, FailOn::Assertion];
+        let unsupported = !options.fail_on.contains(&FailOn::ParseError) || counted.iter().any(|c| options.fail_on.contains(c));
+        if options.asynchronous && unsupported {
+            return Err(InvalidArgument(String::from(
+                "--async requires --fail-on to include parse-error, and neither rejected-tx nor assertion",
+            )));
+        }
+        if options.signing_key.is_some() && (options.output.is_none() || options.follow) {
+            return Err(InvalidArgument(String::from("--signing-key requires --output, and cannot be combined with --follow")));
+        }
+        if options.top_n.is_some() && (options.follow || !options.tenants.is_empty()) {
+            return Err(InvalidArgument(String::from("--top-n cannot be combined with --follow or --tenant")));
+        }
+        if options.near_reserve.is_some() && (options.follow || !options.tenants.is_empty()) {
+            return Err(InvalidArgument(String::from("--near-reserve cannot be combined with --follow or --tenant")));
+        }
+        if options.negative_balances && (options.follow || !options.tenants.is_empty()) {
+            return Err(InvalidArgument(String::from("--negative-balances cannot be combined with --follow or --tenant")));
+        }
+        if options.follow && options.reconcile.is_some() {
+            return Err(InvalidArgument(String::from("--reconcile cannot be combined with --follow")));
+        }
+        if options.follow && options.fail_on != [FailOn::ParseError] {
+            return Err(InvalidArgument(String::from("--fail-on cannot be combined with --follow")));
+        }
+        if options.processor.input.mapping.is_some() && (options.asynchronous || options.follow) {
+            return Err(InvalidArgument(String::from("A [mapping] cannot be combined with --async or --follow")));
+        }
+        if options.processor.input.aliases.is_some() && (options.asynchronous || options.follow) {
+            return Err(InvalidArgument(String::from("--aliases cannot be combined with --async or --follow")));
+        }
+        if options.processor.input.clients.is_some() && (options.asynchronous || options.follow) {
+            return Err(InvalidArgument(String::from("--client cannot be combined with --async or --follow")));
+        }
+        if options.processor.input.range.is_some() && (options.asynchronous || options.follow) {
+            return Err(InvalidArgument(String::from("--from and --to cannot be combined with --async or --follow")));
+        }
+        if options.processor.input.types.is_some() && (options.asynchronous || options.follow) {
+            return Err(InvalidArgument(String::from("--only and --ignore cannot be combined with --async or --follow")));
+        }
+        if options.follow && (options.paths.len() > 1 || options.asynchronous || options.verify) {
+            return Err(InvalidArgument(String::from(
+                "--follow takes a single path and cannot be combined with --async or --verify",
+            )));
+        }
+        if !options.tenants.is_empty() {
+            options.check_tenants()?;
+        }
+        if options.processor.input.ids.is_some() {
+            options.check_string_ids()?;
+        }
+        Ok(options)
+    }
+}
+
+impl Options {
+    /// Whether `--metrics`, `--metrics-file`, or `--metrics-push` was given.
+    fn metrics_requested(&self) -> bool {
+        self.metrics || self.metrics_file.is_some() || self.metrics_push.is_some()
+    }
+
+    /// Whether each tenant has a report of its own, rather than a row per account in a single one.
+    pub fn report_per_tenant(&self) -> bool {
+        self.output.as_ref().is_some_and(|path| path.to_string_lossy().contains(TENANT))
+    }
+
+    /// The options of one tenant: its own `paths`, and the paths of the sinks with `{tenant}` replaced by `tenant`.
+    pub fn for_tenant(&self, tenant: &str, paths: &[String]) -> Options {
+        let substitute = |path: &Option<PathBuf>| path.as_ref().map(|path| path.to_string_lossy().replace(TENANT, tenant).into());
+        Options {
+            paths: paths.to_vec(),
+            tenants: Vec::new(),
+            output: substitute(&self.output),
+            statements: substitute(&self.statements),
+            audit_log: substitute(&self.audit_log),
+            events: substitute(&self.events),
+            dispute_aging: substitute(&self.dispute_aging),
+            settlement: substitute(&self.settlement),
+            snapshot: substitute(&self.snapshot),
+            journal: substitute(&self.journal),
+            reconcile: substitute(&self.reconcile),
+            ..self.clone()
+        }
+    }
+
+    /// Check the flags combined with `--ids string`. Only the report writes the ids back as text; the other sinks
+    /// would record the numbers they were interned as, which mean nothing to another run.
+    fn check_string_ids(&self) -> Result<(), KrakenError> {
+        let input = &self.processor.input;
+        let filtered = input.aliases.is_some() || input.clients.is_some() || input.range.is_some();
+        if self.asynchronous || self.follow || !self.tenants.is_empty() || filtered {
+            return Err(InvalidArgument(String::from(
+                "--ids string cannot be combined with --async, --follow, --tenant, --aliases, --client, --from, or --to",
+            )));
+        }
+        #[allow(unused_mut)]
+        let mut sinks = self.statements.is_some()
+            || self.journal.is_some()
+            || self.audit_log.is_some()
+            || self.events.is_some()
+            || self.dispute_aging.is_some()
+            || self.settlement.is_some()
+            || self.top_n.is_some()
+            || self.near_reserve.is_some()
+            || self.negative_balances
+            || self.snapshot.is_some()
+            || self.reconcile.is_some();
+        #[cfg(feature = "database")]
+        {
+            sinks |= self.database.is_some();
+        }
+        if sinks {
+            return Err(InvalidArgument(String::from(
+                "--ids string cannot be combined with --statements, --journal, --audit-log, --events, --dispute-aging, --settlement, \
+                 --top-n, --near-reserve, --negative-balances, --snapshot, --reconcile, or --database",
+            )));
+        }
+        if !matches!(self.output_format, OutputFormat::Csv | OutputFormat::Json | OutputFormat::JsonLines) {
+            return Err(InvalidArgument(String::from("--ids string reports as csv, json, or jsonl")));
+        }
+        Ok(())
+    }
+
+    /// Check the flags combined with `--tenant`. Every file written or read per tenant needs `{tenant}` in its
+    /// path, so the books stay apart.
+    fn check_tenants(&self) -> Result<(), KrakenError> {
+        if self.tenants.iter().flat_map(|(_, paths)| paths).any(|path| path == STDIN) {
+            return Err(InvalidArgument(String::from("--tenant cannot read from stdin")));
+        }
+        if self.follow {
+            return Err(InvalidArgument(String::from("--tenant cannot be combined with --follow")));
+        }
+        #[cfg(feature = "database")]
+        if self.database.is_some() {
+            return Err(InvalidArgument(String::from("--tenant cannot be combined with --database")));
+        }
+        let per_tenant = [
+            ("--statements", &self.statements),
+            ("--audit-log", &self.audit_log),
+            ("--events", &self.events),
+            ("--dispute-aging", &self.dispute_aging),
+            ("--settlement", &self.settlement),
+            ("--snapshot", &self.snapshot),
+            ("--journal", &self.journal),
+            ("--reconcile", &self.reconcile),
+        ];
+        for (flag, path) in per_tenant {
+            if path.as_ref().is_some_and(|path| !path.to_string_lossy().contains(TENANT)) {
+                return Err(InvalidArgument(format!("{flag} needs {TENANT} in its path with --tenant")));
+            }
+        }
+        let combined = matches!(self.output_format, OutputFormat::Csv | OutputFormat::Json | OutputFormat::JsonLines);
+        if !combined && !self.report_per_tenant() {
+            return Err(InvalidArgument(format!(
+                "--output-format table and parquet need {TENANT} in the path of --output with --tenant"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Options for the `validate` subcommand.
+#[derive(Debug)]
+pub struct ValidateOptions {
+    pub paths: Vec<String>,
+    pub input: InputOptions,
+    /// Print problems as JSON Lines instead of text.
+    pub json: bool,
+}
+
+/// Options for the `stats` subcommand.
+#[derive(Debug, Default)]
+pub struct StatsOptions {
+    pub paths: Vec<String>,
+    pub input: InputOptions,
+    /// Print the summary as a JSON object instead of aligned text.
+    pub json: bool,
+}
+
+/// Options for the `merge` subcommand.
+#[derive(Debug)]
+pub struct MergeOptions {
+    pub snapshots: Vec<PathBuf>,
+    /// Where the merged snapshot is saved.
+    pub output: PathBuf,
+    /// Aliases whose accounts are merged into those of their canonical clients once the snapshots are merged.
+    pub aliases: Option<Arc<Aliases>>,
+}
+
+/// Options for the `diff` subcommand.
+#[derive(Debug)]
+pub struct DiffOptions {
+    pub before: PathBuf,
+    pub after: PathBuf,
+    /// Print the changes as JSON Lines instead of CSV.
+    pub json: bool,
+}
+
+/// Options for the `dormant` subcommand.
+#[derive(Debug)]
+pub struct DormantOptions {
+    pub snapshot: PathBuf,
+    pub days: u64,
+    /// Time the report is as of, in seconds since 1970-01-01 UTC.
+    pub as_of: i64,
+    /// Print the accounts as JSON Lines instead of CSV.
+    pub json: bool,
+}
+
+/// Options for the `verify-report` subcommand.
+#[derive(Debug)]
+pub struct VerifyReportOptions {
+    pub report: PathBuf,
+    /// File holding the key the report was signed with.
+    pub signing_key: PathBuf,
+    pub signature: PathBuf,
+}
+
+/// Options for the `decrypt` subcommand.
+#[derive(Debug)]
+pub struct DecryptOptions {
+    pub path: PathBuf,
+    /// Where the decrypted file is written, stdout when `None`.
+    pub output: Option<PathBuf>,
+}
+
+/// Options for the `purge-client` subcommand.
+#[derive(Debug)]
+pub struct PurgeClientOptions {
+    pub client: u32,
+    /// State snapshots purged in place.
+    pub snapshots: Vec<PathBuf>,
+    /// Journals purged in place.
+    pub journals: Vec<PathBuf>,
+}
+
+/// Options for the `check-books` subcommand.
+#[derive(Debug)]
+pub struct CheckBooksOptions {
+    pub snapshot: Option<PathBuf>,
+    pub journal: Option<PathBuf>,
+    /// Print the violations as JSON Lines instead of CSV.
+    pub json: bool,
+}
+
+/// Options for the `as-of` subcommand.
+#[derive(Debug)]
+pub struct AsOfOptions {
+    pub events: PathBuf,
+    pub point: AsOf,
+    /// Only print this client's balances.
+    pub client: Option<u32>,
+    /// Checkpoints to replay from, written first if missing.
+    pub checkpoints: Option<PathBuf>,
+    pub checkpoint_every: u64,
+    /// Print the balances as JSON Lines instead of CSV.
+    pub json: bool,
+}
+
+/// Options for the `test` subcommand.
+#[derive(Debug)]
+pub struct TestOptions {
+    /// Directory searched for test cases.
+    pub directory: PathBuf,
+    pub processor: ProcessorConfig,
+}
+
+/// Options for the `replay` subcommand.
+#[derive(Debug)]
+pub struct ReplayOptions {
+    pub paths: Vec<String>,
+    pub input: InputOptions,
+    /// Snapshot resumed from, when it exists, and saved to once the inputs are applied.
+    pub state: PathBuf,
+    /// Refuse transactions the state has already seen, keeping the keys of those seen in the state.
+    pub idempotent: bool,
+    /// Index of the tx ids used, refusing transactions that reuse one, saved back once the inputs are applied.
+    pub tx_index: Option<PathBuf>,
+    pub max_memory: Option<usize>,
+    pub output_format: OutputFormat,
+    pub output: Option<PathBuf>,
+}
+
+/// Options for the `generate` subcommand.
+#[derive(Debug)]
+pub struct GenerateOptions {
+    pub config: GeneratorConfig,
+    pub output: Option<PathBuf>,
+}
+
+/// Options for the `consume` subcommand.
+#[cfg(feature = "queue")]
+#[derive(Debug)]
+pub struct ConsumeOptions {
+    pub broker: Broker,
+    pub config: ConsumeConfig,
+    pub max_memory: Option<usize>,
+}
+
+/// Take `flag` if it was given, or else parse `value` from the config file with the flag's own parser.
+fn or_config<T>(
+    flag: Option<T>,
+    value: &Option<String>,
+    parse: impl Fn(&str) -> Result<T, KrakenError>,
+) -> Result<Option<T>, KrakenError> {
+    match flag {
+        Some(flag) => Ok(Some(flag)),
+        None => value.as_deref().map(parse).transpose(),
+    }
+}
+
+/// Parse one of the values of an enum parsed by `TryFrom<&str>`.
+fn choice<T>(value: &str) -> Result<T, KrakenError>
+where
+    T: for<'a> TryFrom<&'a str, Error = KrakenError>,
+{
+    T::try_from(value)
+}
+
+/// Parse a non-negative number of seconds, possibly fractional.
+fn parse_seconds(value: &str) -> Result<Duration, KrakenError> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| InvalidArgument(format!("Invalid number of seconds: {value}")))
+}
+
+/// Parse a fraction between 0 and 1.
+fn parse_ratio(value: &str) -> Result<f64, KrakenError> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|ratio| (0.0..=1.0).contains(ratio))
+        .ok_or_else(|| InvalidArgument(format!("Expected a ratio between 0 and 1: {value}")))
+}
+
+/// Parse a `YYYY-MM-DD` date.
+fn parse_iso_date(value: &str) -> Result<String, KrakenError> {
+    let bytes = value.as_bytes();
+    let valid = bytes.len() == 10
+        && bytes.iter().enumerate().all(|(index, byte)| match index {
+            4 | 7 => *byte == b'-',
+            _ => byte.is_ascii_digit(),
+        });
+    match valid {
+        true => Ok(value.to_string()),
+        false => Err(InvalidArgument(format!("Expected a YYYY-MM-DD date: {value}"))),
+    }
+}
+
+/// Parse a `NAME=PATTERN` tenant.
+fn parse_tenant(value: &str) -> Result<(String, String), KrakenError> {
+    match value.split_once('=') {
+        Some((name, pattern)) if !pattern.is_empty() => Ok((parse_tenant_name(name)?, pattern.to_string())),
+        _ => Err(InvalidArgument(format!("Expected NAME=PATTERN: {value}"))),
+    }
+}
+
+/// Parse a tenant name, which is written into reports and paths, so it's kept to letters, digits, `-`, and `_`.
+fn parse_tenant_name(value: &str) -> Result<String, KrakenError> {
+    match !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        true => Ok(value.to_string()),
+        false => Err(InvalidArgument(format!("Expected a tenant name of letters, digits, - and _: {value}"))),
+    }
+}
+
+/// Parse a commodity name, which journals need to be alphanumeric.
+fn parse_commodity(value: &str) -> Result<String, KrakenError> {
+    match !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric()) {
+        true => Ok(value.to_string()),
+        false => Err(InvalidArgument(format!("Expected an alphanumeric commodity name: {value}"))),
+    }
+}
+
+fn parse_push_url(value: &str) -> Result<String, KrakenError> {
+    match value.strip_prefix("http://").is_some_and(|rest| !rest.is_empty() && !rest.starts_with('/')) {
+        true => Ok(value.to_string()),
+        false => Err(InvalidArgument(format!("Expected an http:// URL: {value}"))),
+    }
+}
+
+#[cfg(feature = "remote")]
+fn parse_url(value: &str) -> Result<String, KrakenError> {
+    match paymentprocessor::remote::is_url(value) {
+        true => Ok(value.to_string()),
+        false => Err(InvalidArgument(format!("Expected an http(s):// or s3:// URL: {value}"))),
+    }
+}
+
+#[cfg(feature = "database")]
+fn parse_identifier(value: &str) -> Result<String, KrakenError> {
+    match paymentprocessor::database::is_identifier(value) {
+        true => Ok(value.to_string()),
+        false => Err(InvalidArgument(format!("Expected a plain SQL identifier: {value}"))),
+    }
+}
+
+/// Parse a field delimiter: a single ASCII character, or `tab` (also `\t`) for tab-separated input.
+pub fn parse_delimiter(value: &str) -> Result<u8, KrakenError> {
+    match value {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        _ if value.len() == 1 && value.is_ascii() && value != "\n" && value != "\r" => Ok(value.as_bytes()[0]),
+        _ => Err(InvalidArgument(format!("Invalid delimiter: {value}"))),
+    }
+}
+
+/// Parse a byte size such as `512`, `64K`, `200M`, or `2G` (binary multiples, optional trailing `B`).
+pub fn parse_size(value: &str) -> Result<usize, KrakenError> {
+    let invalid = || InvalidArgument(format!("Invalid size: {value}"));
+
+    let upper = value.trim().to_ascii_uppercase();
+    let digits = upper.strip_suffix('B').unwrap_or(&upper);
+    let (digits, multiplier) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 1usize << 10),
+        Some('M') => (&digits[..digits.len() - 1], 1 << 20),
+        Some('G') => (&digits[..digits.len() - 1], 1 << 30),
+        Some('T') => (&digits[..digits.len() - 1], 1 << 40),
+        _ => (digits, 1),
+    };
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(invalid)
+}
+
+/// Read the aliases file at `path`, if given.
+fn load_aliases(path: Option<PathBuf>) -> Result<Option<Arc<Aliases>>, KrakenError> {
+    path.map(|path| Aliases::load(&path).map(Arc::new)).transpose()
+}
+
+/// Expand every glob pattern in `paths`, keeping their order.
+fn expand_paths(paths: &[String]) -> Result<Vec<String>, KrakenError> {
+    let mut expanded = Vec::with_capacity(paths.len());
+    for path in paths {
+        expanded.extend(expand_path(path)?);
+    }
+    Ok(expanded)
+}
+
+/// Expand a glob pattern into the sorted list of files it matches. Plain paths are passed through untouched.
+pub fn expand_path(path: &str) -> Result<Vec<String>, KrakenError> {
+    if !path.contains(['*', '?', '[']) {
+        return Ok(vec![path.to_string()]);
+    }
+
+    let mut matches = glob::glob(path)
+        .map_err(|e| InvalidArgument(format!("Invalid pattern {path}: {e}")))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.is_file())
+        .map(|entry| entry.display().to_string())
+        .collect::<Vec<_>>();
+    if matches.is_empty() {
+        return Err(InvalidArgument(format!("No files match {path}")));
+    }
+
+    matches.sort();
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Command;
+
+    #[test]
+    fn test_sinks_refuse_follow() {
+        let refused = |args: &[&str]| match Command::try_parse_from(args) {
+            Err(e) => e.to_string().contains("cannot be combined with --follow"),
+            Ok(_) => false,
+        };
+        assert!(refused(&["paymentprocessor", "--events", "events.jsonl", "--follow", "example.csv"]));
+        // Written from the main pass, so stdin is read once
+        assert!(!refused(&["paymentprocessor", "--events", "events.jsonl", "-"]));
+        // The engine adds up minor units itself, without a second pass over the input
+        assert!(!refused(&["paymentprocessor", "--amounts", "minor", "-"]));
+    }
+}
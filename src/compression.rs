@@ -0,0 +1,113 @@
+use crate::errors::KrakenError;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression applied to an input file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect compression from the file's magic bytes, falling back to its extension (`.gz`, `.zst`).
+    pub fn detect(path: impl AsRef<Path>) -> Result<Self, KrakenError> {
+        let path = path.as_ref();
+        let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+        File::open(path)
+            .and_then(|file| file.take(ZSTD_MAGIC.len() as u64).read_to_end(&mut magic))
+            .map_err(|_| KrakenError::IO)?;
+
+        if magic.starts_with(&GZIP_MAGIC) {
+            return Ok(Compression::Gzip);
+        }
+        if magic.starts_with(&ZSTD_MAGIC) {
+            return Ok(Compression::Zstd);
+        }
+
+        Ok(match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        })
+    }
+}
+
+/// Open `path` for reading, transparently decompressing it if needed.
+pub fn open(path: impl AsRef<Path>) -> Result<Box<dyn Read + Send>, KrakenError> {
+    let compression = Compression::detect(&path)?;
+    decoder(compression, BufReader::new(File::open(path).map_err(|_| KrakenError::IO)?))
+}
+
+/// Wrap a stream that can't be reopened, such as stdin, in a decompressor if its magic bytes call for one.
+/// The magic bytes are peeked at, not consumed.
+pub fn decompress<R: BufRead + Send + 'static>(mut reader: R) -> Result<Box<dyn Read + Send>, KrakenError> {
+    let magic = reader.fill_buf().map_err(|_| KrakenError::IO)?;
+    let compression = if magic.starts_with(&GZIP_MAGIC) {
+        Compression::Gzip
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    };
+    decoder(compression, reader)
+}
+
+fn decoder<R: BufRead + Send + 'static>(compression: Compression, reader: R) -> Result<Box<dyn Read + Send>, KrakenError> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        // Multi-member aware, since concatenated `.gz` files are common for rotated logs
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader).map_err(|_| KrakenError::IO)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{decompress, Compression};
+    use std::io::Read;
+    use crate::processor::tests::{TEST_CASES, TEST_DIR};
+    use crate::processor::{compute_account_totals, ProcessorConfig};
+    use std::io::Write;
+
+    #[test]
+    fn test_compressed_csv() {
+        for (file_name, expected) in TEST_CASES {
+            let raw = std::fs::read(String::from(TEST_DIR) + file_name).unwrap();
+
+            let mut gzip = tempfile::Builder::new().suffix(".csv.gz").tempfile().unwrap();
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&raw).unwrap();
+            gzip.write_all(&encoder.finish().unwrap()).unwrap();
+
+            // No extension, so only the magic bytes give it away
+            let mut zstd = tempfile::NamedTempFile::new().unwrap();
+            zstd.write_all(&zstd::encode_all(raw.as_slice(), 0).unwrap()).unwrap();
+
+            for (file, compression) in [(gzip, Compression::Gzip), (zstd, Compression::Zstd)] {
+                let path = file.path().to_str().unwrap();
+                assert_eq!(compression, Compression::detect(path).unwrap());
+
+                let totals = compute_account_totals(path, &ProcessorConfig::default()).unwrap();
+                assert_eq!(String::from(expected), totals.get(&1).expect("").to_str_row(1).unwrap())
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompress_stream() {
+        let raw = std::fs::read(String::from(TEST_DIR) + TEST_CASES[0].0).unwrap();
+        let compressed = zstd::encode_all(raw.as_slice(), 0).unwrap();
+
+        for input in [raw.clone(), compressed] {
+            let mut decompressed = Vec::new();
+            decompress(std::io::Cursor::new(input)).unwrap().read_to_end(&mut decompressed).unwrap();
+            assert_eq!(raw, decompressed);
+        }
+    }
+}
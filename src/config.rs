@@ -0,0 +1,337 @@
+use crate::encryption::KEY_VAR;
+use crate::errors::KrakenError;
+use crate::errors::KrakenError::InvalidArgument;
+use crate::mapping::{Column, SchemaMapping};
+use crate::rules::{LockedPolicy, Rules};
+use crate::structures::TransactionType;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the environment variables mirroring the keys of the file, named `PAYPROC_<SECTION>_<KEY>` in upper
+/// case, such as `PAYPROC_LIMITS_MAX_MEMORY`.
+pub const ENV_PREFIX: &str = "PAYPROC_";
+/// Environment variable naming the file itself, when `--config` isn't given.
+pub const CONFIG_VAR: &str = "PAYPROC_CONFIG";
+
+/// Defaults read from a `--config` TOML file, which flags given on the command line override. Values are written
+/// as they would be on the command line, such as `max_memory = "2G"` or `fail_on = ["rejected-tx"]`, and parsed the
+/// same way. Unknown keys are refused, so a misspelled one doesn't go unnoticed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub input: InputConfig,
+    pub processing: ProcessingConfig,
+    pub limits: LimitsConfig,
+    pub output: OutputConfig,
+    pub log: LoggingConfig,
+    /// `[tenants]`: the inputs of each `--tenant`, as `name = ["pattern", ...]`.
+    pub tenants: BTreeMap<String, Vec<String>>,
+    /// `[mapping]`: the layout of CSV input that doesn't follow `type, client, tx, amount`, with `[mapping.types]`
+    /// spelling out its transaction types.
+    pub mapping: Option<SchemaMapping>,
+    /// `[rules]`: the policies disputes, chargebacks, and authorizations follow, for every subcommand.
+    pub rules: Rules,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile, KrakenError> {
+        let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+        let text = std::fs::read_to_string(path).map_err(|_| in_file(KrakenError::IO))?;
+        toml::from_str(&text).map_err(|e| in_file(KrakenError::Parse(e.message().to_string())))
+    }
+
+    /// Override the values of the file with those of the `PAYPROC_*` variables among `vars`, ignoring the rest and
+    /// `PAYPROC_CONFIG`.
+    /// Lists, such as `PAYPROC_PROCESSING_FAIL_ON`, are comma separated, and `PAYPROC_MAPPING_TYPES` is written as
+    /// `DEP=deposit,WD=withdrawal`. `PAYPROC_TENANTS_<NAME>` lists the inputs of tenant `name`. Unknown `PAYPROC_*` variables are refused,
+    /// like unknown keys.
+    pub fn with_env(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Result<ConfigFile, KrakenError> {
+        for (name, value) in vars {
+            if name == CONFIG_VAR || name == KEY_VAR {
+                continue;
+            }
+            if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                self.set(&key.to_ascii_lowercase(), value)?;
+            }
+        }
+        Ok(self)
+    }
+
+    fn set(&mut self, key: &str, value: String) -> Result<(), KrakenError> {
+        let (input, processing, output) = (&mut self.input, &mut self.processing, &mut self.output);
+        match key {
+            "input_format" => input.format = Some(value),
+            "input_delimiter" => input.delimiter = Some(value),
+            "input_sheet" => input.sheet = Some(value),
+            "input_reader" => input.reader = Some(value),
+            "input_ids" => input.ids = Some(value),
+            "input_aliases" => input.aliases = Some(value.into()),
+            "input_clients" => {
+                let parse = |client: &str| client.trim().parse().map_err(|_| InvalidArgument(format!("Invalid client: {client}")));
+                input.clients = Some(value.split(',').map(parse).collect::<Result<_, _>>()?);
+            }
+            "processing_parallel" => processing.parallel = Some(value),
+            "processing_threads" => {
+                let threads = value.parse().map_err(|_| InvalidArgument(format!("Invalid number of threads: {value}")))?;
+                processing.threads = Some(threads);
+            }
+            "processing_amounts" => processing.amounts = Some(value),
+            "processing_reconcile" => processing.reconcile = Some(value.into()),
+            "processing_fail_on" => processing.fail_on = Some(value.split(',').map(|c| c.trim().to_string()).collect()),
+            "limits_max_memory" => self.limits.max_memory = Some(value),
+            "output_format" => output.format = Some(value),
+            "output_path" => output.path = Some(value.into()),
+            "output_signing_key" => output.signing_key = Some(value.into()),
+            "output_encryption_key" => output.encryption_key = Some(value.into()),
+            "output_statements" => output.statements = Some(value.into()),
+            "output_audit_log" => output.audit_log = Some(value.into()),
+            "output_events" => output.events = Some(value.into()),
+            "output_dispute_aging" => output.dispute_aging = Some(value.into()),
+            "output_settlement" => output.settlement = Some(value.into()),
+            "output_settle_every" => output.settle_every = Some(value),
+            "output_snapshot" => output.snapshot = Some(value.into()),
+            "output_journal" => output.journal = Some(value.into()),
+            "output_journal_format" => output.journal_format = Some(value),
+            "output_journal_commodity" => output.journal_commodity = Some(value),
+            "output_metrics_file" => output.metrics_file = Some(value.into()),
+            "output_metrics_push" => output.metrics_push = Some(value),
+            "output_top_n" => {
+                let n = value.parse().map_err(|_| InvalidArgument(format!("Invalid number of accounts: {value}")))?;
+                output.top_n = Some(n);
+            }
+            "output_top_n_by" => output.top_n_by = Some(value),
+            "output_near_reserve" => {
+                let margin = value.parse().map_err(|_| InvalidArgument(format!("Invalid amount: {value}")))?;
+                output.near_reserve = Some(margin);
+            }
+            #[cfg(feature = "database")]
+            "output_database" => output.database = Some(value),
+            #[cfg(feature = "database")]
+            "output_database_table" => output.database_table = Some(value),
+            "log_level" => self.log.level = Some(value),
+            "log_diagnostics" => self.log.diagnostics = Some(value),
+            "log_redact" => {
+                let redact = value.parse().map_err(|_| InvalidArgument(format!("Expected true or false: {value}")))?;
+                self.log.redact = Some(redact);
+            }
+            "mapping_type"
+            | "mapping_client"
+            | "mapping_tx"
+            | "mapping_amount"
+            | "mapping_memo"
+            | "mapping_idempotency_key"
+            | "mapping_timestamp" => {
+                let mapping = self.mapping.get_or_insert_default();
+                let column = Some(Column::try_from(value.as_str())?);
+                match key {
+                    "mapping_type" => mapping.kind = column,
+                    "mapping_client" => mapping.client = column,
+                    "mapping_tx" => mapping.tx = column,
+                    "mapping_amount" => mapping.amount = column,
+                    "mapping_memo" => mapping.memo = column,
+                    "mapping_timestamp" => mapping.timestamp = column,
+                    _ => mapping.idempotency_key = column,
+                }
+            }
+            "mapping_headers" => {
+                let headers = value.parse().map_err(|_| InvalidArgument(format!("Expected true or false: {value}")))?;
+                self.mapping.get_or_insert_default().headers = headers;
+            }
+            // Spellings are listed as `DEP=deposit,WD=withdrawal`
+            "mapping_types" => {
+                let mut types = std::collections::HashMap::new();
+                for spelling in value.split(',').filter(|spelling| !spelling.trim().is_empty()) {
+                    let (spelling, kind) = spelling
+                        .split_once('=')
+                        .ok_or_else(|| InvalidArgument(format!("Expected SPELLING=type: {spelling}")))?;
+                    types.insert(spelling.trim().to_string(), TransactionType::try_from(kind.trim())?);
+                }
+                self.mapping.get_or_insert_default().types = types;
+            }
+            "rules_withdrawals_disputable" | "rules_chargeback_locks" | "rules_reversal_unlocks" => {
+                let enabled = value.parse().map_err(|_| InvalidArgument(format!("Expected true or false: {value}")))?;
+                match key {
+                    "rules_withdrawals_disputable" => self.rules.withdrawals_disputable = enabled,
+                    "rules_chargeback_locks" => self.rules.chargeback_locks = enabled,
+                    _ => self.rules.reversal_unlocks = enabled,
+                }
+            }
+            "rules_max_open_disputes" => {
+                let max = value.parse().map_err(|_| InvalidArgument(format!("Expected a number of disputes: {value}")))?;
+                self.rules.max_open_disputes = Some(max);
+            }
+            "rules_authorization_expiry_days" => {
+                let days = value.parse().map_err(|_| InvalidArgument(format!("Invalid number of days: {value}")))?;
+                self.rules.authorization_expiry_days = Some(days);
+            }
+            "rules_deposit_delay_rows" => {
+                let rows = value.parse().map_err(|_| InvalidArgument(format!("Expected a number of rows: {value}")))?;
+                self.rules.deposit_delay_rows = Some(rows);
+            }
+            "rules_reserve" => {
+                let reserve = value.parse().map_err(|_| InvalidArgument(format!("Invalid amount: {value}")))?;
+                self.rules.reserve = Some(reserve);
+            }
+            "rules_reserves" => {
+                let mut reserves = BTreeMap::new();
+                for reserve in value.split(',').filter(|reserve| !reserve.trim().is_empty()) {
+                    let invalid = || InvalidArgument(format!("Expected CLIENT=AMOUNT: {reserve}"));
+                    let (client, amount) = reserve.split_once('=').ok_or_else(invalid)?;
+                    reserves.insert(client.trim().parse().map_err(|_| invalid())?, amount.trim().parse().map_err(|_| invalid())?);
+                }
+                self.rules.reserves = reserves;
+            }
+            "rules_locked_deposits" => self.rules.locked_deposits = LockedPolicy::try_from(value.as_str())?,
+            "rules_locked_withdrawals" => self.rules.locked_withdrawals = LockedPolicy::try_from(value.as_str())?,
+            "rules_deposit_delay_days" => {
+                let days = value.parse().map_err(|_| InvalidArgument(format!("Invalid number of days: {value}")))?;
+                self.rules.deposit_delay_days = Some(days);
+            }
+            _ if key.starts_with("tenants_") => {
+                let patterns = value.split(',').map(|pattern| pattern.trim().to_string()).filter(|pattern| !pattern.is_empty());
+                self.tenants.insert(key["tenants_".len()..].to_string(), patterns.collect());
+            }
+            _ => return Err(InvalidArgument(format!("Unknown variable {ENV_PREFIX}{}", key.to_ascii_uppercase()))),
+        }
+        Ok(())
+    }
+}
+
+/// `[input]`: `--format`, `--delimiter`, `--sheet`, `--reader`, `--ids`, `--aliases`, and `--client` as `clients`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub format: Option<String>,
+    pub delimiter: Option<String>,
+    pub sheet: Option<String>,
+    pub reader: Option<String>,
+    pub ids: Option<String>,
+    pub aliases: Option<PathBuf>,
+    pub clients: Option<Vec<u32>>,
+}
+
+/// `[processing]`: `--parallel`, `--threads`, `--amounts`, `--reconcile`, and `--fail-on`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessingConfig {
+    pub parallel: Option<String>,
+    pub threads: Option<NonZeroUsize>,
+    pub amounts: Option<String>,
+    pub reconcile: Option<PathBuf>,
+    pub fail_on: Option<Vec<String>>,
+}
+
+/// `[limits]`: `--max-memory`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_memory: Option<String>,
+}
+
+/// `[output]`: the report's `format` and `path` (`--output-format` and `--output`), and the other sinks.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub format: Option<String>,
+    pub path: Option<PathBuf>,
+    pub signing_key: Option<PathBuf>,
+    pub encryption_key: Option<PathBuf>,
+    pub statements: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub events: Option<PathBuf>,
+    pub dispute_aging: Option<PathBuf>,
+    pub settlement: Option<PathBuf>,
+    pub settle_every: Option<String>,
+    pub snapshot: Option<PathBuf>,
+    pub journal: Option<PathBuf>,
+    pub journal_format: Option<String>,
+    pub journal_commodity: Option<String>,
+    pub metrics_file: Option<PathBuf>,
+    pub metrics_push: Option<String>,
+    pub top_n: Option<usize>,
+    pub top_n_by: Option<String>,
+    pub near_reserve: Option<f64>,
+    #[cfg(feature = "database")]
+    pub database: Option<String>,
+    #[cfg(feature = "database")]
+    pub database_table: Option<String>,
+}
+
+/// `[log]`: `--log-level`, `--diagnostics`, and `--redact`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: Option<String>,
+    pub diagnostics: Option<String>,
+    pub redact: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ConfigFile;
+    use crate::mapping::Column;
+    use crate::rules::LockedPolicy;
+    use crate::structures::TransactionType;
+    use std::io::Write;
+
+    #[test]
+    fn test_load() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"[processing]\nparallel = \"rayon\"\nthreads = 4\n\n[limits]\nmax_memory = \"2G\"\n").unwrap();
+        let config = ConfigFile::load(file.path()).unwrap();
+        assert_eq!(Some("rayon"), config.processing.parallel.as_deref());
+        assert_eq!(4, config.processing.threads.unwrap().get());
+        assert_eq!(Some("2G"), config.limits.max_memory.as_deref());
+        assert_eq!(None, config.output.format);
+
+        file.write_all(b"[output]\nformt = \"json\"\n").unwrap();
+        let error = ConfigFile::load(file.path()).unwrap_err().to_string();
+        assert!(error.contains("formt"), "{error}");
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"[rules]\nreserve = 10.0\nlocked_withdrawals = \"accept\"\n\n[rules.reserves]\n7 = 50.0\n").unwrap();
+        let rules = ConfigFile::load(file.path()).unwrap().rules;
+        assert_eq!((Some(50.0), Some(10.0)), (rules.reserve_of(7), rules.reserve_of(8)));
+        assert_eq!(LockedPolicy::Accept, rules.locked_withdrawals);
+    }
+
+    #[test]
+    fn test_with_env() {
+        let var = |name: &str, value: &str| (name.to_string(), value.to_string());
+        let config = ConfigFile::default()
+            .with_env([
+                var("PAYPROC_PROCESSING_THREADS", "2"),
+                var("PAYPROC_PROCESSING_AMOUNTS", "minor"),
+                var("PAYPROC_PROCESSING_FAIL_ON", "parse-error, rejected-tx"),
+                var("PAYPROC_OUTPUT_JOURNAL_FORMAT", "beancount"),
+                var("PAYPROC_MAPPING_CLIENT", "3"),
+                var("PAYPROC_MAPPING_TYPES", "DEP=deposit, WD=withdrawal"),
+                var("PAYPROC_TENANTS_ACME", "acme/*.csv, late.csv"),
+                var("PAYPROC_RULES_CHARGEBACK_LOCKS", "false"),
+                var("PAYPROC_RULES_MAX_OPEN_DISPUTES", "3"),
+                var("PAYPROC_RULES_DEPOSIT_DELAY_DAYS", "2"),
+                var("PAYPROC_RULES_RESERVES", "1=5.5, 2=0"),
+                var("PAYPROC_RULES_LOCKED_DEPOSITS", "hold"),
+                var("HOME", "/root"),
+            ])
+            .unwrap();
+        assert_eq!(2, config.processing.threads.unwrap().get());
+        assert_eq!(Some("minor"), config.processing.amounts.as_deref());
+        assert_eq!(Some(vec![String::from("parse-error"), String::from("rejected-tx")]), config.processing.fail_on);
+        assert_eq!(Some("beancount"), config.output.journal_format.as_deref());
+        let mapping = config.mapping.unwrap();
+        assert_eq!(Some(Column::Position(3)), mapping.client);
+        assert_eq!(Some(&TransactionType::Withdrawal), mapping.types.get("WD"));
+        assert_eq!(vec![String::from("acme/*.csv"), String::from("late.csv")], config.tenants["acme"]);
+        assert!(!config.rules.chargeback_locks && !config.rules.withdrawals_disputable);
+        assert_eq!(Some(3), config.rules.max_open_disputes);
+        assert_eq!((None, Some(2)), (config.rules.deposit_delay_rows, config.rules.deposit_delay_days));
+        assert_eq!((Some(5.5), Some(0.0), None), (config.rules.reserve_of(1), config.rules.reserve_of(2), config.rules.reserve_of(3)));
+        assert_eq!((LockedPolicy::Hold, LockedPolicy::Reject), (config.rules.locked_deposits, config.rules.locked_withdrawals));
+
+        assert!(ConfigFile::default().with_env([var("PAYPROC_OUTPUT_FORMT", "json")]).is_err());
+        assert!(ConfigFile::default().with_env([var("PAYPROC_PROCESSING_THREADS", "0")]).is_err());
+    }
+}
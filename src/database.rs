@@ -0,0 +1,157 @@
+use crate::errors::KrakenError;
+use crate::errors::KrakenError::Database;
+use crate::output::AccountSummary;
+use crate::structures::ClientAccount;
+use std::collections::HashMap;
+
+/// Default table the balances are upserted into.
+pub const DEFAULT_TABLE: &str = "accounts";
+
+/// Upsert the final balances into `table`, creating it if it doesn't exist, in a single transaction.
+/// Rows are keyed by client, so re-running over newer data updates them in place.
+///
+/// `url` picks the database: `sqlite://path/to/file.db` (feature `sqlite`) or `postgres://user@host/db`
+/// (feature `postgres`). Returns the number of rows written.
+pub fn upsert_accounts(url: &str, table: &str, accounts: &HashMap<u32, ClientAccount>) -> Result<usize, KrakenError> {
+    if !is_identifier(table) {
+        return Err(KrakenError::InvalidArgument(format!("Invalid table name: {table}")));
+    }
+
+    let mut summaries: Vec<AccountSummary> =
+        accounts.iter().map(|(client, account)| AccountSummary::new(*client, account)).collect::<Result<_, _>>()?;
+    summaries.sort_by_key(|summary| summary.client);
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = url.strip_prefix("sqlite://").or_else(|| url.strip_prefix("sqlite:")) {
+        return upsert_sqlite(path, table, &summaries);
+    }
+    #[cfg(feature = "postgres")]
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        return upsert_postgres(url, table, &summaries);
+    }
+    Err(KrakenError::InvalidArgument(format!("Unsupported database URL: {url}")))
+}
+
+/// Table names are spliced into the SQL, so only plain identifiers are accepted.
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(feature = "sqlite")]
+fn upsert_sqlite(path: &str, table: &str, summaries: &[AccountSummary]) -> Result<usize, KrakenError> {
+    let sqlite_error = |e: rusqlite::Error| Database(e.to_string());
+
+    let mut connection = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+    let transaction = connection.transaction().map_err(sqlite_error)?;
+    transaction
+        .execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {table} (
+                client INTEGER PRIMARY KEY,
+                available NUMERIC NOT NULL,
+                held NUMERIC NOT NULL,
+                total NUMERIC NOT NULL,
+                locked BOOLEAN NOT NULL,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )"
+        ))
+        .map_err(sqlite_error)?;
+    {
+        let mut statement = transaction
+            .prepare(&format!(
+                "INSERT INTO {table} (client, available, held, total, locked) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (client) DO UPDATE SET available = excluded.available, held = excluded.held,
+                 total = excluded.total, locked = excluded.locked, updated_at = CURRENT_TIMESTAMP"
+            ))
+            .map_err(sqlite_error)?;
+        for summary in summaries {
+            statement
+                .execute(rusqlite::params![
+                    summary.client,
+                    round(summary.available),
+                    round(summary.held),
+                    round(summary.total),
+                    summary.locked
+                ])
+                .map_err(sqlite_error)?;
+        }
+    }
+    transaction.commit().map_err(sqlite_error)?;
+    Ok(summaries.len())
+}
+
+/// SQLite has no fixed-point type, so amounts are stored as reals rounded to the report's four places.
+#[cfg(feature = "sqlite")]
+fn round(amount: f64) -> f64 {
+    (amount * 10_000.0).round() / 10_000.0
+}
+
+#[cfg(feature = "postgres")]
+fn upsert_postgres(url: &str, table: &str, summaries: &[AccountSummary]) -> Result<usize, KrakenError> {
+    let postgres_error = |e: postgres::Error| Database(e.to_string());
+
+    let mut client = postgres::Client::connect(url, postgres::NoTls).map_err(postgres_error)?;
+    let mut transaction = client.transaction().map_err(postgres_error)?;
+    transaction
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {table} (
+                client BIGINT PRIMARY KEY,
+                available NUMERIC(18, 4) NOT NULL,
+                held NUMERIC(18, 4) NOT NULL,
+                total NUMERIC(18, 4) NOT NULL,
+                locked BOOLEAN NOT NULL,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )"
+        ))
+        .map_err(postgres_error)?;
+    // Amounts are sent as decimal text and converted by the server, so they land in NUMERIC exactly
+    let statement = transaction
+        .prepare(&format!(
+            "INSERT INTO {table} (client, available, held, total, locked)
+             VALUES ($1, $2::text::numeric, $3::text::numeric, $4::text::numeric, $5)
+             ON CONFLICT (client) DO UPDATE SET available = excluded.available, held = excluded.held,
+             total = excluded.total, locked = excluded.locked, updated_at = now()"
+        ))
+        .map_err(postgres_error)?;
+    for summary in summaries {
+        let [available, held, total] = [summary.available, summary.held, summary.total].map(|amount| format!("{amount:.4}"));
+        transaction
+            .execute(&statement, &[&i64::from(summary.client), &available, &held, &total, &summary.locked])
+            .map_err(postgres_error)?;
+    }
+    transaction.commit().map_err(postgres_error)?;
+    Ok(summaries.len())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use crate::database::{upsert_accounts, DEFAULT_TABLE};
+    use crate::processor::tests::TEST_DIR;
+    use crate::processor::{compute_account_totals, ProcessorConfig};
+
+    #[test]
+    fn test_sqlite_upsert() {
+        let directory = tempfile::tempdir().unwrap();
+        let url = format!("sqlite://{}", directory.path().join("report.db").display());
+
+        for file_name in ["0-trivial.csv", "1-dispute-after-withdraw.csv"] {
+            let path = String::from(TEST_DIR) + file_name;
+            let totals = compute_account_totals(&path, &ProcessorConfig::default()).unwrap();
+            upsert_accounts(&url, DEFAULT_TABLE, &totals).unwrap();
+        }
+
+        // Client 1 is updated in place by the second run, and client 2 is left as the first run wrote it
+        let connection = rusqlite::Connection::open(directory.path().join("report.db")).unwrap();
+        let rows: Vec<(u32, f64, f64, bool)> = connection
+            .prepare("SELECT client, available, held, locked FROM accounts ORDER BY client")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(vec![(1, -9.5, 10.0, false), (2, 2.0, 0.0, false)], rows);
+
+        assert!(upsert_accounts(&url, "accounts; DROP TABLE accounts", &Default::default()).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use crate::errors::KrakenError;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Split a time into a UTC civil date, `(year, month, day)`, and the seconds into that day.
+pub fn civil_from_time(time: SystemTime) -> ((i64, i64, i64), u64) {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+    (civil_from_days(days as i64), seconds)
+}
+
+/// Days since 1970-01-01 to a UTC civil date, `(year, month, day)`, after Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    (year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// Format a time's UTC date as `YYYY-MM-DD`.
+pub fn iso_date(time: SystemTime) -> String {
+    let ((year, month, day), _) = civil_from_time(time);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Days from 1970-01-01 to a UTC civil date, after Howard Hinnant's `days_from_civil`.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Parse a timestamp into seconds since 1970-01-01 UTC: a number of those seconds, a `YYYY-MM-DD` date, taken as
+/// its midnight, or an RFC 3339 date and time such as `2024-01-31T09:30:00Z` or `2024-01-31 09:30:00+02:00`.
+/// Fractions of a second are dropped, and a time without an offset is taken as UTC.
+pub fn parse_timestamp(value: &str) -> Result<i64, KrakenError> {
+    let invalid = || KrakenError::Parse(format!("Invalid timestamp: {value}"));
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Ok(seconds);
+    }
+    let number = |range: std::ops::Range<usize>| value.get(range).filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()));
+    let field = |range| number(range).and_then(|digits: &str| digits.parse::<i64>().ok()).ok_or_else(invalid);
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    if value.get(4..5) != Some("-") || value.get(7..8) != Some("-") || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    let days = days_from_civil(year, month, day);
+    if value.len() == 10 {
+        return Ok(days * 86_400);
+    }
+
+    if !matches!(value.get(10..11), Some("T" | "t" | " ")) || value.get(13..14) != Some(":") || value.get(16..17) != Some(":") {
+        return Err(invalid());
+    }
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if hour > 23 || minute > 59 || second > 60 {
+        return Err(invalid());
+    }
+    let mut rest = &value[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        rest = fraction.trim_start_matches(|c: char| c.is_ascii_digit());
+    }
+    let offset = match rest {
+        "" | "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.get(..1) {
+                Some("+") => 1,
+                Some("-") => -1,
+                _ => return Err(invalid()),
+            };
+            let (hours, minutes) = rest[1..].split_once(':').ok_or_else(invalid)?;
+            let parse = |part: &str| part.parse::<i64>().ok().filter(|_| part.len() == 2).ok_or_else(invalid);
+            sign * (parse(hours)? * 3_600 + parse(minutes)? * 60)
+        }
+    };
+    Ok(days * 86_400 + hour * 3_600 + minute * 60 + second - offset)
+}
+
+/// Format seconds since 1970-01-01 UTC as an RFC 3339 time in UTC, such as `2024-01-31T09:30:00Z`.
+pub fn format_timestamp(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let seconds = seconds.rem_euclid(86_400);
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", seconds / 3_600, seconds % 3_600 / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dates::{format_timestamp, iso_date, parse_timestamp};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(1_706_693_400, parse_timestamp("2024-01-31T09:30:00Z").unwrap());
+        assert_eq!(1_706_693_400, parse_timestamp("2024-01-31 11:30:00.250+02:00").unwrap());
+        assert_eq!(1_706_693_400, parse_timestamp("1706693400").unwrap());
+        assert_eq!(1_706_659_200, parse_timestamp("2024-01-31").unwrap());
+        assert_eq!(951_782_400, parse_timestamp("2000-02-29").unwrap());
+        for invalid in ["", "2024-13-01", "2024-01-31T25:00:00Z", "2024-01-31T09:30", "31/01/2024", "2024-01-31T09:30:00+2"] {
+            assert!(parse_timestamp(invalid).is_err(), "{invalid}");
+        }
+
+        assert_eq!("2024-01-31T09:30:00Z", format_timestamp(1_706_693_400));
+        assert_eq!("1969-12-31T23:59:59Z", format_timestamp(-1));
+        assert_eq!("2024-01-31", iso_date(UNIX_EPOCH + Duration::from_secs(1_706_693_400)));
+    }
+}
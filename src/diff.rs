@@ -0,0 +1,228 @@
+use crate::encryption;
+use crate::errors::KrakenError;
+use crate::input::csv_error;
+use crate::output::{four_places, AccountSummary};
+use crate::snapshot::Snapshot;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// An account as read back from a report. `total` may be left out, in which case it's taken to be
+/// `available + held + pending`, and `pending` is only there in reports written with `--show-pending`.
+#[derive(Debug, Clone, Deserialize)]
+struct BalanceRow {
+    client: u32,
+    available: f64,
+    held: f64,
+    #[serde(default)]
+    pending: Option<f64>,
+    total: Option<f64>,
+    locked: bool,
+}
+
+impl From<BalanceRow> for AccountSummary {
+    fn from(row: BalanceRow) -> Self {
+        AccountSummary {
+            client: row.client,
+            available: row.available,
+            held: row.held,
+            pending: row.pending,
+            total: row.total.unwrap_or(row.available + row.held + row.pending.unwrap_or_default()),
+            locked: row.locked,
+            lock: None,
+        }
+    }
+}
+
+/// A `.json` file: either a report, or a state snapshot.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonBalances {
+    Report(Vec<BalanceRow>),
+    Snapshot(Snapshot),
+}
+
+/// Read the balance of every client from a report written as CSV (`client, available, held, total, locked`),
+/// JSON, or JSON Lines, or from a state snapshot, told apart by the file's extension and, for `.json`, its
+/// contents. A client listed twice is refused.
+pub fn read_balances(path: &Path) -> Result<BTreeMap<u32, AccountSummary>, KrakenError> {
+    let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+    let parse = |e: serde_json::Error| in_file(KrakenError::Parse(e.to_string()));
+    let mut rows: Vec<AccountSummary> = Vec::new();
+    match path.extension().and_then(|extension| extension.to_str()) {
+        // Snapshots may be encrypted
+        Some("json") => {
+            let bytes = encryption::read_file(path, encryption::installed()).map_err(in_file)?;
+            match serde_json::from_slice(&bytes).map_err(parse)? {
+                JsonBalances::Report(report) => rows.extend(report.into_iter().map(AccountSummary::from)),
+                JsonBalances::Snapshot(snapshot) => rows.extend(snapshot.accounts.iter().map(|account| AccountSummary {
+                    client: account.client,
+                    available: account.available,
+                    held: account.held,
+                    pending: Some(account.pending()),
+                    total: account.available + account.held + account.pending(),
+                    locked: account.locked,
+                    lock: None,
+                })),
+            }
+        }
+        Some("jsonl" | "ndjson") => {
+            let file = File::open(path).map_err(|_| in_file(KrakenError::IO))?;
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|_| in_file(KrakenError::IO))?;
+                if !line.trim().is_empty() {
+                    rows.push(serde_json::from_str::<BalanceRow>(&line).map_err(parse)?.into());
+                }
+            }
+        }
+        _ => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_path(path)
+                .map_err(|e| in_file(csv_error(e)))?;
+            for row in reader.deserialize::<BalanceRow>() {
+                rows.push(row.map_err(|e| in_file(csv_error(e)))?.into());
+            }
+        }
+    }
+
+    let mut balances = BTreeMap::new();
+    for row in rows {
+        let client = row.client;
+        if balances.insert(client, row).is_some() {
+            return Err(in_file(KrakenError::Parse(format!("Client {client} is listed twice"))));
+        }
+    }
+    Ok(balances)
+}
+
+/// How a client's account changed between two reports.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    /// Only in the later report.
+    Added,
+    /// Only in the earlier report.
+    Removed,
+    Changed,
+}
+
+/// What changed for one client: the amount each balance moved by, counting missing accounts as empty, and its
+/// lock state before and after, where it had an account.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delta {
+    pub client: u32,
+    pub change: Change,
+    #[serde(serialize_with = "four_places")]
+    pub available: f64,
+    #[serde(serialize_with = "four_places")]
+    pub held: f64,
+    #[serde(serialize_with = "four_places")]
+    pub total: f64,
+    pub was_locked: Option<bool>,
+    pub locked: Option<bool>,
+}
+
+impl fmt::Display for Delta {
+    /// A `client, change, available, held, total, locked` row, with signed amounts, and the lock state written as
+    /// `before -> after` when it changed.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let locked = match (self.was_locked, self.locked) {
+            (Some(before), Some(after)) if before != after => format!("{before} -> {after}"),
+            (_, Some(locked)) | (Some(locked), None) => locked.to_string(),
+            (None, None) => String::new(),
+        };
+        let change = match self.change {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Changed => "changed",
+        };
+        write!(f, "{}, {change}, {:+.4}, {:+.4}, {:+.4}, {locked}", self.client, self.available, self.held, self.total)
+    }
+}
+
+/// The clients whose accounts differ between `before` and `after`, by client. Amounts are compared to the four
+/// places they are reported to.
+pub fn diff_balances(before: &BTreeMap<u32, AccountSummary>, after: &BTreeMap<u32, AccountSummary>) -> Vec<Delta> {
+    let mut clients: Vec<u32> = before.keys().chain(after.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+
+    let amounts = |summary: Option<&AccountSummary>| summary.map_or((0.0, 0.0, 0.0), |s| (s.available, s.held, s.total));
+    clients
+        .into_iter()
+        .filter_map(|client| {
+            let (old, new) = (before.get(&client), after.get(&client));
+            let change = match (old, new) {
+                (None, _) => Change::Added,
+                (_, None) => Change::Removed,
+                (Some(old), Some(new))
+                    if same_amount(old.available, new.available)
+                        && same_amount(old.held, new.held)
+                        && same_amount(old.total, new.total)
+                        && old.locked == new.locked =>
+                {
+                    return None;
+                }
+                _ => Change::Changed,
+            };
+            let ((available, held, total), (was_available, was_held, was_total)) = (amounts(new), amounts(old));
+            Some(Delta {
+                client,
+                change,
+                available: available - was_available,
+                held: held - was_held,
+                total: total - was_total,
+                was_locked: old.map(|old| old.locked),
+                locked: new.map(|new| new.locked),
+            })
+        })
+        .collect()
+}
+
+/// Whether two amounts are equal to the four places they are reported to.
+pub(crate) fn same_amount(a: f64, b: f64) -> bool {
+    (a * 1e4).round() == (b * 1e4).round()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{diff_balances, read_balances};
+    use crate::snapshot::Snapshot;
+    use crate::structures::ClientAccount;
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    #[test]
+    fn test_diff_balances() {
+        let mut report = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        report.write_all(b"client, available, held, total, locked\n1, 1.5, 0.0, 1.5, false\n2, 3.0, 1.0, , false\n3, 1, 0, 1, false\n")
+            .unwrap();
+        let before = read_balances(report.path()).unwrap();
+        assert_eq!(4.0, before[&2].total);
+
+        // The later balances come from a snapshot
+        let account = |available, held, locked| ClientAccount { available, held, locked, ..Default::default() };
+        let accounts = HashMap::from([
+            (1, account(1.50001, 0.0, false)),
+            (2, account(2.0, 0.0, true)),
+            (4, account(5.0, 0.0, false)),
+        ]);
+        let snapshot = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        Snapshot::capture(&accounts).unwrap().save(snapshot.path()).unwrap();
+        let after = read_balances(snapshot.path()).unwrap();
+
+        let deltas: Vec<String> = diff_balances(&before, &after).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            vec![
+                "2, changed, -1.0000, -1.0000, -2.0000, false -> true",
+                "3, removed, -1.0000, +0.0000, -1.0000, false",
+                "4, added, +5.0000, +0.0000, +5.0000, false",
+            ],
+            deltas
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use crate::dates::format_timestamp;
+use crate::output::four_places;
+use crate::snapshot::Snapshot;
+use serde::Serialize;
+use std::fmt;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// An account with no activity for the number of days asked for, with its balances. `last_activity` and `idle_days`
+/// are left out for accounts none of whose transactions had a time, which can't be told apart from dormant ones.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DormantAccount {
+    pub client: u32,
+    #[serde(serialize_with = "four_places")]
+    pub available: f64,
+    #[serde(serialize_with = "four_places")]
+    pub held: f64,
+    #[serde(serialize_with = "four_places")]
+    pub total: f64,
+    pub locked: bool,
+    /// Time of the latest transaction, as `dates::format_timestamp` writes it.
+    pub last_activity: Option<String>,
+    /// Whole days from the latest transaction to the time the report is as of.
+    pub idle_days: Option<u64>,
+}
+
+impl fmt::Display for DormantAccount {
+    /// A `client, available, held, total, locked, last_activity, idle_days` row, with the last two empty when unknown.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last_activity = self.last_activity.as_deref().unwrap_or_default();
+        let idle_days = self.idle_days.map(|days| days.to_string()).unwrap_or_default();
+        write!(f, "{}, {:.4}, {:.4}, {:.4}, {}, {last_activity}, {idle_days}", self.client, self.available, self.held,
+               self.total, self.locked)
+    }
+}
+
+/// The accounts of `snapshot` with no activity for at least `days` days as of `as_of`, in seconds since
+/// 1970-01-01 UTC, by client. Accounts whose latest transaction is after `as_of` aren't dormant.
+pub fn dormant_accounts(snapshot: &Snapshot, as_of: i64, days: u64) -> Vec<DormantAccount> {
+    let threshold = i64::try_from(days).unwrap_or(i64::MAX).saturating_mul(SECONDS_PER_DAY);
+    let mut dormant: Vec<DormantAccount> = snapshot
+        .accounts
+        .iter()
+        .filter(|account| account.last_activity.is_none_or(|last| as_of.saturating_sub(last) >= threshold))
+        .map(|account| DormantAccount {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            total: account.available + account.held + account.pending(),
+            locked: account.locked,
+            last_activity: account.last_activity.map(format_timestamp),
+            idle_days: account.last_activity.map(|last| (as_of.saturating_sub(last) / SECONDS_PER_DAY) as u64),
+        })
+        .collect();
+    dormant.sort_by_key(|account| account.client);
+    dormant
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dates::parse_timestamp;
+    use crate::dormant::dormant_accounts;
+    use crate::engine::Engine;
+    use crate::snapshot::Snapshot;
+    use crate::structures::Transaction;
+
+    #[test]
+    fn test_dormant_accounts() {
+        let rows = [
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 5.0, "timestamp": "2024-01-01T12:00:00Z"}"#,
+            r#"{"type": "deposit", "client": 2, "tx": 2, "amount": 3.0, "timestamp": 1706659200}"#,
+            // Balance assertions don't count as activity
+            r#"{"type": "assert_balance", "client": 1, "tx": 3, "amount": 5.0, "timestamp": "2024-03-01"}"#,
+            r#"{"type": "deposit", "client": 3, "tx": 4, "amount": 1.0}"#,
+        ];
+        let mut engine = Engine::new();
+        engine.process(rows.iter().map(|row| serde_json::from_str::<Transaction>(row).unwrap()));
+        let snapshot = Snapshot::capture(engine.accounts()).unwrap();
+
+        let as_of = parse_timestamp("2024-03-01").unwrap();
+        let dormant = dormant_accounts(&snapshot, as_of, 30);
+        assert_eq!(vec![1, 2, 3], dormant.iter().map(|account| account.client).collect::<Vec<_>>());
+        assert_eq!("1, 5.0000, 0.0000, 5.0000, false, 2024-01-01T12:00:00Z, 59", dormant[0].to_string());
+        assert_eq!(Some(30), dormant[1].idle_days);
+        assert_eq!("3, 1.0000, 0.0000, 1.0000, false, , ", dormant[2].to_string());
+
+        // Client 2 was active 30 days before, so isn't dormant for 31
+        let dormant = dormant_accounts(&snapshot, as_of, 31);
+        assert_eq!(vec![1, 3], dormant.iter().map(|account| account.client).collect::<Vec<_>>());
+        // The time survives a round trip through a saved snapshot
+        let directory = tempfile::tempdir().unwrap();
+        snapshot.save(directory.path().join("state.json")).unwrap();
+        let snapshot = Snapshot::load(directory.path().join("state.json")).unwrap();
+        assert_eq!(dormant, dormant_accounts(&snapshot, as_of, 31));
+    }
+}
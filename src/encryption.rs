@@ -0,0 +1,167 @@
+use crate::errors::KrakenError;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use std::fmt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Environment variable holding the key itself, in hex, when no key file is given.
+pub const KEY_VAR: &str = "PAYPROC_ENCRYPTION_KEY";
+
+/// First bytes of an encrypted file, with the format's version. They are authenticated along with the contents.
+const MAGIC: &[u8; 8] = b"PPENC01\n";
+const NONCE_LEN: usize = 12;
+
+/// An AES-256 key files are encrypted with at rest, written as 64 hex digits. Its `Debug` leaves the key out, so it
+/// never ends up in a log.
+#[derive(Clone, PartialEq)]
+pub struct EncryptionKey {
+    key: [u8; 32],
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    /// Parse a key written as 64 hex digits, such as by `openssl rand -hex 32`, ignoring surrounding whitespace.
+    pub fn from_hex(text: &str) -> Result<Self, KrakenError> {
+        let invalid = || KrakenError::Encryption(String::from("The key must be 64 hex digits"));
+        let text = text.trim();
+        if text.len() != 64 {
+            return Err(invalid());
+        }
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = text.get(2 * i..2 * i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()).ok_or_else(invalid)?;
+        }
+        Ok(Self { key })
+    }
+
+    /// Read a key from the file at `path`, written as `from_hex` expects.
+    pub fn load(path: &Path) -> Result<Self, KrakenError> {
+        let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+        let text = std::fs::read_to_string(path).map_err(|_| in_file(KrakenError::IO))?;
+        Self::from_hex(&text).map_err(in_file)
+    }
+
+    /// Encrypt and authenticate `plaintext` with AES-256-GCM under a random nonce, as `MAGIC`, the nonce, then the
+    /// ciphertext and its tag.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, KrakenError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(&nonce, Payload { msg: plaintext, aad: MAGIC })
+            .map_err(|_| KrakenError::Encryption(String::from("Failed to encrypt")))?;
+        let mut bytes = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+        Ok(bytes)
+    }
+
+    /// Decrypt what `encrypt` wrote, failing if it was encrypted with another key or changed since.
+    pub fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, KrakenError> {
+        let rest = bytes.strip_prefix(MAGIC.as_slice()).ok_or_else(|| KrakenError::Encryption(String::from("Not encrypted")))?;
+        if rest.len() < NONCE_LEN {
+            return Err(KrakenError::Encryption(String::from("Truncated")));
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        self.cipher().decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: MAGIC }).map_err(|_| {
+            KrakenError::Encryption(String::from("Failed to decrypt: encrypted with another key, or changed since"))
+        })
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.key))
+    }
+}
+
+/// Whether `bytes` were written by `EncryptionKey::encrypt`.
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Read the file at `path`, decrypting it with `key` if it's encrypted. Files that aren't are read as they are,
+/// so turning encryption on doesn't strand the files written before.
+pub fn read_file(path: &Path, key: Option<&EncryptionKey>) -> Result<Vec<u8>, KrakenError> {
+    let bytes = std::fs::read(path).map_err(|_| KrakenError::IO)?;
+    match (is_encrypted(&bytes), key) {
+        (false, _) => Ok(bytes),
+        (true, Some(key)) => key.decrypt(&bytes),
+        (true, None) => Err(KrakenError::Encryption(String::from("Encrypted, and no encryption key was given"))),
+    }
+}
+
+/// Write `bytes` to `path`, encrypted with `key` if there is one.
+pub fn write_file(path: &Path, bytes: &[u8], key: Option<&EncryptionKey>) -> Result<(), KrakenError> {
+    let encrypted;
+    let bytes = match key {
+        Some(key) => {
+            encrypted = key.encrypt(bytes)?;
+            &encrypted
+        }
+        None => bytes,
+    };
+    std::fs::write(path, bytes).map_err(|_| KrakenError::IO)
+}
+
+static KEY: OnceLock<EncryptionKey> = OnceLock::new();
+
+/// Encrypt the snapshots and journals the process writes with `key` from now on, and decrypt those it reads. As
+/// with `rules::install`, a key can only be installed once; installing the same key again does nothing.
+pub fn install(key: EncryptionKey) -> Result<(), KrakenError> {
+    let installed = KEY.get_or_init(|| key.clone());
+    match *installed == key {
+        true => Ok(()),
+        false => Err(KrakenError::InvalidArgument(String::from("A different encryption key is already installed"))),
+    }
+}
+
+/// The key installed, if any.
+pub fn installed() -> Option<&'static EncryptionKey> {
+    KEY.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::{is_encrypted, read_file, write_file, EncryptionKey};
+
+    #[test]
+    fn test_encryption() {
+        let key = EncryptionKey::from_hex(&format!("{}\n", "0f".repeat(32))).unwrap();
+        assert_eq!(EncryptionKey::new([0x0f; 32]), key);
+        assert!(EncryptionKey::from_hex("0f").is_err() && EncryptionKey::from_hex(&"zz".repeat(32)).is_err());
+        assert_eq!("EncryptionKey(..)", format!("{key:?}"));
+
+        let plaintext = br#"{"accounts":[]}"#;
+        let encrypted = key.encrypt(plaintext).unwrap();
+        assert!(is_encrypted(&encrypted) && !is_encrypted(plaintext));
+        assert_eq!(8 + 12 + plaintext.len() + 16, encrypted.len());
+        // Every encryption takes a nonce of its own
+        assert_ne!(encrypted, key.encrypt(plaintext).unwrap());
+        assert_eq!(plaintext.to_vec(), key.decrypt(&encrypted).unwrap());
+
+        // Another key, or a changed byte, fails to decrypt
+        let other = EncryptionKey::new([1; 32]);
+        assert!(other.decrypt(&encrypted).unwrap_err().to_string().contains("Failed to decrypt"));
+        let mut changed = encrypted.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        assert_eq!("encryption", key.decrypt(&changed).unwrap_err().code());
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("journal.ledger");
+        write_file(&path, plaintext, Some(&key)).unwrap();
+        assert_eq!(plaintext.to_vec(), read_file(&path, Some(&key)).unwrap());
+        assert!(read_file(&path, None).unwrap_err().to_string().contains("no encryption key was given"));
+        // Files written without a key are read as they are, with a key or without
+        write_file(&path, plaintext, None).unwrap();
+        assert_eq!(plaintext.to_vec(), read_file(&path, Some(&key)).unwrap());
+    }
+}
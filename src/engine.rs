@@ -0,0 +1,1042 @@
+use crate::errors::KrakenError;
+use crate::history::{HistoryFilter, MemoryBudget};
+use crate::idempotency::{IdempotencyKey, IdempotencyKeys};
+use crate::input::InputSource;
+use crate::invariants;
+use crate::output::Balances;
+use crate::processor::{default_threads, ParallelMode, ProcessorConfig};
+use crate::rules::{self, Rules};
+use crate::structures::{ClientAccount, LockHold, LockReason, Transaction, TransactionType};
+use crate::tx_index::TxIndex;
+#[cfg(feature = "stream")]
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+
+/// Callbacks an `Engine` makes as it applies transactions, for embedders to hang their own side effects on, such as
+/// notifications or extra bookkeeping. Every callback does nothing unless implemented.
+pub trait EngineObserver: Send {
+    /// A transaction was given to the engine, and `outcome` is what became of it, applied or refused. Called before
+    /// the other callbacks, in the order transactions are given, for refusals before the account as well.
+    fn on_outcome(&mut self, _outcome: &Outcome) {}
+
+    /// `transaction` was applied, leaving the client's account as `account`.
+    fn on_applied(&mut self, _transaction: &Transaction, _account: &ClientAccount) {}
+
+    /// `transaction` was refused for `error`, leaving the account as it was.
+    fn on_rejected(&mut self, _transaction: &Transaction, _error: &KrakenError) {}
+
+    /// A chargeback by `tx` locked the account of `client`. Called after `on_applied`.
+    fn on_account_locked(&mut self, _client: u32, _tx: u32) {}
+
+    /// A dispute started holding `amount` of deposit `tx` of `client`. Called after `on_applied`.
+    fn on_dispute_opened(&mut self, _client: u32, _tx: u32, _amount: f64) {}
+
+    /// `tx` turned the available funds of `client` negative, leaving them at `available`. Called after `on_applied`,
+    /// and again for the client only once its funds have recovered and turned negative anew.
+    fn on_negative_balance(&mut self, _client: u32, _tx: u32, _available: f64) {}
+}
+
+/// An observer shared with whoever set it up, to look at or finish once the engine is done with it.
+impl<O: EngineObserver> EngineObserver for Arc<Mutex<O>> {
+    fn on_outcome(&mut self, outcome: &Outcome) {
+        self.lock().unwrap().on_outcome(outcome);
+    }
+
+    fn on_applied(&mut self, transaction: &Transaction, account: &ClientAccount) {
+        self.lock().unwrap().on_applied(transaction, account);
+    }
+
+    fn on_rejected(&mut self, transaction: &Transaction, error: &KrakenError) {
+        self.lock().unwrap().on_rejected(transaction, error);
+    }
+
+    fn on_account_locked(&mut self, client: u32, tx: u32) {
+        self.lock().unwrap().on_account_locked(client, tx);
+    }
+
+    fn on_dispute_opened(&mut self, client: u32, tx: u32, amount: f64) {
+        self.lock().unwrap().on_dispute_opened(client, tx, amount);
+    }
+
+    fn on_negative_balance(&mut self, client: u32, tx: u32, available: f64) {
+        self.lock().unwrap().on_negative_balance(client, tx, available);
+    }
+}
+
+/// Single-threaded transaction engine.
+/// Routes each transaction to its client's account, creating the account the first time the client is seen.
+#[derive(Default)]
+pub struct Engine {
+    accounts: HashMap<u32, ClientAccount>,
+    budget: Option<MemoryBudget>,
+    /// Policies for disputes and chargebacks, instead of the installed ones.
+    rules: Option<Arc<Rules>>,
+    observers: Vec<Box<dyn EngineObserver>>,
+    /// Keys of every transaction given so far, when transactions given twice are refused.
+    idempotency: Option<IdempotencyKeys>,
+    /// Tx ids each client has used, when transactions reusing one are refused.
+    tx_index: Option<TxIndex>,
+    /// What undoes the changes made since each savepoint still held, oldest first.
+    savepoints: Vec<Undo>,
+    /// Id of the next savepoint.
+    next_savepoint: u64,
+}
+
+/// A point the state of an `Engine` can be taken back to, made by `Engine::savepoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(u64);
+
+/// The state a savepoint took the engine back to, as far as it changed since.
+#[derive(Debug)]
+struct Undo {
+    id: u64,
+    /// Each account changed since, as it was then, or `None` for one opened since.
+    accounts: HashMap<u32, Option<ClientAccount>>,
+    /// Idempotency keys recorded since.
+    keys: Vec<IdempotencyKey>,
+    /// Client and tx ids recorded in the tx index since.
+    tx_ids: Vec<(u32, u32)>,
+}
+
+impl fmt::Debug for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Engine")
+            .field("accounts", &self.accounts)
+            .field("budget", &self.budget)
+            .field("rules", &self.rules)
+            .field("observers", &self.observers.len())
+            .field("idempotency", &self.idempotency.as_ref().map(IdempotencyKeys::len))
+            .field("tx_index", &self.tx_index.as_ref().map(TxIndex::len))
+            .field("savepoints", &self.savepoints.len())
+            .finish()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// An engine whose account histories count against (and may spill under) `budget`.
+    pub fn with_budget(budget: Option<MemoryBudget>) -> Self {
+        Self {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    /// Resume from previously computed accounts, such as a restored snapshot.
+    pub fn from_accounts(accounts: HashMap<u32, ClientAccount>, budget: Option<MemoryBudget>) -> Self {
+        Self {
+            accounts,
+            budget,
+            ..Default::default()
+        }
+    }
+
+    /// Apply `rules` to disputes and chargebacks instead of the installed ones, when given.
+    pub fn with_rules(mut self, rules: Option<Arc<Rules>>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Also call `observer` back for every transaction applied from now on, after any observers added before.
+    pub fn with_observer(mut self, observer: impl EngineObserver + 'static) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /// Refuse every transaction whose idempotency key is among `keys`, or among those of the transactions given
+    /// from now on, when given. Transactions without a key are identified by their type and tx id.
+    pub fn with_idempotency(mut self, keys: Option<IdempotencyKeys>) -> Self {
+        self.idempotency = keys;
+        self
+    }
+
+    /// Refuse every transaction reusing a tx id its client used before, as recorded in `index`, or by transactions
+    /// given from now on, when given.
+    pub fn with_tx_index(mut self, index: Option<TxIndex>) -> Self {
+        self.tx_index = index;
+        self
+    }
+
+    /// Apply a single transaction to its client's account.
+    /// Refusals are logged at debug level, with their reason, except failed balance assertions, which are warned about.
+    pub fn apply(&mut self, mut transaction: Transaction) -> Result<(), KrakenError> {
+        if let Some(keys) = &mut self.idempotency {
+            match keys.admit(&mut transaction) {
+                Ok(key) => {
+                    if let Some(undo) = self.savepoints.last_mut() {
+                        undo.keys.push(key);
+                    }
+                }
+                Err(e) => return self.refuse(transaction, e),
+            }
+        }
+        if let Some(index) = &mut self.tx_index
+            && TxIndex::indexes(&transaction.kind)
+        {
+            if !index.insert(transaction.client, transaction.tx) {
+                let tx = transaction.tx;
+                return self.refuse(transaction, KrakenError::DuplicateTransaction(tx));
+            }
+            if let Some(undo) = self.savepoints.last_mut() {
+                undo.tx_ids.push((transaction.client, transaction.tx));
+            }
+        }
+        self.save_account(transaction.client);
+        if !self.observers.is_empty() {
+            return self.apply_observed(transaction);
+        }
+        let budget = self.budget.as_ref();
+        let (client, tx, kind, memo) = (transaction.client, transaction.tx, transaction.kind.clone(), transaction.memo.take());
+        let rules = self.rules.as_deref().unwrap_or_else(|| rules::current());
+        let account = self.accounts.entry(client).or_insert_with(|| ClientAccount::new(budget));
+        let result = invariants::apply_checked(account, transaction, rules);
+        log_refusal(&result, client, tx, &kind, memo.as_deref());
+        result
+    }
+
+    /// Apply every transaction of `transactions`, from whatever source, in order, returning how many were refused.
+    /// Refusals are logged as by `apply`; `apply_batch` tells why each was refused.
+    pub fn process(&mut self, transactions: impl IntoIterator<Item = Transaction>) -> usize {
+        transactions.into_iter().map(|transaction| self.apply(transaction)).filter(Result::is_err).count()
+    }
+
+    /// `process`, for transactions arriving on an async `Stream`, such as a channel or a network feed. Each is
+    /// applied as it arrives.
+    #[cfg(feature = "stream")]
+    pub async fn process_stream(&mut self, transactions: impl Stream<Item = Transaction>) -> usize {
+        let mut transactions = std::pin::pin!(transactions);
+        let mut refused = 0;
+        while let Some(transaction) = transactions.next().await {
+            refused += usize::from(self.apply(transaction).is_err());
+        }
+        refused
+    }
+
+    /// Apply `transactions` in order, as `apply` would one by one, returning the outcome of each, in the same order.
+    /// A refused transaction doesn't stop the rest, so the caller decides what to retry or report.
+    pub fn apply_batch(&mut self, transactions: Vec<Transaction>) -> Vec<ApplyResult> {
+        transactions
+            .into_iter()
+            .map(|transaction| {
+                let (client, tx, kind) = (transaction.client, transaction.tx, transaction.kind.clone());
+                ApplyResult { client, tx, kind, result: self.apply(transaction) }
+            })
+            .collect()
+    }
+
+    /// Release the funds held by the authorizations of `client` that have expired by `now`, as applying a transaction
+    /// of the client at that time would first, returning how much was released.
+    pub fn expire_authorizations(&mut self, client: u32, now: Option<i64>) -> Result<f64, KrakenError> {
+        self.save_account(client);
+        let rules = self.rules.as_deref().unwrap_or_else(|| rules::current());
+        match self.accounts.get_mut(&client) {
+            Some(account) => account.expire_authorizations(now, rules),
+            None => Ok(0.0),
+        }
+    }
+
+    /// Make the pending deposits of `client` due by its next transaction, at time `now`, available, as applying it
+    /// would first, returning how much was released.
+    pub fn release_pending(&mut self, client: u32, now: Option<i64>) -> Result<f64, KrakenError> {
+        self.save_account(client);
+        self.accounts.get_mut(&client).map_or(Ok(0.0), |account| account.release_pending(now))
+    }
+
+    /// Lock the account of `client` for `reason`, as an operator or a fraud rule would, at time `at` if known. An
+    /// account is opened for a client not seen yet. Returns whether it wasn't locked already. Observers aren't called,
+    /// as no transaction locked it.
+    pub fn lock_account(&mut self, client: u32, reason: LockReason, at: Option<i64>) -> bool {
+        self.save_account(client);
+        let budget = self.budget.as_ref();
+        self.accounts.entry(client).or_insert_with(|| ClientAccount::new(budget)).lock(reason, at)
+    }
+
+    /// Unlock the account of `client` for `reason`, whatever locked it. Returns whether it was locked.
+    pub fn unlock_account(&mut self, client: u32, reason: LockReason, at: Option<i64>) -> Result<bool, KrakenError> {
+        self.save_account(client);
+        self.accounts.get_mut(&client).map_or(Ok(false), |account| account.unlock(reason, at))
+    }
+
+    /// Mark the current state, so that `rollback_to` can undo everything done from now on, as when a batch is applied
+    /// tentatively, until what it was taken from is committed. Savepoints nest: rolling back to one undoes those made
+    /// after it too. Each account is copied the first time it's changed after a savepoint, history included, so
+    /// savepoints held over many transactions cost memory; `release` one once its changes are kept.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_savepoint;
+        self.next_savepoint += 1;
+        self.savepoints.push(Undo { id, accounts: HashMap::new(), keys: Vec::new(), tx_ids: Vec::new() });
+        Savepoint(id)
+    }
+
+    /// Take the accounts, the idempotency keys, and the tx index back to where they were at `savepoint`, dropping
+    /// the savepoints made after it. The savepoint itself is kept, to roll back to again. Observers aren't called
+    /// back, so whatever they did for the transactions undone stays done. Returns whether the savepoint was held,
+    /// as it isn't once released or rolled back past.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> bool {
+        let Some(position) = self.savepoints.iter().position(|undo| undo.id == savepoint.0) else {
+            return false;
+        };
+        for undo in self.savepoints.drain(position..).rev() {
+            for (client, account) in undo.accounts {
+                match account {
+                    Some(account) => self.accounts.insert(client, account),
+                    None => self.accounts.remove(&client),
+                };
+            }
+            if let Some(keys) = &mut self.idempotency {
+                undo.keys.iter().for_each(|key| _ = keys.remove(key));
+            }
+            if let Some(index) = &mut self.tx_index {
+                undo.tx_ids.iter().for_each(|&(client, tx)| _ = index.remove(client, tx));
+            }
+        }
+        self.savepoints.push(Undo { id: savepoint.0, accounts: HashMap::new(), keys: Vec::new(), tx_ids: Vec::new() });
+        true
+    }
+
+    /// Keep what was done since `savepoint`, and stop being able to roll back to it, or to those made after it.
+    /// Rolling back to a savepoint made before it still undoes it all. Returns whether the savepoint was held.
+    pub fn release(&mut self, savepoint: Savepoint) -> bool {
+        let Some(position) = self.savepoints.iter().position(|undo| undo.id == savepoint.0) else {
+            return false;
+        };
+        let released: Vec<Undo> = self.savepoints.drain(position..).collect();
+        if let Some(parent) = self.savepoints.last_mut() {
+            for undo in released {
+                for (client, account) in undo.accounts {
+                    parent.accounts.entry(client).or_insert(account);
+                }
+                parent.keys.extend(undo.keys);
+                parent.tx_ids.extend(undo.tx_ids);
+            }
+        }
+        true
+    }
+
+    /// Copy the account of `client` as it is into the latest savepoint, unless it was changed since already.
+    fn save_account(&mut self, client: u32) {
+        if let Some(undo) = self.savepoints.last_mut() {
+            undo.accounts.entry(client).or_insert_with(|| self.accounts.get(&client).cloned());
+        }
+    }
+
+    /// `apply`, handing the transaction and what became of it to the observers.
+    fn apply_observed(&mut self, transaction: Transaction) -> Result<(), KrakenError> {
+        let mut observers = std::mem::take(&mut self.observers);
+        let result = self.apply_outcome(transaction, &mut |observed, outcome, negative_before| {
+            let (client, tx, account) = (outcome.client, outcome.tx, outcome.account);
+            for observer in &mut observers {
+                observer.on_outcome(outcome);
+                match outcome.result {
+                    Ok(()) => {
+                        observer.on_applied(observed, account);
+                        if observed.kind == TransactionType::Dispute {
+                            observer.on_dispute_opened(client, tx, outcome.held_change);
+                        }
+                        if account.locked && !outcome.before.locked {
+                            observer.on_account_locked(client, tx);
+                        }
+                        if account.negative_events > negative_before {
+                            observer.on_negative_balance(client, tx, account.available);
+                        }
+                    }
+                    Err(e) => observer.on_rejected(observed, e),
+                }
+            }
+        });
+        self.observers = observers;
+        result
+    }
+
+    /// Apply `transaction` to its client's account, opening it if need be, and call `observe` with the transaction,
+    /// what became of it, and how many times the account had turned negative before it.
+    fn apply_outcome(
+        &mut self,
+        transaction: Transaction,
+        observe: &mut dyn FnMut(&Transaction, &Outcome, u32),
+    ) -> Result<(), KrakenError> {
+        let budget = self.budget.as_ref();
+        let observed = transaction.clone();
+        let rules = self.rules.as_deref().unwrap_or_else(|| rules::current());
+        let account = self.accounts.entry(observed.client).or_insert_with(|| ClientAccount::new(budget));
+        // Expiry and release happen whether the transaction is applied or not, so are told apart from what it did
+        let prepared = account
+            .expire_authorizations(observed.timestamp, rules)
+            .and_then(|expired| Ok((expired, account.release_pending(observed.timestamp)?)));
+        let before = Balances::try_from(&*account)?;
+        let (pending_before, negative_before) = (account.pending()?, account.negative_events);
+        let holds_before = match account.locked {
+            true => account.lock_holds.clone(),
+            false => Vec::new(),
+        };
+        let ((expired, released), result) = match prepared {
+            Ok(prepared) => (prepared, invariants::apply_checked(account, transaction, rules)),
+            Err(e) => ((0.0, 0.0), Err(e)),
+        };
+        // Transactions keep the pending funds within the limit, so they add up after it as they did before
+        let pending_change = account.pending()? - pending_before;
+        log_refusal(&result, observed.client, observed.tx, &observed.kind, observed.memo.as_deref());
+
+        let outcome = Outcome {
+            client: observed.client,
+            tx: observed.tx,
+            kind: observed.kind.clone(),
+            amount: observed.amount,
+            memo: observed.memo.as_deref(),
+            timestamp: observed.timestamp,
+            result: result.as_ref().copied(),
+            expired,
+            released,
+            held_change: (account.held - before.held).abs(),
+            available_change: account.available - before.available,
+            pending_change,
+            unheld: match before.locked && !account.locked {
+                true => holds_before,
+                false => Vec::new(),
+            },
+            before,
+            account,
+        };
+        observe(&observed, &outcome, negative_before);
+        result
+    }
+
+    /// Refuse `transaction` before it reaches its account, as the account would have.
+    fn refuse(&mut self, transaction: Transaction, error: KrakenError) -> Result<(), KrakenError> {
+        let result = Err(error);
+        if !self.observers.is_empty() {
+            // The outcome is told with the account, as it would be without one yet
+            let unopened;
+            let account = match self.accounts.get(&transaction.client) {
+                Some(account) => account,
+                None => {
+                    unopened = ClientAccount::new(None);
+                    &unopened
+                }
+            };
+            let before = Balances::try_from(account)?;
+            let outcome = Outcome {
+                client: transaction.client,
+                tx: transaction.tx,
+                kind: transaction.kind.clone(),
+                amount: transaction.amount,
+                memo: transaction.memo.as_deref(),
+                timestamp: transaction.timestamp,
+                result: result.as_ref().copied(),
+                expired: 0.0,
+                released: 0.0,
+                before,
+                account,
+                held_change: 0.0,
+                available_change: 0.0,
+                pending_change: 0.0,
+                unheld: Vec::new(),
+            };
+            for observer in &mut self.observers {
+                observer.on_outcome(&outcome);
+                if let Err(e) = outcome.result {
+                    observer.on_rejected(&transaction, e);
+                }
+            }
+        }
+        log_refusal(&result, transaction.client, transaction.tx, &transaction.kind, transaction.memo.as_deref());
+        result
+    }
+
+    pub fn budget(&self) -> Option<&MemoryBudget> {
+        self.budget.as_ref()
+    }
+
+    pub fn accounts(&self) -> &HashMap<u32, ClientAccount> {
+        &self.accounts
+    }
+
+    pub fn account(&self, client: u32) -> Option<&ClientAccount> {
+        self.accounts.get(&client)
+    }
+
+    /// The deposit or withdrawal `tx` of `client`, with its dispute state, if `client` made it.
+    pub fn transaction(&self, client: u32, tx: u32) -> Result<Option<Transaction>, KrakenError> {
+        match self.accounts.get(&client) {
+            Some(account) => account.history.get(tx),
+            None => Ok(None),
+        }
+    }
+
+    /// The stored deposits and withdrawals of `client` that `filter` matches, in tx order. Spilled history is read
+    /// back without being loaded into memory again.
+    pub fn history(&self, client: u32, filter: &HistoryFilter) -> Result<Vec<Transaction>, KrakenError> {
+        let Some(account) = self.accounts.get(&client) else {
+            return Ok(Vec::new());
+        };
+        let mut transactions: Vec<Transaction> =
+            account.history.transactions()?.into_iter().filter(|transaction| filter.matches(transaction)).collect();
+        transactions.sort_unstable_by_key(|transaction| transaction.tx);
+        Ok(transactions)
+    }
+
+    /// Keys of every transaction given so far, if they're tracked.
+    pub fn idempotency(&self) -> Option<&IdempotencyKeys> {
+        self.idempotency.as_ref()
+    }
+
+    /// Tx ids each client has used so far, if they're tracked.
+    pub fn tx_index(&self) -> Option<&TxIndex> {
+        self.tx_index.as_ref()
+    }
+
+    pub fn into_accounts(self) -> HashMap<u32, ClientAccount> {
+        self.accounts
+    }
+}
+
+/// The outcome of one transaction of `Engine::apply_batch`, with what identifies it.
+#[derive(Debug)]
+pub struct ApplyResult {
+    pub client: u32,
+    pub tx: u32,
+    pub kind: TransactionType,
+    /// `Ok` if it was applied, or why it was refused.
+    pub result: Result<(), KrakenError>,
+}
+
+impl ApplyResult {
+    pub fn is_applied(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Everything an engine, and a run processing files through engines like it, is set up with, as checked by
+/// `EngineBuilder`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineConfig {
+    /// Policies for disputes and chargebacks.
+    pub rules: Rules,
+    /// How a run spreads its work across cores.
+    pub parallel: ParallelMode,
+    /// Workers of `parallel`: threads, Rayon pool size, or tokio workers.
+    pub threads: usize,
+    /// Estimated byte budget for account histories, beyond which they spill to a temporary file. Unbounded when
+    /// `None`.
+    pub max_memory: Option<usize>,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            rules: Rules::default(),
+            parallel: ParallelMode::default(),
+            threads: default_threads(),
+            max_memory: None,
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Settings for processing files with `compute_combined_totals` under this configuration.
+    pub fn processor_config(&self) -> ProcessorConfig {
+        ProcessorConfig {
+            parallel: self.parallel,
+            threads: self.threads,
+            max_memory: self.max_memory,
+            rules: Some(Arc::new(self.rules.clone())),
+            ..Default::default()
+        }
+    }
+}
+
+/// Sets up an `Engine` from one place, rather than from the loose `rules::install` and `ProcessorConfig` fields,
+/// checking the settings together before anything is built.
+#[derive(Default)]
+pub struct EngineBuilder {
+    config: EngineConfig,
+    observers: Vec<Box<dyn EngineObserver>>,
+}
+
+impl EngineBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_rules(mut self, rules: Rules) -> Self {
+        self.config.rules = rules;
+        self
+    }
+
+    pub fn with_parallel(mut self, parallel: ParallelMode) -> Self {
+        self.config.parallel = parallel;
+        self
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    pub fn with_max_memory(mut self, max_memory: usize) -> Self {
+        self.config.max_memory = Some(max_memory);
+        self
+    }
+
+    pub fn with_observer(mut self, observer: impl EngineObserver + 'static) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /// The configuration, once checked: a run needs at least one worker, and histories at least a byte of memory.
+    pub fn config(&self) -> Result<EngineConfig, KrakenError> {
+        let config = &self.config;
+        if config.threads == 0 {
+            return Err(KrakenError::InvalidArgument(String::from("An engine needs at least one thread")));
+        }
+        if config.max_memory == Some(0) {
+            return Err(KrakenError::InvalidArgument(String::from("An engine needs a max memory of at least 1 byte")));
+        }
+        Ok(config.clone())
+    }
+
+    /// An engine applying transactions as configured, calling back the observers.
+    pub fn build(self) -> Result<Engine, KrakenError> {
+        let config = self.config()?;
+        let budget = config.max_memory.map(MemoryBudget::new).transpose()?;
+        Ok(Engine {
+            budget,
+            rules: Some(Arc::new(config.rules)),
+            observers: self.observers,
+            ..Default::default()
+        })
+    }
+}
+
+/// One transaction as an engine applied it, with what became of it, as seen by `EngineObserver::on_outcome` and
+/// `replay`.
+pub struct Outcome<'a> {
+    pub client: u32,
+    pub tx: u32,
+    pub kind: TransactionType,
+    pub amount: Option<f64>,
+    pub memo: Option<&'a str>,
+    pub timestamp: Option<i64>,
+    pub result: Result<(), &'a KrakenError>,
+    /// Funds released by authorizations of the client that expired by the time of the transaction, right before it.
+    pub expired: f64,
+    /// Pending deposits of the client that became available by the transaction, right before it.
+    pub released: f64,
+    /// The client's balances right before the transaction, after any expiry, all zero for a client not seen before.
+    pub before: Balances,
+    /// The client's account right after the transaction.
+    pub account: &'a ClientAccount,
+    /// How much moved into or out of `held`. For disputes, resolves, and chargebacks, this is the amount of the
+    /// referenced transaction.
+    pub held_change: f64,
+    /// How much `available` rose by, or fell by when negative.
+    pub available_change: f64,
+    /// How much the pending deposits rose by, or fell by when negative.
+    pub pending_change: f64,
+    /// Deposits and withdrawals the lock held, released as the transaction unlocked the account.
+    pub unheld: Vec<LockHold>,
+}
+
+/// Log why a transaction of `client` was refused, if it was, along with its memo.
+pub(crate) fn log_refusal(
+    result: &Result<(), KrakenError>,
+    client: u32,
+    tx: u32,
+    kind: &TransactionType,
+    memo: Option<&str>,
+) {
+    match result {
+        Err(e @ KrakenError::BalanceAssertion(..)) => warn!(code = e.code(), client, tx, memo, "{e}"),
+        Err(e) => debug!(code = e.code(), client, tx, ?kind, memo, reason = %e, "Refused transaction"),
+        Ok(()) => {}
+    }
+}
+
+/// Apply `source` serially, in input order, with an engine of its own, calling `observe` after every transaction.
+/// The first error `observe` returns ends the replay.
+pub fn replay<S, F>(mut source: S, budget: Option<MemoryBudget>, mut observe: F) -> Result<(), KrakenError>
+where
+    S: InputSource,
+    F: FnMut(&Outcome) -> Result<(), KrakenError>,
+{
+    let mut engine = Engine::with_budget(budget);
+    while let Some(batch) = source.next_batch() {
+        for transaction in batch? {
+            let mut observed = Ok(());
+            let _ = engine.apply_outcome(transaction, &mut |_, outcome, _| observed = observe(outcome));
+            observed?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::books::{check_snapshot, BookViolation};
+    use crate::engine::{Engine, EngineBuilder, EngineObserver};
+    use crate::errors::KrakenError;
+    use crate::history::{HistoryFilter, MemoryBudget};
+    use crate::idempotency::IdempotencyKeys;
+    use crate::processor::{compute_account_totals, ParallelMode};
+    use crate::processor::tests::TEST_DIR;
+    use crate::rules::Rules;
+    use crate::snapshot::Snapshot;
+    use crate::structures::{ClientAccount, DisputeState, LockEvent, LockReason, Transaction, TransactionType};
+    use crate::tx_index::TxIndex;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_balance_assertion() {
+        let transaction = |kind, tx, amount| Transaction { kind, client: 1, tx, amount: Some(amount), memo: None, idempotency_key: None, timestamp: None, state: None };
+        let mut engine = Engine::new();
+        engine.apply(transaction(TransactionType::Deposit, 1, 2.5)).unwrap();
+        engine.apply(transaction(TransactionType::AssertBalance, 2, 2.50001)).unwrap();
+
+        let result = engine.apply(transaction(TransactionType::AssertBalance, 3, 3.0));
+        assert!(matches!(result, Err(KrakenError::BalanceAssertion(1, 3, 3.0, 2.5))), "{result:?}");
+        // The account is left as it was
+        assert_eq!(2.5, engine.accounts()[&1].available);
+        assert!(matches!(
+            engine.apply(Transaction { amount: None, ..transaction(TransactionType::AssertBalance, 4, 0.0) }),
+            Err(KrakenError::MissingAmount(4))
+        ));
+    }
+
+    #[test]
+    fn test_authorize_and_capture() {
+        let rules = Rules { authorization_expiry_days: Some(7), ..Default::default() };
+        let mut engine = Engine::new().with_rules(Some(Arc::new(rules.clone())));
+        let apply = |engine: &mut Engine, row: &str| engine.apply(serde_json::from_str::<Transaction>(row).unwrap());
+        let balances = |engine: &Engine| (engine.accounts()[&1].available, engine.accounts()[&1].held);
+        apply(&mut engine, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.0}"#).unwrap();
+        apply(&mut engine, r#"{"type": "authorize", "client": 1, "tx": 2, "amount": 4.0, "timestamp": "2024-01-01"}"#).unwrap();
+        assert_eq!((6.0, 4.0), balances(&engine));
+        let result = apply(&mut engine, r#"{"type": "authorize", "client": 1, "tx": 3, "amount": 7.0}"#);
+        assert!(matches!(result, Err(KrakenError::InsufficientFunds(1))), "{result:?}");
+
+        // Capturing less releases the rest, and only once
+        apply(&mut engine, r#"{"type": "capture", "client": 1, "tx": 2, "amount": 3.0}"#).unwrap();
+        assert_eq!((7.0, 0.0), balances(&engine));
+        assert!(apply(&mut engine, r#"{"type": "capture", "client": 1, "tx": 2}"#).is_err());
+        // Only authorizations are captured, and they aren't disputed
+        assert!(apply(&mut engine, r#"{"type": "capture", "client": 1, "tx": 1}"#).is_err());
+        assert!(apply(&mut engine, r#"{"type": "dispute", "client": 1, "tx": 2}"#).is_err());
+
+        apply(&mut engine, r#"{"type": "authorize", "client": 1, "tx": 4, "amount": 5.0, "timestamp": "2024-01-02"}"#).unwrap();
+        assert!(apply(&mut engine, r#"{"type": "capture", "client": 1, "tx": 4, "amount": 6.0}"#).is_err());
+        // Not yet expired six days later, but seven days later, even when the transaction itself is refused
+        apply(&mut engine, r#"{"type": "deposit", "client": 1, "tx": 5, "amount": 1.0, "timestamp": "2024-01-08"}"#).unwrap();
+        assert_eq!((3.0, 5.0), balances(&engine));
+        let result = apply(&mut engine, r#"{"type": "withdrawal", "client": 1, "tx": 6, "amount": 9.0, "timestamp": "2024-01-09"}"#);
+        assert!(matches!(result, Err(KrakenError::InsufficientFunds(1))), "{result:?}");
+        assert_eq!((8.0, 0.0), balances(&engine));
+        assert!(apply(&mut engine, r#"{"type": "capture", "client": 1, "tx": 4}"#).is_err());
+
+        let history = engine.accounts()[&1].history.transactions().unwrap();
+        let state = |tx| history.iter().find(|transaction| transaction.tx == tx).unwrap().state;
+        assert_eq!((Some(DisputeState::Captured), Some(DisputeState::Expired)), (state(2), state(4)));
+
+        // The time of an open authorization survives a snapshot, so it still expires once restored
+        apply(&mut engine, r#"{"type": "authorize", "client": 1, "tx": 7, "amount": 8.0, "timestamp": "2024-01-10"}"#).unwrap();
+        let snapshot = Snapshot::capture(engine.accounts()).unwrap();
+        let mut engine = Engine::from_accounts(snapshot.restore(None).unwrap(), None).with_rules(Some(Arc::new(rules)));
+        assert_eq!((0.0, 8.0), balances(&engine));
+        apply(&mut engine, r#"{"type": "assert_balance", "client": 1, "tx": 8, "amount": 8.0, "timestamp": "2024-01-17"}"#).unwrap();
+    }
+
+    #[test]
+    fn test_pending_deposits() {
+        let rules = Rules { deposit_delay_rows: Some(3), deposit_delay_days: Some(1), ..Default::default() };
+        let mut engine = Engine::new().with_rules(Some(Arc::new(rules.clone())));
+        let apply = |engine: &mut Engine, row: &str| engine.apply(serde_json::from_str::<Transaction>(row).unwrap());
+        let balances = |engine: &Engine| {
+            let account = &engine.accounts()[&1];
+            (account.available, account.held, account.pending().unwrap(), account.total().unwrap())
+        };
+        apply(&mut engine, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 5.0}"#).unwrap();
+        // Pending funds can't be withdrawn, but the refusal still counts towards the delay
+        let result = apply(&mut engine, r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": 1.0}"#);
+        assert!(matches!(result, Err(KrakenError::InsufficientFunds(1))), "{result:?}");
+        assert_eq!((0.0, 0.0, 5.0, 5.0), balances(&engine));
+
+        // A dispute of a pending deposit holds it from the pending funds, while tx 1 is available to the third row
+        // after it
+        apply(&mut engine, r#"{"type": "deposit", "client": 1, "tx": 3, "amount": 3.0}"#).unwrap();
+        apply(&mut engine, r#"{"type": "dispute", "client": 1, "tx": 3}"#).unwrap();
+        assert_eq!((5.0, 3.0, 0.0, 8.0), balances(&engine));
+        // A day later, before its rows are up
+        apply(&mut engine, r#"{"type": "deposit", "client": 1, "tx": 4, "amount": 2.0, "timestamp": "2024-01-01"}"#).unwrap();
+        apply(&mut engine, r#"{"type": "withdrawal", "client": 1, "tx": 5, "amount": 6.0, "timestamp": "2024-01-02"}"#).unwrap();
+        assert_eq!((1.0, 3.0, 0.0, 4.0), balances(&engine));
+
+        // What's left of the delay survives a snapshot
+        apply(&mut engine, r#"{"type": "deposit", "client": 1, "tx": 6, "amount": 1.0}"#).unwrap();
+        let snapshot = Snapshot::capture(engine.accounts()).unwrap();
+        let mut engine = Engine::from_accounts(snapshot.restore(None).unwrap(), None).with_rules(Some(Arc::new(rules)));
+        assert_eq!((1.0, 3.0, 1.0, 5.0), balances(&engine));
+        apply(&mut engine, r#"{"type": "resolve", "client": 1, "tx": 3}"#).unwrap();
+        apply(&mut engine, r#"{"type": "assert_balance", "client": 1, "tx": 7, "amount": 4.0}"#).unwrap();
+        assert_eq!((4.0, 0.0, 1.0, 5.0), balances(&engine));
+        apply(&mut engine, r#"{"type": "withdrawal", "client": 1, "tx": 8, "amount": 5.0}"#).unwrap();
+        assert_eq!((0.0, 0.0, 0.0, 0.0), balances(&engine));
+        assert!(engine.accounts()[&1].pending().unwrap().is_sign_positive());
+
+        // A resolve returns a disputed pending deposit to the pending funds, with its delay, and a chargeback drops it
+        let rules = Rules { deposit_delay_rows: Some(100), ..Default::default() };
+        let mut engine = Engine::new().with_rules(Some(Arc::new(rules)));
+        let withdraw = r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": 10.0}"#;
+        apply(&mut engine, r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.0}"#).unwrap();
+        assert!(matches!(apply(&mut engine, withdraw), Err(KrakenError::InsufficientFunds(1))));
+        apply(&mut engine, r#"{"type": "dispute", "client": 1, "tx": 1}"#).unwrap();
+        assert_eq!((0.0, 10.0, 0.0, 10.0), balances(&engine));
+        assert_eq!(Vec::<BookViolation>::new(), check_snapshot(&Snapshot::capture(engine.accounts()).unwrap()));
+        apply(&mut engine, r#"{"type": "resolve", "client": 1, "tx": 1}"#).unwrap();
+        assert_eq!((0.0, 0.0, 10.0, 10.0), balances(&engine));
+        assert!(matches!(apply(&mut engine, withdraw), Err(KrakenError::InsufficientFunds(1))));
+        apply(&mut engine, r#"{"type": "deposit", "client": 1, "tx": 3, "amount": 4.0}"#).unwrap();
+        apply(&mut engine, r#"{"type": "dispute", "client": 1, "tx": 3}"#).unwrap();
+        apply(&mut engine, r#"{"type": "chargeback", "client": 1, "tx": 3}"#).unwrap();
+        assert_eq!((0.0, 0.0, 10.0, 10.0), balances(&engine));
+        assert_eq!(1, engine.accounts()[&1].pending_deposits.len());
+    }
+
+    #[test]
+    fn test_chargeback_reversal() {
+        let rules = Rules { withdrawals_disputable: true, reversal_unlocks: true, ..Default::default() };
+        let mut engine = Engine::new().with_rules(Some(Arc::new(rules.clone())));
+        let mut apply = |row: &str| engine.apply(Transaction::try_from(row).unwrap());
+        let rows = ["deposit, 1, 1, 10.0", "deposit, 1, 2, 5.0", "withdrawal, 1, 3, 2.0", "dispute, 1, 1,", "chargeback, 1, 1,"];
+        for row in rows {
+            apply(row).unwrap();
+        }
+        // Only what stands charged back is reversed
+        assert!(matches!(apply("chargeback_reversal, 1, 2,"), Err(KrakenError::DisputeStateError(_))));
+        assert!(matches!(apply("chargeback_reversal, 1, 9,"), Err(KrakenError::NoSuchTransactionError(9))));
+        let balances = |engine: &Engine| (engine.accounts()[&1].available, engine.accounts()[&1].locked);
+        assert_eq!((3.0, true), balances(&engine));
+
+        // The account stays locked while the chargeback of the withdrawal stands
+        let mut apply = |row: &str| engine.apply(Transaction::try_from(row).unwrap());
+        apply("dispute, 1, 3,").unwrap();
+        apply("chargeback, 1, 3,").unwrap();
+        apply("chargeback_reversal, 1, 1,").unwrap();
+        assert_eq!((15.0, true), balances(&engine));
+        assert!(engine.apply(Transaction::try_from("chargeback_reversal, 1, 1,").unwrap()).is_err());
+
+        // A snapshot remembers the chargeback left standing, and the withdrawal stands again once reversed
+        let snapshot = Snapshot::capture(engine.accounts()).unwrap();
+        let mut engine = Engine::from_accounts(snapshot.restore(None).unwrap(), None).with_rules(Some(Arc::new(rules)));
+        engine.apply(Transaction::try_from("chargeback_reversal, 1, 3,").unwrap()).unwrap();
+        assert_eq!((13.0, false), balances(&engine));
+        let history = engine.accounts()[&1].history.transactions().unwrap();
+        let state = |tx| history.iter().find(|transaction| transaction.tx == tx).unwrap().state;
+        assert_eq!((Some(DisputeState::Reversed), None, Some(DisputeState::Reversed)), (state(1), state(2), state(3)));
+
+        // Unless the rules say so, reversals leave the account locked
+        let mut engine = Engine::new();
+        for row in ["deposit, 2, 1, 1.0", "dispute, 2, 1,", "chargeback, 2, 1,", "chargeback_reversal, 2, 1,"] {
+            engine.apply(Transaction::try_from(row).unwrap()).unwrap();
+        }
+        assert!(engine.accounts()[&2].locked && engine.accounts()[&2].available == 1.0);
+    }
+
+    #[test]
+    fn test_lock_events() {
+        let rules = Rules { reversal_unlocks: true, ..Default::default() };
+        let mut engine = Engine::new().with_rules(Some(Arc::new(rules.clone())));
+        let rows = [
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 2.0}"#,
+            r#"{"type": "dispute", "client": 1, "tx": 1}"#,
+            r#"{"type": "chargeback", "client": 1, "tx": 1, "timestamp": 100}"#,
+            r#"{"type": "chargeback_reversal", "client": 1, "tx": 1, "timestamp": 200}"#,
+        ];
+        for row in rows {
+            engine.apply(serde_json::from_str(row).unwrap()).unwrap();
+        }
+        let event = |locked, reason, at| LockEvent { locked, reason, at };
+        let mut expected = vec![
+            event(true, LockReason::Chargeback { tx: 1 }, Some(100)),
+            event(false, LockReason::ChargebackReversal { tx: 1 }, Some(200)),
+        ];
+        assert_eq!(expected, engine.accounts()[&1].lock_events);
+        assert_eq!(None, engine.accounts()[&1].lock_event());
+
+        // Locking what is locked already records nothing, and a reversal leaves a fraud rule's lock alone
+        engine.apply(Transaction::try_from("deposit, 1, 2, 1.0").unwrap()).unwrap();
+        let fraud = LockReason::Fraud { rule: String::from("velocity") };
+        assert!(engine.lock_account(1, fraud.clone(), Some(300)));
+        assert!(!engine.lock_account(1, LockReason::Admin { note: None }, None));
+        for row in ["dispute, 1, 2,", "chargeback, 1, 2,", "chargeback_reversal, 1, 2,"] {
+            engine.apply(Transaction::try_from(row).unwrap()).unwrap();
+        }
+        expected.push(event(true, fraud.clone(), Some(300)));
+        assert_eq!(Some(&expected[2]), engine.accounts()[&1].lock_event());
+        let admin = LockReason::Admin { note: Some(String::from("cleared")) };
+        assert!(engine.unlock_account(1, admin.clone(), None).unwrap());
+        assert!(!engine.unlock_account(2, admin.clone(), None).unwrap());
+        expected.push(event(false, admin, None));
+
+        // Snapshots keep the events, which read as flat objects
+        let snapshot = Snapshot::capture(engine.accounts()).unwrap();
+        let json = serde_json::to_string(&snapshot.accounts[0].locks[2]).unwrap();
+        assert_eq!(r#"{"locked":true,"reason":"fraud","rule":"velocity","at":300}"#, json);
+        let accounts = snapshot.restore(None).unwrap();
+        assert_eq!(expected, accounts[&1].lock_events);
+    }
+
+    #[test]
+    fn test_savepoints() {
+        // A one-byte budget spills history, which rolling back must leave readable
+        let mut engine = Engine::with_budget(Some(MemoryBudget::new(1).unwrap()))
+            .with_idempotency(Some(IdempotencyKeys::new()))
+            .with_tx_index(Some(TxIndex::new()));
+        let apply = |engine: &mut Engine, row: &str| engine.apply(Transaction::try_from(row).unwrap());
+        apply(&mut engine, "deposit, 1, 1, 5.0").unwrap();
+        let batch = engine.savepoint();
+        for row in ["withdrawal, 1, 2, 1.0", "dispute, 1, 1, ", "deposit, 2, 3, 4.0"] {
+            apply(&mut engine, row).unwrap();
+        }
+        let nested = engine.savepoint();
+        apply(&mut engine, "chargeback, 1, 1, ").unwrap();
+        assert!(engine.lock_account(2, LockReason::Admin { note: None }, None));
+        assert!(engine.rollback_to(nested));
+        assert_eq!("1, -1.0000, 5.0000, 4.0000, false", engine.accounts()[&1].to_str_row(1).unwrap());
+        assert!(!engine.accounts()[&2].locked);
+
+        // Rolling back to the first savepoint undoes the nested one's changes as well, which it can't roll back to
+        apply(&mut engine, "resolve, 1, 1, ").unwrap();
+        assert!(engine.rollback_to(batch));
+        assert!(!engine.rollback_to(nested));
+        assert_eq!("1, 5.0000, 0.0000, 5.0000, false", engine.accounts()[&1].to_str_row(1).unwrap());
+        assert_eq!(None, engine.transaction(1, 1).unwrap().unwrap().state);
+        assert!(engine.account(2).is_none() && engine.transaction(1, 2).unwrap().is_none());
+        assert_eq!((1, 1), (engine.idempotency().unwrap().len(), engine.tx_index().unwrap().len()));
+
+        // The undone transactions may be given again, and what a released savepoint kept stays
+        apply(&mut engine, "withdrawal, 1, 2, 1.0").unwrap();
+        assert!(apply(&mut engine, "deposit, 1, 1, 5.0").is_err());
+        assert!(engine.release(batch) && !engine.release(batch) && !engine.rollback_to(batch));
+        assert_eq!("1, 4.0000, 0.0000, 4.0000, false", engine.accounts()[&1].to_str_row(1).unwrap());
+    }
+
+    /// Writes down every callback as text.
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl EngineObserver for Recorder {
+        fn on_applied(&mut self, transaction: &Transaction, account: &ClientAccount) {
+            self.0.lock().unwrap().push(format!("applied {} {}", transaction.tx, account.available));
+        }
+
+        fn on_rejected(&mut self, transaction: &Transaction, error: &KrakenError) {
+            self.0.lock().unwrap().push(format!("rejected {}: {error}", transaction.tx));
+        }
+
+        fn on_account_locked(&mut self, client: u32, tx: u32) {
+            self.0.lock().unwrap().push(format!("locked {client} by {tx}"));
+        }
+
+        fn on_dispute_opened(&mut self, client: u32, tx: u32, amount: f64) {
+            self.0.lock().unwrap().push(format!("disputed {client} {tx} {amount}"));
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = Engine::new().with_observer(Recorder(events.clone()));
+        let transaction = |kind, tx, amount| Transaction { kind, client: 1, tx, amount, memo: None, idempotency_key: None, timestamp: None, state: None };
+        engine.apply(transaction(TransactionType::Deposit, 1, Some(2.5))).unwrap();
+        engine.apply(transaction(TransactionType::Dispute, 1, None)).unwrap();
+        engine.apply(transaction(TransactionType::Chargeback, 1, None)).unwrap();
+        engine.apply(transaction(TransactionType::Deposit, 2, Some(1.0))).unwrap_err();
+
+        assert_eq!(
+            vec![
+                "applied 1 2.5",
+                "applied 1 0",
+                "disputed 1 1 2.5",
+                "applied 1 0",
+                "locked 1 by 1",
+                "rejected 2: Account is locked: 1",
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_builder() {
+        assert!(EngineBuilder::new().with_threads(0).build().is_err());
+        assert!(EngineBuilder::new().with_max_memory(0).config().is_err());
+
+        let rules = Rules { chargeback_locks: false, ..Default::default() };
+        let builder = EngineBuilder::new().with_rules(rules).with_parallel(ParallelMode::Rayon).with_threads(2).with_max_memory(1);
+        let config = builder.config().unwrap();
+        let path = String::from(TEST_DIR) + "2-chargeback-after-withdraw.csv";
+        let totals = compute_account_totals(&path, &config.processor_config()).unwrap();
+        assert_eq!("1, -9.5000, 0.0000, -9.5000, false", totals[&1].to_str_row(1).unwrap());
+
+        let mut engine = builder.build().unwrap();
+        for row in ["deposit, 1, 1, 2.0", "dispute, 1, 1, ", "chargeback, 1, 1, ", "deposit, 1, 2, 1.0"] {
+            engine.apply(Transaction::try_from(row).unwrap()).unwrap();
+        }
+        assert_eq!("1, 1.0000, 0.0000, 1.0000, false", engine.accounts()[&1].to_str_row(1).unwrap());
+        assert_eq!(1, engine.budget().unwrap().limit());
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let rows = ["deposit, 1, 1, 2.0", "withdrawal, 1, 2, 5.0", "dispute, 2, 9, ", "withdrawal, 1, 3, 1.5"];
+        let mut engine = Engine::new();
+        let results = engine.apply_batch(rows.iter().map(|row| Transaction::try_from(*row).unwrap()).collect());
+
+        let outcomes: Vec<_> = results.iter().map(|result| (result.client, result.tx, result.is_applied())).collect();
+        assert_eq!(vec![(1, 1, true), (1, 2, false), (2, 9, false), (1, 3, true)], outcomes);
+        assert!(matches!(results[1].result, Err(KrakenError::InsufficientFunds(1))));
+        assert!(matches!(results[2].result, Err(KrakenError::NoSuchTransactionError(9))));
+        assert_eq!(0.5, engine.accounts()[&1].available);
+    }
+
+    #[test]
+    fn test_process() {
+        let rows = ["deposit, 1, 1, 2.0", "withdrawal, 1, 2, 5.0", "deposit, 2, 3, 1.0", "dispute, 2, 3, "];
+        let transactions = || rows.iter().map(|row| Transaction::try_from(*row).unwrap());
+        let mut engine = Engine::new();
+        assert_eq!(1, engine.process(transactions()));
+        assert_eq!("2, 0.0000, 1.0000, 1.0000, false", engine.accounts()[&2].to_str_row(2).unwrap());
+
+        #[cfg(feature = "stream")]
+        {
+            let mut streamed = Engine::new();
+            let runtime = crate::processor::runtime(1).unwrap();
+            assert_eq!(1, runtime.block_on(streamed.process_stream(futures_util::stream::iter(transactions()))));
+            assert_eq!("1, 2.0000, 0.0000, 2.0000, false", streamed.accounts()[&1].to_str_row(1).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_queries() {
+        // A one-byte budget spills history, so queries read some of it back from disk
+        let mut engine = Engine::with_budget(Some(MemoryBudget::new(1).unwrap()));
+        let rows = ["deposit, 1, 1, 2.0", "deposit, 1, 3, 9.0", "withdrawal, 1, 2, 1.0", "deposit, 2, 4, 5.0", "dispute, 1, 3, "];
+        engine.process(rows.iter().map(|row| Transaction::try_from(*row).unwrap()));
+
+        assert_eq!(10.0, engine.account(1).unwrap().total().unwrap());
+        assert!(engine.account(3).is_none());
+        let disputed = engine.transaction(1, 3).unwrap().unwrap();
+        assert_eq!((9.0, Some(DisputeState::Open)), (disputed.amount.unwrap(), disputed.state));
+        assert!(engine.transaction(2, 3).unwrap().is_none());
+        assert!(engine.accounts()[&1].history.spilled_len() > 0);
+
+        let txs = |filter: HistoryFilter| engine.history(1, &filter).unwrap().iter().map(|t| t.tx).collect::<Vec<_>>();
+        assert_eq!(vec![1, 2, 3], txs(HistoryFilter::default()));
+        assert_eq!(vec![1, 3], txs(HistoryFilter::default().with_kind(TransactionType::Deposit)));
+        assert_eq!(vec![1, 2], txs(HistoryFilter::default().with_state(None)));
+        assert_eq!(vec![1], txs(HistoryFilter::default().with_state(None).with_amount_range(Some(1.5), Some(9.0))));
+    }
+}
@@ -1,5 +1,17 @@
+use crate::redact::{Amount, Client};
 use thiserror::Error;
 
+/// Exit status of a run failing for any reason without a status of its own.
+pub const EXIT_FAILURE: u8 = 1;
+/// Exit status of a run failing to read or write something.
+pub const EXIT_IO: u8 = 3;
+/// Exit status of a run failing on input that doesn't parse or validate.
+pub const EXIT_SCHEMA: u8 = 4;
+/// Exit status of a run failing on transactions refused by the business rules, or the accounts they locked.
+pub const EXIT_REJECTED: u8 = 5;
+/// Exit status of a run whose balances don't match those `--reconcile` or its balance assertions expect.
+pub const EXIT_UNBALANCED: u8 = 6;
+
 #[derive(Error, Debug)]
 pub enum KrakenError {
     #[error("IO Error")]
@@ -14,12 +26,211 @@ pub enum KrakenError {
     #[error("No Such Transaction Error: {0}")]
     NoSuchTransactionError(u32),
 
-    #[error("Account is locked: {0}")]
+    #[error("Account is locked: {}", Client(*.0))]
     AccountLocked(u32),
 
-    #[error("Insufficient Funds for account: {0}")]
+    #[error("Insufficient Funds for account: {}", Client(*.0))]
     InsufficientFunds(u32),
 
+    #[error("Account {} would fall below its reserve of {:.4}", Client(*.0), Amount(*.1))]
+    BelowReserve(u32, f64),
+
+    #[error("Missing amount for transaction: {0}")]
+    MissingAmount(u32),
+
+    #[error("Duplicate transaction: {0}")]
+    DuplicateTransaction(u32),
+
+    #[error("Already processed: {0}")]
+    AlreadyProcessed(String),
+
+    #[error("Balance assertion failed for client {} at tx {}: asserted {:.4}, available {:.4}", Client(*.0), .1, Amount(*.2), Amount(*.3))]
+    BalanceAssertion(u32, u32, f64, f64),
+
+    #[error("Parse Error: {0}")]
+    Parse(String),
+
+    #[error("Verification failed: {0} client(s) differ between serial and parallel runs")]
+    Verification(usize),
+
+    #[error("Validation failed: {0} problem(s) found")]
+    Validation(usize),
+
+    #[error("{0}: {1}")]
+    InFile(String, Box<KrakenError>),
+
+    #[error("Remote input error: {0}")]
+    Remote(String),
+
+    #[error("Database error: {0}")]
+    Database(String),
+
+    #[error("Message broker error: {0}")]
+    Broker(String),
+
+    #[error("Telemetry error: {0}")]
+    Telemetry(String),
+
+    #[error("Metrics push failed: {0}")]
+    MetricsPush(String),
+
+    #[error("Webhook failed: {0}")]
+    Webhook(String),
+
+    #[error("{0} transaction(s) were rejected")]
+    RejectedTransactions(u64),
+
+    #[error("{0} account(s) are locked")]
+    LockedAccounts(usize),
+
+    #[error("{0} balance assertion(s) failed")]
+    FailedAssertions(u64),
+
+    #[error("Reconciliation failed: {0} client(s) differ from the expected balances")]
+    Unbalanced(usize),
+
+    #[error("{0} golden test case(s) failed")]
+    GoldenFailures(usize),
+
+    #[error("{0} violation(s) found in the books")]
+    BookViolations(usize),
+
+    #[error("Interrupted after {0} row(s), so the report is incomplete")]
+    Interrupted(u64, u8),
+
+    #[error("{} conflict(s) merging the snapshots, the first: {}", .0.len(), .0.first().map_or("", String::as_str))]
+    MergeConflicts(Vec<String>),
+
+    #[error("Audit log broken at record {0}: {1}")]
+    AuditChain(u64, String),
+
+    #[error("Bad signature: {0}")]
+    BadSignature(String),
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Amount out of range: {0}")]
+    AmountOverflow(String),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
     #[error("Error")]
     Error,
 }
+
+impl KrakenError {
+    /// Stable identifier of the kind of error, as reported by `--diagnostics json`. Errors in a file take the
+    /// code of the error they wrap.
+    pub fn code(&self) -> &'static str {
+        match self {
+            KrakenError::IO => "io",
+            KrakenError::Enum(_) => "invalid_value",
+            KrakenError::DisputeStateError(_) => "dispute_state",
+            KrakenError::NoSuchTransactionError(_) => "no_such_transaction",
+            KrakenError::AccountLocked(_) => "account_locked",
+            KrakenError::InsufficientFunds(_) => "insufficient_funds",
+            KrakenError::BelowReserve(..) => "below_reserve",
+            KrakenError::MissingAmount(_) => "missing_amount",
+            KrakenError::DuplicateTransaction(_) => "duplicate_transaction",
+            KrakenError::AlreadyProcessed(_) => "already_processed",
+            KrakenError::BalanceAssertion(..) => "balance_assertion",
+            KrakenError::Parse(_) => "parse",
+            KrakenError::Verification(_) => "verification",
+            KrakenError::Validation(_) => "validation",
+            KrakenError::InFile(_, e) => e.code(),
+            KrakenError::Remote(_) => "remote",
+            KrakenError::Database(_) => "database",
+            KrakenError::Broker(_) => "broker",
+            KrakenError::Telemetry(_) => "telemetry",
+            KrakenError::MetricsPush(_) => "metrics_push",
+            KrakenError::Webhook(_) => "webhook",
+            KrakenError::RejectedTransactions(_) => "rejected_transactions",
+            KrakenError::LockedAccounts(_) => "locked_accounts",
+            KrakenError::FailedAssertions(_) => "failed_assertions",
+            KrakenError::Unbalanced(_) => "unbalanced",
+            KrakenError::GoldenFailures(_) => "golden_failures",
+            KrakenError::BookViolations(_) => "book_violations",
+            KrakenError::Interrupted(..) => "interrupted",
+            KrakenError::MergeConflicts(_) => "merge_conflict",
+            KrakenError::AuditChain(..) => "audit_chain",
+            KrakenError::BadSignature(_) => "bad_signature",
+            KrakenError::Encryption(_) => "encryption",
+            KrakenError::AmountOverflow(_) => "amount_overflow",
+            KrakenError::InvalidArgument(_) => "invalid_argument",
+            KrakenError::Error => "error",
+        }
+    }
+
+    /// Exit status of a run ending in this error. Errors in a file take the status of the error they wrap, and
+    /// interrupted runs that of the signal.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            KrakenError::IO
+            | KrakenError::Remote(_)
+            | KrakenError::Database(_)
+            | KrakenError::Broker(_)
+            | KrakenError::MetricsPush(_)
+            | KrakenError::Webhook(_) => EXIT_IO,
+            KrakenError::Enum(_) | KrakenError::Parse(_) | KrakenError::MissingAmount(_) | KrakenError::Validation(_) => {
+                EXIT_SCHEMA
+            }
+            KrakenError::DisputeStateError(_)
+            | KrakenError::NoSuchTransactionError(_)
+            | KrakenError::AccountLocked(_)
+            | KrakenError::InsufficientFunds(_)
+            | KrakenError::BelowReserve(..)
+            | KrakenError::DuplicateTransaction(_)
+            | KrakenError::AlreadyProcessed(_)
+            | KrakenError::RejectedTransactions(_)
+            | KrakenError::LockedAccounts(_) => EXIT_REJECTED,
+            KrakenError::BalanceAssertion(..)
+            | KrakenError::FailedAssertions(_)
+            | KrakenError::Unbalanced(_)
+            | KrakenError::GoldenFailures(_)
+            | KrakenError::BookViolations(_) => EXIT_UNBALANCED,
+            KrakenError::InFile(_, e) => e.exit_code(),
+            KrakenError::Interrupted(_, status) => *status,
+            KrakenError::Verification(_)
+            | KrakenError::Telemetry(_)
+            | KrakenError::MergeConflicts(_)
+            | KrakenError::AuditChain(..)
+            | KrakenError::BadSignature(_)
+            | KrakenError::Encryption(_)
+            | KrakenError::AmountOverflow(_)
+            | KrakenError::InvalidArgument(_)
+            | KrakenError::Error => EXIT_FAILURE,
+        }
+    }
+}
+
+/// Condition `--fail-on` fails a run on. Whatever the conditions, a run failing to read or write still fails.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailOn {
+    /// A row that fails to parse. Otherwise the input ends before the batch holding it, with a warning. The default.
+    ParseError,
+    /// Any transaction refused by the business rules.
+    RejectedTx,
+    /// Any account locked by a chargeback at the end of the run.
+    LockedAccount,
+    /// Any `assert_balance` row not matching the available funds. Otherwise it's only warned about.
+    Assertion,
+    /// None of the above.
+    Never,
+}
+
+impl TryFrom<&str> for FailOn {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, KrakenError> {
+        match value {
+            "parse-error" => Ok(FailOn::ParseError),
+            "rejected-tx" => Ok(FailOn::RejectedTx),
+            "assertion" => Ok(FailOn::Assertion),
+            "locked-account" => Ok(FailOn::LockedAccount),
+            "never" => Ok(FailOn::Never),
+            _ => Err(KrakenError::Enum(format!("Invalid String for FailOn: {value}"))),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::engine::{replay, Outcome};
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::InputSource;
+use crate::output::Balances;
+use crate::structures::TransactionType;
+use serde::Serialize;
+use std::io::Write;
+
+/// One applied transaction, and the balances of its client's account before and after it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangeEvent {
+    /// Position of the event in the stream, counting from 1.
+    pub seq: u64,
+    pub tx: u32,
+    pub client: u32,
+    #[serde(rename = "type")]
+    pub kind: TransactionType,
+    pub amount: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+    pub before: Balances,
+    pub after: Balances,
+}
+
+/// Replay `source` serially, writing its events to `writer` as `EventWriter` does. Returns how many were written.
+pub fn write_events<S: InputSource, W: Write>(
+    source: S,
+    writer: W,
+    budget: Option<MemoryBudget>,
+) -> Result<u64, KrakenError> {
+    let mut events = EventWriter::new(writer);
+    replay(source, budget, |outcome| events.record(outcome))?;
+    Ok(events.finish()?.0)
+}
+
+/// Writes a `ChangeEvent` as a line of JSON for every transaction applied. Refused transactions change nothing, so
+/// have no event.
+pub struct EventWriter<W: Write> {
+    writer: W,
+    seq: u64,
+}
+
+impl<W: Write> EventWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, seq: 0 }
+    }
+
+    pub fn record(&mut self, outcome: &Outcome) -> Result<(), KrakenError> {
+        if outcome.result.is_err() {
+            return Ok(());
+        }
+        self.seq += 1;
+        let event = ChangeEvent {
+            seq: self.seq,
+            tx: outcome.tx,
+            client: outcome.client,
+            kind: outcome.kind.clone(),
+            amount: outcome.amount,
+            memo: outcome.memo.map(String::from),
+            before: outcome.before.clone(),
+            after: Balances::try_from(outcome.account)?,
+        };
+        serde_json::to_writer(&mut self.writer, &event).map_err(|_| KrakenError::IO)?;
+        writeln!(self.writer).map_err(|_| KrakenError::IO)
+    }
+
+    /// Flush the events, returning how many were written, and the writer.
+    pub fn finish(mut self) -> Result<(u64, W), KrakenError> {
+        self.writer.flush().map_err(|_| KrakenError::IO)?;
+        Ok((self.seq, self.writer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::write_events;
+    use crate::input::CsvSource;
+    use crate::processor::tests::TEST_DIR;
+
+    #[test]
+    fn test_events() {
+        let source = CsvSource::open(String::from(TEST_DIR) + "1-dispute-after-withdraw.csv", b',').unwrap();
+        let mut events = Vec::new();
+        assert_eq!(4, write_events(source, &mut events, None).unwrap());
+
+        let events = String::from_utf8(events).unwrap();
+        let dispute = events.lines().last().unwrap();
+        let expected = r#"{"seq":4,"tx":0,"client":1,"type":"dispute","amount":null,"before":{"available":0.5,"held":0.0,"total":0.5,"locked":false},"after":{"available":-9.5,"held":10.0,"total":0.5,"locked":false}}"#;
+        assert_eq!(expected, dispute);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::errors::KrakenError;
+use crate::input::RowSource;
+use crate::structures::{unquoted_position, Columns, Transaction};
+use memmap2::Mmap;
+use std::fs::File;
+use std::path::Path;
+
+/// Memory-mapped CSV scanner.
+/// The file is mapped rather than read, and each row is decoded from a borrowed slice of the mapping, so
+/// the only allocations are the `Transaction`s themselves. Columns are found by the names of the header.
+pub struct MmapReader {
+    mmap: Mmap,
+    /// Offset of the first row after the header.
+    start: usize,
+    /// Offset of the next row `next_row` will decode.
+    position: usize,
+    delimiter: u8,
+    columns: Columns,
+}
+
+impl MmapReader {
+    pub fn open(path: impl AsRef<Path>, delimiter: u8) -> Result<Self, KrakenError> {
+        let file = File::open(path).map_err(|_| KrakenError::IO)?;
+        // SAFETY: the mapping is read-only and only lives as long as this reader. Truncating the file
+        // while it is being processed is unsupported, as with any other reader.
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|_| KrakenError::IO)?;
+
+        let (header, rows) = next_line(&mmap, delimiter).unwrap_or_default();
+        let columns = Columns::from_header(header, delimiter);
+        let start = mmap.len() - rows.len();
+
+        Ok(Self {
+            mmap,
+            start,
+            position: start,
+            delimiter,
+            columns,
+        })
+    }
+
+    /// Decode every row after the header, in file order. Blank lines are skipped.
+    pub fn transactions(&self) -> impl Iterator<Item = Result<Transaction, KrakenError>> + '_ {
+        let mut rest = &self.mmap[self.start..];
+        std::iter::from_fn(move || {
+            let line;
+            (line, rest) = next_line(rest, self.delimiter)?;
+            Some(line)
+        })
+        .filter(|line| !line.trim_ascii().is_empty())
+        .map(|line| Transaction::parse_columns(line, self.delimiter, &self.columns))
+    }
+}
+
+/// The first line of `rest` and what follows it, or `None` once it's empty. Newlines inside quoted fields belong to
+/// the field, rather than ending the line.
+fn next_line(rest: &[u8], delimiter: u8) -> Option<(&[u8], &[u8])> {
+    if rest.is_empty() {
+        return None;
+    }
+    Some(match unquoted_position(rest, delimiter, |byte| byte == b'\n') {
+        Some(end) => (&rest[..end], &rest[end + 1..]),
+        None => (rest, &rest[rest.len()..]),
+    })
+}
+
+impl RowSource for MmapReader {
+    /// Decode the row after the last one returned, or `None` at the end of the file. Blank lines are skipped.
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>> {
+        while let Some((line, rest)) = next_line(&self.mmap[self.position..], self.delimiter) {
+            self.position = self.mmap.len() - rest.len();
+            if !line.trim_ascii().is_empty() {
+                return Some(Transaction::parse_columns(line, self.delimiter, &self.columns));
+            }
+        }
+        None
+    }
+}
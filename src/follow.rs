@@ -0,0 +1,121 @@
+use crate::engine::Engine;
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::resolve_delimiter;
+use crate::structures::{ClientAccount, Transaction};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for more data after reaching the current end of the file.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Default time between two flushes of the balances while following.
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tails a growing CSV, in the manner of `tail -f`, applying rows as they are appended.
+/// A row is only applied once its terminating newline has been written, so a writer caught mid-row is
+/// never misread.
+#[derive(Debug)]
+pub struct Follower {
+    reader: BufReader<File>,
+    /// Text read past the last complete row, waiting for the rest of its line.
+    partial: String,
+    header_skipped: bool,
+    delimiter: u8,
+    engine: Engine,
+}
+
+impl Follower {
+    pub fn open(path: impl AsRef<Path>, budget: Option<MemoryBudget>) -> Result<Self, KrakenError> {
+        Ok(Self {
+            reader: BufReader::new(File::open(&path).map_err(|_| KrakenError::IO)?),
+            partial: String::new(),
+            header_skipped: false,
+            delimiter: resolve_delimiter(None, path),
+            engine: Engine::with_budget(budget),
+        })
+    }
+
+    /// Split rows on `delimiter` instead of the one implied by the file extension.
+    pub fn with_delimiter(mut self, delimiter: Option<u8>) -> Self {
+        self.delimiter = delimiter.unwrap_or(self.delimiter);
+        self
+    }
+
+    /// Apply every complete row written since the last poll, returning how many rows were read.
+    pub fn poll(&mut self) -> Result<usize, KrakenError> {
+        let mut rows = 0;
+        // Only polls finding rows are traced, as most find none
+        let mut span = None;
+        loop {
+            if self.reader.read_line(&mut self.partial).map_err(|_| KrakenError::IO)? == 0
+                || !self.partial.ends_with('\n')
+            {
+                return Ok(rows);
+            }
+
+            let line = std::mem::take(&mut self.partial);
+            span.get_or_insert_with(|| tracing::info_span!("apply").entered());
+            if !self.header_skipped {
+                self.header_skipped = true;
+            } else if !line.trim().is_empty() {
+                // Swallow results since we aren't tracking them
+                let _ = self.engine.apply(Transaction::parse_delimited(line.trim_end().as_bytes(), self.delimiter)?);
+                rows += 1;
+            }
+        }
+    }
+
+    pub fn accounts(&self) -> &HashMap<u32, ClientAccount> {
+        self.engine.accounts()
+    }
+
+    /// Follow the file until an error occurs, calling `flush` with the balances whenever new rows have been
+    /// applied and at least `interval` has passed since the previous flush.
+    pub fn run<F>(&mut self, interval: Duration, mut flush: F) -> Result<(), KrakenError>
+    where
+        F: FnMut(&HashMap<u32, ClientAccount>),
+    {
+        let mut last_flush = Instant::now();
+        let mut dirty = false;
+        loop {
+            let rows = self.poll()?;
+            dirty |= rows > 0;
+
+            if dirty && last_flush.elapsed() >= interval {
+                tracing::info_span!("output").in_scope(|| flush(self.accounts()));
+                last_flush = Instant::now();
+                dirty = false;
+            }
+            if rows == 0 {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::follow::Follower;
+    use std::io::Write;
+
+    #[test]
+    fn test_follow_appended_rows() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "type, client, tx, amount\ndeposit, 1, 1, 2.0\ndeposit, 1, 2,").unwrap();
+        file.flush().unwrap();
+
+        let mut follower = Follower::open(file.path(), None).unwrap();
+        assert_eq!(1, follower.poll().unwrap());
+        assert_eq!(0, follower.poll().unwrap());
+
+        write!(file, " 3.0\nwithdrawal, 1, 3, 1.0\n").unwrap();
+        file.flush().unwrap();
+        assert_eq!(2, follower.poll().unwrap());
+        assert_eq!("1, 4.0000, 0.0000, 4.0000, false", follower.accounts()[&1].to_str_row(1).unwrap());
+    }
+}
@@ -0,0 +1,323 @@
+use crate::errors::KrakenError;
+use crate::structures::{Transaction, TransactionType};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Rows after its deposit within which a dispute is opened, and after the dispute within which it is closed.
+const DISPUTE_WINDOW: u64 = 1000;
+
+/// How generated deposit and withdrawal amounts are distributed. Amounts are rounded to four places and are
+/// never smaller than `0.0001`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmountDistribution {
+    /// Every amount is the same.
+    Fixed(f64),
+    /// Evenly spread between a minimum and a maximum.
+    Uniform(f64, f64),
+    /// Log-normal around a median, with the given spread (the standard deviation of the amounts' logarithm):
+    /// mostly small payments with a long tail of large ones.
+    LogNormal(f64, f64),
+}
+
+impl Default for AmountDistribution {
+    fn default() -> Self {
+        AmountDistribution::LogNormal(50.0, 1.0)
+    }
+}
+
+impl TryFrom<&str> for AmountDistribution {
+    type Error = KrakenError;
+
+    /// Parse `fixed:AMOUNT`, `uniform:MIN:MAX`, or `lognormal:MEDIAN:SPREAD`.
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let invalid = || KrakenError::Enum(format!("Invalid String for AmountDistribution: {value}"));
+        let mut parts = value.split(':');
+        let name = parts.next().unwrap_or_default();
+        let numbers = parts
+            .map(|part| part.trim().parse::<f64>().ok().filter(|number| number.is_finite() && *number >= 0.0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+
+        match (name, numbers.as_slice()) {
+            ("fixed", [amount]) => Ok(AmountDistribution::Fixed(*amount)),
+            ("uniform", [min, max]) if min <= max => Ok(AmountDistribution::Uniform(*min, *max)),
+            ("lognormal", [median, spread]) if *median > 0.0 => Ok(AmountDistribution::LogNormal(*median, *spread)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How generated transactions are spread across clients.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ClientDistribution {
+    /// Every client is as likely as any other.
+    #[default]
+    Uniform,
+    /// Client `k` is picked with a probability proportional to `1 / k^exponent`: client 1 is the busiest, and
+    /// the higher the exponent, the more the traffic concentrates on the first few clients.
+    Zipf(f64),
+    /// The first `clients` share of the clients receives the `traffic` share of the rows, the rest the remainder.
+    Hot { clients: f64, traffic: f64 },
+}
+
+impl TryFrom<&str> for ClientDistribution {
+    type Error = KrakenError;
+
+    /// Parse `uniform`, `zipf:EXPONENT`, or `hot:CLIENTS:TRAFFIC` with both shares between 0 and 1.
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let invalid = || KrakenError::Enum(format!("Invalid String for ClientDistribution: {value}"));
+        let mut parts = value.split(':');
+        let name = parts.next().unwrap_or_default();
+        let numbers = parts
+            .map(|part| part.trim().parse::<f64>().ok().filter(|number| number.is_finite() && *number >= 0.0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+
+        match (name, numbers.as_slice()) {
+            ("uniform", []) => Ok(ClientDistribution::Uniform),
+            ("zipf", [exponent]) => Ok(ClientDistribution::Zipf(*exponent)),
+            ("hot", [clients, traffic]) if *clients <= 1.0 && *traffic <= 1.0 => {
+                Ok(ClientDistribution::Hot { clients: *clients, traffic: *traffic })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Shape of a generated workload.
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    pub rows: u64,
+    /// Clients are numbered from 1 to `clients`.
+    pub clients: u32,
+    /// Share of new transactions that are withdrawals rather than deposits.
+    pub withdrawal_ratio: f64,
+    /// Share of deposits that are later disputed.
+    pub dispute_ratio: f64,
+    /// Share of disputes that end in a chargeback rather than a resolve.
+    pub chargeback_ratio: f64,
+    pub amounts: AmountDistribution,
+    pub distribution: ClientDistribution,
+    /// Seed of the random numbers, so a dataset can be generated again. Taken from the clock if `None`.
+    pub seed: Option<u64>,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            rows: 1000,
+            clients: 100,
+            withdrawal_ratio: 0.3,
+            dispute_ratio: 0.01,
+            chargeback_ratio: 0.2,
+            amounts: AmountDistribution::default(),
+            distribution: ClientDistribution::default(),
+            seed: None,
+        }
+    }
+}
+
+/// SplitMix64: small, fast, and good enough for test data.
+struct Random(u64);
+
+impl Random {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `[0, bound)`.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound.max(1)
+    }
+
+    /// Zipf-distributed in `[1, n]`, by Hörmann and Derflinger's rejection-inversion, which takes constant
+    /// time and memory whatever `n`.
+    fn zipf(&mut self, n: u64, exponent: f64) -> u64 {
+        let (n, s) = (n.max(1) as f64, exponent);
+        // Total area `t` under the hat function bounding the probabilities, and the inverse of its integral
+        let t = if s == 1.0 { 1.0 + n.ln() } else { (n.powf(1.0 - s) - s) / (1.0 - s) };
+        let inverse = |p: f64| match p <= 1.0 {
+            true => p,
+            false if s == 1.0 => (p - 1.0).exp(),
+            false => (p * (1.0 - s) + s).powf(1.0 / (1.0 - s)),
+        };
+        loop {
+            let b = inverse(self.next_f64() * t);
+            let x = (b + 1.0).floor();
+            let ratio = if x > 1.0 { x.powf(-s) * b.powf(s) } else { 1.0 };
+            if self.next_f64() < ratio {
+                return (x as u64).min(n as u64);
+            }
+        }
+    }
+
+    /// Standard normal, by the Box-Muller transform.
+    fn normal(&mut self) -> f64 {
+        let (u, v) = (1.0 - self.next_f64(), self.next_f64());
+        (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+    }
+}
+
+/// Stream of `config.rows` synthetic transactions.
+///
+/// New transactions are deposits and withdrawals of clients picked by `config.distribution`, numbered from
+/// tx 1. A share of the deposits is disputed some rows later, and each dispute is then resolved or charged
+/// back, so disputes only ever reference an earlier deposit of the same client and are never left dangling by
+/// the generator itself. Withdrawals may still exceed the funds, as they would in real traffic. Follow-ups
+/// falling past the last row are dropped.
+pub struct Generator {
+    config: GeneratorConfig,
+    random: Random,
+    /// Rows generated so far.
+    row: u64,
+    next_tx: u32,
+    /// Disputes, resolves, and chargebacks to emit, by the row they're due at.
+    scheduled: BinaryHeap<Reverse<(u64, u32, u32, u8)>>,
+}
+
+impl Generator {
+    /// A generator seeded from `config.seed`, or from the clock so every run produces different data.
+    pub fn new(config: GeneratorConfig) -> Self {
+        let clock = || SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        let seed = config.seed.unwrap_or_else(clock);
+        Self { config, random: Random(seed), row: 0, next_tx: 1, scheduled: BinaryHeap::new() }
+    }
+
+    fn client(&mut self) -> u32 {
+        let clients = u64::from(self.config.clients.max(1));
+        let client = match self.config.distribution {
+            ClientDistribution::Uniform => 1 + self.random.below(clients),
+            ClientDistribution::Zipf(exponent) => self.random.zipf(clients, exponent),
+            ClientDistribution::Hot { clients: share, traffic } => {
+                let hot = ((clients as f64 * share).ceil() as u64).clamp(1, clients);
+                match hot == clients || self.random.next_f64() < traffic {
+                    true => 1 + self.random.below(hot),
+                    false => hot + 1 + self.random.below(clients - hot),
+                }
+            }
+        };
+        client as u32
+    }
+
+    fn amount(&mut self) -> f64 {
+        let amount = match self.config.amounts {
+            AmountDistribution::Fixed(amount) => amount,
+            AmountDistribution::Uniform(min, max) => min + (max - min) * self.random.next_f64(),
+            AmountDistribution::LogNormal(median, spread) => median * (spread * self.random.normal()).exp(),
+        };
+        ((amount * 10_000.0).round() / 10_000.0).max(0.0001)
+    }
+
+    /// Schedule the follow-up of `kind` to tx `tx` of `client`, some rows from now.
+    fn schedule(&mut self, kind: TransactionType, client: u32, tx: u32) {
+        let due = self.row + 1 + self.random.below(DISPUTE_WINDOW);
+        self.scheduled.push(Reverse((due, client, tx, kind.code())));
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        if self.row >= self.config.rows {
+            return None;
+        }
+        self.row += 1;
+
+        if let Some(Reverse((due, client, tx, kind))) = self.scheduled.peek().copied()
+            && due <= self.row
+        {
+            self.scheduled.pop();
+            let kind = TransactionType::try_from(kind).ok()?;
+            if kind == TransactionType::Dispute {
+                let closing = match self.random.next_f64() < self.config.chargeback_ratio {
+                    true => TransactionType::Chargeback,
+                    false => TransactionType::Resolve,
+                };
+                self.schedule(closing, client, tx);
+            }
+            return Some(Transaction { kind, client, amount: None, tx, memo: None, idempotency_key: None, timestamp: None, state: None });
+        }
+
+        let client = self.client();
+        let tx = self.next_tx;
+        self.next_tx = self.next_tx.wrapping_add(1);
+        let kind = match self.random.next_f64() < self.config.withdrawal_ratio {
+            true => TransactionType::Withdrawal,
+            false => TransactionType::Deposit,
+        };
+        if kind == TransactionType::Deposit && self.random.next_f64() < self.config.dispute_ratio {
+            self.schedule(TransactionType::Dispute, client, tx);
+        }
+        Some(Transaction { kind, client, amount: Some(self.amount()), tx, memo: None, idempotency_key: None, timestamp: None, state: None })
+    }
+}
+
+/// Write every transaction of `generator` to `writer` as `type, client, tx, amount` CSV, with a header.
+/// Fails if the rows asked for don't fit in 32-bit tx ids. Returns the number of rows written.
+pub fn write_csv<W: Write>(generator: Generator, mut writer: W) -> Result<u64, KrakenError> {
+    if generator.config.rows > u64::from(u32::MAX) {
+        return Err(KrakenError::InvalidArgument(format!("Cannot generate more than {} rows", u32::MAX)));
+    }
+    let io = |_| KrakenError::IO;
+
+    writeln!(writer, "type, client, tx, amount").map_err(io)?;
+    let mut rows = 0;
+    for transaction in generator {
+        let kind = transaction.kind.name();
+        match transaction.amount {
+            Some(amount) => writeln!(writer, "{kind}, {}, {}, {amount:.4}", transaction.client, transaction.tx),
+            None => writeln!(writer, "{kind}, {}, {},", transaction.client, transaction.tx),
+        }
+        .map_err(io)?;
+        rows += 1;
+    }
+    writer.flush().map_err(io)?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generate::{write_csv, AmountDistribution, ClientDistribution, Generator, GeneratorConfig};
+    use crate::input::InputOptions;
+    use crate::validate::validate;
+
+    #[test]
+    fn test_generate() {
+        assert_eq!(Ok(AmountDistribution::Uniform(1.0, 5.0)), AmountDistribution::try_from("uniform:1:5").map_err(|_| ()));
+        assert!(AmountDistribution::try_from("uniform:5:1").is_err());
+
+        // Whatever the seed, generated data is well-formed and its disputes reference earlier deposits
+        let config = GeneratorConfig { rows: 20_000, clients: 50, dispute_ratio: 0.2, ..Default::default() };
+        let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        assert_eq!(20_000, write_csv(Generator::new(config), file.as_file_mut()).unwrap());
+
+        let validation = validate(&[file.path()], &InputOptions::default(), |problem| panic!("{problem}")).unwrap();
+        assert_eq!(20_000, validation.rows);
+
+        // A seed reproduces the same rows, and skew concentrates them on the first clients
+        let busiest = |distribution| {
+            let config = GeneratorConfig { rows: 10_000, distribution, seed: Some(7), ..Default::default() };
+            let mut counts = [0; 101];
+            Generator::new(config).for_each(|transaction| counts[transaction.client as usize] += 1);
+            (counts[1], counts.iter().sum::<u32>() - counts[1])
+        };
+        assert_eq!(busiest(ClientDistribution::Zipf(1.2)), busiest(ClientDistribution::Zipf(1.2)));
+        let (first, rest) = busiest(ClientDistribution::Zipf(1.2));
+        assert!(first > rest / 4, "{first} {rest}");
+        let (first, rest) = busiest(ClientDistribution::Hot { clients: 0.01, traffic: 0.9 });
+        assert!(first > rest * 8, "{first} {rest}");
+        assert!(ClientDistribution::try_from("hot:0.1:2").is_err());
+    }
+}
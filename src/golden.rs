@@ -0,0 +1,121 @@
+use crate::diff::read_balances;
+use crate::errors::KrakenError;
+use crate::processor::{compute_account_totals, ProcessorConfig};
+use crate::reconcile::{reconcile, Discrepancy};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Transactions of a golden test case, in its own directory.
+pub const INPUT: &str = "input.csv";
+/// Report the case's input is expected to produce, as `client, available, held, total, locked`.
+pub const EXPECTED: &str = "expected.csv";
+
+/// How one case went: the clients whose balances didn't match, or why it couldn't be run.
+#[derive(Debug)]
+pub struct CaseOutcome {
+    /// The case's directory, relative to the suite's.
+    pub case: PathBuf,
+    pub result: anyhow::Result<Vec<Discrepancy>>,
+}
+
+impl CaseOutcome {
+    pub fn passed(&self) -> bool {
+        self.result.as_ref().is_ok_and(Vec::is_empty)
+    }
+}
+
+impl fmt::Display for CaseOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let case = self.case.display();
+        match &self.result {
+            Ok(discrepancies) if discrepancies.is_empty() => write!(f, "ok      {case}"),
+            Ok(discrepancies) => {
+                write!(f, "FAILED  {case}")?;
+                discrepancies.iter().try_for_each(|discrepancy| write!(f, "\n        {discrepancy}"))
+            }
+            Err(e) => write!(f, "FAILED  {case}\n        {e:#}"),
+        }
+    }
+}
+
+/// Every directory under `root`, `root` included, holding both an `input.csv` and an `expected.csv`, in path
+/// order.
+pub fn find_cases(root: &Path) -> Result<Vec<PathBuf>, KrakenError> {
+    let in_file = |path: &Path| KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO));
+    let mut cases = Vec::new();
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        if directory.join(INPUT).is_file() && directory.join(EXPECTED).is_file() {
+            cases.push(directory.clone());
+        }
+        for entry in std::fs::read_dir(&directory).map_err(|_| in_file(&directory))? {
+            let path = entry.map_err(|_| in_file(&directory))?.path();
+            if path.is_dir() {
+                directories.push(path);
+            }
+        }
+    }
+    cases.sort();
+    Ok(cases)
+}
+
+/// Run `input.csv` of the case in `directory` with `config`, and compare the balances with `expected.csv`.
+pub fn run_case(directory: &Path, config: &ProcessorConfig) -> anyhow::Result<Vec<Discrepancy>> {
+    let expected = read_balances(&directory.join(EXPECTED))?;
+    let accounts = compute_account_totals(&directory.join(INPUT).to_string_lossy(), config)?;
+    Ok(reconcile(&expected, &accounts)?)
+}
+
+/// Run every case under `root` with `config`, handing each outcome to `report` as it's known, and return how many
+/// failed. Fails if `root` holds no case, as a mistyped path would otherwise pass.
+pub fn run_suite<F>(root: &Path, config: &ProcessorConfig, mut report: F) -> Result<usize, KrakenError>
+where
+    F: FnMut(&CaseOutcome),
+{
+    let cases = find_cases(root)?;
+    if cases.is_empty() {
+        return Err(KrakenError::InvalidArgument(format!("No {INPUT} and {EXPECTED} pairs under {}", root.display())));
+    }
+    let mut failed = 0;
+    for directory in cases {
+        let result = run_case(&directory, config);
+        let case = match directory.strip_prefix(root) {
+            Ok(case) if !case.as_os_str().is_empty() => case.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let outcome = CaseOutcome { case, result };
+        failed += usize::from(!outcome.passed());
+        report(&outcome);
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::golden::run_suite;
+    use crate::processor::tests::TEST_DIR;
+    use crate::processor::{ParallelMode, ProcessorConfig};
+    use std::path::Path;
+
+    #[test]
+    fn test_golden() {
+        let suite = Path::new(TEST_DIR).join("golden");
+        for parallel in [ParallelMode::Serial, ParallelMode::Actors] {
+            let config = ProcessorConfig { parallel, threads: 2, ..Default::default() };
+            let mut outcomes = Vec::new();
+            assert_eq!(0, run_suite(&suite, &config, |outcome| outcomes.push(outcome.to_string())).unwrap());
+            assert!(outcomes.iter().any(|outcome| outcome == "ok      chargeback-after-withdraw"), "{outcomes:?}");
+        }
+
+        // A case whose expected balances are wrong fails, naming the client
+        let directory = tempfile::tempdir().unwrap();
+        let case = directory.path().join("wrong");
+        std::fs::create_dir(&case).unwrap();
+        std::fs::write(case.join("input.csv"), "type, client, tx, amount\ndeposit, 1, 1, 2.0\n").unwrap();
+        std::fs::write(case.join("expected.csv"), "client, available, held, total, locked\n1, 3.0, 0, 3.0, false\n").unwrap();
+        let mut outcomes = Vec::new();
+        assert_eq!(1, run_suite(directory.path(), &ProcessorConfig::default(), |outcome| outcomes.push(outcome.to_string())).unwrap());
+        assert_eq!(vec!["FAILED  wrong\n        client 1: available 2.0000, expected 3.0000; total 2.0000, expected 3.0000"], outcomes);
+        assert!(run_suite(&case.join("missing"), &ProcessorConfig::default(), |_| {}).is_err());
+    }
+}
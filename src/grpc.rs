@@ -0,0 +1,190 @@
+use crate::errors::KrakenError;
+use crate::output::AccountSummary;
+use crate::server::Ledger;
+use crate::structures::{Transaction, TransactionType};
+use futures_util::Stream;
+use proto::processor_server::{Processor, ProcessorServer};
+use proto::{Account, AccountRequest, WatchRequest};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Request, Response, Status};
+use tracing::Span;
+
+/// Messages and service generated from `proto/paymentprocessor.proto`.
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("paymentprocessor");
+}
+
+impl TryFrom<proto::Transaction> for Transaction {
+    type Error = KrakenError;
+
+    fn try_from(transaction: proto::Transaction) -> Result<Self, Self::Error> {
+        let kind = u8::try_from(transaction.r#type)
+            .map_err(|_| KrakenError::Enum(format!("Invalid discriminant for TransactionType: {}", transaction.r#type)))?;
+        Ok(Transaction {
+            kind: TransactionType::try_from(kind)?,
+            client: transaction.client,
+            amount: transaction.amount,
+            tx: transaction.tx,
+            memo: None,
+            idempotency_key: None,
+            timestamp: None,
+            state: None,
+        })
+    }
+}
+
+impl From<AccountSummary> for Account {
+    /// Amounts are rounded to four decimal places, as in every report.
+    fn from(summary: AccountSummary) -> Self {
+        let round = |amount: f64| (amount * 10_000.0).round() / 10_000.0;
+        Account {
+            client: summary.client,
+            available: round(summary.available),
+            held: round(summary.held),
+            total: round(summary.total),
+            locked: summary.locked,
+            lock_reason: summary.lock.map(|event| event.reason.code().to_string()),
+        }
+    }
+}
+
+fn status(e: KrakenError) -> Status {
+    match e {
+        KrakenError::Parse(_) | KrakenError::Enum(_) | KrakenError::MissingAmount(_) => {
+            Status::invalid_argument(e.to_string())
+        }
+        KrakenError::IO => Status::internal(e.to_string()),
+        _ => Status::failed_precondition(e.to_string()),
+    }
+}
+
+/// The `Processor` gRPC service, applying to the same ledger as the REST API.
+pub struct ProcessorService {
+    ledger: Arc<Ledger>,
+}
+
+impl ProcessorService {
+    pub fn new(ledger: Arc<Ledger>) -> Self {
+        Self { ledger }
+    }
+}
+
+/// Span of one call, continuing the caller's trace when its metadata carries a `traceparent`.
+fn span<T>(method: &str, request: &Request<T>) -> Span {
+    let span = tracing::info_span!("call", method);
+    #[cfg(feature = "otel")]
+    {
+        let headers = request.metadata().clone().into_headers();
+        crate::telemetry::set_remote_parent(&span, headers.iter().map(|(name, value)| (name.as_str(), value.as_bytes())));
+    }
+    span
+}
+
+type AccountStream = Pin<Box<dyn Stream<Item = Result<Account, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Processor for ProcessorService {
+    async fn submit(&self, request: Request<proto::Transaction>) -> Result<Response<Account>, Status> {
+        span("Submit", &request).in_scope(|| {
+            let transaction = Transaction::try_from(request.into_inner()).map_err(status)?;
+            match self.ledger.apply([transaction]).remove(0) {
+                Ok(summary) => Ok(Response::new(summary.into())),
+                Err(e) => Err(status(e)),
+            }
+        })
+    }
+
+    async fn get_account(&self, request: Request<AccountRequest>) -> Result<Response<Account>, Status> {
+        let _span = span("GetAccount", &request).entered();
+        let client = request.into_inner().client;
+        match self.ledger.account(client).map_err(status)? {
+            Some(summary) => Ok(Response::new(summary.into())),
+            None => Err(Status::not_found(format!("No such client: {client}"))),
+        }
+    }
+
+    type WatchAccountsStream = AccountStream;
+
+    async fn watch_accounts(&self, request: Request<WatchRequest>) -> Result<Response<AccountStream>, Status> {
+        let client = request.into_inner().client;
+        let updates = self.ledger.subscribe();
+        let stream = futures_util::stream::unfold(updates, move |mut updates| async move {
+            loop {
+                match updates.recv().await {
+                    Ok(update) if client.is_none_or(|client| client == update.account.client) => {
+                        return Some((Ok(update.account.into()), updates));
+                    }
+                    Ok(_) => {}
+                    // A watcher too slow to keep up misses the oldest updates rather than holding everyone back
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Serve the `Processor` service on `address` until `shutdown` completes.
+pub async fn serve<F>(address: SocketAddr, ledger: Arc<Ledger>, shutdown: F) -> Result<(), KrakenError>
+where
+    F: Future<Output = ()>,
+{
+    tonic::transport::Server::builder()
+        .add_service(ProcessorServer::new(ProcessorService::new(ledger)))
+        .serve_with_shutdown(address, shutdown)
+        .await
+        .map_err(|e| {
+            tracing::error!(code = "io", %address, error = %e, "gRPC server failed");
+            KrakenError::IO
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::grpc::proto::processor_client::ProcessorClient;
+    use crate::grpc::proto::{AccountRequest, Transaction, TransactionType, WatchRequest};
+    use crate::grpc::serve;
+    use crate::server::Ledger;
+    use std::sync::Arc;
+    use tonic::Code;
+
+    #[test]
+    fn test_grpc() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            tokio::spawn(serve(address, Arc::new(Ledger::new(Engine::new())), std::future::pending()));
+            let mut client = loop {
+                match ProcessorClient::connect(format!("http://{address}")).await {
+                    Ok(client) => break client,
+                    Err(_) => tokio::task::yield_now().await,
+                }
+            };
+
+            let mut updates = client.watch_accounts(WatchRequest { client: Some(2) }).await.unwrap().into_inner();
+            let deposit = |client, tx, amount| Transaction {
+                r#type: TransactionType::Deposit.into(),
+                client,
+                tx,
+                amount: Some(amount),
+            };
+            assert_eq!(1.5, client.submit(deposit(1, 1, 1.5)).await.unwrap().into_inner().available);
+            client.submit(deposit(2, 2, 4.0)).await.unwrap();
+
+            // The watcher only sees client 2
+            assert_eq!(4.0, updates.message().await.unwrap().unwrap().total);
+
+            let withdrawal = Transaction { r#type: TransactionType::Withdrawal.into(), ..deposit(1, 3, 9.0) };
+            assert_eq!(Code::FailedPrecondition, client.submit(withdrawal).await.unwrap_err().code());
+            let missing = client.get_account(AccountRequest { client: 7 }).await.unwrap_err();
+            assert_eq!(Code::NotFound, missing.code());
+        });
+    }
+}
@@ -0,0 +1,89 @@
+use crate::errors::KrakenError;
+use crate::structures::{ClientAccount, Transaction, TransactionType};
+use std::sync::{Arc, RwLock};
+
+/// Custom transaction types that can be registered, as `TransactionType::Custom` holds their index in a `u8`.
+pub const MAX_HANDLERS: usize = 64;
+
+/// Accounting rules for a transaction type of the embedder's own, such as `bonus` or `fee_waiver`.
+///
+/// Once registered, rows of the type are read by every input format and applied by the same engine loop as the
+/// built-in types, in every processing mode. Custom transactions aren't kept in the account's history, so they
+/// can't be disputed.
+pub trait TransactionHandler: Send + Sync {
+    /// The type as spelled in input files.
+    fn name(&self) -> &'static str;
+
+    /// Apply `transaction` to its client's `account`, or refuse it with an error, leaving the account as it was.
+    /// Whether a locked account accepts the type is up to the handler.
+    fn apply(&self, account: &mut ClientAccount, transaction: &Transaction) -> Result<(), KrakenError>;
+}
+
+static HANDLERS: RwLock<Vec<Arc<dyn TransactionHandler>>> = RwLock::new(Vec::new());
+
+/// Register `handler` for the rest of the process, returning the type its rows are read as.
+/// Fails if its name is already taken, by a built-in type or an earlier handler, or if `MAX_HANDLERS` are registered.
+pub fn register(handler: impl TransactionHandler + 'static) -> Result<TransactionType, KrakenError> {
+    let name = handler.name();
+    if TransactionType::try_from(name).is_ok() {
+        return Err(KrakenError::InvalidArgument(format!("Transaction type already exists: {name}")));
+    }
+    let mut handlers = HANDLERS.write().unwrap_or_else(|e| e.into_inner());
+    if handlers.len() == MAX_HANDLERS {
+        return Err(KrakenError::InvalidArgument(format!("Too many transaction types, registering {name}")));
+    }
+    handlers.push(Arc::new(handler));
+    Ok(TransactionType::Custom((handlers.len() - 1) as u8))
+}
+
+/// The registered type spelled `name`.
+pub(crate) fn find(name: &[u8]) -> Option<TransactionType> {
+    let handlers = HANDLERS.read().unwrap_or_else(|e| e.into_inner());
+    let index = handlers.iter().position(|handler| handler.name().as_bytes() == name)?;
+    Some(TransactionType::Custom(index as u8))
+}
+
+/// The handler registered as `TransactionType::Custom(index)`.
+pub(crate) fn handler(index: u8) -> Option<Arc<dyn TransactionHandler>> {
+    HANDLERS.read().unwrap_or_else(|e| e.into_inner()).get(index as usize).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::KrakenError;
+    use crate::handlers::{register, TransactionHandler};
+    use crate::processor::{compute_account_totals, ProcessorConfig};
+    use crate::structures::{ClientAccount, Transaction, TransactionType};
+    use std::io::Write;
+
+    /// Credits the amount, capped at 5.0, unless the account is locked.
+    struct Bonus;
+
+    impl TransactionHandler for Bonus {
+        fn name(&self) -> &'static str {
+            "bonus"
+        }
+
+        fn apply(&self, account: &mut ClientAccount, transaction: &Transaction) -> Result<(), KrakenError> {
+            if account.locked {
+                return Err(KrakenError::AccountLocked(transaction.client));
+            }
+            account.available += transaction.amount.ok_or(KrakenError::MissingAmount(transaction.tx))?.min(5.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_type() {
+        let bonus = register(Bonus).unwrap();
+        assert!(register(Bonus).is_err());
+        assert_eq!(bonus, TransactionType::try_from("bonus").unwrap());
+        assert_eq!("bonus", bonus.name());
+
+        let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        file.write_all(b"type, client, tx, amount\ndeposit, 1, 1, 2.0\nbonus, 1, 2, 10.0\nbonus, 2, 3, 1.5\n").unwrap();
+        let accounts = compute_account_totals(file.path().to_str().unwrap(), &ProcessorConfig::default()).unwrap();
+        assert_eq!(7.0, accounts[&1].available);
+        assert_eq!(1.5, accounts[&2].available);
+    }
+}
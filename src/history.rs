@@ -0,0 +1,334 @@
+use crate::errors::KrakenError;
+use crate::structures::{DisputeState, Transaction, TransactionType};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Estimated cost of one in-memory history entry: the key/value pair plus the map's control byte, rounded up.
+const ENTRY_BYTES: usize = size_of::<(u32, Transaction)>() + 8;
+
+/// Estimated cost of one spilled entry, which only keeps a tx -> file offset index in memory.
+const INDEX_BYTES: usize = size_of::<(u32, u64)>() + 8;
+
+/// On-disk record: kind, state, amount-present flag, client, tx, amount.
+const RECORD_BYTES: usize = 1 + 1 + 1 + 4 + 4 + 8;
+
+const NO_STATE: u8 = u8::MAX;
+
+/// Memory budget shared by every account's history.
+/// Histories charge the budget as they grow; once it is exceeded they move their entries into a shared,
+/// anonymous spill file and only keep an offset index in memory.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    inner: Arc<BudgetInner>,
+}
+
+#[derive(Debug)]
+struct BudgetInner {
+    limit: usize,
+    used: AtomicUsize,
+    spill: Mutex<SpillFile>,
+}
+
+#[derive(Debug)]
+struct SpillFile {
+    file: File,
+    len: u64,
+}
+
+impl MemoryBudget {
+    /// Create a budget of `limit` bytes, backed by a temporary spill file that is removed on exit.
+    pub fn new(limit: usize) -> Result<Self, KrakenError> {
+        let file = tempfile::tempfile().map_err(|_| KrakenError::IO)?;
+        Ok(Self {
+            inner: Arc::new(BudgetInner {
+                limit,
+                used: AtomicUsize::new(0),
+                spill: Mutex::new(SpillFile { file, len: 0 }),
+            }),
+        })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// Estimated bytes currently held in memory by histories using this budget.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    /// Charge `bytes` against the budget, returning `true` if the budget is now exceeded.
+    fn charge(&self, bytes: usize) -> bool {
+        self.inner.used.fetch_add(bytes, Ordering::Relaxed) + bytes > self.inner.limit
+    }
+
+    fn release(&self, bytes: usize) {
+        self.inner.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    fn write(&self, record: &[u8; RECORD_BYTES]) -> Result<u64, KrakenError> {
+        let mut spill = self.inner.spill.lock().unwrap();
+        let offset = spill.len;
+        spill.file.seek(SeekFrom::Start(offset)).map_err(|_| KrakenError::IO)?;
+        spill.file.write_all(record).map_err(|_| KrakenError::IO)?;
+        spill.len += RECORD_BYTES as u64;
+        Ok(offset)
+    }
+
+    fn read(&self, offset: u64) -> Result<[u8; RECORD_BYTES], KrakenError> {
+        let mut spill = self.inner.spill.lock().unwrap();
+        let mut record = [0; RECORD_BYTES];
+        spill.file.seek(SeekFrom::Start(offset)).map_err(|_| KrakenError::IO)?;
+        spill.file.read_exact(&mut record).map_err(|_| KrakenError::IO)?;
+        Ok(record)
+    }
+}
+
+/// A client's stored Deposits and Withdrawals, keyed by tx.
+/// Without a budget this is a plain in-memory map. With one, entries may live in the spill file and are
+/// faulted back into memory when a dispute-flow transaction needs them.
+#[derive(Debug, Default)]
+pub struct History {
+    entries: HashMap<u32, Transaction>,
+    spilled: HashMap<u32, u64>,
+    budget: Option<MemoryBudget>,
+    charged: usize,
+}
+
+impl History {
+    pub fn with_budget(budget: &MemoryBudget) -> Self {
+        Self {
+            entries: HashMap::new(),
+            spilled: HashMap::new(),
+            budget: Some(budget.clone()),
+            charged: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.spilled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of entries currently held in the spill file rather than memory.
+    pub fn spilled_len(&self) -> usize {
+        self.spilled.len()
+    }
+
+    /// Store a transaction under its tx, replacing any previous entry with the same tx. Its memo and idempotency key
+    /// are dropped, as entries are budgeted, and spilled, at a fixed size.
+    pub fn insert(&mut self, mut transaction: Transaction) -> Result<(), KrakenError> {
+        transaction.memo = None;
+        transaction.idempotency_key = None;
+        transaction.timestamp = None;
+        if self.spilled.remove(&transaction.tx).is_some() {
+            self.uncharge(INDEX_BYTES);
+        }
+        if self.entries.insert(transaction.tx, transaction).is_none() && self.charge(ENTRY_BYTES) {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Look up a transaction for modification, loading it back from the spill file if needed.
+    pub fn get_mut(&mut self, tx: u32) -> Result<Option<&mut Transaction>, KrakenError> {
+        if let Some(offset) = self.spilled.remove(&tx) {
+            let budget = self.budget.as_ref().expect("Only budgeted histories spill");
+            let transaction = decode(&budget.read(offset)?)?;
+            self.uncharge(INDEX_BYTES);
+            self.charge(ENTRY_BYTES);
+            self.entries.insert(tx, transaction);
+        }
+        Ok(self.entries.get_mut(&tx))
+    }
+
+    /// A copy of the transaction stored under `tx`, read from the spill file without loading it back if needed.
+    pub fn get(&self, tx: u32) -> Result<Option<Transaction>, KrakenError> {
+        if let Some(transaction) = self.entries.get(&tx) {
+            return Ok(Some(transaction.clone()));
+        }
+        match (self.spilled.get(&tx), &self.budget) {
+            (Some(offset), Some(budget)) => Ok(Some(decode(&budget.read(*offset)?)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Every stored transaction, including spilled ones, in no particular order.
+    pub fn transactions(&self) -> Result<Vec<Transaction>, KrakenError> {
+        let mut transactions: Vec<Transaction> = self.entries.values().cloned().collect();
+        if let Some(budget) = &self.budget {
+            for offset in self.spilled.values() {
+                transactions.push(decode(&budget.read(*offset)?)?);
+            }
+        }
+        Ok(transactions)
+    }
+
+    /// Move every in-memory entry into the spill file.
+    fn spill(&mut self) -> Result<(), KrakenError> {
+        let Some(budget) = self.budget.clone() else {
+            return Ok(());
+        };
+
+        for (tx, transaction) in self.entries.drain() {
+            self.spilled.insert(tx, budget.write(&encode(&transaction))?);
+            self.charged = self.charged + INDEX_BYTES - ENTRY_BYTES;
+            budget.release(ENTRY_BYTES - INDEX_BYTES);
+        }
+        self.entries.shrink_to_fit();
+        Ok(())
+    }
+
+    fn charge(&mut self, bytes: usize) -> bool {
+        match &self.budget {
+            Some(budget) => {
+                self.charged += bytes;
+                budget.charge(bytes)
+            }
+            None => false,
+        }
+    }
+
+    fn uncharge(&mut self, bytes: usize) {
+        if let Some(budget) = &self.budget {
+            self.charged -= bytes;
+            budget.release(bytes);
+        }
+    }
+}
+
+/// Which stored transactions a query of a history returns. Every condition left as `None` matches anything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistoryFilter {
+    pub kind: Option<TransactionType>,
+    /// The dispute state, where `Some(None)` matches transactions that were never disputed.
+    pub state: Option<Option<DisputeState>>,
+    /// Smallest amount, inclusive.
+    pub min_amount: Option<f64>,
+    /// Largest amount, inclusive.
+    pub max_amount: Option<f64>,
+}
+
+impl HistoryFilter {
+    pub fn with_kind(mut self, kind: TransactionType) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    pub fn with_state(mut self, state: Option<DisputeState>) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub fn with_amount_range(mut self, min: Option<f64>, max: Option<f64>) -> Self {
+        (self.min_amount, self.max_amount) = (min, max);
+        self
+    }
+
+    pub fn matches(&self, transaction: &Transaction) -> bool {
+        let amount = transaction.amount.unwrap_or_default();
+        self.kind.as_ref().is_none_or(|kind| *kind == transaction.kind)
+            && self.state.is_none_or(|state| state == transaction.state)
+            && self.min_amount.is_none_or(|min| amount >= min)
+            && self.max_amount.is_none_or(|max| amount <= max)
+    }
+}
+
+/// A copy sharing the records of spilled entries, which are never written over, and charged to the same budget.
+impl Clone for History {
+    fn clone(&self) -> Self {
+        if let Some(budget) = &self.budget {
+            budget.charge(self.charged);
+        }
+        Self {
+            entries: self.entries.clone(),
+            spilled: self.spilled.clone(),
+            budget: self.budget.clone(),
+            charged: self.charged,
+        }
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(self.charged);
+        }
+    }
+}
+
+fn encode(transaction: &Transaction) -> [u8; RECORD_BYTES] {
+    let mut record = [0; RECORD_BYTES];
+    record[0] = transaction.kind.code();
+    record[1] = transaction.state.as_ref().map_or(NO_STATE, DisputeState::code);
+    record[2] = transaction.amount.is_some() as u8;
+    record[3..7].copy_from_slice(&transaction.client.to_le_bytes());
+    record[7..11].copy_from_slice(&transaction.tx.to_le_bytes());
+    record[11..19].copy_from_slice(&transaction.amount.unwrap_or_default().to_le_bytes());
+    record
+}
+
+fn decode(record: &[u8; RECORD_BYTES]) -> Result<Transaction, KrakenError> {
+    Ok(Transaction {
+        kind: TransactionType::try_from(record[0])?,
+        state: match record[1] {
+            NO_STATE => None,
+            state => Some(DisputeState::try_from(state)?),
+        },
+        amount: (record[2] != 0).then(|| f64::from_le_bytes(record[11..19].try_into().unwrap())),
+        client: u32::from_le_bytes(record[3..7].try_into().unwrap()),
+        tx: u32::from_le_bytes(record[7..11].try_into().unwrap()),
+        memo: None,
+        idempotency_key: None,
+        timestamp: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::MemoryBudget;
+    use crate::structures::{ClientAccount, DisputeState, Transaction, TransactionType};
+
+    fn transaction(kind: TransactionType, tx: u32, amount: Option<f64>) -> Transaction {
+        Transaction {
+            kind,
+            client: 1,
+            amount,
+            tx,
+            memo: None,
+            idempotency_key: None,
+            timestamp: None,
+            state: None,
+        }
+    }
+
+    #[test]
+    fn test_spilled_history_still_disputes() {
+        let budget = MemoryBudget::new(1).unwrap();
+        let mut account = ClientAccount::with_budget(&budget);
+
+        for tx in 0..10 {
+            account
+                .apply_transaction(transaction(TransactionType::Deposit, tx, Some(1.0)))
+                .unwrap();
+        }
+        assert_eq!(10, account.history.len());
+        assert_eq!(10, account.history.spilled_len());
+
+        account.apply_transaction(transaction(TransactionType::Dispute, 3, None)).unwrap();
+        account.apply_transaction(transaction(TransactionType::Deposit, 10, Some(1.0))).unwrap();
+        account.apply_transaction(transaction(TransactionType::Chargeback, 3, None)).unwrap();
+
+        assert_eq!("1, 10.0000, 0.0000, 10.0000, true", account.to_str_row(1).unwrap());
+        assert_eq!(Some(DisputeState::ChargedBack), account.history.get_mut(3).unwrap().unwrap().state);
+        drop(account);
+        assert_eq!(0, budget.used());
+    }
+}
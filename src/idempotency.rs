@@ -0,0 +1,158 @@
+use crate::errors::KrakenError;
+use crate::snapshot::AccountSnapshot;
+use crate::structures::{DisputeState, Transaction, TransactionType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+/// What identifies a transaction across inputs. Written to snapshots as the key itself, or as `[type code, tx]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IdempotencyKey {
+    /// Read from the input's idempotency key column.
+    Given(String),
+    /// Derived from a transaction without a key: the code of its type and its tx id, so a deposit and the dispute of
+    /// it are told apart.
+    Derived(u8, u32),
+}
+
+impl IdempotencyKey {
+    /// The key of `transaction`, taking its idempotency key if it has one.
+    pub fn take(transaction: &mut Transaction) -> Self {
+        match transaction.idempotency_key.take() {
+            Some(key) => IdempotencyKey::Given(key),
+            None => IdempotencyKey::Derived(transaction.kind.code(), transaction.tx),
+        }
+    }
+}
+
+impl fmt::Display for IdempotencyKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdempotencyKey::Given(key) => write!(f, "key {key}"),
+            IdempotencyKey::Derived(code, tx) => match TransactionType::try_from(*code) {
+                Ok(kind) => write!(f, "{} tx {tx}", kind.name()),
+                Err(_) => write!(f, "tx {tx}"),
+            },
+        }
+    }
+}
+
+/// Keys of every transaction an engine was given, applied or refused, so that one given again, as when an input
+/// overlapping one already processed is replayed, is refused rather than processed twice.
+#[derive(Debug, Clone, Default)]
+pub struct IdempotencyKeys {
+    keys: HashSet<IdempotencyKey>,
+}
+
+impl IdempotencyKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keys of the transactions that brought the accounts of a snapshot saved without keys to where they are: the
+    /// deposits, withdrawals, and authorizations of their histories, and the disputes, resolves, chargebacks, their
+    /// reversals, and captures that their dispute states show. Transactions refused before the snapshot left no trace, so aren't among them.
+    pub fn derive(accounts: &[AccountSnapshot]) -> Self {
+        let mut keys = HashSet::new();
+        for entry in accounts.iter().flat_map(|account| &account.history) {
+            let steps: &[TransactionType] = match entry.state {
+                None => &[],
+                Some(DisputeState::Open) => &[TransactionType::Dispute],
+                Some(DisputeState::Resolved) => &[TransactionType::Dispute, TransactionType::Resolve],
+                Some(DisputeState::ChargedBack) => &[TransactionType::Dispute, TransactionType::Chargeback],
+                // Expiry isn't a transaction of the input
+                Some(DisputeState::Authorized | DisputeState::Expired) => &[],
+                Some(DisputeState::Captured) => &[TransactionType::Capture],
+                Some(DisputeState::Reversed) => {
+                    &[TransactionType::Dispute, TransactionType::Chargeback, TransactionType::ChargebackReversal]
+                }
+            };
+            for kind in std::iter::once(&entry.kind).chain(steps) {
+                keys.insert(IdempotencyKey::Derived(kind.code(), entry.tx));
+            }
+        }
+        Self { keys }
+    }
+
+    /// Take the key of `transaction` and record it, returning it, failing if it was recorded before.
+    pub fn admit(&mut self, transaction: &mut Transaction) -> Result<IdempotencyKey, KrakenError> {
+        let key = IdempotencyKey::take(transaction);
+        if self.keys.contains(&key) {
+            return Err(KrakenError::AlreadyProcessed(key.to_string()));
+        }
+        self.keys.insert(key.clone());
+        Ok(key)
+    }
+
+    /// Forget `key`, as if the transaction it identifies was never given, returning whether it was recorded.
+    pub fn remove(&mut self, key: &IdempotencyKey) -> bool {
+        self.keys.remove(key)
+    }
+
+    pub fn contains(&self, key: &IdempotencyKey) -> bool {
+        self.keys.contains(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Every key, in order, as snapshots keep them.
+    pub fn sorted(&self) -> Vec<IdempotencyKey> {
+        let mut keys: Vec<IdempotencyKey> = self.keys.iter().cloned().collect();
+        keys.sort_unstable();
+        keys
+    }
+}
+
+impl FromIterator<IdempotencyKey> for IdempotencyKeys {
+    fn from_iter<I: IntoIterator<Item = IdempotencyKey>>(keys: I) -> Self {
+        Self { keys: keys.into_iter().collect() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::idempotency::{IdempotencyKey, IdempotencyKeys};
+    use crate::snapshot::Snapshot;
+    use crate::structures::{Transaction, TransactionType};
+
+    #[test]
+    fn test_idempotency() {
+        let rows = ["deposit, 1, 1, 5.0", "withdrawal, 1, 2, 9.0", "dispute, 1, 1, ", "resolve, 1, 1, "];
+        let transactions = || rows.iter().map(|row| Transaction::try_from(*row).unwrap());
+        let mut engine = Engine::new().with_idempotency(Some(IdempotencyKeys::new()));
+        assert_eq!(1, engine.process(transactions()));
+
+        // Processing the same rows again, the refused withdrawal included, changes nothing
+        assert_eq!(4, engine.process(transactions()));
+        engine.apply(Transaction::try_from("deposit, 1, 3, 9.0").unwrap()).unwrap();
+        assert_eq!("1, 14.0000, 0.0000, 14.0000, false", engine.accounts()[&1].to_str_row(1).unwrap());
+        assert_eq!(
+            "Already processed: withdrawal tx 2",
+            engine.apply(Transaction::try_from("withdrawal, 1, 2, 9.0").unwrap()).unwrap_err().to_string()
+        );
+
+        // A given key identifies the transaction instead of its type and tx
+        let mut deposit = Transaction::try_from("deposit, 1, 4, 1.0").unwrap();
+        deposit.idempotency_key = Some(String::from("order-7"));
+        engine.apply(deposit.clone()).unwrap();
+        assert_eq!("Already processed: key order-7", engine.apply(deposit).unwrap_err().to_string());
+
+        // Keys derived from a snapshot cover what its histories show was applied
+        let snapshot = Snapshot::capture(engine.accounts()).unwrap();
+        let keys = IdempotencyKeys::derive(&snapshot.accounts);
+        let derived = |kind: TransactionType, tx| IdempotencyKey::Derived(kind.code(), tx);
+        assert_eq!(5, keys.len());
+        assert!(keys.contains(&derived(TransactionType::Resolve, 1)));
+        assert!(!keys.contains(&derived(TransactionType::Withdrawal, 2)));
+        let json = serde_json::to_string(&[derived(TransactionType::Deposit, 1), IdempotencyKey::Given(String::from("a"))]);
+        assert_eq!(r#"[[0,1],"a"]"#, json.unwrap());
+    }
+}
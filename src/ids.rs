@@ -0,0 +1,95 @@
+use crate::errors::KrakenError;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Id of a client account. Numeric ids are used as they are; textual ones stand for the id they were interned as.
+pub type ClientId = u32;
+/// Id of a transaction, unique across every client.
+pub type TxId = u32;
+
+/// How the client and tx ids of CSV input are written.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IdKind {
+    /// Unsigned 32-bit integers, as in the classic `type, client, tx, amount` layout.
+    #[default]
+    Numeric,
+    /// Any text, such as UUIDs or references, interned into numeric ids as it's read.
+    String,
+}
+
+impl TryFrom<&str> for IdKind {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "numeric" => Ok(IdKind::Numeric),
+            "string" => Ok(IdKind::String),
+            _ => Err(KrakenError::Enum(format!("Invalid String for IdKind: {value}"))),
+        }
+    }
+}
+
+/// Numbers textual ids in the order they're first seen, counting from 0, and remembers each one's text.
+#[derive(Debug, Default)]
+pub struct Interner {
+    names: Mutex<Names>,
+}
+
+#[derive(Debug, Default)]
+struct Names {
+    ids: HashMap<Box<str>, u32>,
+    names: Vec<Box<str>>,
+}
+
+impl Interner {
+    /// The id of `name`, numbering it if it's new.
+    pub fn intern(&self, name: &str) -> Result<u32, KrakenError> {
+        let mut names = self.names.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(id) = names.ids.get(name) {
+            return Ok(*id);
+        }
+        let id = u32::try_from(names.names.len()).map_err(|_| KrakenError::Parse(format!("Too many distinct ids: {name}")))?;
+        names.ids.insert(name.into(), id);
+        names.names.push(name.into());
+        Ok(id)
+    }
+
+    /// The text `id` was interned from.
+    pub fn name(&self, id: u32) -> Option<String> {
+        let names = self.names.lock().unwrap_or_else(|e| e.into_inner());
+        names.names.get(id as usize).map(|name| name.to_string())
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.lock().unwrap_or_else(|e| e.into_inner()).names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The textual ids of a run, interned apart for clients and transactions. Shared by every input, so the same text
+/// always stands for the same id.
+#[derive(Debug, Default)]
+pub struct Identifiers {
+    pub clients: Interner,
+    pub txs: Interner,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ids::Interner;
+
+    #[test]
+    fn test_interner() {
+        let interner = Interner::default();
+        let first = interner.intern("6f1c2a4e-0d7b-4b8e-9a43-5c2f0e8d1b77").unwrap();
+        let second = interner.intern("acct-42").unwrap();
+        assert_eq!((0, 1), (first, second));
+        assert_eq!(first, interner.intern("6f1c2a4e-0d7b-4b8e-9a43-5c2f0e8d1b77").unwrap());
+        assert_eq!(Some(String::from("acct-42")), interner.name(second));
+        assert_eq!(None, interner.name(2));
+        assert_eq!(2, interner.len());
+    }
+}
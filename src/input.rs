@@ -0,0 +1,811 @@
+use crate::aliases::Aliases;
+use crate::compression::{self, Compression};
+use crate::errors::KrakenError::Parse;
+use crate::errors::{KrakenError, EXIT_SCHEMA};
+use crate::fast_reader::MmapReader;
+use crate::ids::Identifiers;
+#[cfg(feature = "iso20022")]
+use crate::iso20022::Iso20022Source;
+use crate::mapping::{MappedSource, SchemaMapping};
+#[cfg(feature = "ofx")]
+use crate::ofx::{OfxSource, QifSource};
+#[cfg(feature = "polars")]
+use crate::polars_reader::{IpcSource, ParquetSource, PolarsSource};
+use crate::provenance::Provenance;
+use crate::range::Range;
+use crate::structures::{Transaction, TransactionType};
+#[cfg(feature = "remote")]
+use crate::remote;
+#[cfg(feature = "xlsx")]
+use crate::xlsx_reader::XlsxSource;
+use itertools::Either;
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Default number of rows per batch yielded by an `InputSource`.
+pub const DEFAULT_BATCH_ROWS: usize = 8192;
+
+/// Path that stands for standard input wherever an input path is accepted.
+pub const STDIN: &str = "-";
+
+/// A source of transactions, yielded in input order as batches of rows.
+///
+/// Every reader implements it: CSV (`CsvSource`, `MmapReader`, `PolarsSource`), JSON Lines, Parquet, Arrow IPC,
+/// workbooks, ISO 20022, OFX, and QIF bank files, stdin, and remote URLs. The processing modes only ever see an
+/// `InputSource`, so supporting a new format takes an implementation, an `InputFormat` variant, and an arm in
+/// `open_source`.
+/// A batch may hold any number of rows; `DEFAULT_BATCH_ROWS` is a reasonable size for sources free to choose.
+pub trait InputSource {
+    /// Read the next batch, or `None` once the input is exhausted.
+    /// After an error, the source may not be read any further.
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>>;
+
+    /// Iterate over the remaining batches.
+    fn batches(&mut self) -> impl Iterator<Item = Result<Vec<Transaction>, KrakenError>> + '_
+    where
+        Self: Sized,
+    {
+        std::iter::from_fn(move || self.next_batch())
+    }
+}
+
+/// A source decoded one row at a time, which carries on past a malformed row.
+///
+/// `InputSource` hands out batches, and a malformed row ends its batch along with the rows before it. Where
+/// every row counts on its own, as when profiling or validating input, rows are read through this instead.
+/// Readers built row by row (CSV, JSON Lines, workbooks, OFX, and QIF) implement it; `open_rows` opens one.
+pub trait RowSource {
+    /// Read the next row, or `None` once the input is exhausted. A malformed row is returned as a `Parse` or
+    /// `Enum` error, and reading carries on with the row after it. Any other error ends the input.
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>>;
+}
+
+impl<S: RowSource + ?Sized> RowSource for Box<S> {
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>> {
+        (**self).next_row()
+    }
+}
+
+impl<S: InputSource + ?Sized> InputSource for Box<S> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        (**self).next_batch()
+    }
+}
+
+impl<S: InputSource + ?Sized> InputSource for &mut S {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        (**self).next_batch()
+    }
+}
+
+/// Decoder used to turn the input file into transactions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReaderKind {
+    /// Polars' batched CSV reader, converting one set of parsed chunks at a time.
+    #[cfg(feature = "polars")]
+    Polars,
+    /// Memory-mapped scanner decoding rows straight from the mapped bytes.
+    Fast,
+    /// `csv` + serde reader, the default in `minimal` builds.
+    Csv,
+}
+
+impl Default for ReaderKind {
+    fn default() -> Self {
+        #[cfg(all(feature = "polars", not(feature = "minimal")))]
+        return ReaderKind::Polars;
+        #[cfg(any(not(feature = "polars"), feature = "minimal"))]
+        return ReaderKind::Csv;
+    }
+}
+
+impl TryFrom<&str> for ReaderKind {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            #[cfg(feature = "polars")]
+            "polars" => Ok(ReaderKind::Polars),
+            "fast" => Ok(ReaderKind::Fast),
+            "csv" => Ok(ReaderKind::Csv),
+            _ => Err(KrakenError::Enum(format!(
+                "Invalid String for ReaderKind: {value}"
+            ))),
+        }
+    }
+}
+
+/// File format of an input. CSV rows are decoded by the selected `ReaderKind`; other formats have a
+/// dedicated reader.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    /// Newline-delimited JSON, one transaction object per line.
+    JsonLines,
+    /// Apache Parquet, read through Polars a slice of row groups at a time.
+    #[cfg(feature = "polars")]
+    Parquet,
+    /// Arrow IPC, as either a file (Feather v2) or a stream, decoded one record batch at a time.
+    #[cfg(feature = "polars")]
+    Ipc,
+    /// Excel or OpenDocument workbook, read from a single sheet.
+    #[cfg(feature = "xlsx")]
+    Xlsx,
+    /// ISO 20022 XML: camt.053 bank statements or pain.001 payment initiations.
+    #[cfg(feature = "iso20022")]
+    Iso20022,
+    /// OFX or QFX bank and credit card statements.
+    #[cfg(feature = "ofx")]
+    Ofx,
+    /// Quicken Interchange Format exports.
+    #[cfg(feature = "ofx")]
+    Qif,
+}
+
+impl InputFormat {
+    /// Guess the format from the file extension, falling back to CSV.
+    pub fn detect(path: impl AsRef<Path>) -> Self {
+        match data_extension(path.as_ref()).as_deref() {
+            Some("jsonl" | "ndjson") => InputFormat::JsonLines,
+            #[cfg(feature = "polars")]
+            Some("parquet" | "pq") => InputFormat::Parquet,
+            #[cfg(feature = "polars")]
+            Some("arrow" | "arrows" | "feather" | "ipc") => InputFormat::Ipc,
+            #[cfg(feature = "xlsx")]
+            Some("xlsx" | "xlsm" | "xls" | "ods") => InputFormat::Xlsx,
+            #[cfg(feature = "iso20022")]
+            Some("xml") => InputFormat::Iso20022,
+            #[cfg(feature = "ofx")]
+            Some("ofx" | "qfx") => InputFormat::Ofx,
+            #[cfg(feature = "ofx")]
+            Some("qif") => InputFormat::Qif,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl TryFrom<&str> for InputFormat {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::JsonLines),
+            #[cfg(feature = "polars")]
+            "parquet" => Ok(InputFormat::Parquet),
+            #[cfg(feature = "polars")]
+            "ipc" | "arrow" | "feather" => Ok(InputFormat::Ipc),
+            #[cfg(feature = "xlsx")]
+            "xlsx" => Ok(InputFormat::Xlsx),
+            #[cfg(feature = "iso20022")]
+            "iso20022" | "camt" | "pain" => Ok(InputFormat::Iso20022),
+            #[cfg(feature = "ofx")]
+            "ofx" | "qfx" => Ok(InputFormat::Ofx),
+            #[cfg(feature = "ofx")]
+            "qif" => Ok(InputFormat::Qif),
+            _ => Err(KrakenError::Enum(format!(
+                "Invalid String for InputFormat: {value}"
+            ))),
+        }
+    }
+}
+
+/// Lowercased extension of a file, looking through a `.gz` or `.zst` compression suffix.
+fn data_extension(path: &Path) -> Option<String> {
+    let extension = |path: &Path| path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase);
+    match extension(path).as_deref() {
+        Some("gz" | "zst") => extension(Path::new(path.file_stem()?)),
+        _ => extension(path),
+    }
+}
+
+/// Field delimiter for a CSV file: `delimiter` if given, otherwise a tab for `.tsv` and `.tab` files and a
+/// comma for anything else.
+pub fn resolve_delimiter(delimiter: Option<u8>, path: impl AsRef<Path>) -> u8 {
+    delimiter.unwrap_or_else(|| match data_extension(path.as_ref()).as_deref() {
+        Some("tsv" | "tab") => b'\t',
+        _ => b',',
+    })
+}
+
+/// How input files are opened and decoded.
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    pub reader: ReaderKind,
+    /// Format of every input file. Detected per file from its extension when `None`.
+    pub format: Option<InputFormat>,
+    /// CSV field delimiter. Chosen per file by `resolve_delimiter` when `None`.
+    pub delimiter: Option<u8>,
+    /// Workbook sheet to read. The first sheet is used when `None`.
+    pub sheet: Option<String>,
+    /// Layout of CSV input that doesn't follow `type, client, tx, amount`.
+    pub mapping: Option<Arc<SchemaMapping>>,
+    /// Where the textual client and tx ids of CSV input are interned. Ids are numbers when `None`.
+    pub ids: Option<Arc<Identifiers>>,
+    /// Clients whose ids `MultiSource` replaces with those of the clients they're aliases of.
+    pub aliases: Option<Arc<Aliases>>,
+    /// The only clients whose transactions `MultiSource` passes on, by the ids they're reported under. Every client's
+    /// are when `None`.
+    pub clients: Option<Arc<HashSet<u32>>>,
+    /// The part of the input `MultiSource` passes on. All of it when `None`.
+    pub range: Option<Range>,
+    /// The transaction types `MultiSource` passes on. Every type when `None`.
+    pub types: Option<TypeFilter>,
+    /// Whether `MultiSource` logs and skips rows that fail to parse, reading on past them, rather than failing.
+    pub skip_malformed: bool,
+}
+
+/// Transaction types to apply, to see what the balances would be without some of them, such as without chargebacks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeFilter {
+    /// Only these types.
+    Only(Vec<TransactionType>),
+    /// Every type but these.
+    Ignore(Vec<TransactionType>),
+}
+
+impl TypeFilter {
+    pub fn passes(&self, kind: &TransactionType) -> bool {
+        match self {
+            TypeFilter::Only(types) => types.contains(kind),
+            TypeFilter::Ignore(types) => !types.contains(kind),
+        }
+    }
+}
+
+impl InputOptions {
+    /// Whether CSV needs the streaming reader, which alone reads mapped layouts and textual ids.
+    fn streams_csv(&self) -> bool {
+        self.mapping.is_some() || self.ids.is_some()
+    }
+
+    /// The streaming reader of a CSV stream, laid out by the mapping if any, or as usual.
+    fn mapped<R: Read>(&self, reader: R, delimiter: u8) -> MappedSource<R> {
+        MappedSource::from_reader(reader, delimiter, self.mapping.clone().unwrap_or_default()).with_ids(self.ids.clone())
+    }
+}
+
+/// Decode a CSV stream split on `delimiter`, laid out as `options.mapping` describes if given.
+pub fn csv_source(reader: impl Read + 'static, delimiter: u8, options: &InputOptions) -> Box<dyn InputSource> {
+    match options.streams_csv() {
+        true => Box::new(options.mapped(reader, delimiter)),
+        false => Box::new(CsvSource::from_reader(reader, delimiter)),
+    }
+}
+
+/// Decode a CSV stream split on `delimiter` row by row, laid out as `options.mapping` describes if given.
+fn csv_rows(reader: impl Read + 'static, delimiter: u8, options: &InputOptions) -> Box<dyn RowSource> {
+    match options.streams_csv() {
+        true => Box::new(options.mapped(reader, delimiter)),
+        false => Box::new(CsvSource::from_reader(reader, delimiter)),
+    }
+}
+
+/// Open a single input file with the requested format and reader.
+/// Neither memory-mapping nor Polars' batched reader can work on a compressed stream, so compressed CSV is
+/// always decoded by the streaming CSV reader, as is CSV with a mapping or textual ids. Other formats only have
+/// numeric ids.
+pub fn open_source(path: impl AsRef<Path>, options: &InputOptions) -> Result<Box<dyn InputSource>, KrakenError> {
+    let path = path.as_ref();
+    if path == Path::new(STDIN) {
+        return open_stdin(options);
+    }
+    #[cfg(feature = "remote")]
+    if let Some(url) = path.to_str().filter(|path| remote::is_url(path)) {
+        return remote::open_url(url, options);
+    }
+
+    let format = options.format.unwrap_or_else(|| InputFormat::detect(path));
+    if format != InputFormat::Csv && options.ids.is_some() {
+        return Err(KrakenError::InvalidArgument(format!("String ids are only read from CSV, not {format:?} input")));
+    }
+    match format {
+        InputFormat::Csv => {}
+        InputFormat::JsonLines => {
+            return Ok(Box::new(JsonLinesSource::from_reader(BufReader::new(compression::open(path)?))));
+        }
+        #[cfg(feature = "polars")]
+        InputFormat::Parquet => return Ok(Box::new(ParquetSource::open(path)?)),
+        #[cfg(feature = "polars")]
+        InputFormat::Ipc => return Ok(Box::new(IpcSource::open(path)?)),
+        #[cfg(feature = "xlsx")]
+        InputFormat::Xlsx => return Ok(Box::new(XlsxSource::open(path, options.sheet.as_deref())?)),
+        #[cfg(feature = "iso20022")]
+        InputFormat::Iso20022 => {
+            return Ok(Box::new(Iso20022Source::from_reader(BufReader::new(compression::open(path)?))));
+        }
+        #[cfg(feature = "ofx")]
+        InputFormat::Ofx => return Ok(Box::new(OfxSource::from_reader(BufReader::new(compression::open(path)?)))),
+        #[cfg(feature = "ofx")]
+        InputFormat::Qif => return Ok(Box::new(QifSource::from_reader(BufReader::new(compression::open(path)?)))),
+    }
+
+    let delimiter = resolve_delimiter(options.delimiter, path);
+    if Compression::detect(path)? != Compression::None || options.streams_csv() {
+        return Ok(csv_source(compression::open(path)?, delimiter, options));
+    }
+
+    Ok(match options.reader {
+        #[cfg(feature = "polars")]
+        ReaderKind::Polars => Box::new(PolarsSource::open(path, delimiter)?),
+        ReaderKind::Fast => Box::new(MmapReader::open(path, delimiter)?),
+        ReaderKind::Csv => Box::new(CsvSource::open(path, delimiter)?),
+    })
+}
+
+/// Read standard input as CSV (the default), JSON Lines, ISO 20022 XML, OFX, or QIF, decompressing it if it
+/// starts with gzip or zstd magic bytes. Stdin can only be streamed, so formats that need to seek are rejected.
+pub fn open_stdin(options: &InputOptions) -> Result<Box<dyn InputSource>, KrakenError> {
+    let stdin = compression::decompress(BufReader::new(std::io::stdin()))?;
+    let format = options.format.unwrap_or(InputFormat::Csv);
+    if format != InputFormat::Csv && options.ids.is_some() {
+        return Err(KrakenError::InvalidArgument(format!("String ids are only read from CSV, not {format:?} input")));
+    }
+    match format {
+        InputFormat::Csv => Ok(csv_source(stdin, options.delimiter.unwrap_or(b','), options)),
+        InputFormat::JsonLines => Ok(Box::new(JsonLinesSource::from_reader(BufReader::new(stdin)))),
+        #[cfg(feature = "iso20022")]
+        InputFormat::Iso20022 => Ok(Box::new(Iso20022Source::from_reader(BufReader::new(stdin)))),
+        #[cfg(feature = "ofx")]
+        InputFormat::Ofx => Ok(Box::new(OfxSource::from_reader(BufReader::new(stdin)))),
+        #[cfg(feature = "ofx")]
+        InputFormat::Qif => Ok(Box::new(QifSource::from_reader(BufReader::new(stdin)))),
+        #[allow(unreachable_patterns)]
+        format => Err(KrakenError::InvalidArgument(format!("{format:?} input cannot be read from stdin"))),
+    }
+}
+
+/// Open a single input file, or stdin, to be read row by row. Returns `None` for formats read a batch at a
+/// time (Parquet, Arrow IPC, and ISO 20022) and for remote URLs, which can only be read with `open_source`.
+/// Uncompressed CSV is memory-mapped, whatever the requested reader, unless it has a mapping.
+pub fn open_rows(path: impl AsRef<Path>, options: &InputOptions) -> Result<Option<Box<dyn RowSource>>, KrakenError> {
+    let path = path.as_ref();
+    #[cfg(feature = "remote")]
+    if path.to_str().is_some_and(remote::is_url) {
+        return Ok(None);
+    }
+
+    if path == Path::new(STDIN) {
+        let stdin = compression::decompress(BufReader::new(std::io::stdin()))?;
+        return Ok(match options.format.unwrap_or(InputFormat::Csv) {
+            InputFormat::Csv => Some(csv_rows(stdin, options.delimiter.unwrap_or(b','), options)),
+            InputFormat::JsonLines => Some(Box::new(JsonLinesSource::from_reader(BufReader::new(stdin)))),
+            #[cfg(feature = "ofx")]
+            InputFormat::Ofx => Some(Box::new(OfxSource::from_reader(BufReader::new(stdin)))),
+            #[cfg(feature = "ofx")]
+            InputFormat::Qif => Some(Box::new(QifSource::from_reader(BufReader::new(stdin)))),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        });
+    }
+
+    Ok(match options.format.unwrap_or_else(|| InputFormat::detect(path)) {
+        InputFormat::Csv => {
+            let delimiter = resolve_delimiter(options.delimiter, path);
+            match Compression::detect(path)? {
+                Compression::None if !options.streams_csv() => Some(Box::new(MmapReader::open(path, delimiter)?)),
+                _ => Some(csv_rows(compression::open(path)?, delimiter, options)),
+            }
+        }
+        InputFormat::JsonLines => {
+            Some(Box::new(JsonLinesSource::from_reader(BufReader::new(compression::open(path)?))))
+        }
+        #[cfg(feature = "xlsx")]
+        InputFormat::Xlsx => Some(Box::new(XlsxSource::open(path, options.sheet.as_deref())?)),
+        #[cfg(feature = "ofx")]
+        InputFormat::Ofx => Some(Box::new(OfxSource::from_reader(BufReader::new(compression::open(path)?)))),
+        #[cfg(feature = "ofx")]
+        InputFormat::Qif => Some(Box::new(QifSource::from_reader(BufReader::new(compression::open(path)?)))),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    })
+}
+
+/// Open a single input file, or stdin, whose malformed rows are logged and skipped. Formats read a batch at a
+/// time can't tell the rows of a malformed batch apart, so there the batch ends the input instead.
+fn open_lenient(path: &Path, options: &InputOptions) -> Result<Box<dyn InputSource>, KrakenError> {
+    Ok(match open_rows(path, options)? {
+        Some(rows) => Box::new(Lenient::Rows(rows)),
+        None => Box::new(Lenient::Batches(open_source(path, options)?)),
+    })
+}
+
+/// A source passing over what fails to parse, as `open_lenient` opens it.
+enum Lenient {
+    Rows(Box<dyn RowSource>),
+    Batches(Box<dyn InputSource>),
+}
+
+impl InputSource for Lenient {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        match self {
+            Lenient::Rows(source) => {
+                let mut rows = std::iter::from_fn(|| source.next_row()).filter(|row| match row {
+                    Err(e) if e.exit_code() == EXIT_SCHEMA => {
+                        warn!(code = e.code(), error = %e, "Skipping a malformed row");
+                        false
+                    }
+                    _ => true,
+                });
+                next_chunk(&mut rows, DEFAULT_BATCH_ROWS)
+            }
+            Lenient::Batches(source) => match source.next_batch()? {
+                Err(e) if e.exit_code() == EXIT_SCHEMA => {
+                    warn!(code = e.code(), error = %e, "Ending the input at a malformed batch");
+                    None
+                }
+                batch => Some(batch),
+            },
+        }
+    }
+}
+
+/// Several input files read back to back as one logical stream.
+/// Files are opened lazily, and any error is attributed to the file it came from. The client ids of aliases in
+/// `InputOptions::aliases` are replaced by their canonical ones, and the transactions of clients other than
+/// `InputOptions::clients` are dropped as soon as they're read, as are those outside `InputOptions::range` and those
+/// of types `InputOptions::types` filters out.
+pub struct MultiSource {
+    paths: VecDeque<PathBuf>,
+    options: InputOptions,
+    current: Option<(PathBuf, Box<dyn InputSource>)>,
+    /// Inputs opened so far.
+    opened: usize,
+    /// Rows read so far, across every input.
+    rows: u64,
+    provenance: Option<Provenance>,
+}
+
+impl MultiSource {
+    pub fn new<P: AsRef<Path>>(paths: &[P], options: InputOptions) -> Self {
+        Self {
+            paths: paths.iter().map(|path| path.as_ref().to_path_buf()).collect(),
+            options,
+            current: None,
+            opened: 0,
+            rows: 0,
+            provenance: None,
+        }
+    }
+
+    /// Count the rows read from each input into `provenance`, which must list the same paths.
+    pub fn with_provenance(mut self, provenance: Provenance) -> Self {
+        self.provenance = Some(provenance);
+        self
+    }
+}
+
+impl InputSource for MultiSource {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        loop {
+            if self.options.range.is_some_and(|range| range.ends_by(self.rows)) {
+                return None;
+            }
+            if self.current.is_none() {
+                let path = self.paths.pop_front()?;
+                let opened = match self.options.skip_malformed {
+                    true => open_lenient(&path, &self.options),
+                    false => open_source(&path, &self.options),
+                };
+                match opened {
+                    Ok(source) => self.current = Some((path, source)),
+                    Err(e) => return Some(Err(in_file(&path, e))),
+                }
+                self.opened += 1;
+                if let Some(provenance) = &self.provenance {
+                    provenance.count(self.opened - 1, 0);
+                }
+            }
+
+            let (path, source) = self.current.as_mut()?;
+            match source.next_batch() {
+                Some(Ok(mut batch)) => {
+                    if let Some(aliases) = &self.options.aliases {
+                        for transaction in &mut batch {
+                            transaction.client = aliases.canonical(transaction.client);
+                        }
+                    }
+                    if let Some(provenance) = &self.provenance {
+                        provenance.count(self.opened - 1, batch.len() as u64);
+                    }
+                    let mut row = self.rows;
+                    self.rows += batch.len() as u64;
+                    if let Some(range) = &self.options.range {
+                        let inside = batch.iter().map(|transaction| {
+                            row += 1;
+                            range.contains(row, transaction)
+                        });
+                        let mut inside = match inside.collect::<Result<Vec<_>, _>>() {
+                            Ok(inside) => inside.into_iter(),
+                            Err(e) => return Some(Err(in_file(path, e))),
+                        };
+                        batch.retain(|_| inside.next().unwrap_or_default());
+                    }
+                    if let Some(types) = &self.options.types {
+                        batch.retain(|transaction| types.passes(&transaction.kind));
+                    }
+                    if let Some(clients) = &self.options.clients {
+                        batch.retain(|transaction| clients.contains(&transaction.client));
+                    }
+                    return Some(Ok(batch));
+                }
+                Some(Err(e)) => return Some(Err(in_file(path, e))),
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+fn in_file(path: &Path, error: KrakenError) -> KrakenError {
+    KrakenError::InFile(path.display().to_string(), Box::new(error))
+}
+
+/// Decode a single transaction delivered as a message: either a JSON object, as in JSON Lines input, or a
+/// bare `type, client, tx, amount` CSV row.
+pub fn parse_message(payload: &[u8]) -> Result<Transaction, KrakenError> {
+    let payload = payload.trim_ascii();
+    if payload.starts_with(b"{") {
+        serde_json::from_slice(payload).map_err(|e| Parse(e.to_string()))
+    } else {
+        Transaction::try_from(payload)
+    }
+}
+
+/// Map a textual reference, such as a bank reference or account number, onto a numeric client or tx id.
+/// References that are already numbers keep their value; any other is hashed with 32-bit FNV-1a, so the
+/// same reference always gets the same id.
+pub fn reference_id(reference: &str) -> u32 {
+    reference.parse().unwrap_or_else(|_| {
+        reference.bytes().fold(0x811c_9dc5, |hash: u32, byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193))
+    })
+}
+
+/// Flatten a source's batches back into individual rows.
+pub fn rows<S: InputSource>(source: &mut S) -> impl Iterator<Item = Result<Transaction, KrakenError>> + '_ {
+    source.batches().flat_map(|batch| match batch {
+        Ok(batch) => Either::Left(batch.into_iter().map(Ok)),
+        Err(e) => Either::Right(std::iter::once(Err(e))),
+    })
+}
+
+/// Group a row iterator into batches of at most `size` rows. A failed row ends its batch with that error.
+pub fn batched<I>(mut rows: I, size: usize) -> impl Iterator<Item = Result<Vec<Transaction>, KrakenError>>
+where
+    I: Iterator<Item = Result<Transaction, KrakenError>>,
+{
+    std::iter::from_fn(move || next_chunk(&mut rows, size))
+}
+
+/// Take the next batch of at most `size` rows, or `None` if `rows` is exhausted.
+pub fn next_chunk<I>(rows: &mut I, size: usize) -> Option<Result<Vec<Transaction>, KrakenError>>
+where
+    I: Iterator<Item = Result<Transaction, KrakenError>>,
+{
+    match rows.take(size.max(1)).collect::<Result<Vec<_>, _>>() {
+        Ok(batch) if batch.is_empty() => None,
+        batch => Some(batch),
+    }
+}
+
+/// Lightweight CSV reader built on the `csv` crate and serde, for builds without Polars.
+pub struct CsvSource<R: Read> {
+    reader: csv::Reader<R>,
+    batch_rows: usize,
+}
+
+impl CsvSource<File> {
+    pub fn open(path: impl AsRef<Path>, delimiter: u8) -> Result<Self, KrakenError> {
+        Ok(Self::from_reader(File::open(path).map_err(|_| KrakenError::IO)?, delimiter))
+    }
+}
+
+impl<R: Read> CsvSource<R> {
+    /// Read a headed `type, client, tx, amount` CSV split on `delimiter`. Whitespace around fields is
+    /// trimmed and the amount column may be short or empty for dispute-flow rows.
+    pub fn from_reader(reader: R, delimiter: u8) -> Self {
+        Self {
+            reader: csv::ReaderBuilder::new()
+                .delimiter(delimiter)
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .from_reader(reader),
+            batch_rows: DEFAULT_BATCH_ROWS,
+        }
+    }
+}
+
+impl<R: Read> InputSource for CsvSource<R> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        // `deserialize` discards any error reading the header, so surface it here
+        if let Err(e) = self.reader.headers() {
+            return Some(Err(csv_error(e)));
+        }
+
+        let mut rows = self.reader.deserialize::<Transaction>().map(|row| row.map_err(csv_error));
+        next_chunk(&mut rows, self.batch_rows)
+    }
+}
+
+impl<R: Read> RowSource for CsvSource<R> {
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>> {
+        self.reader.deserialize::<Transaction>().next().map(|row| row.map_err(csv_error))
+    }
+}
+
+/// Unwrap errors raised by the underlying reader (a failed download, say) back into the `KrakenError` they
+/// started as. Anything else is a parse error.
+pub(crate) fn csv_error(error: csv::Error) -> KrakenError {
+    if !error.is_io_error() {
+        return Parse(error.to_string());
+    }
+    match error.into_kind() {
+        csv::ErrorKind::Io(error) => match error.into_inner().map(|inner| inner.downcast::<KrakenError>()) {
+            Some(Ok(error)) => *error,
+            _ => KrakenError::IO,
+        },
+        _ => KrakenError::IO,
+    }
+}
+
+/// Newline-delimited JSON reader, e.g. `{"type":"deposit","client":1,"tx":1,"amount":1.5}` per line.
+/// Uses the same serde mapping as `CsvSource`, so `amount` may be omitted or `null` for dispute-flow rows.
+pub struct JsonLinesSource<R: BufRead> {
+    lines: Lines<R>,
+    line_number: usize,
+    batch_rows: usize,
+}
+
+impl<R: BufRead> JsonLinesSource<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            line_number: 0,
+            batch_rows: DEFAULT_BATCH_ROWS,
+        }
+    }
+}
+
+impl<R: BufRead> RowSource for JsonLinesSource<R> {
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>> {
+        loop {
+            self.line_number += 1;
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(_) => return Some(Err(KrakenError::IO)),
+            };
+            if !line.trim().is_empty() {
+                return Some(
+                    serde_json::from_str(&line).map_err(|e| Parse(format!("line {}: {e}", self.line_number))),
+                );
+            }
+        }
+    }
+}
+
+impl<R: BufRead> InputSource for JsonLinesSource<R> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        let batch_rows = self.batch_rows;
+        next_chunk(&mut std::iter::from_fn(|| self.next_row()), batch_rows)
+    }
+}
+
+impl InputSource for MmapReader {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        next_chunk(&mut std::iter::from_fn(|| self.next_row()), DEFAULT_BATCH_ROWS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{parse_message, rows, CsvSource, InputFormat, InputOptions, MultiSource, ReaderKind, TypeFilter};
+    use crate::provenance::Provenance;
+    use crate::structures::TransactionType;
+    use crate::processor::tests::{TEST_CASES, TEST_DIR};
+    use crate::processor::{compute_account_totals, ProcessorConfig};
+    use std::collections::HashSet;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_jsonl() {
+        for (file_name, expected) in TEST_CASES {
+            let mut file = tempfile::Builder::new().suffix(".jsonl").tempfile().unwrap();
+            for row in rows(&mut CsvSource::open(String::from(TEST_DIR) + file_name, b',').unwrap()) {
+                let row = row.unwrap();
+                let kind = row.kind.name();
+                match row.amount {
+                    Some(amount) => writeln!(
+                        file,
+                        r#"{{"type":"{kind}","client":{},"tx":{},"amount":{amount}}}"#,
+                        row.client, row.tx
+                    ),
+                    None => writeln!(file, r#"{{"type":"{kind}","client":{},"tx":{}}}"#, row.client, row.tx),
+                }
+                .unwrap();
+            }
+            file.flush().unwrap();
+
+            let totals = compute_account_totals(file.path().to_str().unwrap(), &ProcessorConfig::default()).unwrap();
+            assert_eq!(String::from(expected), totals.get(&1).expect("").to_str_row(1).unwrap());
+        }
+
+        assert_eq!(InputFormat::JsonLines, InputFormat::detect("transactions.ndjson.gz"));
+    }
+
+    #[test]
+    fn test_named_columns() {
+        let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        writeln!(file, "timestamp, tx, type, client, note, amount, memo, idempotency_key").unwrap();
+        writeln!(file, "2024-01-31T09:30:00Z, 7, deposit, 3, unused, 1.5, order 5, m-1").unwrap();
+        writeln!(file, ", 8, withdrawal, 3, , 0.5, , ").unwrap();
+        file.flush().unwrap();
+
+        let paths = [file.path().to_str().unwrap().to_string()];
+        let readers = [
+            #[cfg(feature = "polars")]
+            ReaderKind::Polars,
+            ReaderKind::Fast,
+            ReaderKind::Csv,
+        ];
+        for reader in readers {
+            let mut source = MultiSource::new(&paths, InputOptions { reader, ..Default::default() });
+            let rows: Vec<_> = rows(&mut source).collect::<Result<_, _>>().unwrap();
+            let (first, second) = (&rows[0], &rows[1]);
+            assert_eq!((&TransactionType::Deposit, 3, 7, Some(1.5)), (&first.kind, first.client, first.tx, first.amount));
+            assert_eq!((Some("order 5"), Some("m-1")), (first.memo.as_deref(), first.idempotency_key.as_deref()));
+            assert_eq!(Some(1_706_693_400), first.timestamp, "{reader:?}");
+            assert_eq!(&TransactionType::Withdrawal, &second.kind);
+            assert_eq!((None, None, None), (second.memo.as_deref(), second.idempotency_key.as_deref(), second.timestamp));
+        }
+    }
+
+    #[test]
+    fn test_jsonl_error_line() {
+        let mut file = tempfile::Builder::new().suffix(".jsonl").tempfile().unwrap();
+        writeln!(file, r#"{{"type":"deposit","client":1,"tx":1,"amount":1.5}}"#).unwrap();
+        writeln!(file).unwrap();
+        writeln!(file, r#"{{"type":"deposit","client":1}}"#).unwrap();
+        file.flush().unwrap();
+
+        let error = compute_account_totals(file.path().to_str().unwrap(), &ProcessorConfig::default()).unwrap_err();
+        assert!(error.to_string().contains("line 3: missing field `tx`"), "{error}");
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let json = parse_message(br#"{"type":"deposit","client":2,"tx":7,"amount":1.5}"#).unwrap();
+        let row = parse_message(b"deposit, 2, 7, 1.5\n").unwrap();
+        for transaction in [json, row] {
+            assert_eq!(TransactionType::Deposit, transaction.kind);
+            assert_eq!((2, 7, Some(1.5)), (transaction.client, transaction.tx, transaction.amount));
+        }
+        assert!(parse_message(b"{not json").is_err());
+    }
+
+    #[test]
+    fn test_client_filter() {
+        let path = String::from(TEST_DIR) + "0-trivial.csv";
+        let provenance = Provenance::new(std::slice::from_ref(&path));
+        let options = InputOptions { clients: Some(Arc::new(HashSet::from([2]))), ..Default::default() };
+        let mut source = MultiSource::new(&[&path], options).with_provenance(provenance.clone());
+        let clients: Vec<u32> = rows(&mut source).map(|row| row.unwrap().client).collect();
+        assert_eq!(vec![2, 2], clients);
+        // Every row read is still counted
+        assert_eq!(Some(5), provenance.inputs().unwrap()[0].rows);
+    }
+
+    #[test]
+    fn test_type_filter() {
+        let path = String::from(TEST_DIR) + "0-trivial.csv";
+        let kinds = |types| {
+            let options = InputOptions { types: Some(types), ..Default::default() };
+            rows(&mut MultiSource::new(&[&path], options)).map(|row| row.unwrap().kind).collect::<Vec<_>>()
+        };
+        assert_eq!(vec![TransactionType::Withdrawal; 2], kinds(TypeFilter::Only(vec![TransactionType::Withdrawal])));
+        assert_eq!(3, kinds(TypeFilter::Ignore(vec![TransactionType::Withdrawal, TransactionType::Chargeback])).len());
+    }
+}
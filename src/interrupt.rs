@@ -0,0 +1,110 @@
+use crate::errors::KrakenError;
+use crate::input::InputSource;
+use crate::structures::Transaction;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Whether a run was asked to stop, by SIGINT or SIGTERM once `install`ed. Runs reading through an
+/// `Interruptible` source stop at the end of the batch being read, and wrap up with what they applied.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt {
+    requested: Arc<AtomicBool>,
+    signal: Arc<AtomicUsize>,
+}
+
+impl Interrupt {
+    /// Listen for SIGINT and SIGTERM. A second one, while the run is still wrapping up after the first, exits at
+    /// once with the status of the signal.
+    pub fn install() -> Result<Interrupt, KrakenError> {
+        let interrupt = Interrupt::default();
+        for signal in [SIGINT, SIGTERM] {
+            // Handlers run in the order they are registered, so the first signal doesn't exit
+            flag::register_conditional_shutdown(signal, 128 + signal, interrupt.requested.clone())
+                .and_then(|_| flag::register_usize(signal, interrupt.signal.clone(), signal as usize))
+                .and_then(|_| flag::register(signal, interrupt.requested.clone()))
+                .map_err(|_| KrakenError::IO)?;
+        }
+        Ok(interrupt)
+    }
+
+    /// Ask the run to stop, as a signal would.
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Exit status of a run cut short by the interruption: 128 plus the signal, as shells report it, or 130 (as
+    /// for SIGINT) if it was `request`ed.
+    pub fn exit_code(&self) -> u8 {
+        match self.signal.load(Ordering::SeqCst) {
+            0 => 128 + SIGINT as u8,
+            signal => 128 + signal as u8,
+        }
+    }
+}
+
+/// Source ending its input at the first batch boundary after an interruption, counting the rows it passed on.
+pub struct Interruptible<S: InputSource> {
+    source: S,
+    interrupt: Interrupt,
+    rows: u64,
+    interrupted: bool,
+}
+
+impl<S: InputSource> Interruptible<S> {
+    pub fn new(source: S, interrupt: Interrupt) -> Self {
+        Self { source, interrupt, rows: 0, interrupted: false }
+    }
+
+    /// Rows passed on so far.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Whether the input was cut short. An interruption arriving once it was read to the end doesn't count.
+    pub fn interrupted(&self) -> bool {
+        self.interrupted
+    }
+}
+
+impl<S: InputSource> InputSource for Interruptible<S> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        if self.interrupt.requested() {
+            self.interrupted = true;
+            return None;
+        }
+        let batch = self.source.next_batch()?;
+        if let Ok(rows) = &batch {
+            self.rows += rows.len() as u64;
+        }
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{rows, CsvSource};
+    use crate::interrupt::{Interrupt, Interruptible};
+
+    #[test]
+    fn test_interruptible() {
+        let csv = "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 1.0\ndeposit, 1, 3, 1.0\n";
+        let interrupt = Interrupt::default();
+        let mut source = Interruptible::new(CsvSource::from_reader(csv.as_bytes(), b','), interrupt.clone());
+        assert_eq!(3, rows(&mut source).count());
+        assert_eq!(3, source.rows());
+        assert!(!source.interrupted());
+
+        // Once interrupted, no other batch is read
+        let mut source = Interruptible::new(CsvSource::from_reader(csv.as_bytes(), b','), interrupt.clone());
+        interrupt.request();
+        assert_eq!(0, rows(&mut source).count());
+        assert!(source.interrupted());
+        assert_eq!(130, interrupt.exit_code());
+    }
+}
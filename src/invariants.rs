@@ -0,0 +1,181 @@
+use crate::errors::KrakenError;
+use crate::output::Balances;
+use crate::redact::{Amount, Client};
+use crate::rules::{LockedPolicy, Rules};
+use crate::structures::{ClientAccount, Transaction, TransactionType};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::error;
+
+/// How far an amount may stray from what it should be, from rounding alone.
+const TOLERANCE: f64 = 1e-9;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Check the invariants around every transaction applied from now on, in every engine of the process, aborting
+/// the process on the first violation. A debugging aid: it slows every transaction down.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// An invariant a transaction broke, with everything needed to tell how.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// The invariant, as a sentence.
+    pub invariant: &'static str,
+    pub client: u32,
+    pub tx: u32,
+    pub kind: TransactionType,
+    pub amount: Option<f64>,
+    /// Why the transaction was refused, if it was.
+    pub refusal: Option<String>,
+    pub before: Balances,
+    pub after: Balances,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let balances = |balances: &Balances| {
+            format!(
+                "available {:.4}, held {:.4}, total {:.4}, locked {}",
+                Amount(balances.available),
+                Amount(balances.held),
+                Amount(balances.total),
+                balances.locked
+            )
+        };
+        writeln!(f, "Invariant violated: {}", self.invariant)?;
+        write!(f, "  transaction: {} tx {} of client {}", self.kind.name(), self.tx, Client(self.client))?;
+        match self.amount {
+            Some(amount) => writeln!(f, ", amount {:.4}", Amount(amount))?,
+            None => writeln!(f)?,
+        }
+        match &self.refusal {
+            Some(reason) => writeln!(f, "  outcome: refused, {reason}")?,
+            None => writeln!(f, "  outcome: applied")?,
+        }
+        writeln!(f, "  before: {}", balances(&self.before))?;
+        write!(f, "  after: {}", balances(&self.after))
+    }
+}
+
+/// The first invariant `transaction` broke, in what it did to `account`, which was `before` it, with `result`:
+///
+/// - `available + held + pending == total`, with every balance a finite number
+/// - `held >= 0`, as only disputes and authorizations hold funds, and only what they dispute or authorize. The processor has no setting allowing
+///   negative balances; available funds can only go negative through a dispute of funds already withdrawn.
+/// - a locked account refuses deposits and withdrawals as locked, unless `rules` has it accept them, and stays
+///   locked unless a chargeback reversal unlocks it
+/// - a refused transaction leaves the account as it was
+pub fn check(
+    transaction: &Transaction,
+    before: &Balances,
+    account: &ClientAccount,
+    result: &Result<(), KrakenError>,
+    rules: &Rules,
+) -> Option<Violation> {
+    // Balances too large to add up under `AmountBackend::Minor` fail whatever reports them, rather than an invariant
+    let (Ok(after), Ok(pending)) = (Balances::try_from(account), account.pending()) else {
+        return None;
+    };
+    let invariant = if ![after.available, after.held, after.total].iter().all(|amount| amount.is_finite())
+        || (after.available + after.held + pending - after.total).abs() > TOLERANCE
+    {
+        Some("available + held + pending == total")
+    } else if after.held < -TOLERANCE {
+        Some("held >= 0")
+    } else if before.locked && !after.locked && transaction.kind != TransactionType::ChargebackReversal {
+        Some("locked accounts stay locked")
+    } else if before.locked
+        && match transaction.kind {
+            TransactionType::Deposit => rules.locked_deposits == LockedPolicy::Reject,
+            TransactionType::Withdrawal => rules.locked_withdrawals == LockedPolicy::Reject,
+            _ => false,
+        }
+        && !matches!(result, Err(KrakenError::AccountLocked(_)))
+    {
+        Some("locked accounts refuse deposits and withdrawals")
+    } else if result.is_err() && after != *before {
+        Some("refused transactions leave the account as it was")
+    } else {
+        None
+    };
+
+    invariant.map(|invariant| Violation {
+        invariant,
+        client: transaction.client,
+        tx: transaction.tx,
+        kind: transaction.kind.clone(),
+        amount: transaction.amount,
+        refusal: result.as_ref().err().map(ToString::to_string),
+        before: before.clone(),
+        after,
+    })
+}
+
+/// Apply `transaction` to `account` with `rules`, checking the invariants around it if `enable` was called. On a
+/// violation, it's logged in full and the process aborted, before anything else can build on the broken account.
+pub(crate) fn apply_checked(account: &mut ClientAccount, transaction: Transaction, rules: &Rules) -> Result<(), KrakenError> {
+    if !enabled() {
+        return account.apply_with_rules(transaction, rules);
+    }
+    // Expiry and release happen whether the transaction is applied or not, so are left out of what it did
+    account.expire_authorizations(transaction.timestamp, rules)?;
+    account.release_pending(transaction.timestamp)?;
+    let before = Balances::try_from(&*account)?;
+    let checked = transaction.clone();
+    let result = account.apply_with_rules(transaction, rules);
+    if let Some(violation) = check(&checked, &before, account, &result, rules) {
+        error!(code = "invariant", client = violation.client, tx = violation.tx, "{violation}");
+        std::process::abort();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::KrakenError;
+    use crate::invariants::check;
+    use crate::output::Balances;
+    use crate::rules::{LockedPolicy, Rules};
+    use crate::structures::{ClientAccount, Transaction};
+
+    #[test]
+    fn test_invariants() {
+        let deposit = Transaction::try_from("deposit, 1, 7, 2.0").unwrap();
+        let account = |available, held, locked| ClientAccount { available, held, locked, ..Default::default() };
+        let locked = Balances::try_from(&account(1.0, 0.0, true)).unwrap();
+        let rules = Rules::default();
+
+        // A deposit refused by a locked account breaks nothing
+        let refused = Err(KrakenError::AccountLocked(1));
+        assert!(check(&deposit, &locked, &account(1.0, 0.0, true), &refused, &rules).is_none());
+
+        let violations = [
+            (locked.clone(), account(3.0, 0.0, true), Ok(()), "locked accounts refuse deposits and withdrawals"),
+            (locked.clone(), account(1.0, 0.0, false), Ok(()), "locked accounts stay locked"),
+            (locked.clone(), account(3.0, 0.0, true), refused, "refused transactions leave the account as it was"),
+            (Balances::default(), account(2.0, -1.0, false), Ok(()), "held >= 0"),
+            (Balances::default(), account(f64::NAN, 0.0, false), Ok(()), "available + held + pending == total"),
+        ];
+        for (before, after, result, invariant) in violations {
+            let violation = check(&deposit, &before, &after, &result, &rules).unwrap();
+            assert_eq!(invariant, violation.invariant);
+        }
+
+        let violation = check(&deposit, &locked, &account(3.0, 0.0, true), &Ok(()), &rules).unwrap();
+        let expected = "Invariant violated: locked accounts refuse deposits and withdrawals\n  \
+                        transaction: deposit tx 7 of client 1, amount 2.0000\n  outcome: applied\n  \
+                        before: available 1.0000, held 0.0000, total 1.0000, locked true\n  \
+                        after: available 3.0000, held 0.0000, total 3.0000, locked true";
+        assert_eq!(expected, violation.to_string());
+
+        // Unless the rules have locked accounts accept deposits
+        let rules = Rules { locked_deposits: LockedPolicy::Hold, ..Default::default() };
+        assert!(check(&deposit, &locked, &account(1.0, 2.0, true), &Ok(()), &rules).is_none());
+    }
+}
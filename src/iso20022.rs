@@ -0,0 +1,254 @@
+use crate::errors::KrakenError;
+use crate::errors::KrakenError::Parse;
+use crate::input::{next_chunk, reference_id, InputSource, DEFAULT_BATCH_ROWS};
+use crate::structures::{Transaction, TransactionType};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::io::BufRead;
+
+/// Placeholder some banks send instead of leaving an optional reference out.
+const NOT_PROVIDED: &str = "NOTPROVIDED";
+
+/// Reader for ISO 20022 bank files: camt.053 statements (as well as camt.052 reports and camt.054
+/// notifications, which share their entries) and pain.001 credit transfer initiations.
+///
+/// The client is the account a statement or payment block is for, its `IBAN` or `Othr/Id` mapped to a
+/// client id by `reference_id`. In statements, booked credit entries become deposits and booked debit
+/// entries withdrawals; pending and informational entries are skipped. Each entry's tx id comes from its
+/// `AcctSvcrRef`, falling back to its `NtryRef` and then to the first `EndToEndId` in its details.
+/// In payment initiations, every credit transfer is a withdrawal from the debtor account, identified by
+/// its `InstrId`, falling back to its `EndToEndId`.
+/// The document is streamed, so files of any size are read in constant memory.
+pub struct Iso20022Source<R: BufRead> {
+    reader: Reader<R>,
+    buffer: Vec<u8>,
+    /// Local names of the open elements, outermost first.
+    path: Vec<String>,
+    /// Text of the innermost open element read so far.
+    text: String,
+    /// Account of the current statement or payment block.
+    account: Option<String>,
+    /// Statement entry or credit transfer being read.
+    entry: Option<Entry>,
+    /// Whether the root `Document` element has been seen.
+    document: bool,
+}
+
+/// Fields of a statement entry or credit transfer collected so far.
+#[derive(Default)]
+struct Entry {
+    amount: Option<String>,
+    credit: Option<bool>,
+    /// `BOOK`, `PDNG`, or `INFO`. Credit transfers have none.
+    status: Option<String>,
+    /// Best reference seen so far, with its rank: lower ranks are preferred.
+    reference: Option<(u8, String)>,
+}
+
+impl<R: BufRead> Iso20022Source<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self {
+            reader: Reader::from_reader(reader),
+            buffer: Vec::new(),
+            path: Vec::new(),
+            text: String::new(),
+            account: None,
+            entry: None,
+            document: false,
+        }
+    }
+
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>> {
+        loop {
+            self.buffer.clear();
+            let event = match self.reader.read_event_into(&mut self.buffer) {
+                Ok(event) => event,
+                Err(e) => return Some(Err(Parse(format!("byte {}: {e}", self.reader.error_position())))),
+            };
+
+            match event {
+                Event::Start(start) => {
+                    let name = String::from_utf8_lossy(start.local_name().as_ref()).into_owned();
+                    if self.path.is_empty() && !self.document {
+                        if name != "Document" {
+                            return Some(Err(Parse(format!("Expected an ISO 20022 Document, found {name}"))));
+                        }
+                        self.document = true;
+                    }
+                    match name.as_str() {
+                        "Stmt" | "Rpt" | "Ntfctn" | "PmtInf" => self.account = None,
+                        "Ntry" => self.entry = Some(Entry::default()),
+                        "CdtTrfTxInf" => self.entry = Some(Entry { credit: Some(false), ..Default::default() }),
+                        _ => {}
+                    }
+                    self.path.push(name);
+                    self.text.clear();
+                }
+                Event::Text(text) => match text.decode() {
+                    Ok(text) => self.text.push_str(&text),
+                    Err(e) => return Some(Err(Parse(e.to_string()))),
+                },
+                Event::GeneralRef(reference) => {
+                    let reference = match reference.decode() {
+                        Ok(reference) => format!("&{reference};"),
+                        Err(e) => return Some(Err(Parse(e.to_string()))),
+                    };
+                    match quick_xml::escape::unescape(&reference) {
+                        Ok(text) => self.text.push_str(&text),
+                        Err(e) => return Some(Err(Parse(e.to_string()))),
+                    }
+                }
+                Event::End(_) => {
+                    let text = std::mem::take(&mut self.text);
+                    let row = self.close(text.trim());
+                    self.path.pop();
+                    if row.is_some() {
+                        return row;
+                    }
+                }
+                Event::Eof if !self.document => {
+                    // Mark the input as read, so the error is only reported once
+                    self.document = true;
+                    return Some(Err(Parse(String::from("Not an ISO 20022 document"))));
+                }
+                Event::Eof => return None,
+                _ => {}
+            }
+        }
+    }
+
+    /// Record the text of the innermost element, which is being closed.
+    /// Returns the transaction completed by closing an entry, unless it is skipped.
+    fn close(&mut self, text: &str) -> Option<Result<Transaction, KrakenError>> {
+        if self.entry.is_none() {
+            // Outside of entries, the only thing of interest is the account they belong to
+            if self.at(&["Acct", "Id", "IBAN"])
+                || self.at(&["Acct", "Id", "Othr", "Id"])
+                || self.at(&["DbtrAcct", "Id", "IBAN"])
+                || self.at(&["DbtrAcct", "Id", "Othr", "Id"])
+            {
+                self.account = Some(text.to_string());
+            }
+            return None;
+        }
+
+        if self.at(&["Ntry"]) || self.at(&["CdtTrfTxInf"]) {
+            let entry = self.entry.take()?;
+            return entry.into_transaction(self.account.as_deref(), self.reader.buffer_position());
+        }
+
+        let rank = if self.at(&["Ntry", "AcctSvcrRef"]) || self.at(&["PmtId", "InstrId"]) {
+            Some(0)
+        } else if self.at(&["Ntry", "NtryRef"]) || self.at(&["PmtId", "EndToEndId"]) {
+            Some(1)
+        } else if self.at(&["Refs", "EndToEndId"]) {
+            Some(2)
+        } else {
+            None
+        };
+        let amount = self.at(&["Ntry", "Amt"]) || self.at(&["Amt", "InstdAmt"]);
+        let indicator = self.at(&["Ntry", "CdtDbtInd"]);
+        let status = self.at(&["Ntry", "Sts"]) || self.at(&["Ntry", "Sts", "Cd"]);
+
+        let entry = self.entry.as_mut()?;
+        if amount {
+            entry.amount = Some(text.to_string());
+        } else if indicator {
+            entry.credit = Some(text == "CRDT");
+        } else if status && !text.is_empty() {
+            // Older versions give the status as text, newer ones as a code, leaving the outer element empty
+            entry.status = Some(text.to_string());
+        } else if let Some(rank) = rank.filter(|rank| {
+            !text.is_empty() && text != NOT_PROVIDED && entry.reference.as_ref().is_none_or(|(best, _)| rank < best)
+        }) {
+            entry.reference = Some((rank, text.to_string()));
+        }
+        None
+    }
+
+    /// Whether the innermost open elements are `suffix`.
+    fn at(&self, suffix: &[&str]) -> bool {
+        self.path.len() >= suffix.len() && self.path[self.path.len() - suffix.len()..].iter().zip(suffix).all(|(a, b)| a == b)
+    }
+}
+
+impl Entry {
+    /// Turn the entry into a transaction of `account`, or `None` if it hasn't been booked.
+    /// `position` is the byte offset of the entry's end, to locate errors.
+    fn into_transaction(self, account: Option<&str>, position: u64) -> Option<Result<Transaction, KrakenError>> {
+        if self.status.as_deref().is_some_and(|status| status != "BOOK") {
+            return None;
+        }
+
+        let missing = |field: &str| Parse(format!("Missing {field} in entry ending at byte {position}"));
+        let transaction = (|| {
+            let amount = self.amount.ok_or_else(|| missing("amount"))?;
+            Ok(Transaction {
+                kind: match self.credit.ok_or_else(|| missing("CdtDbtInd"))? {
+                    true => TransactionType::Deposit,
+                    false => TransactionType::Withdrawal,
+                },
+                client: reference_id(account.ok_or_else(|| missing("account"))?),
+                tx: reference_id(&self.reference.ok_or_else(|| missing("reference"))?.1),
+                amount: Some(
+                    amount
+                        .parse()
+                        .map_err(|_| Parse(format!("Invalid amount {amount} in entry ending at byte {position}")))?,
+                ),
+                memo: None,
+                idempotency_key: None,
+                timestamp: None,
+                state: None,
+            })
+        })();
+        Some(transaction)
+    }
+}
+
+impl<R: BufRead> InputSource for Iso20022Source<R> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        next_chunk(&mut std::iter::from_fn(|| self.next_row()), DEFAULT_BATCH_ROWS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{reference_id, rows, InputFormat};
+    use crate::iso20022::Iso20022Source;
+    use crate::processor::tests::TEST_DIR;
+    use crate::processor::{compute_account_totals, ProcessorConfig};
+    use crate::structures::TransactionType;
+
+    #[test]
+    fn test_iso20022() {
+        // Two booked credits, one booked debit, and a pending debit that is skipped
+        let path = String::from(TEST_DIR) + "7-camt053.xml";
+        assert_eq!(InputFormat::Iso20022, InputFormat::detect(&path));
+        let totals = compute_account_totals(&path, &ProcessorConfig::default()).unwrap();
+        assert_eq!("42, 175.2500, 0.0000, 175.2500, false", totals[&42].to_str_row(42).unwrap());
+
+        let pain = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:pain.001.001.09">
+  <CstmrCdtTrfInitn>
+    <PmtInf>
+      <DbtrAcct><Id><IBAN>DE89370400440532013000</IBAN></Id></DbtrAcct>
+      <CdtTrfTxInf>
+        <PmtId><InstrId>1001</InstrId><EndToEndId>R&amp;D-7</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="EUR">12.50</InstdAmt></Amt>
+      </CdtTrfTxInf>
+      <CdtTrfTxInf>
+        <PmtId><EndToEndId>R&amp;D-8</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="EUR">3</InstdAmt></Amt>
+      </CdtTrfTxInf>
+    </PmtInf>
+  </CstmrCdtTrfInitn>
+</Document>"#;
+        let transactions: Vec<_> =
+            rows(&mut Iso20022Source::from_reader(pain.as_bytes())).collect::<Result<_, _>>().unwrap();
+        assert_eq!(2, transactions.len());
+        assert!(transactions.iter().all(|transaction| transaction.kind == TransactionType::Withdrawal));
+        assert!(transactions.iter().all(|transaction| transaction.client == reference_id("DE89370400440532013000")));
+        assert_eq!((1001, Some(12.5)), (transactions[0].tx, transactions[0].amount));
+        assert_eq!((reference_id("R&D-8"), Some(3.0)), (transactions[1].tx, transactions[1].amount));
+    }
+}
@@ -0,0 +1,348 @@
+use crate::engine::{replay, Outcome};
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::InputSource;
+use crate::structures::TransactionType;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+
+/// Account holding the processor's own funds.
+const CASH: &str = "Assets:Cash";
+/// Parent of the accounts `purge_journal` moves purged clients' funds to.
+const PURGED: &str = "Liabilities:Clients:Purged";
+
+/// Plain-text accounting format of a journal.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum JournalFormat {
+    /// Ledger, as read by `ledger` and `hledger`.
+    #[default]
+    Ledger,
+    /// Beancount, which also needs every account opened before use.
+    Beancount,
+}
+
+impl JournalFormat {
+    /// Beancount for files ending in `.beancount` or `.bean`, Ledger otherwise.
+    pub fn detect(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension().and_then(|extension| extension.to_str()) {
+            Some("beancount" | "bean") => JournalFormat::Beancount,
+            _ => JournalFormat::Ledger,
+        }
+    }
+}
+
+impl TryFrom<&str> for JournalFormat {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "ledger" | "hledger" => Ok(JournalFormat::Ledger),
+            "beancount" => Ok(JournalFormat::Beancount),
+            _ => Err(KrakenError::Enum(format!(
+                "Invalid String for JournalFormat: {value}"
+            ))),
+        }
+    }
+}
+
+/// How journal entries are dated and denominated. Inputs carry neither, so both are chosen by the caller.
+#[derive(Debug, Clone)]
+pub struct JournalOptions {
+    pub format: JournalFormat,
+    /// `YYYY-MM-DD` date of every entry.
+    pub date: String,
+    pub commodity: String,
+}
+
+/// Replay `source` serially, writing its journal to `writer` as `JournalWriter` does. Returns the number of entries
+/// written.
+pub fn write_journal<S: InputSource, W: Write>(
+    source: S,
+    writer: W,
+    options: &JournalOptions,
+    budget: Option<MemoryBudget>,
+) -> Result<usize, KrakenError> {
+    let mut journal = JournalWriter::new(writer, options.clone());
+    replay(source, budget, |outcome| journal.record(outcome))?;
+    Ok(journal.finish()?.0)
+}
+
+/// Writes a double-entry journal of every applied transaction.
+///
+/// Client funds are liabilities of the processor, held in `Liabilities:Clients:<id>:Available` and
+/// `Liabilities:Clients:<id>:Held`, against the processor's `Assets:Cash`:
+/// deposits and withdrawals move money between cash and the client's available funds, disputes and resolves
+/// move it between available and held, as do authorizations and their expiry, and chargebacks pay held funds back out
+/// of cash, as captures do. Deposits and withdrawals a locked account holds go to held funds, and leave them for
+/// available funds or cash once it's unlocked.
+/// Rejected transactions are kept as comments.
+pub struct JournalWriter<W: Write> {
+    writer: W,
+    options: JournalOptions,
+    /// Accounts opened so far, in Beancount.
+    opened: HashSet<String>,
+    entries: usize,
+}
+
+impl<W: Write> JournalWriter<W> {
+    pub fn new(writer: W, options: JournalOptions) -> Self {
+        Self { writer, options, opened: HashSet::new(), entries: 0 }
+    }
+
+    pub fn record(&mut self, outcome: &Outcome) -> Result<(), KrakenError> {
+        let io = |_| KrakenError::IO;
+        let (client, tx) = (outcome.client, outcome.tx);
+        let kind = outcome.kind.name();
+        let available = format!("Liabilities:Clients:{client}:Available");
+        let held = format!("Liabilities:Clients:{client}:Held");
+        let pending = format!("Liabilities:Clients:{client}:Pending");
+        // Authorizations expire, and deposits become available, whatever becomes of the transaction
+        if outcome.expired > 0.0 {
+            let description = format!("expiry of authorizations for client {client}");
+            self.entry(&description, &held, &available, outcome.expired)?;
+        }
+        if outcome.released > 0.0 {
+            let description = format!("release of pending deposits for client {client}");
+            self.entry(&description, &pending, &available, outcome.released)?;
+        }
+        if let Err(e) = outcome.result {
+            return writeln!(self.writer, "; rejected {kind} tx {tx} for client {client}: {e}\n").map_err(io);
+        }
+        // Moves no money, so is only noted
+        if outcome.kind == TransactionType::AssertBalance {
+            let available = outcome.account.available;
+            return writeln!(self.writer, "; {kind} tx {tx} for client {client}: available {available:.4}\n").map_err(io);
+        }
+        // Custom types move money by their handler's rules, which the journal can't post, so are only noted
+        if let TransactionType::Custom(_) = outcome.kind {
+            let account = outcome.account;
+            let (available, held) = (account.available, account.held);
+            return writeln!(self.writer, "; {kind} tx {tx} for client {client}: available {available:.4}, held {held:.4}\n")
+                .map_err(io);
+        }
+
+        // Deposits the lock held become available as it's lifted, apart from what the transaction itself restores
+        let unheld_deposits: f64 =
+            outcome.unheld.iter().filter(|hold| hold.kind == TransactionType::Deposit).map(|hold| hold.amount).sum();
+        let restored = outcome.available_change - unheld_deposits;
+        // (debited, credited, amount)
+        let (debit, credit, amount) = match outcome.kind {
+            // Deposits under a delay are pending until released above
+            TransactionType::Deposit if outcome.pending_change > 0.0 => (CASH, pending.as_str(), outcome.pending_change),
+            // Deposits and withdrawals a locked account holds until it's unlocked
+            TransactionType::Deposit if outcome.held_change > 0.0 => (CASH, held.as_str(), outcome.held_change),
+            TransactionType::Deposit => (CASH, available.as_str(), outcome.amount.unwrap_or_default()),
+            TransactionType::Withdrawal if outcome.held_change > 0.0 => (available.as_str(), held.as_str(), outcome.held_change),
+            TransactionType::Withdrawal => (available.as_str(), CASH, outcome.amount.unwrap_or_default()),
+            // Disputes of withdrawals, when the rules allow them, hold money returning from cash instead
+            TransactionType::Dispute if outcome.pending_change < 0.0 => (pending.as_str(), held.as_str(), outcome.held_change),
+            TransactionType::Dispute if outcome.available_change < 0.0 => (available.as_str(), held.as_str(), outcome.held_change),
+            TransactionType::Dispute => (CASH, held.as_str(), outcome.held_change),
+            TransactionType::Resolve if outcome.pending_change > 0.0 => (held.as_str(), pending.as_str(), outcome.held_change),
+            TransactionType::Resolve if outcome.available_change > 0.0 => (held.as_str(), available.as_str(), outcome.held_change),
+            TransactionType::Resolve => (held.as_str(), CASH, outcome.held_change),
+            TransactionType::Chargeback if outcome.available_change > 0.0 => {
+                (held.as_str(), available.as_str(), outcome.held_change)
+            }
+            TransactionType::Chargeback => (held.as_str(), CASH, outcome.held_change),
+            // A reversal pays a deposit's funds back in, or a withdrawal's back out
+            TransactionType::ChargebackReversal if restored < 0.0 => (available.as_str(), CASH, -restored),
+            TransactionType::ChargebackReversal => (CASH, available.as_str(), restored),
+            TransactionType::Authorize => (available.as_str(), held.as_str(), outcome.held_change),
+            // What a capture doesn't take is released below
+            TransactionType::Capture => (held.as_str(), CASH, outcome.held_change - outcome.available_change),
+            TransactionType::AssertBalance | TransactionType::Custom(_) => unreachable!("noted above"),
+        };
+
+        let description = format!("{kind} tx {tx} for client {client}");
+        self.entry(&description, debit, credit, amount)?;
+        if outcome.kind == TransactionType::Capture && outcome.available_change > 0.0 {
+            let description = format!("release of tx {tx} for client {client}");
+            self.entry(&description, &held, &available, outcome.available_change)?;
+        }
+        for hold in &outcome.unheld {
+            let description = format!("release of held tx {} for client {client}", hold.tx);
+            let credit = if hold.kind == TransactionType::Deposit { available.as_str() } else { CASH };
+            self.entry(&description, &held, credit, hold.amount)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the journal, returning the number of entries written, and the writer.
+    pub fn finish(mut self) -> Result<(usize, W), KrakenError> {
+        self.writer.flush().map_err(|_| KrakenError::IO)?;
+        Ok((self.entries, self.writer))
+    }
+
+    fn entry(&mut self, description: &str, debit: &str, credit: &str, amount: f64) -> Result<(), KrakenError> {
+        write_entry(&mut self.writer, &self.options, &mut self.opened, description, debit, credit, amount)
+            .map_err(|_| KrakenError::IO)?;
+        self.entries += 1;
+        Ok(())
+    }
+}
+
+/// Write an entry moving `amount` from `credit` to `debit`, opening the accounts first in Beancount.
+fn write_entry<W: Write>(
+    writer: &mut W,
+    options: &JournalOptions,
+    opened: &mut HashSet<String>,
+    description: &str,
+    debit: &str,
+    credit: &str,
+    amount: f64,
+) -> std::io::Result<()> {
+    if options.format == JournalFormat::Beancount {
+        for account in [debit, credit] {
+            if opened.insert(account.to_string()) {
+                writeln!(writer, "{} open {account} {}", options.date, options.commodity)?;
+            }
+        }
+        writeln!(writer, "{} * \"{description}\"", options.date)?;
+    } else {
+        writeln!(writer, "{} * {description}", options.date)?;
+    }
+    writeln!(writer, "    {debit:<40}  {amount:>15.4} {}", options.commodity)?;
+    writeln!(writer, "    {credit:<40}  {:>15.4} {}", -amount, options.commodity)?;
+    writeln!(writer)
+}
+
+/// Remove every entry and note of `client` from `journal`, as written by `write_journal`, for a deletion request.
+/// The postings of its entries are folded into one entry appended at the end, moving their net amounts between
+/// `Assets:Cash` and `Liabilities:Clients:Purged:Available` and `Held` instead of the client's own accounts, so the
+/// journal still balances and its totals only change in which liability accounts hold them. Returns the journal and
+/// how many entries and notes were removed.
+pub fn purge_journal(journal: &str, client: u32) -> (String, usize) {
+    let prefix = format!("Liabilities:Clients:{client}:");
+    let note = format!(" for client {client}:");
+    let beancount = journal.lines().any(|line| line.split_whitespace().nth(1) == Some("open"));
+    let mut purged = String::with_capacity(journal.len());
+    // Net amount posted to each account by the entries removed
+    let mut totals: BTreeMap<String, f64> = BTreeMap::new();
+    let (mut date, mut commodity) = (None, None);
+    let mut removed = 0;
+
+    let mut lines = journal.lines().peekable();
+    while let Some(line) = lines.next() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            _ if line.starts_with(';') && line.contains(&note) => {
+                removed += 1;
+                lines.next_if(|line| line.is_empty());
+            }
+            // Opens one of the client's accounts, which no entry is left to use
+            [_, "open", account, ..] if account.starts_with(&prefix) => {}
+            [entry_date, "*", ..] => {
+                let mut entry = vec![line];
+                while let Some(posting) = lines.next_if(|line| line.starts_with(' ')) {
+                    entry.push(posting);
+                }
+                let postings: Vec<Vec<&str>> = entry[1..].iter().map(|posting| posting.split_whitespace().collect()).collect();
+                if !postings.iter().any(|posting| posting.first().is_some_and(|account| account.starts_with(&prefix))) {
+                    for line in entry {
+                        purged.push_str(line);
+                        purged.push('\n');
+                    }
+                    continue;
+                }
+                removed += 1;
+                lines.next_if(|line| line.is_empty());
+                date = Some(entry_date.to_string());
+                for posting in postings {
+                    if let [account, amount, unit, ..] = posting.as_slice() {
+                        let account = account.strip_prefix(&prefix).map_or(account.to_string(), |rest| format!("{PURGED}:{rest}"));
+                        *totals.entry(account).or_default() += amount.parse::<f64>().unwrap_or_default();
+                        commodity = Some(unit.to_string());
+                    }
+                }
+            }
+            _ => {
+                purged.push_str(line);
+                purged.push('\n');
+            }
+        }
+    }
+
+    // Amounts are posted to four places, so anything smaller is what summing them left over
+    totals.retain(|_, amount| amount.abs() >= 0.00005);
+    if let (Some(date), Some(commodity), false) = (date, commodity, totals.is_empty()) {
+        if beancount {
+            for account in totals.keys().filter(|account| account.starts_with(PURGED)) {
+                if !journal.contains(&format!(" open {account} ")) {
+                    purged.push_str(&format!("{date} open {account} {commodity}\n"));
+                }
+            }
+            purged.push_str(&format!("{date} * \"purged client\"\n"));
+        } else {
+            purged.push_str(&format!("{date} * purged client\n"));
+        }
+        for (account, amount) in &totals {
+            purged.push_str(&format!("    {account:<40}  {amount:>15.4} {commodity}\n"));
+        }
+        purged.push('\n');
+    }
+    (purged, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::CsvSource;
+    use crate::journal::{purge_journal, write_journal, JournalFormat, JournalOptions};
+    use crate::processor::tests::TEST_DIR;
+
+    #[test]
+    fn test_beancount_journal() {
+        let source = CsvSource::open(String::from(TEST_DIR) + "1-dispute-after-withdraw.csv", b',').unwrap();
+        let options = JournalOptions {
+            format: JournalFormat::Beancount,
+            date: String::from("2024-01-31"),
+            commodity: String::from("USD"),
+        };
+        let mut journal = Vec::new();
+        assert_eq!(4, write_journal(source, &mut journal, &options, None).unwrap());
+
+        let journal = String::from_utf8(journal).unwrap();
+        assert!(journal.starts_with("2024-01-31 open Assets:Cash USD\n2024-01-31 open Liabilities:Clients:1:Available USD\n"));
+        // The dispute moves the deposit's amount from available to held
+        assert!(journal.ends_with(
+            "2024-01-31 * \"dispute tx 0 for client 1\"
+    Liabilities:Clients:1:Available                   10.0000 USD
+    Liabilities:Clients:1:Held                       -10.0000 USD
+
+"
+        ));
+    }
+
+    #[test]
+    fn test_purge_journal() {
+        let write = |file: &str, format| {
+            let source = CsvSource::open(String::from(TEST_DIR) + file, b',').unwrap();
+            let options = JournalOptions { format, date: String::from("2024-01-31"), commodity: String::from("USD") };
+            let mut journal = Vec::new();
+            write_journal(source, &mut journal, &options, None).unwrap();
+            String::from_utf8(journal).unwrap()
+        };
+
+        // Client 2's deposit and refused withdrawal go, and its deposit's money is left to the purged accounts
+        let (purged, removed) = purge_journal(&write("0-trivial.csv", JournalFormat::Ledger), 2);
+        assert_eq!(2, removed);
+        assert!(!purged.contains("client 2") && !purged.contains("Clients:2:"), "{purged}");
+        assert!(purged.contains("* deposit tx 3 for client 1\n"));
+        assert!(purged.ends_with(
+            "2024-01-31 * purged client
+    Assets:Cash                                        2.0000 USD
+    Liabilities:Clients:Purged:Available              -2.0000 USD
+
+"
+        ));
+        // Purging again finds nothing
+        assert_eq!((purged.clone(), 0), purge_journal(&purged, 2));
+
+        // Beancount accounts of the client are no longer opened, while those of the purged accounts are
+        let (purged, removed) = purge_journal(&write("1-dispute-after-withdraw.csv", JournalFormat::Beancount), 1);
+        assert_eq!(4, removed);
+        assert!(purged.starts_with("2024-01-31 open Assets:Cash USD\n2024-01-31 open Liabilities:Clients:Purged:Available USD\n"));
+        assert!(!purged.contains("client 1"), "{purged}");
+    }
+}
@@ -0,0 +1,65 @@
+use crate::errors::KrakenError;
+use crate::errors::KrakenError::Broker;
+use crate::history::MemoryBudget;
+use crate::input::parse_message;
+use crate::queue::{checkpoint, restore_state, ConsumeConfig};
+use crate::structures::ClientAccount;
+use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+use std::collections::HashMap;
+use std::time::Instant;
+use tracing::warn;
+
+/// Apply transactions from a Kafka topic until an error occurs.
+///
+/// Each message holds one transaction, as a JSON object or a `type, client, tx, amount` row. Offsets are
+/// only committed to Kafka after a snapshot covering them has been synced to `config.state`, so a crash
+/// never loses an applied transaction. Messages applied after the last snapshot are redelivered on restart,
+/// and the offsets stored in the snapshot are used to skip any that it already reflects.
+///
+/// `on_checkpoint` is called with the balances after every checkpoint.
+pub fn consume<F>(config: &ConsumeConfig, budget: Option<MemoryBudget>, mut on_checkpoint: F) -> Result<(), KrakenError>
+where
+    F: FnMut(&HashMap<u32, ClientAccount>),
+{
+    let (mut engine, mut offsets) = restore_state(&config.state, budget)?;
+
+    let mut consumer = Consumer::from_hosts(config.brokers.clone())
+        .with_topic(config.topic.clone())
+        .with_group(config.group.clone())
+        .with_fallback_offset(FetchOffset::Earliest)
+        .with_offset_storage(Some(GroupOffsetStorage::Kafka))
+        .create()
+        .map_err(|e| Broker(e.to_string()))?;
+
+    let mut last_checkpoint = Instant::now();
+    let mut pending = false;
+    loop {
+        for set in consumer.poll().map_err(|e| Broker(e.to_string()))?.iter() {
+            let key = format!("{}/{}", set.topic(), set.partition());
+            let next = offsets.get(&key).copied().unwrap_or(0);
+            for message in set.messages().iter().filter(|message| message.offset >= next) {
+                match parse_message(message.value) {
+                    // Swallow results since we aren't tracking them
+                    Ok(transaction) => {
+                        let _ = engine.apply(transaction);
+                    }
+                    Err(e) => {
+                        warn!(code = e.code(), partition = %key, offset = message.offset, error = %e, "Skipping malformed message")
+                    }
+                }
+                offsets.insert(key.clone(), message.offset + 1);
+                pending = true;
+            }
+            consumer.consume_messageset(set).map_err(|e| Broker(e.to_string()))?;
+        }
+
+        if pending && last_checkpoint.elapsed() >= config.checkpoint_interval {
+            checkpoint(&engine, &offsets, &config.state)?;
+            consumer.commit_consumed().map_err(|e| Broker(e.to_string()))?;
+
+            on_checkpoint(engine.accounts());
+            last_checkpoint = Instant::now();
+            pending = false;
+        }
+    }
+}
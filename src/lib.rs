@@ -0,0 +1,83 @@
+pub mod actor;
+pub mod aging;
+pub mod aliases;
+pub mod alerts;
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod async_engine;
+pub mod audit;
+pub mod books;
+pub mod compression;
+pub mod config;
+#[cfg(feature = "database")]
+pub mod database;
+pub mod dates;
+pub mod diff;
+pub mod dormant;
+pub mod encryption;
+pub mod engine;
+pub mod errors;
+pub mod events;
+pub mod fast_reader;
+pub mod follow;
+pub mod generate;
+pub mod golden;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handlers;
+pub mod history;
+pub mod idempotency;
+pub mod ids;
+pub mod input;
+pub mod interrupt;
+pub mod invariants;
+#[cfg(feature = "iso20022")]
+pub mod iso20022;
+pub mod journal;
+pub mod logging;
+pub mod mapping;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod metrics;
+pub mod minor_units;
+pub mod negative;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "ofx")]
+pub mod ofx;
+pub mod output;
+#[cfg(feature = "polars")]
+pub mod polars_reader;
+pub mod processor;
+pub mod progress;
+pub mod provenance;
+pub mod queue;
+pub mod range;
+pub mod ranking;
+pub mod reconcile;
+pub mod reserve;
+pub mod redact;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod rules;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod settlement;
+pub mod signature;
+pub mod snapshot;
+pub mod statements;
+pub mod stats;
+pub mod structures;
+#[cfg(feature = "server")]
+pub mod tcp;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod time_travel;
+pub mod tx_index;
+pub mod validate;
+#[cfg(feature = "server")]
+pub mod webhook;
+#[cfg(feature = "xlsx")]
+pub mod xlsx_reader;
@@ -0,0 +1,270 @@
+use crate::errors::KrakenError;
+use crate::redact;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::field::MakeExt;
+use tracing_subscriber::fmt::format::debug_fn;
+use tracing_subscriber::fmt::{FormatFields, MakeWriter};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Least severe level of the logs written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    /// Startup messages and summaries of each run. The default.
+    Info,
+    /// Also the reason every refused transaction was refused for.
+    Debug,
+    Trace,
+}
+
+impl TryFrom<&str> for LogLevel {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, KrakenError> {
+        match value {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(KrakenError::Enum(format!("Invalid String for LogLevel: {value}"))),
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// How logs are written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Diagnostics {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One `Diagnostic` per line, as JSON.
+    Json,
+}
+
+impl TryFrom<&str> for Diagnostics {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, KrakenError> {
+        match value {
+            "text" => Ok(Diagnostics::Text),
+            "json" => Ok(Diagnostics::Json),
+            _ => Err(KrakenError::Enum(format!("Invalid String for Diagnostics: {value}"))),
+        }
+    }
+}
+
+/// What the command line asks of the logs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogConfig {
+    /// Overrides `RUST_LOG` when given.
+    pub level: Option<LogLevel>,
+    pub diagnostics: Diagnostics,
+    /// Redact client ids, amounts, and memos, as `redact::enable` describes.
+    pub redact: bool,
+}
+
+/// One log record, as written by `--diagnostics json`. Events name their `code`, and the `tx`, `client`, input
+/// `line`, and `path` they concern when there is one. Any other field of the event is kept as a string.
+#[derive(Debug, Default, Serialize)]
+pub struct Diagnostic {
+    /// `error`, `warn`, `info`, `debug`, or `trace`.
+    pub level: String,
+    /// Stable identifier of what happened, such as `insufficient_funds` or `parse`, for tools to match on.
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub message: String,
+    #[serde(flatten)]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Visit for Diagnostic {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "tx" => self.tx = Some(value),
+            "client" => match redact::field("client", &value.to_string()) {
+                Some(pseudonym) => {
+                    self.fields.insert(String::from("client"), pseudonym);
+                }
+                None => self.client = Some(value),
+            },
+            "line" => self.line = Some(value),
+            name => {
+                let value = value.to_string();
+                self.fields.insert(name.to_string(), redact::field(name, &value).unwrap_or(value));
+            }
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        match u64::try_from(value) {
+            Ok(value) => self.record_u64(field, value),
+            Err(_) => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{value:?}");
+        match field.name() {
+            "message" => self.message = value,
+            "code" => self.code = value,
+            "path" => self.path = Some(value),
+            name => {
+                self.fields.insert(name.to_string(), redact::field(name, &value).unwrap_or(value));
+            }
+        }
+    }
+}
+
+/// Writes every event it's given to `writer` as a `Diagnostic`.
+struct JsonDiagnostics<W> {
+    writer: W,
+}
+
+impl<S: Subscriber, W: for<'a> MakeWriter<'a> + 'static> Layer<S> for JsonDiagnostics<W> {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let mut diagnostic = Diagnostic {
+            level: event.metadata().level().as_str().to_ascii_lowercase(),
+            // Events without a code of their own are named after where they come from
+            code: event.metadata().target().rsplit("::").next().unwrap_or_default().to_string(),
+            ..Default::default()
+        };
+        event.record(&mut diagnostic);
+        // A diagnostic always serializes, and there's nowhere left to report a failing stderr
+        let _ = writeln!(self.writer.make_writer(), "{}", serde_json::to_string(&diagnostic).unwrap_or_default());
+    }
+}
+
+/// Formats the fields of text logs as `tracing_subscriber` does, but for the client ids, amounts, and memos
+/// `redact` redacts.
+fn text_fields() -> impl for<'writer> FormatFields<'writer> + 'static {
+    debug_fn(|writer, field, value| {
+        let name = field.name();
+        if name == "message" {
+            return write!(writer, "{value:?}");
+        }
+        match redact::enabled().then(|| redact::field(name, &format!("{value:?}"))).flatten() {
+            Some(redacted) => write!(writer, "{name}={redacted}"),
+            None => write!(writer, "{name}={value:?}"),
+        }
+    })
+    .delimited(" ")
+}
+
+/// Keeps the logging set up by `init` alive, flushing anything buffered when dropped.
+pub struct Logging {
+    #[cfg(feature = "otel")]
+    _telemetry: Option<crate::telemetry::Telemetry>,
+}
+
+/// Write logs to stderr at `config.level` and above, or as `RUST_LOG` directs when no level is given, which
+/// allows per-module directives such as `paymentprocessor::tcp=debug`. Without either, the level is `info`.
+/// With the `otel` feature, spans are exported as well, as `telemetry::layer` describes.
+pub fn init(config: LogConfig) -> Result<Logging, KrakenError> {
+    let filter = || match config.level {
+        Some(level) => EnvFilter::default().add_directive(LevelFilter::from(level).into()),
+        None => EnvFilter::builder().with_default_directive(LevelFilter::INFO.into()).from_env_lossy(),
+    };
+    let (text, json) = match config.diagnostics {
+        Diagnostics::Text => {
+            let text = tracing_subscriber::fmt::layer()
+                .fmt_fields(text_fields())
+                .with_writer(std::io::stderr)
+                .with_ansi(std::io::stderr().is_terminal())
+                .with_filter(filter());
+            (Some(text), None)
+        }
+        Diagnostics::Json => (None, Some(JsonDiagnostics { writer: std::io::stderr }.with_filter(filter()))),
+    };
+
+    #[cfg(feature = "otel")]
+    let (spans, telemetry) = crate::telemetry::layer()?.unzip();
+    #[cfg(not(feature = "otel"))]
+    let spans: Option<tracing_subscriber::layer::Identity> = None;
+    tracing_subscriber::registry()
+        .with(spans)
+        .with(text)
+        .with(json)
+        .try_init()
+        .map_err(|e| KrakenError::Telemetry(e.to_string()))?;
+
+    Ok(Logging {
+        #[cfg(feature = "otel")]
+        _telemetry: telemetry,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::{JsonDiagnostics, LogLevel};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(LevelFilter::DEBUG, LevelFilter::from(LogLevel::try_from("debug").unwrap()));
+        assert!(LogLevel::try_from("verbose").is_err());
+    }
+
+    #[test]
+    fn test_json_diagnostics() {
+        struct Capture(Arc<Mutex<Vec<u8>>>);
+        impl Write for Capture {
+            fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(bytes)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let captured = output.clone();
+        let subscriber = tracing_subscriber::registry().with(JsonDiagnostics { writer: move || Capture(captured.clone()) });
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(code = "parse", line = 3, peer = "127.0.0.1:9000", "Skipping malformed line");
+            tracing::debug!(client = 1u32, tx = 7u32, "Refused transaction");
+        });
+
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        let expected = r#"{"level":"warn","code":"parse","line":3,"message":"Skipping malformed line","peer":"127.0.0.1:9000"}"#;
+        assert_eq!(expected, lines[0]);
+        // Without a code, the event is named after its module
+        assert_eq!(r#"{"level":"debug","code":"tests","tx":7,"client":1,"message":"Refused transaction"}"#, lines[1]);
+    }
+}
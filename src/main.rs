@@ -1,141 +1,925 @@
-mod errors;
-mod structures;
+mod cli;
 
-use crate::errors::KrakenError;
-use crate::errors::KrakenError::Error;
-use crate::structures::{ClientAccount, Transaction, TransactionType};
+use crate::cli::{Command, Options};
 use anyhow::Result;
-use itertools::multizip;
-use polars::prelude::*;
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::Mutex;
-use std::{env};
-use crossbeam_utils::thread;
-
-// I debated between this LazyFrame implementation and streaming with `csv-async`. This was far less
-// verbose and might actually tolerate very-large datasets.
-// Docs: https://docs.pola.rs/user-guide/io/csv/#read-write
-fn parse_csv(file_in: &str) -> Result<LazyFrame> {
-    let schema = Schema::from_iter(vec![
-        Field::new("type".into(), DataType::String),
-        Field::new("client".into(), DataType::UInt32), // Using U32 due to limitations on the CSV reader's functionality
-        Field::new("tx".into(), DataType::UInt32),
-        Field::new("amount".into(), DataType::Float64),
-    ]);
-    Ok(LazyCsvReader::new(PlPath::new(file_in))
-        .with_schema(Some(SchemaRef::from(schema)))
-        .with_has_header(false)
-        .with_skip_rows(1)
-        .finish()?) // Skipping rows in order to compensate for the lack of a `with_clean_column_names` method for lazy readers
-}
-
-fn compute_account_totals(path: &str) -> Result<Arc<Mutex<HashMap<u32, ClientAccount>>>> {
-    // Don't need to drop, since it's lazy and is memory-light
-    let lazy_data: LazyFrame = parse_csv(path)?;
-
-    // Partition by client to simplify downstream logic. Not required, and may not yield any performance improvement.
-    let parts = lazy_data.collect()?.partition_by(["client"], true)?;
-
-    // Wrap the HashMap in an multi-threaded ref counter and simple lock
-    let client_accounts: Arc<Mutex<HashMap<u32, ClientAccount>>> = Arc::new(Mutex::new(HashMap::new())); // Master collection of accounts
-
-    // Collect a list of thread handles to join and prevent dangling threads from dying as main is terminated
-    let mut handles = vec![];
-
-    for df in &*parts {
-        // Clone the ref counter
-        let accounts = client_accounts.clone();
-        let handle = thread::scope(|s| {
-
-            // Use individual synchronized iterators for each column. Iterating by row is a discouraged
-            // antipattern, as the docs/stackoverflow made abundantly clear.
-
-            let columns = df.columns(["type", "client", "tx", "amount"]).unwrap();
-
-            let type_col_iter = columns[0].str().unwrap().iter();
-            let client_col_iter = columns[1].u32().unwrap().iter(); // Using U32 due to limitations on the CSV reader's functionality
-            let tx_col_iter = columns[2].u32().unwrap().iter();
-            let amount_col_iter = columns[3].f64().unwrap().iter();
-
-            let full_row_iter =
-                multizip((type_col_iter, client_col_iter, tx_col_iter, amount_col_iter));
-
-            let transaction_objects: Vec<Transaction> = full_row_iter
-                .map(|(kind, client, tx, amount)| Transaction {
-                    kind: TransactionType::try_from(kind.expect("Type may not be null"))
-                        .expect(format!("Invalid transaction type: {:#?}", kind).as_str()),
-                    client: client.expect("client may not be null"),
-                    amount,
-                    tx: tx.expect(""),
-                    state: None,
-                })
-                .collect();
-
-            let client_id = transaction_objects[0].client;
-            let mut account: ClientAccount = Default::default();
-
-            for transaction in transaction_objects {
-                // Swallow results since we aren't tracking them
-                match account.apply_transaction(transaction) {
-                    Ok(_) => {}
-                    Err(_) => {}
+use paymentprocessor::async_engine::AsyncEngine;
+use paymentprocessor::aging::DisputeAging;
+use paymentprocessor::audit::{verify_audit_log, AuditWriter};
+use paymentprocessor::books::{self, check_journal, check_snapshot, compare};
+use paymentprocessor::events::EventWriter;
+use paymentprocessor::diff::{diff_balances, read_balances};
+use paymentprocessor::dormant::dormant_accounts;
+use paymentprocessor::encryption::{self, EncryptionKey};
+use paymentprocessor::engine::{EngineObserver, Outcome};
+use paymentprocessor::errors::{FailOn, KrakenError, EXIT_FAILURE, EXIT_IO};
+use paymentprocessor::follow::Follower;
+use paymentprocessor::generate::{write_csv, Generator};
+use paymentprocessor::golden::run_suite;
+use paymentprocessor::history::MemoryBudget;
+use paymentprocessor::dates::iso_date;
+use paymentprocessor::ids::Identifiers;
+use paymentprocessor::input::{InputSource, MultiSource, STDIN};
+use paymentprocessor::interrupt::{Interrupt, Interruptible};
+use paymentprocessor::invariants;
+use paymentprocessor::journal::{purge_journal, JournalFormat, JournalOptions, JournalWriter};
+use paymentprocessor::logging::{self, Diagnostics};
+use paymentprocessor::metrics::{push, Metrics, MetricsReport};
+use paymentprocessor::negative::{self, negative_accounts};
+use paymentprocessor::output::{
+    show_pending, write_accounts, write_named_accounts, write_table, write_tenant_accounts, AccountSummary, OutputFormat,
+};
+use paymentprocessor::processor::{apply_source, apply_source_observed, compute_combined_totals, diff_accounts, runtime, ParallelMode, ProcessorConfig};
+use paymentprocessor::progress::{estimate_rows, ProgressBar, ProgressSource};
+use paymentprocessor::statements::Statements;
+use paymentprocessor::settlement::SettlementWriter;
+use paymentprocessor::reconcile::reconcile;
+use paymentprocessor::redact;
+use paymentprocessor::rules;
+use paymentprocessor::provenance::Provenance;
+use paymentprocessor::ranking::{self, top_accounts};
+use paymentprocessor::reserve::{self, near_reserve};
+use paymentprocessor::signature::SigningKey;
+use paymentprocessor::snapshot::{replay_onto, ReplayConfig, Snapshot, ROWS_OFFSET};
+use paymentprocessor::stats::collect_stats;
+use paymentprocessor::structures::ClientAccount;
+use paymentprocessor::time_travel::{self, balances_as_of, write_checkpoints, ClientBalances};
+use paymentprocessor::validate::validate;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::TempDir;
+use tracing::{error, info};
+
+fn print_accounts(accounts: &HashMap<u32, ClientAccount>, format: OutputFormat) -> Result<(), KrakenError> {
+    let stdout = std::io::stdout();
+    // Tables are colorized for terminals, unless NO_COLOR is set
+    if format == OutputFormat::Table && stdout.is_terminal() && env::var_os("NO_COLOR").is_none() {
+        return write_table(stdout.lock(), accounts, true);
+    }
+    write_accounts(stdout.lock(), accounts, format)
+}
+
+/// Write the report to `output`, replacing any earlier one, or print it.
+fn write_report(
+    accounts: &HashMap<u32, ClientAccount>,
+    output: Option<&Path>,
+    format: OutputFormat,
+) -> Result<(), KrakenError> {
+    match output {
+        Some(path) => {
+            let file = File::create(path).map_err(|_| KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO)))?;
+            write_accounts(BufWriter::new(file), accounts, format)
+        }
+        None => print_accounts(accounts, format),
+    }
+}
+
+/// Write the report of a run reading `--ids string` to `output`, or print it, with the clients' textual ids.
+fn write_named_report(
+    accounts: &HashMap<u32, ClientAccount>,
+    output: Option<&Path>,
+    format: OutputFormat,
+    ids: &Identifiers,
+) -> Result<(), KrakenError> {
+    match output {
+        Some(path) => {
+            let file = File::create(path).map_err(|_| KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO)))?;
+            write_named_accounts(BufWriter::new(file), accounts, format, &ids.clients)
+        }
+        None => write_named_accounts(std::io::stdout().lock(), accounts, format, &ids.clients),
+    }
+}
+
+/// Write the report to `--output` or print it, then upsert it into `--database`.
+fn report(accounts: &HashMap<u32, ClientAccount>, options: &Options) -> Result<(), KrakenError> {
+    match &options.processor.input.ids {
+        Some(ids) => write_named_report(accounts, options.output.as_deref(), options.output_format, ids)?,
+        None => write_report(accounts, options.output.as_deref(), options.output_format)?,
+    }
+
+    #[cfg(feature = "database")]
+    if let Some(url) = &options.database {
+        paymentprocessor::database::upsert_accounts(url, &options.database_table, accounts)?;
+    }
+    Ok(())
+}
+
+/// Write one report for the books of every tenant to `output`, replacing any earlier one, or print it.
+fn write_tenant_report(
+    books: &BTreeMap<String, HashMap<u32, ClientAccount>>,
+    output: Option<&Path>,
+    format: OutputFormat,
+) -> Result<(), KrakenError> {
+    match output {
+        Some(path) => {
+            let file = File::create(path).map_err(|_| KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO)))?;
+            write_tenant_accounts(BufWriter::new(file), books, format)
+        }
+        None => write_tenant_accounts(std::io::stdout().lock(), books, format),
+    }
+}
+
+/// Sign the report written to `output` with `key`, if both are given, writing the signature next to it.
+fn sign_report(key: Option<&SigningKey>, output: Option<&Path>) -> Result<(), KrakenError> {
+    if let (Some(key), Some(output)) = (key, output) {
+        let signature = key.write_signature(output)?;
+        info!(signature = %signature.display(), "Signed the report");
+    }
+    Ok(())
+}
+
+/// Where the report of an interrupted run is written: next to `output`, with `.incomplete` appended to its name.
+fn incomplete(output: Option<&Path>) -> Option<PathBuf> {
+    output.map(|path| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".incomplete");
+        PathBuf::from(name)
+    })
+}
+
+/// Save `accounts` to `path` as a state snapshot, recording the `rows` a run got through if it was interrupted, and
+/// the inputs of `provenance`.
+fn save_snapshot(
+    accounts: &HashMap<u32, ClientAccount>,
+    path: &Path,
+    rows: Option<u64>,
+    provenance: &Provenance,
+) -> Result<(), KrakenError> {
+    let mut snapshot = Snapshot::capture(accounts)?;
+    snapshot.inputs = provenance.inputs()?;
+    if let Some(rows) = rows {
+        snapshot.offsets.insert(String::from(ROWS_OFFSET), rows as i64);
+    }
+    snapshot.save(path).map_err(|e| KrakenError::InFile(path.display().to_string(), Box::new(e)))
+}
+
+/// Print the end-of-run metrics to stderr for `--metrics`, write them to `--metrics-file` as JSON, and push
+/// their counters to `--metrics-push`.
+fn report_metrics(metrics: &Metrics, report: &MetricsReport, options: &Options) -> Result<()> {
+    if options.metrics {
+        eprint!("{report}");
+    }
+    if let Some(path) = &options.metrics_file {
+        let file = File::create(path).map_err(|_| KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO)))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), report)?;
+    }
+    if let Some(url) = &options.metrics_push {
+        push(url, metrics)?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<ExitCode> {
+    let started = Instant::now();
+    let args: Vec<String> = env::args().collect();
+    // Prints usage, or the help and version when asked for, and exits
+    let (command, log_config, rules, encryption_key) = Command::try_parse_from(&args).unwrap_or_else(|e| e.exit());
+    if log_config.redact {
+        redact::enable();
+    }
+    // Flushes anything still buffered, such as the last spans, when dropped
+    let _logging = logging::init(log_config)?;
+    rules::install(rules)?;
+    if let Some(key) = encryption_key {
+        encryption::install(key)?;
+    }
+
+    let Err(e) = run(command, started) else {
+        return Ok(ExitCode::SUCCESS);
+    };
+    let error = e.downcast_ref::<KrakenError>();
+    match log_config.diagnostics {
+        Diagnostics::Text => eprintln!("Error: {e:?}"),
+        // The error ending the run is a diagnostic like any other, rather than a line of text
+        Diagnostics::Json => {
+            let path = match error {
+                Some(KrakenError::InFile(path, _)) => Some(path.as_str()),
+                _ => None,
+            };
+            error!(code = error.map_or("error", KrakenError::code), path, "{e}");
+        }
+    }
+    let status = match error {
+        Some(error) => error.exit_code(),
+        None if e.downcast_ref::<std::io::Error>().is_some() => EXIT_IO,
+        None => EXIT_FAILURE,
+    };
+    Ok(ExitCode::from(status))
+}
+
+/// Carry out `command`, for a run that began at `started`.
+fn run(command: Command, started: Instant) -> Result<()> {
+    let options = match command {
+        Command::Process(options) => *options,
+        Command::Validate(options) => {
+            let validation = validate(&options.paths, &options.input, |problem| {
+                if options.json {
+                    // A problem always serializes
+                    println!("{}", serde_json::to_string(problem).unwrap_or_default());
+                } else {
+                    println!("{problem}");
                 }
+            })?;
+            info!(rows = validation.rows, problems = validation.problems, "Validated the input");
+            if validation.problems > 0 {
+                Err(KrakenError::Validation(validation.problems))?
             }
+            return Ok(());
+        }
+        Command::Stats(options) => {
+            let stats = collect_stats(&options.paths, &options.input)?;
+            if options.json {
+                println!("{}", serde_json::to_string(&stats)?);
+            } else {
+                print!("{stats}");
+            }
+            return Ok(());
+        }
+        #[cfg(feature = "queue")]
+        Command::Consume(options) => {
+            let budget = options.max_memory.map(MemoryBudget::new).transpose()?;
+            paymentprocessor::queue::consume(options.broker, &options.config, budget, |accounts| {
+                if let Err(e) = print_accounts(accounts, OutputFormat::Csv) {
+                    error!(code = e.code(), error = %e, "Failed to print the balances");
+                }
+                println!();
+            })?;
+            return Ok(());
+        }
+        #[cfg(feature = "server")]
+        Command::Serve(config) => {
+            paymentprocessor::server::serve(&config)?;
+            return Ok(());
+        }
+        Command::Replay(options) => {
+            let budget = options.max_memory.map(MemoryBudget::new).transpose()?;
+            let interrupt = Interrupt::install()?;
+            let provenance = Provenance::new(&options.paths);
+            let source = MultiSource::new(&options.paths, options.input.clone()).with_provenance(provenance.clone());
+            let mut source = Interruptible::new(source, interrupt.clone());
+            let config = ReplayConfig {
+                budget,
+                idempotent: options.idempotent,
+                tx_index: options.tx_index.clone(),
+                provenance: Some(provenance),
+                aliases: options.input.aliases.clone(),
+            };
+            let accounts = replay_onto(&mut source, &options.state, config)?;
+            if source.interrupted() {
+                write_report(&accounts, incomplete(options.output.as_deref()).as_deref(), options.output_format)?;
+                Err(KrakenError::Interrupted(source.rows(), interrupt.exit_code()))?
+            }
+            write_report(&accounts, options.output.as_deref(), options.output_format)?;
+            info!(state = %options.state.display(), accounts = accounts.len(), "Replayed the input onto the state");
+            return Ok(());
+        }
+        Command::Merge(options) => {
+            let names: Vec<String> = options.snapshots.iter().map(|path| path.display().to_string()).collect();
+            let mut snapshots = Vec::with_capacity(names.len());
+            for (name, path) in names.iter().zip(&options.snapshots) {
+                let snapshot = Snapshot::load(path).map_err(|e| KrakenError::InFile(name.clone(), Box::new(e)))?;
+                snapshots.push((name.as_str(), snapshot));
+            }
+            let merged = Snapshot::merge(snapshots).and_then(|merged| match &options.aliases {
+                Some(aliases) => merged.alias(aliases),
+                None => Ok(merged),
+            });
+            let merged = match merged {
+                Ok(merged) => merged,
+                Err(KrakenError::MergeConflicts(conflicts)) => {
+                    for conflict in &conflicts {
+                        error!(code = "merge_conflict", "{conflict}");
+                    }
+                    Err(KrakenError::MergeConflicts(conflicts))?
+                }
+                Err(e) => Err(e)?,
+            };
+            let output = options.output.display().to_string();
+            merged.save(&options.output).map_err(|e| KrakenError::InFile(output, Box::new(e)))?;
+            info!(snapshots = names.len(), accounts = merged.accounts.len(), "Merged the snapshots");
+            return Ok(());
+        }
+        Command::Diff(options) => {
+            let deltas = diff_balances(&read_balances(&options.before)?, &read_balances(&options.after)?);
+            if !options.json {
+                println!("client, change, available, held, total, locked");
+            }
+            for delta in &deltas {
+                if options.json {
+                    println!("{}", serde_json::to_string(delta)?);
+                } else {
+                    println!("{delta}");
+                }
+            }
+            info!(clients = deltas.len(), "Compared the balances");
+            return Ok(());
+        }
+        Command::Dormant(options) => {
+            let path = options.snapshot.display().to_string();
+            let snapshot = Snapshot::load(&options.snapshot).map_err(|e| KrakenError::InFile(path, Box::new(e)))?;
+            let dormant = dormant_accounts(&snapshot, options.as_of, options.days);
+            if !options.json {
+                println!("client, available, held, total, locked, last_activity, idle_days");
+            }
+            for account in &dormant {
+                if options.json {
+                    println!("{}", serde_json::to_string(account)?);
+                } else {
+                    println!("{account}");
+                }
+            }
+            info!(accounts = snapshot.accounts.len(), dormant = dormant.len(), "Listed the dormant accounts");
+            return Ok(());
+        }
+        Command::Test(options) => {
+            let failed = run_suite(&options.directory, &options.processor, |outcome| println!("{outcome}"))?;
+            info!(failed, "Ran the golden test cases");
+            if failed > 0 {
+                Err(KrakenError::GoldenFailures(failed))?
+            }
+            return Ok(());
+        }
+        Command::VerifyAudit(path) => {
+            let head = verify_audit_log(&path)?;
+            println!("{} record(s), last hash {}", head.records, head.hash);
+            return Ok(());
+        }
+        Command::Decrypt(options) => {
+            let in_file = |e| KrakenError::InFile(options.path.display().to_string(), Box::new(e));
+            let key = encryption::installed()
+                .ok_or_else(|| KrakenError::InvalidArgument(String::from("decrypt requires --encryption-key")))?;
+            let bytes = std::fs::read(&options.path).map_err(|_| in_file(KrakenError::IO))?;
+            let decrypted = key.decrypt(&bytes).map_err(in_file)?;
+            match &options.output {
+                Some(path) => encryption::write_file(path, &decrypted, None)
+                    .map_err(|e| KrakenError::InFile(path.display().to_string(), Box::new(e)))?,
+                None => std::io::stdout().lock().write_all(&decrypted)?,
+            }
+            return Ok(());
+        }
+        Command::PurgeClient(options) => {
+            let client = options.client;
+            for path in &options.snapshots {
+                let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+                let mut snapshot = Snapshot::load(path).map_err(in_file)?;
+                match snapshot.purge(client) {
+                    Some(tombstone) => {
+                        snapshot.save(path).map_err(in_file)?;
+                        println!("{}: purged {} transaction(s)", path.display(), tombstone.transactions);
+                    }
+                    None => println!("{}: no such client", path.display()),
+                }
+            }
+            for path in &options.journals {
+                let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+                let key = encryption::installed();
+                let journal = String::from_utf8(encryption::read_file(path, key).map_err(in_file)?)
+                    .map_err(|e| in_file(KrakenError::Parse(e.to_string())))?;
+                match purge_journal(&journal, client) {
+                    (_, 0) => println!("{}: no such client", path.display()),
+                    (purged, removed) => {
+                        encryption::write_file(path, purged.as_bytes(), key).map_err(in_file)?;
+                        println!("{}: purged {removed} entries and notes", path.display());
+                    }
+                }
+            }
+            info!(snapshots = options.snapshots.len(), journals = options.journals.len(), "Purged the client");
+            return Ok(());
+        }
+        Command::AsOf(options) => {
+            let in_file = |path: &Path| {
+                let path = path.display().to_string();
+                move |e| KrakenError::InFile(path, Box::new(e))
+            };
+            let open = |path: &Path| File::open(path).map_err(|_| in_file(path)(KrakenError::IO));
+            let checkpoints = match &options.checkpoints {
+                Some(path) if !path.exists() => {
+                    // Written aside and renamed into place, so a failed pass doesn't leave checkpoints of part of
+                    // the events behind
+                    let mut temporary = path.as_os_str().to_owned();
+                    temporary.push(".tmp");
+                    let temporary = PathBuf::from(temporary);
+                    let file = File::create(&temporary).map_err(|_| in_file(&temporary)(KrakenError::IO))?;
+                    let events = BufReader::new(open(&options.events)?);
+                    let written = write_checkpoints(events, BufWriter::new(file), options.checkpoint_every)
+                        .map_err(in_file(&options.events))
+                        .and_then(|written| {
+                            std::fs::rename(&temporary, path).map_err(|_| in_file(path)(KrakenError::IO))?;
+                            Ok(written)
+                        })
+                        .inspect_err(|_| {
+                            let _ = std::fs::remove_file(&temporary);
+                        })?;
+                    info!(checkpoints = written, path = %path.display(), "Wrote the checkpoints");
+                    Some(BufReader::new(open(path)?))
+                }
+                Some(path) => Some(BufReader::new(open(path)?)),
+                None => None,
+            };
+            let balances = balances_as_of(open(&options.events)?, checkpoints, options.point)
+                .map_err(in_file(&options.events))?;
+            if !options.json {
+                println!("{}", time_travel::HEADER);
+            }
+            let clients = balances.into_iter().filter(|(client, _)| options.client.is_none_or(|only| only == *client));
+            for (client, balances) in clients {
+                let balances = ClientBalances { client, balances };
+                if options.json {
+                    println!("{}", serde_json::to_string(&balances)?);
+                } else {
+                    println!("{balances}");
+                }
+            }
+            return Ok(());
+        }
+        Command::CheckBooks(options) => {
+            let snapshot = match &options.snapshot {
+                Some(path) => Some(Snapshot::load(path).map_err(|e| KrakenError::InFile(path.display().to_string(), Box::new(e)))?),
+                None => None,
+            };
+            let mut violations = snapshot.as_ref().map(check_snapshot).unwrap_or_default();
+            if let Some(path) = &options.journal {
+                let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+                let journal = String::from_utf8(encryption::read_file(path, encryption::installed()).map_err(in_file)?)
+                    .map_err(|e| in_file(KrakenError::Parse(e.to_string())))?;
+                let (totals, found) = check_journal(&journal);
+                violations.extend(found);
+                if let Some(snapshot) = &snapshot {
+                    violations.extend(compare(snapshot, &totals));
+                }
+            }
+            if !options.json {
+                println!("{}", books::HEADER);
+            }
+            for violation in &violations {
+                if options.json {
+                    println!("{}", serde_json::to_string(violation)?);
+                } else {
+                    println!("{violation}");
+                }
+            }
+            info!(violations = violations.len(), "Checked the books");
+            if !violations.is_empty() {
+                Err(KrakenError::BookViolations(violations.len()))?
+            }
+            return Ok(());
+        }
+        Command::VerifyReport(options) => {
+            SigningKey::load(&options.signing_key)?.verify(&options.report, &options.signature)?;
+            println!("{} matches its signature", options.report.display());
+            return Ok(());
+        }
+        Command::Generate(options) => {
+            let generator = Generator::new(options.config);
+            let rows = match &options.output {
+                Some(path) => write_csv(generator, BufWriter::new(File::create(path)?))?,
+                None => write_csv(generator, BufWriter::new(std::io::stdout().lock()))?,
+            };
+            info!(rows, "Generated transactions");
+            return Ok(());
+        }
+};
 
-            let mut accounts_lock = accounts.lock().unwrap();
-            accounts_lock.insert(client_id, account);
-        });
+    if options.check_invariants {
+        invariants::enable();
+    }
+    if options.show_pending {
+        show_pending();
+    }
+    for path in &options.paths {
+        #[cfg(feature = "remote")]
+        if paymentprocessor::remote::is_url(path) {
+            continue;
+        }
+        if path != STDIN && !Path::new(path).exists() {
+            Err(KrakenError::InFile(path.clone(), Box::new(KrakenError::IO)))?
+        }
+    }
+
+    if options.follow {
+        let budget = options.processor.max_memory.map(MemoryBudget::new).transpose()?;
+        let mut follower = Follower::open(&options.paths[0], budget)?.with_delimiter(options.processor.input.delimiter);
+        info!(path = %options.paths[0], "Following the input");
+        follower.run(options.flush_interval, |accounts| {
+            if let Err(e) = report(accounts, &options) {
+                error!(code = e.code(), error = %e, "Failed to report the balances");
+            }
+            if options.output.is_none() {
+                println!();
+            }
+        })?;
+        return Ok(());
+    }
 
-        handles.push(handle);
+    // The tokio pipeline doesn't read through an `InputSource`, so it's left to be killed
+    let interrupt = match options.asynchronous {
+        true => Interrupt::default(),
+        false => Interrupt::install()?,
+    };
+    // Read up front, so a missing key doesn't wait for the input to be processed
+    let signing_key = options.signing_key.as_deref().map(SigningKey::load).transpose()?;
+    if !options.tenants.is_empty() {
+        return run_tenants(&options, &interrupt, signing_key.as_ref(), started);
     }
 
-    let account_lock = client_accounts.lock().unwrap();
-    println!("client, available, held, total, locked");
-    for key in account_lock.keys() {
-        if let Some(account) = account_lock.get(key) {
-            println!("{}", account.to_str_row(*key))
+    // Read up front, so a missing or malformed file doesn't wait for the input to be processed
+    let expected = options.reconcile.as_deref().map(read_balances).transpose()?;
+    let provenance = Provenance::new(&options.paths);
+    // Opened up front, so a tampered audit log or an unwritable sink doesn't wait for the input to be processed
+    let sinks = Sinks::open(&options)?;
+    let (accounts, interrupted) = apply(&options, &interrupt, &provenance, sinks.clone())?;
+    if let Some(rows) = interrupted {
+        // Every row read was applied. The partial report is kept apart from a complete one, and from the sinks a
+        // complete one would update, and the snapshot records how far the run got.
+        if let Some(path) = &options.snapshot {
+            save_snapshot(&accounts, path, Some(rows), &provenance)?;
         }
+        let output = incomplete(options.output.as_deref());
+        match &options.processor.input.ids {
+            Some(ids) => write_named_report(&accounts, output.as_deref(), options.output_format, ids)?,
+            None => write_report(&accounts, output.as_deref(), options.output_format)?,
+        }
+        Err(KrakenError::Interrupted(rows, interrupt.exit_code()))?
     }
 
-    Ok(client_accounts.clone())
+    let output_started = Instant::now();
+    let output_span = tracing::info_span!("output").entered();
+    write_sinks(sinks, &accounts, &options, &provenance)?;
+    report(&accounts, &options)?;
+    sign_report(signing_key.as_ref(), options.output.as_deref())?;
+    if let Some((n, by)) = options.top_n {
+        eprintln!("{}", ranking::HEADER);
+        for account in top_accounts(&accounts, n, by)? {
+            eprintln!("{account}");
+        }
+    }
+    if let Some(margin) = options.near_reserve {
+        eprintln!("{}", reserve::HEADER);
+        for account in near_reserve(&accounts, rules::current(), margin) {
+            eprintln!("{account}");
+        }
+    }
+    if options.negative_balances {
+        eprintln!("{}", negative::HEADER);
+        for account in negative_accounts(&accounts) {
+            eprintln!("{account}");
+        }
+    }
+    output_span.exit();
+    info!(inputs = options.paths.len(), accounts = accounts.len(), elapsed = ?started.elapsed(), "Processed the input");
+
+    if let Some(metrics) = &options.processor.metrics {
+        report_metrics(metrics, &metrics.report(started.elapsed(), output_started.elapsed()), &options)?;
+    }
+    if let Some(expected) = &expected {
+        let discrepancies = log_discrepancies(expected, &accounts)?;
+        if discrepancies > 0 {
+            Err(KrakenError::Unbalanced(discrepancies))?
+        }
+        info!(clients = expected.len(), "The balances match the expected ones");
+    }
+    fail_on(locked(&accounts), &options)
 }
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+/// Process the input of every `--tenant` as a separate book, one tenant after the other. Each book has accounts of
+/// its own, so the same client may be found in several, and its own sinks, with `{tenant}` in their paths
+/// replaced by its name. The report is written per tenant when the path of `--output` holds `{tenant}` as well,
+/// and as a single report with a `tenant` column otherwise.
+fn run_tenants(
+    options: &Options,
+    interrupt: &Interrupt,
+    signing_key: Option<&SigningKey>,
+    started: Instant,
+) -> Result<()> {
+    let tenants: Vec<(&str, Options)> =
+        options.tenants.iter().map(|(tenant, paths)| (tenant.as_str(), options.for_tenant(tenant, paths))).collect();
+    // Read up front, so a missing or malformed file doesn't wait for the input to be processed
+    let expected = tenants
+        .iter()
+        .map(|(_, options)| options.reconcile.as_deref().map(read_balances).transpose())
+        .collect::<Result<Vec<_>, _>>()?;
+    let per_tenant = options.report_per_tenant();
 
-    if args.len() < 2 {
-        println!("Invalid arguments: Must supply path to data csv");
-        Err(Error)?
+    let mut books = BTreeMap::new();
+    let (mut output_elapsed, mut discrepancies) = (Duration::ZERO, 0);
+    for ((tenant, options), expected) in tenants.iter().zip(&expected) {
+        let _span = tracing::info_span!("tenant", tenant).entered();
+        let provenance = Provenance::new(&options.paths);
+        let sinks = Sinks::open(options)?;
+        let (accounts, interrupted) = apply(options, interrupt, &provenance, sinks.clone())?;
+        if let Some(rows) = interrupted {
+            // As for a single book, with the books processed so far in a combined report
+            if let Some(path) = &options.snapshot {
+                save_snapshot(&accounts, path, Some(rows), &provenance)?;
+            }
+            let output = incomplete(options.output.as_deref());
+            books.insert(tenant.to_string(), accounts);
+            match per_tenant {
+                true => write_report(&books[*tenant], output.as_deref(), options.output_format)?,
+                false => write_tenant_report(&books, output.as_deref(), options.output_format)?,
+            }
+            return Err(KrakenError::Interrupted(rows, interrupt.exit_code()).into());
+        }
+
+        let output_started = Instant::now();
+        let output_span = tracing::info_span!("output").entered();
+        write_sinks(sinks, &accounts, options, &provenance)?;
+        if per_tenant {
+            write_report(&accounts, options.output.as_deref(), options.output_format)?;
+            sign_report(signing_key, options.output.as_deref())?;
+        }
+        output_span.exit();
+        output_elapsed += output_started.elapsed();
+        info!(inputs = options.paths.len(), accounts = accounts.len(), "Processed the tenant's input");
+        if let Some(expected) = expected {
+            discrepancies += log_discrepancies(expected, &accounts)?;
+        }
+        books.insert(tenant.to_string(), accounts);
+    }
+    if !per_tenant {
+        let output_started = Instant::now();
+        write_tenant_report(&books, options.output.as_deref(), options.output_format)?;
+        sign_report(signing_key, options.output.as_deref())?;
+        output_elapsed += output_started.elapsed();
     }
+    let accounts: usize = books.values().map(HashMap::len).sum();
+    info!(tenants = books.len(), inputs = options.paths.len(), accounts, elapsed = ?started.elapsed(), "Processed the input");
 
-    let path = Path::new(&args[1]);
-    if !path.exists() {
-        Err(KrakenError::IO)?
+    if let Some(metrics) = &options.processor.metrics {
+        report_metrics(metrics, &metrics.report(started.elapsed(), output_elapsed), options)?;
+    }
+    if discrepancies > 0 {
+        Err(KrakenError::Unbalanced(discrepancies))?
     }
+    fail_on(books.values().map(locked).sum(), options)
+}
 
-    compute_account_totals(path.to_str().unwrap()).expect("");
-    Ok(())
+/// Apply the input of `options` to empty accounts, checking them against a serial run for `--verify`. Returns the
+/// accounts, along with the rows applied if `interrupt` ended the run early. The rows read from each input are counted
+/// in `provenance`, except by the tokio pipeline. With `sinks`, the input is applied serially, recording what became
+/// of every transaction in them.
+fn apply(
+    options: &Options,
+    interrupt: &Interrupt,
+    provenance: &Provenance,
+    sinks: Option<Arc<Mutex<Sinks>>>,
+) -> Result<(HashMap<u32, ClientAccount>, Option<u64>)> {
+    let budget = options.processor.max_memory.map(MemoryBudget::new).transpose()?;
+    let accounts = if options.asynchronous && sinks.is_none() {
+        let engine = AsyncEngine::default()
+            .with_budget(budget)
+            .with_delimiter(options.processor.input.delimiter);
+        runtime(options.processor.threads)?.block_on(engine.process_files(&options.paths))?
+    } else {
+        let source = MultiSource::new(&options.paths, options.processor.input.clone()).with_provenance(provenance.clone());
+        let source: Box<dyn InputSource> = match !options.no_progress && std::io::stderr().is_terminal() {
+            true => Box::new(ProgressSource::new(source, ProgressBar::new(estimate_rows(&options.paths, &options.processor.input)))),
+            false => Box::new(source),
+        };
+        let mut source = Interruptible::new(source, interrupt.clone());
+        let accounts = match sinks {
+            Some(sinks) => apply_source_observed(&mut source, &options.processor, budget.as_ref(), sinks)?,
+            None => apply_source(&mut source, &options.processor, budget.as_ref())?,
+        };
+        if source.interrupted() {
+            return Ok((accounts, Some(source.rows())));
+        }
+        accounts
+    };
+
+    if options.verify {
+        // The reference run isn't part of the metrics
+        let serial = ProcessorConfig {
+            parallel: ParallelMode::Serial,
+            metrics: None,
+            ..options.processor.clone()
+        };
+        let serial = compute_combined_totals(&options.paths, &serial)?;
+        let mismatches = diff_accounts(&serial, &accounts);
+        for mismatch in &mismatches {
+            error!(code = "verification", %mismatch, "Differs from the serial run");
+        }
+        if !mismatches.is_empty() {
+            Err(KrakenError::Verification(mismatches.len()))?
+        }
+    }
+    Ok((accounts, None))
+}
+
+/// The sinks written from what became of every transaction, as the main pass applies it: statements, the journal, the
+/// audit log, change events, dispute aging, and settlement. Each is staged beside where it belongs until `finish`, so
+/// a run that fails or is interrupted leaves them as they were.
+#[derive(Default)]
+struct Sinks {
+    statements: Option<Statements>,
+    journal: Option<JournalWriter<Staged>>,
+    audit_log: Option<AuditWriter>,
+    events: Option<EventWriter<Staged>>,
+    dispute_aging: Option<(DisputeAging, PathBuf)>,
+    settlement: Option<SettlementWriter<Staged>>,
+    /// The first error a sink met, after which nothing more is recorded, until `finish` reports it.
+    error: Option<KrakenError>,
+}
+
+impl Sinks {
+    /// The sinks `options` ask for, if any. An existing audit log is checked here, before the input is processed.
+    fn open(options: &Options) -> Result<Option<Arc<Mutex<Self>>>, KrakenError> {
+        let journal = |path: &Path| {
+            let journal_options = JournalOptions {
+                format: options.journal_format.unwrap_or_else(|| JournalFormat::detect(path)),
+                date: options.journal_date.clone().unwrap_or_else(|| iso_date(SystemTime::now())),
+                commodity: options.journal_commodity.clone(),
+            };
+            Staged::create(path, encryption::installed()).map(|staged| JournalWriter::new(staged, journal_options))
+        };
+        let settlement = |path: &Path| SettlementWriter::new(Staged::create(path, None)?, options.settle_every);
+        let sinks = Self {
+            statements: options.statements.as_deref().map(Statements::create).transpose()?,
+            journal: options.journal.as_deref().map(journal).transpose()?,
+            audit_log: options.audit_log.as_deref().map(AuditWriter::open).transpose()?,
+            events: options.events.as_deref().map(|path| Staged::create(path, None).map(EventWriter::new)).transpose()?,
+            dispute_aging: options.dispute_aging.clone().map(|path| (DisputeAging::default(), path)),
+            settlement: options.settlement.as_deref().map(settlement).transpose()?,
+            error: None,
+        };
+        let any = sinks.statements.is_some()
+            || sinks.journal.is_some()
+            || sinks.audit_log.is_some()
+            || sinks.events.is_some()
+            || sinks.dispute_aging.is_some()
+            || sinks.settlement.is_some();
+        Ok(any.then(|| Arc::new(Mutex::new(sinks))))
+    }
+
+    fn record(&mut self, outcome: &Outcome) -> Result<(), KrakenError> {
+        if let Some(statements) = &mut self.statements {
+            statements.record(outcome)?;
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.record(outcome)?;
+        }
+        if let Some(log) = &mut self.audit_log {
+            log.record(outcome)?;
+        }
+        if let Some(events) = &mut self.events {
+            events.record(outcome)?;
+        }
+        if let Some((aging, _)) = &mut self.dispute_aging {
+            aging.record(outcome);
+        }
+        if let Some(settlement) = &mut self.settlement {
+            settlement.record(outcome)?;
+        }
+        Ok(())
+    }
+
+    /// Write out every sink and put it in place, the audit log followed by the inputs of `provenance`.
+    fn finish(self, provenance: &Provenance) -> Result<(), KrakenError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if let Some(statements) = self.statements {
+            statements.finish()?;
+        }
+        if let Some(journal) = self.journal {
+            journal.finish()?.1.commit()?;
+        }
+        if let Some(log) = self.audit_log {
+            let head = log.finish(Some(provenance))?;
+            info!(records = head.records, hash = %head.hash, "Appended to the audit log");
+        }
+        if let Some(events) = self.events {
+            let (events, staged) = events.finish()?;
+            staged.commit()?;
+            info!(events, "Wrote the change events");
+        }
+        if let Some((aging, path)) = self.dispute_aging {
+            let mut staged = Staged::create(&path, None)?;
+            let disputes = aging.finish(&mut staged)?;
+            staged.commit()?;
+            info!(disputes = disputes.len(), "Wrote the open disputes");
+        }
+        if let Some(settlement) = self.settlement {
+            let (cycles, staged) = settlement.finish()?;
+            staged.commit()?;
+            info!(cycles, "Wrote the settlement cycles");
+        }
+        Ok(())
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::compute_account_totals;
+impl EngineObserver for Sinks {
+    fn on_outcome(&mut self, outcome: &Outcome) {
+        if self.error.is_none()
+            && let Err(e) = self.record(outcome)
+        {
+            self.error = Some(e);
+        }
+    }
+}
+
+/// A file written into a temporary directory beside `path`, which only takes its place once committed. Encrypted files
+/// are held in memory instead, as they're encrypted whole, the tag authenticating everything before it.
+enum Staged {
+    File(PathBuf, TempDir, BufWriter<File>),
+    Encrypted(PathBuf, &'static EncryptionKey, Vec<u8>),
+}
 
-    const TEST_DIR: &str = "./test/";
-    const TEST_CASES: [(&str, &str); 6] = [
-        ("0-trivial.csv", "1, 1.5000, 0.0000, 1.5000, false"),
-        ("1-dispute-after-withdraw.csv", "1, -9.5000, 10.0000, 0.5000, false"),
-        ("2-chargeback-after-withdraw.csv", "1, -9.5000, 0.0000, -9.5000, true"),
-        ("3-resolve-without-dispute.csv", "1, 11.0000, 0.0000, 11.0000, false"),
-        ("4-oversized-withdrawal.csv", "1, 100.0000, 0.0000, 100.0000, false"),
-        ("5-very-parallel.csv", "1, 10.0000, 0.0000, 10.0000, false")
-    ];
-    #[test]
-    fn test_csv() {
-        for (file_name, expected) in TEST_CASES {
-            let totals = compute_account_totals((String::from(TEST_DIR) + file_name).as_str()).unwrap();
-            assert_eq!(String::from(expected), totals.lock().unwrap().get(&1).expect("").to_str_row(1))
+impl Staged {
+    fn create(path: &Path, key: Option<&'static EncryptionKey>) -> Result<Self, KrakenError> {
+        if let Some(key) = key {
+            return Ok(Staged::Encrypted(path.to_path_buf(), key, Vec::new()));
         }
+        let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let staging = tempfile::tempdir_in(directory).map_err(|_| in_file(path, KrakenError::IO))?;
+        let file = File::create(staging.path().join("staged")).map_err(|_| in_file(path, KrakenError::IO))?;
+        Ok(Staged::File(path.to_path_buf(), staging, BufWriter::new(file)))
     }
+
+    fn commit(self) -> Result<(), KrakenError> {
+        match self {
+            Staged::File(path, staging, writer) => {
+                let file = writer.into_inner().map_err(|_| in_file(&path, KrakenError::IO))?;
+                file.sync_all().map_err(|_| in_file(&path, KrakenError::IO))?;
+                std::fs::rename(staging.path().join("staged"), &path).map_err(|_| in_file(&path, KrakenError::IO))?;
+            }
+            Staged::Encrypted(path, key, bytes) => {
+                encryption::write_file(&path, &bytes, Some(key)).map_err(|e| in_file(&path, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for Staged {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Staged::File(_, _, writer) => writer.write(buf),
+            Staged::Encrypted(_, _, bytes) => bytes.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Staged::File(_, _, writer) => writer.flush(),
+            Staged::Encrypted(..) => Ok(()),
+        }
+    }
+}
+
+fn in_file(path: &Path, error: KrakenError) -> KrakenError {
+    KrakenError::InFile(path.display().to_string(), Box::new(error))
+}
+
+/// Finish the `sinks` of the run, if any, and save the snapshot of `accounts`, with the inputs of `provenance`.
+fn write_sinks(
+    sinks: Option<Arc<Mutex<Sinks>>>,
+    accounts: &HashMap<u32, ClientAccount>,
+    options: &Options,
+    provenance: &Provenance,
+) -> Result<()> {
+    if let Some(sinks) = sinks {
+        std::mem::take(&mut *sinks.lock().unwrap()).finish(provenance)?;
+    }
+    if let Some(path) = &options.snapshot {
+        save_snapshot(accounts, path, None, provenance)?;
+    }
+    Ok(())
+}
+
+/// Log every client of `accounts` whose balances differ from the `expected` ones, returning how many there are.
+fn log_discrepancies(expected: &BTreeMap<u32, AccountSummary>, accounts: &HashMap<u32, ClientAccount>) -> Result<usize> {
+    let discrepancies = reconcile(expected, accounts)?;
+    for discrepancy in &discrepancies {
+        error!(code = "reconciliation", client = discrepancy.client, "{discrepancy}");
+    }
+    Ok(discrepancies.len())
+}
+
+/// How many of `accounts` are locked.
+fn locked(accounts: &HashMap<u32, ClientAccount>) -> usize {
+    accounts.values().filter(|account| account.locked).count()
+}
+
+/// Fail a run that has written its report, with `locked` accounts, if it meets any of the `--fail-on` conditions
+/// left to check.
+fn fail_on(locked: usize, options: &Options) -> Result<()> {
+    let metrics = options.processor.metrics.as_ref();
+    let failed = metrics.map_or(0, |metrics| metrics.failed_assertions());
+    if options.fail_on.contains(&FailOn::Assertion) && failed > 0 {
+        Err(KrakenError::FailedAssertions(failed))?
+    }
+    let rejected = metrics.map_or(0, |metrics| metrics.rejected());
+    if options.fail_on.contains(&FailOn::RejectedTx) && rejected > 0 {
+        Err(KrakenError::RejectedTransactions(rejected))?
+    }
+    if options.fail_on.contains(&FailOn::LockedAccount) && locked > 0 {
+        Err(KrakenError::LockedAccounts(locked))?
+    }
+    Ok(())
 }
@@ -0,0 +1,299 @@
+use crate::dates::parse_timestamp;
+use crate::errors::KrakenError;
+use crate::errors::KrakenError::Parse;
+use crate::ids::Identifiers;
+use crate::input::{csv_error, next_chunk, InputSource, RowSource, DEFAULT_BATCH_ROWS};
+use crate::structures::{Transaction, TransactionType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+
+/// Fields of a transaction, in the order they are usually laid out.
+const FIELDS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Where a field is found in the rows of a mapped CSV file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Column {
+    /// The column under this header.
+    Name(String),
+    /// The column at this position, counting from 1.
+    Position(usize),
+}
+
+impl TryFrom<&str> for Column {
+    type Error = KrakenError;
+
+    /// Read a column as a position if it's a number, or as a header otherwise.
+    fn try_from(value: &str) -> Result<Self, KrakenError> {
+        match value.parse::<usize>() {
+            Ok(0) => Err(KrakenError::Enum(format!("Invalid String for Column: {value}"))),
+            Ok(position) => Ok(Column::Position(position)),
+            Err(_) => Ok(Column::Name(value.to_string())),
+        }
+    }
+}
+
+/// Where the fields of a transaction are found in each row.
+#[derive(Debug, Clone, Copy)]
+struct Indices {
+    /// Indices of `FIELDS`.
+    fields: [usize; 4],
+    memo: Option<usize>,
+    idempotency_key: Option<usize>,
+    timestamp: Option<usize>,
+}
+
+/// How the columns and type spellings of a CSV file map onto `type, client, tx, amount`, so bank exports and
+/// other files laid out their own way can be read as they are. Fields left unmapped are read from the column of
+/// their usual name, or from their usual position in files without headers.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchemaMapping {
+    #[serde(rename = "type")]
+    pub kind: Option<Column>,
+    pub client: Option<Column>,
+    pub tx: Option<Column>,
+    pub amount: Option<Column>,
+    /// Column of the optional memo, `memo` by default in files with headers.
+    pub memo: Option<Column>,
+    /// Column of the optional idempotency key, `idempotency_key` by default in files with headers.
+    pub idempotency_key: Option<Column>,
+    /// Column of the optional time of each transaction, `timestamp` by default in files with headers.
+    pub timestamp: Option<Column>,
+    /// Whether the first row holds the headers. Without them, columns can only be mapped by position.
+    pub headers: bool,
+    /// Spellings of the transaction types, such as `DEP = "deposit"`. The usual names are still understood.
+    pub types: HashMap<String, TransactionType>,
+}
+
+impl Default for SchemaMapping {
+    fn default() -> Self {
+        Self {
+            kind: None,
+            client: None,
+            tx: None,
+            amount: None,
+            memo: None,
+            idempotency_key: None,
+            timestamp: None,
+            headers: true,
+            types: HashMap::new(),
+        }
+    }
+}
+
+impl SchemaMapping {
+    /// Refuse mappings that can't match any file: positions of 0, and headers in files without any.
+    pub fn check(&self) -> Result<(), KrakenError> {
+        let memo = self.memo.iter().map(|column| (&"memo", column.clone()));
+        let idempotency_key = self.idempotency_key.iter().map(|column| (&"idempotency_key", column.clone()));
+        let timestamp = self.timestamp.iter().map(|column| (&"timestamp", column.clone()));
+        for (field, column) in FIELDS.iter().zip(self.columns()).chain(memo).chain(idempotency_key).chain(timestamp) {
+            match column {
+                Column::Position(0) => return Err(Parse(format!("Column of {field}: positions count from 1"))),
+                Column::Name(name) if !self.headers => {
+                    return Err(Parse(format!("Column of {field}: `{name}` can't be found without headers")));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Where each of `FIELDS` is read from.
+    fn columns(&self) -> [Column; 4] {
+        let mapped = [&self.kind, &self.client, &self.tx, &self.amount];
+        std::array::from_fn(|index| match (mapped[index], self.headers) {
+            (Some(column), _) => column.clone(),
+            (None, true) => Column::Name(FIELDS[index].to_string()),
+            (None, false) => Column::Position(index + 1),
+        })
+    }
+
+    /// Indices of `FIELDS`, the memo, the idempotency key, and the timestamp in the rows under `headers`, if the file
+    /// has any. Without a mapped memo column, only a file with a `memo` header has memos, and likewise for the others.
+    fn resolve(&self, headers: Option<&csv::StringRecord>) -> Result<Indices, KrakenError> {
+        let index = |column: Column| match (column, headers) {
+            (Column::Position(position), _) => Ok(position.saturating_sub(1)),
+            (Column::Name(name), Some(headers)) => {
+                headers.iter().position(|header| header == name).ok_or_else(|| Parse(format!("Missing column: {name}")))
+            }
+            (Column::Name(name), None) => Err(Parse(format!("Missing column: {name}"))),
+        };
+        let mut fields = [0; 4];
+        for (field, column) in fields.iter_mut().zip(self.columns()) {
+            *field = index(column)?;
+        }
+        let optional = |column: &Option<Column>, name: &str| match (column, headers) {
+            (Some(column), _) => index(column.clone()).map(Some),
+            (None, Some(headers)) => Ok(headers.iter().position(|header| header == name)),
+            (None, None) => Ok(None),
+        };
+        let memo = optional(&self.memo, "memo")?;
+        let idempotency_key = optional(&self.idempotency_key, "idempotency_key")?;
+        let timestamp = optional(&self.timestamp, "timestamp")?;
+        Ok(Indices { fields, memo, idempotency_key, timestamp })
+    }
+
+    /// Decode `record`, whose fields are at `indices`, interning its client and tx into `ids` if given.
+    fn transaction(
+        &self,
+        record: &csv::StringRecord,
+        indices: &Indices,
+        ids: Option<&Identifiers>,
+    ) -> Result<Transaction, KrakenError> {
+        let field = |index: usize| record.get(indices.fields[index]).unwrap_or_default();
+        let invalid = |name: &str| Parse(format!("Invalid {name} in row: {}", record.iter().collect::<Vec<_>>().join(",")));
+
+        let kind = match self.types.get(field(0)) {
+            Some(kind) => kind.clone(),
+            None => TransactionType::try_from(field(0))
+                .map_err(|_| KrakenError::Enum(format!("Invalid String for TransactionType: {}", field(0))))?,
+        };
+        let amount = match field(3) {
+            "" => None,
+            amount => Some(amount.parse::<f64>().map_err(|_| invalid("amount"))?),
+        };
+        let (client, tx) = match ids {
+            Some(_) if field(1).is_empty() => return Err(invalid("client")),
+            Some(_) if field(2).is_empty() => return Err(invalid("tx")),
+            Some(ids) => (ids.clients.intern(field(1))?, ids.txs.intern(field(2))?),
+            None => (field(1).parse().map_err(|_| invalid("client"))?, field(2).parse().map_err(|_| invalid("tx"))?),
+        };
+        let optional = |index: Option<usize>| {
+            index.and_then(|index| record.get(index)).filter(|field| !field.is_empty()).map(String::from)
+        };
+        let (memo, idempotency_key) = (optional(indices.memo), optional(indices.idempotency_key));
+        let timestamp = match indices.timestamp.and_then(|index| record.get(index)) {
+            None | Some("") => None,
+            Some(timestamp) => Some(parse_timestamp(timestamp)?),
+        };
+        Ok(Transaction { kind, client, amount, tx, memo, idempotency_key, timestamp, state: None })
+    }
+}
+
+/// CSV reader laid out by a `SchemaMapping`. Built on the `csv` crate, whichever reader is asked for.
+pub struct MappedSource<R: Read> {
+    reader: csv::Reader<R>,
+    mapping: Arc<SchemaMapping>,
+    /// Where textual client and tx ids are interned. Ids are numbers when `None`.
+    ids: Option<Arc<Identifiers>>,
+    /// Indices of the fields, once the headers are read. An error finding them ends the input.
+    indices: Option<Result<Indices, ()>>,
+    record: csv::StringRecord,
+}
+
+impl<R: Read> MappedSource<R> {
+    pub fn from_reader(reader: R, delimiter: u8, mapping: Arc<SchemaMapping>) -> Self {
+        Self {
+            reader: csv::ReaderBuilder::new()
+                .delimiter(delimiter)
+                .has_headers(mapping.headers)
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .from_reader(reader),
+            mapping,
+            ids: None,
+            indices: None,
+            record: csv::StringRecord::new(),
+        }
+    }
+
+    /// Read the client and tx ids as text, interned into `ids`.
+    pub fn with_ids(mut self, ids: Option<Arc<Identifiers>>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    fn indices(&mut self) -> Option<Result<Indices, KrakenError>> {
+        if let Some(indices) = self.indices {
+            return indices.ok().map(Ok);
+        }
+        let indices = match self.mapping.headers {
+            true => self.reader.headers().map_err(csv_error).and_then(|headers| self.mapping.resolve(Some(headers))),
+            false => self.mapping.resolve(None),
+        };
+        self.indices = Some(indices.as_ref().map(|indices| *indices).map_err(|_| ()));
+        Some(indices)
+    }
+}
+
+impl<R: Read> RowSource for MappedSource<R> {
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>> {
+        let indices = match self.indices()? {
+            Ok(indices) => indices,
+            Err(e) => return Some(Err(e)),
+        };
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => Some(self.mapping.transaction(&self.record, &indices, self.ids.as_deref())),
+            Ok(false) => None,
+            Err(e) => Some(Err(csv_error(e))),
+        }
+    }
+}
+
+impl<R: Read> InputSource for MappedSource<R> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        next_chunk(&mut std::iter::from_fn(|| self.next_row()), DEFAULT_BATCH_ROWS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ids::Identifiers;
+    use crate::input::rows;
+    use crate::mapping::{Column, MappedSource, SchemaMapping};
+    use crate::structures::TransactionType;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_mapped_source() {
+        let export = "Date;Ref;Kind;Customer;Value\n2024-01-02;7;DEP;3;10.5\n2024-01-03;8;WD;3;2\n2024-01-04;7;dispute;3;\n";
+        let mapping = SchemaMapping {
+            kind: Some(Column::Name(String::from("Kind"))),
+            client: Some(Column::Name(String::from("Customer"))),
+            tx: Some(Column::Position(2)),
+            amount: Some(Column::Name(String::from("Value"))),
+            types: HashMap::from([
+                (String::from("DEP"), TransactionType::Deposit),
+                (String::from("WD"), TransactionType::Withdrawal),
+            ]),
+            ..Default::default()
+        };
+        let mut source = MappedSource::from_reader(export.as_bytes(), b';', Arc::new(mapping.clone()));
+        let transactions: Vec<_> = rows(&mut source).map(Result::unwrap).collect();
+        let fields: Vec<_> = transactions.iter().map(|t| (t.kind.clone(), t.client, t.tx, t.amount)).collect();
+        assert_eq!(
+            vec![
+                (TransactionType::Deposit, 3, 7, Some(10.5)),
+                (TransactionType::Withdrawal, 3, 8, Some(2.0)),
+                (TransactionType::Dispute, 3, 7, None),
+            ],
+            fields
+        );
+
+        // A missing column ends the input
+        let mut source = MappedSource::from_reader("Kind;Client\n".as_bytes(), b';', Arc::new(mapping.clone()));
+        let errors: Vec<_> = rows(&mut source).collect();
+        assert_eq!(1, errors.len());
+        assert_eq!("Parse Error: Missing column: Customer", errors[0].as_ref().unwrap_err().to_string());
+        assert!(SchemaMapping { headers: false, ..mapping }.check().is_err());
+
+        // Textual ids are interned in the order they're first seen
+        let ids = Arc::new(Identifiers::default());
+        let export = "type,client,tx,amount\ndeposit,alice,tx-a,1\ndeposit,bob,tx-b,2\nwithdrawal,alice,tx-c,1\n";
+        let mut source = MappedSource::from_reader(export.as_bytes(), b',', Arc::default()).with_ids(Some(ids.clone()));
+        let interned: Vec<_> = rows(&mut source).map(Result::unwrap).map(|t| (t.client, t.tx)).collect();
+        assert_eq!(vec![(0, 0), (1, 1), (0, 2)], interned);
+        assert_eq!(Some(String::from("bob")), ids.clients.name(1));
+
+        // A file with an `idempotency_key` header has keys, those left empty excepted
+        let export = "type,client,tx,amount,idempotency_key\ndeposit,1,1,1,msg-1\ndeposit,1,2,1,\n";
+        let mut source = MappedSource::from_reader(export.as_bytes(), b',', Arc::default());
+        let keys: Vec<_> = rows(&mut source).map(|t| t.unwrap().idempotency_key).collect();
+        assert_eq!(vec![Some(String::from("msg-1")), None], keys);
+    }
+}
@@ -0,0 +1,364 @@
+use crate::errors::KrakenError;
+use crate::input::InputSource;
+use crate::rules;
+use crate::structures::{Transaction, TransactionType};
+use serde::Serialize;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Transaction types, in code order, as labelled in the Prometheus exposition. Types registered with
+/// `handlers::register` are counted together as `custom`.
+pub const TRANSACTION_TYPES: [&str; 10] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "assert_balance",
+    "authorize",
+    "capture",
+    "chargeback_reversal",
+    "custom",
+];
+/// Reasons a transaction is refused for, as labelled in the Prometheus exposition.
+pub const REJECTION_REASONS: [&str; 8] = [
+    "insufficient_funds",
+    "account_locked",
+    "no_such_transaction",
+    "dispute_state",
+    "missing_amount",
+    "balance_assertion",
+    "below_reserve",
+    "other",
+];
+/// Upper bounds of the apply latency histogram's buckets, in nanoseconds.
+pub const LATENCY_BUCKETS: [u64; 10] = [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+fn type_index(kind: &TransactionType) -> usize {
+    match kind {
+        TransactionType::Custom(_) => TRANSACTION_TYPES.len() - 1,
+        kind => kind.code() as usize,
+    }
+}
+
+fn reason(e: &KrakenError) -> usize {
+    match e {
+        KrakenError::InsufficientFunds(_) => 0,
+        KrakenError::AccountLocked(_) => 1,
+        KrakenError::NoSuchTransactionError(_) => 2,
+        KrakenError::DisputeStateError(_) => 3,
+        KrakenError::MissingAmount(_) => 4,
+        KrakenError::BalanceAssertion(..) => 5,
+        KrakenError::BelowReserve(..) => 6,
+        _ => 7,
+    }
+}
+
+/// Outcomes of the transactions one thread applied, kept without any synchronization and added to `Metrics`
+/// in bulk.
+#[derive(Debug, Default)]
+pub struct Tally {
+    applied: [u64; TRANSACTION_TYPES.len()],
+    rejected: [u64; REJECTION_REASONS.len()],
+    /// Transactions per latency bucket, the last counting those slower than every bound.
+    latency: [u64; LATENCY_BUCKETS.len() + 1],
+    latency_sum: u64,
+}
+
+impl Tally {
+    /// Count a transaction of `kind` that took `elapsed` to apply, with its `result`.
+    pub fn record(&mut self, kind: TransactionType, result: &Result<(), KrakenError>, elapsed: Duration) {
+        match result {
+            Ok(()) => self.applied[type_index(&kind)] += 1,
+            Err(e) => self.rejected[reason(e)] += 1,
+        }
+        let nanos = elapsed.as_nanos() as u64;
+        self.latency[LATENCY_BUCKETS.partition_point(|&bound| bound < nanos)] += 1;
+        self.latency_sum += nanos;
+    }
+}
+
+/// Counters and stage timers of a processing run, shared by every thread taking part in it.
+/// Durations are kept in nanoseconds. Threads add their share in bulk, once per chunk or once at the end,
+/// so the counters aren't contended row by row.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    rows: AtomicU64,
+    rejected: AtomicU64,
+    applied_by_type: [AtomicU64; TRANSACTION_TYPES.len()],
+    rejected_by_reason: [AtomicU64; REJECTION_REASONS.len()],
+    latency: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum: AtomicU64,
+    parse: AtomicU64,
+    partition: AtomicU64,
+    apply: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add_tally(&self, tally: &Tally) {
+        let add = |counters: &[AtomicU64], counts: &[u64]| {
+            for (counter, count) in counters.iter().zip(counts) {
+                counter.fetch_add(*count, Ordering::Relaxed);
+            }
+        };
+        add(&self.applied_by_type, &tally.applied);
+        add(&self.rejected_by_reason, &tally.rejected);
+        add(&self.latency, &tally.latency);
+        self.latency_sum.fetch_add(tally.latency_sum, Ordering::Relaxed);
+        self.rejected.fetch_add(tally.rejected.iter().sum(), Ordering::Relaxed);
+    }
+
+    pub fn add_partition(&self, elapsed: Duration) {
+        self.partition.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_apply(&self, elapsed: Duration) {
+        self.apply.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Transactions refused so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// `assert_balance` rows refused so far, for not matching the available funds.
+    pub fn failed_assertions(&self) -> u64 {
+        self.rejected_by_reason[5].load(Ordering::Relaxed)
+    }
+
+    /// Time spent reading and decoding input so far.
+    pub fn parse(&self) -> Duration {
+        Duration::from_nanos(self.parse.load(Ordering::Relaxed))
+    }
+
+    /// Summarize the run, given its total wall time and the time spent writing its results.
+    pub fn report(&self, wall: Duration, output: Duration) -> MetricsReport {
+        let seconds = |nanos: &AtomicU64| Duration::from_nanos(nanos.load(Ordering::Relaxed)).as_secs_f64();
+        let rows = self.rows.load(Ordering::Relaxed);
+        MetricsReport {
+            rows,
+            rejected: self.rejected.load(Ordering::Relaxed),
+            rows_per_second: if wall.is_zero() { 0.0 } else { rows as f64 / wall.as_secs_f64() },
+            wall_seconds: wall.as_secs_f64(),
+            parse_seconds: seconds(&self.parse),
+            partition_seconds: seconds(&self.partition),
+            apply_seconds: seconds(&self.apply),
+            output_seconds: output.as_secs_f64(),
+            peak_memory_bytes: peak_memory(),
+        }
+    }
+
+    /// Count rows read outside of a `Metered` source, such as those posted to the server.
+    pub fn add_rows(&self, rows: u64) {
+        self.rows.fetch_add(rows, Ordering::Relaxed);
+    }
+
+    /// Render the per-transaction counters in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let labelled = |label: &str, names: &[&str], counters: &[AtomicU64]| -> Vec<(String, String)> {
+            let sample = |(name, counter)| (format!("{{{label}=\"{name}\"}}"), load(counter).to_string());
+            names.iter().zip(counters).map(sample).collect()
+        };
+        // Every successful chargeback locks its account, unless the rules say otherwise, and a locked account refuses
+        // every later transaction
+        let locked = match rules::current().chargeback_locks {
+            true => load(&self.applied_by_type[type_index(&TransactionType::Chargeback)]),
+            false => 0,
+        };
+
+        let mut text = String::new();
+        family(&mut text, "rows_total", "counter", "Rows read.", vec![(String::new(), load(&self.rows).to_string())]);
+        let applied = labelled("type", &TRANSACTION_TYPES, &self.applied_by_type);
+        family(&mut text, "transactions_applied_total", "counter", "Transactions applied, by type.", applied);
+        let rejected = labelled("reason", &REJECTION_REASONS, &self.rejected_by_reason);
+        family(&mut text, "transactions_rejected_total", "counter", "Transactions refused, by reason.", rejected);
+        let locked = vec![(String::new(), locked.to_string())];
+        family(&mut text, "accounts_locked_total", "counter", "Accounts locked by a chargeback.", locked);
+
+        let mut count = 0;
+        let mut latency = Vec::new();
+        for (index, bucket) in self.latency.iter().enumerate() {
+            count += load(bucket);
+            let bound = LATENCY_BUCKETS.get(index).map_or(String::from("+Inf"), |&nanos| (nanos as f64 / 1e9).to_string());
+            latency.push((format!("_bucket{{le=\"{bound}\"}}"), count.to_string()));
+        }
+        latency.push((String::from("_sum"), (load(&self.latency_sum) as f64 / 1e9).to_string()));
+        latency.push((String::from("_count"), count.to_string()));
+        family(&mut text, "apply_latency_seconds", "histogram", "Time taken to apply one transaction.", latency);
+        text
+    }
+}
+
+/// Append a metric family to a Prometheus exposition: its help and type, then each sample, given as the suffix
+/// and labels following the family's name, and the value.
+fn family(text: &mut String, name: &str, kind: &str, help: &str, samples: Vec<(String, String)>) {
+    text.push_str(&format!("# HELP paymentprocessor_{name} {help}\n# TYPE paymentprocessor_{name} {kind}\n"));
+    for (suffix, value) in samples {
+        text.push_str(&format!("paymentprocessor_{name}{suffix} {value}\n"));
+    }
+}
+
+/// Push `metrics` to a Prometheus Pushgateway, replacing those of its group, with a PUT to `url`, such as
+/// `http://localhost:9091/metrics/job/paymentprocessor`. Only plain `http://` URLs are supported.
+pub fn push(url: &str, metrics: &Metrics) -> Result<(), KrakenError> {
+    let failed = |e: std::io::Error| KrakenError::MetricsPush(format!("{url}: {e}"));
+    let rest = url.strip_prefix("http://").ok_or_else(|| KrakenError::InvalidArgument(format!("Not an http:// URL: {url}")))?;
+    let (host, path) = rest.split_once('/').map_or((rest, String::from("/")), |(host, path)| (host, format!("/{path}")));
+    let address = match host.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+        _ => format!("{host}:80"),
+    };
+
+    let body = metrics.prometheus();
+    let mut stream = TcpStream::connect(&address).map_err(failed)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10))).map_err(failed)?;
+    let request = format!(
+        "PUT {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).map_err(failed)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(failed)?;
+
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(KrakenError::MetricsPush(format!("{url}: {status}"))),
+    }
+}
+
+/// End-of-run metrics, as printed by `--metrics` and written by `--metrics-file`.
+#[derive(Debug, Serialize)]
+pub struct MetricsReport {
+    /// Rows decoded from the input.
+    pub rows: u64,
+    /// Rows the engine refused, such as withdrawals exceeding the available funds.
+    pub rejected: u64,
+    pub rows_per_second: f64,
+    pub wall_seconds: f64,
+    /// Time the reader spent decoding input.
+    pub parse_seconds: f64,
+    /// Time spent splitting chunks between workers. Zero for the modes that don't partition.
+    pub partition_seconds: f64,
+    /// Time spent applying rows, summed across workers, so it can exceed the wall time.
+    pub apply_seconds: f64,
+    /// Time spent writing the report, statements, and journal.
+    pub output_seconds: f64,
+    /// Peak resident memory of the process, where the platform reports it.
+    pub peak_memory_bytes: Option<u64>,
+}
+
+impl fmt::Display for MetricsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rows:       {} ({} rejected)", self.rows, self.rejected)?;
+        writeln!(f, "throughput: {:.0} rows/s", self.rows_per_second)?;
+        writeln!(f, "wall:       {:.3}s", self.wall_seconds)?;
+        writeln!(f, "  parse:     {:.3}s", self.parse_seconds)?;
+        writeln!(f, "  partition: {:.3}s", self.partition_seconds)?;
+        writeln!(f, "  apply:     {:.3}s (summed across workers)", self.apply_seconds)?;
+        writeln!(f, "  output:    {:.3}s", self.output_seconds)?;
+        match self.peak_memory_bytes {
+            Some(bytes) => writeln!(f, "peak memory: {:.1} MiB", bytes as f64 / (1 << 20) as f64),
+            None => writeln!(f, "peak memory: unavailable"),
+        }
+    }
+}
+
+/// Peak resident set size of this process, read from `/proc/self/status`, or `None` off Linux.
+pub fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.trim_start_matches("VmHWM:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Source wrapper counting the rows read and timing the reads into `metrics`, if there are any.
+pub struct Metered<'a, S: InputSource> {
+    source: &'a mut S,
+    metrics: Option<&'a Metrics>,
+}
+
+impl<'a, S: InputSource> Metered<'a, S> {
+    pub fn new(source: &'a mut S, metrics: Option<&'a Metrics>) -> Self {
+        Self { source, metrics }
+    }
+}
+
+impl<S: InputSource> InputSource for Metered<'_, S> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        let _span = tracing::info_span!("parse").entered();
+        let Some(metrics) = self.metrics else {
+            return self.source.next_batch();
+        };
+
+        let start = Instant::now();
+        let batch = self.source.next_batch();
+        metrics.parse.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        if let Some(Ok(batch)) = &batch {
+            metrics.rows.fetch_add(batch.len() as u64, Ordering::Relaxed);
+        }
+        batch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{push, Metrics};
+    use crate::processor::tests::TEST_DIR;
+    use crate::processor::{compute_account_totals, ParallelMode, ProcessorConfig};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_metrics() {
+        // 4-oversized-withdrawal.csv has one withdrawal exceeding the funds
+        for mode in [ParallelMode::Serial, ParallelMode::Threads, ParallelMode::Rayon, ParallelMode::Actors] {
+            let metrics = Arc::new(Metrics::default());
+            let config = ProcessorConfig { parallel: mode, metrics: Some(metrics.clone()), ..Default::default() };
+            compute_account_totals(&(String::from(TEST_DIR) + "4-oversized-withdrawal.csv"), &config).unwrap();
+
+            let report = metrics.report(Duration::from_secs(1), Duration::ZERO);
+            assert_eq!((2, 1), (report.rows, report.rejected), "{mode:?}");
+            assert_eq!(2.0, report.rows_per_second);
+
+            let text = metrics.prometheus();
+            assert!(text.contains("paymentprocessor_transactions_applied_total{type=\"deposit\"} 1\n"), "{text}");
+            let rejected = "paymentprocessor_transactions_rejected_total{reason=\"insufficient_funds\"} 1\n";
+            assert!(text.contains(rejected), "{text}");
+            assert!(text.contains("paymentprocessor_apply_latency_seconds_bucket{le=\"+Inf\"} 2\n"), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_push() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/metrics/job/test", listener.local_addr().unwrap());
+        let gateway = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            let head = String::from_utf8(request).unwrap();
+            let length = head.lines().find_map(|line| line.strip_prefix("Content-Length: ")).unwrap();
+            let mut body = vec![0; length.parse().unwrap()];
+            stream.read_exact(&mut body).unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+        push(&url, &Metrics::default()).unwrap();
+        let (head, body) = gateway.join().unwrap();
+        assert!(head.starts_with("PUT /metrics/job/test HTTP/1.1\r\n"), "{head}");
+        assert!(body.contains("# TYPE paymentprocessor_apply_latency_seconds histogram\n"), "{body}");
+        assert!(push("https://localhost/metrics/job/test", &Metrics::default()).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::errors::KrakenError;
+use std::fmt;
+
+/// Minor units in a unit: amounts are kept to four places.
+pub const SCALE: i128 = 10_000;
+
+/// Most minor units a balance may hold either way. Up to this, 2^50, a balance kept as the `f64` nearest to its
+/// four places converts to minor units and back exactly, and such `f64`s compare as their minor units do.
+pub const LIMIT: i128 = 1 << 50;
+
+/// How the engine adds up and compares amounts: as `f64`, the default, or as `MinorUnits`, checked. Under `Minor`,
+/// amounts are rounded to four places as they're applied, and balances are kept as the `f64` nearest to theirs.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AmountBackend {
+    #[default]
+    Float,
+    Minor,
+}
+
+impl AmountBackend {
+    /// `amount` as kept: rounded to four places under `Minor`, failing beyond `LIMIT`.
+    pub fn round(self, amount: f64) -> Result<f64, KrakenError> {
+        match self {
+            AmountBackend::Float => Ok(amount),
+            AmountBackend::Minor => MinorUnits::from_f64(amount).map(MinorUnits::to_f64),
+        }
+    }
+
+    /// `a + b`, added up in minor units under `Minor`, failing for a sum beyond `LIMIT`.
+    pub fn add(self, a: f64, b: f64) -> Result<f64, KrakenError> {
+        match self {
+            AmountBackend::Float => Ok(a + b),
+            AmountBackend::Minor => {
+                let sum = MinorUnits::from_f64(a)?.checked_add(MinorUnits::from_f64(b)?)?;
+                if !sum.in_range() {
+                    return Err(KrakenError::AmountOverflow(format!("{sum} is beyond the amounts kept to four places")));
+                }
+                Ok(sum.to_f64())
+            }
+        }
+    }
+
+    /// `a - b`, as `add`.
+    pub fn sub(self, a: f64, b: f64) -> Result<f64, KrakenError> {
+        self.add(a, -b)
+    }
+
+    /// The sum of `amounts`, added up in minor units under `Minor`, failing for an amount or a sum beyond `LIMIT`.
+    pub fn sum(self, amounts: impl IntoIterator<Item = f64>) -> Result<f64, KrakenError> {
+        match self {
+            AmountBackend::Float => Ok(amounts.into_iter().fold(0.0, |sum, amount| sum + amount)),
+            AmountBackend::Minor => {
+                let sum = amounts
+                    .into_iter()
+                    .try_fold(MinorUnits(0), |sum, amount| sum.checked_add(MinorUnits::from_f64(amount)?))?;
+                if !sum.in_range() {
+                    return Err(KrakenError::AmountOverflow(format!("{sum} is beyond the amounts kept to four places")));
+                }
+                Ok(sum.to_f64())
+            }
+        }
+    }
+}
+
+impl TryFrom<&str> for AmountBackend {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "float" => Ok(AmountBackend::Float),
+            "minor" => Ok(AmountBackend::Minor),
+            _ => Err(KrakenError::Enum(format!("Invalid String for AmountBackend: {value}"))),
+        }
+    }
+}
+
+/// An amount as a whole number of ten-thousandths, whose arithmetic fails rather than overflow or round.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MinorUnits(pub i128);
+
+impl MinorUnits {
+    /// `amount`, rounded to four places, failing for amounts that aren't finite or are beyond `LIMIT`.
+    pub fn from_f64(amount: f64) -> Result<Self, KrakenError> {
+        let units = (amount * SCALE as f64).round();
+        if !units.is_finite() || units.abs() > LIMIT as f64 {
+            return Err(KrakenError::AmountOverflow(format!("{amount} is beyond the amounts kept to four places")));
+        }
+        Ok(Self(units as i128))
+    }
+
+    /// The nearest `f64`, which is the amount to four places for amounts within `LIMIT`.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / SCALE as f64
+    }
+
+    pub fn checked_add(self, other: Self) -> Result<Self, KrakenError> {
+        self.0.checked_add(other.0).map(Self).ok_or_else(|| KrakenError::AmountOverflow(format!("{self} + {other}")))
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self, KrakenError> {
+        self.0.checked_sub(other.0).map(Self).ok_or_else(|| KrakenError::AmountOverflow(format!("{self} - {other}")))
+    }
+
+    /// Whether the amount is within `LIMIT` either way.
+    pub fn in_range(self) -> bool {
+        self.0.abs() <= LIMIT
+    }
+}
+
+impl TryFrom<&str> for MinorUnits {
+    type Error = KrakenError;
+
+    /// Parse a decimal amount, such as `-12.5`, exactly, failing for more than four places that aren't zeros, or
+    /// for amounts too large to count in minor units.
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let invalid = || KrakenError::Parse(format!("Invalid amount: {value}"));
+        let trimmed = value.trim();
+        let (negative, digits) = match trimmed.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let all_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if (whole.is_empty() && fraction.is_empty()) || !all_digits(whole) || !all_digits(fraction) {
+            return Err(invalid());
+        }
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > 4 {
+            return Err(KrakenError::AmountOverflow(format!("{value} has more than four places")));
+        }
+        let overflow = || KrakenError::AmountOverflow(format!("{value} is too large to count in minor units"));
+        let whole: i128 = match whole {
+            "" => 0,
+            whole => whole.parse().map_err(|_| overflow())?,
+        };
+        let fraction: i128 = format!("{fraction:0<4}").parse().map_err(|_| invalid())?;
+        let units = whole.checked_mul(SCALE).and_then(|units| units.checked_add(fraction)).ok_or_else(overflow)?;
+        Ok(Self(if negative { -units } else { units }))
+    }
+}
+
+impl fmt::Display for MinorUnits {
+    /// The amount to four places, such as `-12.5000`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = self.0.unsigned_abs();
+        write!(f, "{sign}{}.{:04}", units / SCALE as u128, units % SCALE as u128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::errors::KrakenError;
+    use crate::minor_units::{AmountBackend, MinorUnits, LIMIT};
+    use crate::rules::Rules;
+    use crate::structures::Transaction;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_minor_units() {
+        let parse = |value: &str| MinorUnits::try_from(value).map(|amount| amount.to_string()).map_err(|e| e.to_string());
+        assert_eq!(Ok(String::from("-12.5000")), parse(" -12.5 "));
+        assert_eq!(Ok(String::from("0.0001")), parse(".00010"));
+        assert!(parse("1.00001").unwrap_err().contains("more than four places"));
+        assert!(parse("1e3").is_err() && parse("-").is_err());
+        assert!(parse(&"9".repeat(40)).unwrap_err().contains("too large to count"));
+        let max = MinorUnits(i128::MAX);
+        assert!(max.checked_add(MinorUnits(1)).is_err() && max.checked_sub(MinorUnits(1)).is_ok());
+        assert_eq!(MinorUnits(1_000), MinorUnits::from_f64(0.1).unwrap());
+        assert!(MinorUnits::from_f64(f64::NAN).is_err() && MinorUnits::from_f64(1e12).is_err());
+        let most = MinorUnits(LIMIT - 1).to_f64();
+        assert_eq!(MinorUnits(LIMIT - 1), MinorUnits::from_f64(most).unwrap());
+        assert_eq!(0.3, AmountBackend::Minor.add(0.1, 0.2).unwrap());
+        assert_ne!(0.3, AmountBackend::Float.add(0.1, 0.2).unwrap());
+
+        // Ten deposits of 0.1 add up to exactly 1 in minor units, so a withdrawal of 1 isn't refused by rounding
+        let deposited = |amounts, deposit_delay_rows| {
+            let rules = Rules { amounts, deposit_delay_rows, ..Default::default() };
+            let mut engine = Engine::new().with_rules(Some(Arc::new(rules)));
+            for tx in 0..10 {
+                engine.apply(Transaction::try_from(format!("deposit, 1, {tx}, 0.1").as_str()).unwrap()).unwrap();
+            }
+            engine
+        };
+        let withdrawal = || Transaction::try_from("withdrawal, 1, 100, 1.0").unwrap();
+        let error = deposited(AmountBackend::Float, None).apply(withdrawal()).unwrap_err();
+        assert!(matches!(error, KrakenError::InsufficientFunds(1)));
+        let mut engine = deposited(AmountBackend::Minor, None);
+        engine.apply(withdrawal()).unwrap();
+        assert_eq!(0.0, engine.account(1).unwrap().available);
+        // As do pending deposits, and the total with them
+        let account = deposited(AmountBackend::Minor, Some(20)).account(1).unwrap().clone();
+        assert_eq!((1.0, 1.0), (account.pending().unwrap(), account.total().unwrap()));
+
+        // Balances beyond the limit refuse the transaction, leaving the account as it was
+        let huge = MinorUnits(LIMIT * 3 / 4).to_f64();
+        engine.apply(Transaction::try_from(format!("deposit, 2, 1, {huge}").as_str()).unwrap()).unwrap();
+        let deposit = Transaction::try_from(format!("deposit, 2, 2, {huge}").as_str()).unwrap();
+        let error = engine.apply(deposit).unwrap_err();
+        assert!(matches!(error, KrakenError::AmountOverflow(_)), "{error}");
+        assert_eq!(huge, engine.account(2).unwrap().available);
+        // As do pending deposits, whose funds are never added up as floats instead
+        let mut engine = deposited(AmountBackend::Minor, Some(20));
+        engine.apply(Transaction::try_from(format!("deposit, 2, 1, {huge}").as_str()).unwrap()).unwrap();
+        let deposit = Transaction::try_from(format!("deposit, 2, 2, {huge}").as_str()).unwrap();
+        assert!(matches!(engine.apply(deposit), Err(KrakenError::AmountOverflow(_))));
+        assert_eq!(huge, engine.account(2).unwrap().pending().unwrap());
+        assert!(matches!(AmountBackend::Minor.sum([huge, huge]), Err(KrakenError::AmountOverflow(_))));
+        assert!(matches!(AmountBackend::Minor.sum([f64::MAX, -f64::MAX]), Err(KrakenError::AmountOverflow(_))));
+    }
+}
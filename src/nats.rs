@@ -0,0 +1,70 @@
+use crate::errors::KrakenError;
+use crate::errors::KrakenError::Broker;
+use crate::queue::{AsyncTransactionSource, QueueMessage};
+use async_nats::jetstream::consumer::pull::{Config, Stream};
+use async_nats::jetstream::consumer::AckPolicy;
+use async_nats::jetstream::Message;
+use futures_util::StreamExt;
+
+/// Durable pull consumer on a NATS JetStream stream.
+/// The consumer acknowledges with `AckPolicy::All`, so acking the latest message acknowledges every earlier
+/// one. Messages are positioned by their stream sequence number.
+pub struct NatsSource {
+    messages: Stream,
+    /// Latest message not yet acknowledged.
+    last: Option<Message>,
+}
+
+impl NatsSource {
+    /// Connect to `servers` and read `stream` through the durable consumer `consumer`, creating it if needed.
+    pub async fn connect(servers: &[String], stream: &str, consumer: &str) -> Result<Self, KrakenError> {
+        let client = async_nats::connect(servers).await.map_err(|e| Broker(e.to_string()))?;
+        let messages = async_nats::jetstream::new(client)
+            .get_stream(stream)
+            .await
+            .map_err(|e| Broker(e.to_string()))?
+            .get_or_create_consumer(
+                consumer,
+                Config {
+                    durable_name: Some(consumer.to_string()),
+                    ack_policy: AckPolicy::All,
+                    // Messages stay unacknowledged until the next checkpoint, so don't cap how many are in flight
+                    max_ack_pending: -1,
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| Broker(e.to_string()))?
+            .messages()
+            .await
+            .map_err(|e| Broker(e.to_string()))?;
+
+        Ok(Self { messages, last: None })
+    }
+}
+
+impl AsyncTransactionSource for NatsSource {
+    async fn recv(&mut self) -> Option<Result<QueueMessage, KrakenError>> {
+        let message = match self.messages.next().await? {
+            Ok(message) => message,
+            Err(e) => return Some(Err(Broker(e.to_string()))),
+        };
+        let position = match message.info() {
+            Ok(info) => (info.stream.to_string(), info.stream_sequence as i64),
+            Err(e) => return Some(Err(Broker(e.to_string()))),
+        };
+        let payload = message.payload.to_vec();
+        self.last = Some(message);
+        Some(Ok(QueueMessage {
+            payload,
+            position: Some(position),
+        }))
+    }
+
+    async fn ack(&mut self) -> Result<(), KrakenError> {
+        if let Some(message) = self.last.take() {
+            message.ack().await.map_err(|e| Broker(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use crate::structures::ClientAccount;
+use std::collections::HashMap;
+use std::fmt;
+
+pub const HEADER: &str = "client, times, lowest, available, locked";
+
+/// An account whose available funds went negative during the run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NegativeAccount {
+    pub client: u32,
+    /// Times the available funds turned negative.
+    pub times: u32,
+    /// Lowest the available funds went.
+    pub lowest: f64,
+    /// Available funds at the end of the run.
+    pub available: f64,
+    pub locked: bool,
+}
+
+impl fmt::Display for NegativeAccount {
+    /// A `client, times, lowest, available, locked` row.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}, {:.4}, {:.4}, {}", self.client, self.times, self.lowest, self.available, self.locked)
+    }
+}
+
+/// Every account of `accounts` whose available funds went negative since it was created or restored, whether or
+/// not they recovered, furthest below zero first, ties going to the lower client id.
+pub fn negative_accounts(accounts: &HashMap<u32, ClientAccount>) -> Vec<NegativeAccount> {
+    let mut negative: Vec<NegativeAccount> = accounts
+        .iter()
+        .filter(|(_, account)| account.negative_events > 0)
+        .map(|(client, account)| NegativeAccount {
+            client: *client,
+            times: account.negative_events,
+            lowest: account.lowest_available,
+            available: account.available,
+            locked: account.locked,
+        })
+        .collect();
+    negative.sort_unstable_by(|a, b| a.lowest.total_cmp(&b.lowest).then(a.client.cmp(&b.client)));
+    negative
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::negative::negative_accounts;
+    use crate::structures::Transaction;
+
+    #[test]
+    fn test_negative_accounts() {
+        let rows = [
+            "deposit, 1, 1, 10.0",
+            "withdrawal, 1, 2, 9.0",
+            "dispute, 1, 1,",
+            "resolve, 1, 1,",
+            "deposit, 1, 7, 10.0",
+            "withdrawal, 1, 8, 11.0",
+            "dispute, 1, 7,",
+            "chargeback, 1, 7,",
+            "deposit, 2, 3, 4.0",
+            "withdrawal, 2, 4, 3.0",
+            "dispute, 2, 3,",
+            "deposit, 2, 5, 5.0",
+            // Never negative
+            "deposit, 3, 6, 3.0",
+            "dispute, 3, 6,",
+        ];
+        let mut engine = Engine::new();
+        engine.process(rows.iter().map(|row| Transaction::try_from(*row).unwrap()));
+
+        let negative: Vec<String> = negative_accounts(engine.accounts()).iter().map(|account| account.to_string()).collect();
+        assert_eq!(vec!["1, 2, -10.0000, -10.0000, true", "2, 1, -3.0000, 2.0000, false"], negative);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::errors::KrakenError;
+use crate::errors::KrakenError::Parse;
+use crate::input::{next_chunk, reference_id, InputSource, RowSource, DEFAULT_BATCH_ROWS};
+use crate::structures::{Transaction, TransactionType};
+use std::io::BufRead;
+
+/// Client of QIF transactions that aren't preceded by an `!Account` block naming their account.
+pub const DEFAULT_QIF_CLIENT: u32 = 0;
+
+/// Turn a signed statement amount into a deposit or withdrawal.
+fn signed_transaction(client: u32, tx: u32, amount: f64) -> Transaction {
+    Transaction {
+        kind: if amount < 0.0 { TransactionType::Withdrawal } else { TransactionType::Deposit },
+        client,
+        amount: Some(amount.abs()),
+        tx,
+        memo: None,
+        idempotency_key: None,
+        timestamp: None,
+        state: None,
+    }
+}
+
+/// Reader for OFX (and Quicken's QFX) bank and credit card statements, in either the SGML syntax of
+/// OFX 1.x, where leaf elements aren't closed, or the XML syntax of OFX 2.x.
+///
+/// Every `STMTTRN` becomes a deposit when its `TRNAMT` is positive and a withdrawal when it is negative.
+/// Its `FITID` is the tx id and the statement's `ACCTID` the client, both mapped by `reference_id`.
+pub struct OfxSource<R: BufRead> {
+    reader: R,
+    buffer: Vec<u8>,
+    /// Leaf element whose value is read next.
+    tag: String,
+    /// Account of the current statement.
+    account: Option<String>,
+    /// `(TRNAMT, FITID)` of the transaction being read.
+    entry: Option<(Option<String>, Option<String>)>,
+    /// Transactions read so far, to locate errors.
+    count: usize,
+}
+
+impl<R: BufRead> OfxSource<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            tag: String::new(),
+            account: None,
+            entry: None,
+            count: 0,
+        }
+    }
+
+    /// Read up to and past `delimiter`, returning what came before it, or `None` at the end of the input.
+    fn read_until(&mut self, delimiter: u8) -> Option<Result<String, KrakenError>> {
+        self.buffer.clear();
+        match self.reader.read_until(delimiter, &mut self.buffer) {
+            Ok(0) => None,
+            Ok(_) => {
+                if self.buffer.last() == Some(&delimiter) {
+                    self.buffer.pop();
+                }
+                Some(Ok(String::from_utf8_lossy(&self.buffer).trim().to_string()))
+            }
+            Err(_) => Some(Err(KrakenError::IO)),
+        }
+    }
+
+    /// Record the value of the current leaf element.
+    fn value(&mut self, value: String) {
+        if value.is_empty() {
+            return;
+        }
+        match (self.tag.as_str(), self.entry.as_mut()) {
+            ("TRNAMT", Some((amount, _))) => *amount = Some(value),
+            ("FITID", Some((_, fitid))) => *fitid = Some(value),
+            // The accounts of transfers, inside transactions, aren't the statement's
+            ("ACCTID", None) => self.account = Some(value),
+            _ => {}
+        }
+    }
+
+    fn transaction(&self, (amount, fitid): (Option<String>, Option<String>)) -> Result<Transaction, KrakenError> {
+        let count = self.count;
+        let missing = |field: &str| Parse(format!("Missing {field} in transaction {count}"));
+        let amount = amount.ok_or_else(|| missing("TRNAMT"))?;
+        Ok(signed_transaction(
+            reference_id(self.account.as_deref().ok_or_else(|| missing("ACCTID"))?),
+            reference_id(&fitid.ok_or_else(|| missing("FITID"))?),
+            // Some exporters use a decimal comma
+            amount
+                .replace(',', ".")
+                .parse()
+                .map_err(|_| Parse(format!("Invalid TRNAMT {amount} in transaction {count}")))?,
+        ))
+    }
+}
+
+impl<R: BufRead> RowSource for OfxSource<R> {
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>> {
+        loop {
+            // The text up to the next tag is the value of the previous one. That includes the headers of
+            // OFX 1.x, which come before the first tag and are ignored along with anything else unknown.
+            let value = match self.read_until(b'<')? {
+                Ok(value) => value,
+                Err(e) => return Some(Err(e)),
+            };
+            self.value(value);
+
+            self.tag = match self.read_until(b'>')? {
+                Ok(tag) => tag.to_ascii_uppercase(),
+                Err(e) => return Some(Err(e)),
+            };
+            match self.tag.as_str() {
+                "STMTTRN" => self.entry = Some((None, None)),
+                "/STMTTRN" => {
+                    if let Some(entry) = self.entry.take() {
+                        self.count += 1;
+                        return Some(self.transaction(entry));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<R: BufRead> InputSource for OfxSource<R> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        next_chunk(&mut std::iter::from_fn(|| self.next_row()), DEFAULT_BATCH_ROWS)
+    }
+}
+
+/// Reader for QIF exports of bank, cash, and credit card accounts.
+///
+/// Each record becomes a deposit when its `T` amount is positive and a withdrawal when it is negative.
+/// QIF has no transaction ids, so the tx id is the record's `N` check number when it has one, and a hash of
+/// the record's position and content otherwise, which stays the same when the file is read again.
+/// The client is the name of the `!Account` the records follow, mapped by `reference_id`, or
+/// `DEFAULT_QIF_CLIENT`. Investment accounts and category, class, and memorized transaction lists are skipped.
+pub struct QifSource<R: BufRead> {
+    lines: std::io::Lines<R>,
+    line_number: usize,
+    /// Whether the current section holds transactions.
+    transactions: bool,
+    /// Whether the current section is an `!Account` block.
+    accounts: bool,
+    client: u32,
+    /// Lines of the record being read.
+    record: Vec<String>,
+    /// Records read so far.
+    count: usize,
+}
+
+impl<R: BufRead> QifSource<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            line_number: 0,
+            transactions: false,
+            accounts: false,
+            client: DEFAULT_QIF_CLIENT,
+            record: Vec::new(),
+            count: 0,
+        }
+    }
+
+    fn transaction(&self, record: &[String]) -> Result<Transaction, KrakenError> {
+        let field = |code: char| record.iter().find_map(|field| field.strip_prefix(code)).map(str::trim);
+        let amount = field('T')
+            .or_else(|| field('U'))
+            .ok_or_else(|| Parse(format!("Missing amount in record ending at line {}", self.line_number)))?;
+        let tx = match field('N').filter(|number| !number.is_empty()) {
+            Some(number) => reference_id(number),
+            None => reference_id(&format!("{}:{}", self.count, record.join("\n"))),
+        };
+        Ok(signed_transaction(
+            self.client,
+            tx,
+            // Amounts may carry thousands separators
+            amount
+                .replace(',', "")
+                .parse()
+                .map_err(|_| Parse(format!("Invalid amount {amount} at line {}", self.line_number)))?,
+        ))
+    }
+}
+
+impl<R: BufRead> RowSource for QifSource<R> {
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>> {
+        loop {
+            self.line_number += 1;
+            let line = match self.lines.next()? {
+                Ok(line) => line.trim().to_string(),
+                Err(_) => return Some(Err(KrakenError::IO)),
+            };
+
+            if let Some(header) = line.strip_prefix('!') {
+                let header = header.to_ascii_lowercase();
+                self.accounts = header == "account";
+                self.transactions = ["type:bank", "type:cash", "type:ccard", "type:oth a", "type:oth l"]
+                    .contains(&header.as_str());
+                self.record.clear();
+            } else if line == "^" {
+                let record = std::mem::take(&mut self.record);
+                if self.accounts {
+                    if let Some(name) = record.iter().find_map(|field| field.strip_prefix('N')) {
+                        self.client = reference_id(name.trim());
+                    }
+                } else if self.transactions && !record.is_empty() {
+                    self.count += 1;
+                    return Some(self.transaction(&record));
+                }
+            } else if !line.is_empty() {
+                self.record.push(line);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> InputSource for QifSource<R> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        next_chunk(&mut std::iter::from_fn(|| self.next_row()), DEFAULT_BATCH_ROWS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{reference_id, rows, InputFormat};
+    use crate::ofx::QifSource;
+    use crate::processor::tests::TEST_DIR;
+    use crate::processor::{compute_account_totals, ProcessorConfig};
+    use crate::structures::TransactionType;
+
+    #[test]
+    fn test_ofx_and_qif() {
+        // A deposit of 250.00 and withdrawals of 42.10 and 7.90, in OFX 1.x syntax
+        let path = String::from(TEST_DIR) + "8-statement.ofx";
+        assert_eq!(InputFormat::Ofx, InputFormat::detect(&path));
+        let totals = compute_account_totals(&path, &ProcessorConfig::default()).unwrap();
+        assert_eq!("1234, 200.0000, 0.0000, 200.0000, false", totals[&1234].to_str_row(1234).unwrap());
+
+        let qif = "!Account\nNChecking\nTBank\n^\n!Type:Bank\nD01/02/2024\nT1,500.00\nN1001\nPEmployer\n^\n\
+                   D01/03/2024\nT-20.25\nPGrocer\n^\n!Type:Cat\nNGroceries\nE\n^\n";
+        let transactions: Vec<_> = rows(&mut QifSource::from_reader(qif.as_bytes())).collect::<Result<_, _>>().unwrap();
+        assert_eq!(2, transactions.len());
+        assert_eq!(TransactionType::Deposit, transactions[0].kind);
+        assert_eq!((1001, Some(1500.0)), (transactions[0].tx, transactions[0].amount));
+        assert_eq!(TransactionType::Withdrawal, transactions[1].kind);
+        assert_eq!(Some(20.25), transactions[1].amount);
+        assert!(transactions.iter().all(|transaction| transaction.client == reference_id("Checking")));
+    }
+}
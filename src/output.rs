@@ -0,0 +1,491 @@
+use crate::errors::KrakenError;
+use crate::ids::Interner;
+use crate::structures::{ClientAccount, LockEvent};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Format of the final account report.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    /// `client, available, held, total, locked` rows under a header.
+    #[default]
+    Csv,
+    /// A single JSON array of account objects.
+    Json,
+    /// One JSON account object per line.
+    JsonLines,
+    /// Aligned columns with a totals row, for reading in a terminal.
+    Table,
+    /// Apache Parquet, with the amounts as `Decimal(18, 4)`.
+    #[cfg(feature = "polars")]
+    Parquet,
+}
+
+impl TryFrom<&str> for OutputFormat {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "table" => Ok(OutputFormat::Table),
+            #[cfg(feature = "polars")]
+            "parquet" => Ok(OutputFormat::Parquet),
+            _ => Err(KrakenError::Enum(format!(
+                "Invalid String for OutputFormat: {value}"
+            ))),
+        }
+    }
+}
+
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Report the deposits still pending in a `pending` column after `held`, from now on. Off by default, so the report
+/// keeps its columns for those reading it; the pending funds are counted in `total` either way.
+pub fn show_pending() {
+    PENDING.store(true, Ordering::Relaxed);
+}
+
+pub fn pending_shown() -> bool {
+    PENDING.load(Ordering::Relaxed)
+}
+
+/// `fields` as a CSV row, without a line ending, quoting those holding a comma, a quote, or a line break.
+pub fn csv_row(fields: &[&str]) -> String {
+    let mut writer = csv::WriterBuilder::new().terminator(csv::Terminator::Any(b'\n')).from_writer(Vec::new());
+    // Writing to memory can't fail, and every field is text
+    let _ = writer.write_record(fields);
+    let mut row = String::from_utf8(writer.into_inner().unwrap_or_default()).unwrap_or_default();
+    row.pop();
+    row
+}
+
+/// One row of the account report.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountSummary {
+    pub client: u32,
+    #[serde(serialize_with = "four_places")]
+    pub available: f64,
+    #[serde(serialize_with = "four_places")]
+    pub held: f64,
+    /// Only with `show_pending`.
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "four_places_if_any")]
+    pub pending: Option<f64>,
+    #[serde(serialize_with = "four_places")]
+    pub total: f64,
+    pub locked: bool,
+    /// What locked the account, while it's locked and that's known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock: Option<LockEvent>,
+}
+
+impl AccountSummary {
+    /// The summary of `account`, failing as `ClientAccount::total` does.
+    pub fn new(client: u32, account: &ClientAccount) -> Result<Self, KrakenError> {
+        Ok(Self {
+            client,
+            available: account.available,
+            held: account.held,
+            pending: pending_shown().then(|| account.pending()).transpose()?,
+            total: account.total()?,
+            locked: account.locked,
+            lock: account.lock_event().cloned(),
+        })
+    }
+}
+
+/// The balances of an account at one point, without its client, rounded as in the report.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Balances {
+    #[serde(serialize_with = "four_places")]
+    pub available: f64,
+    #[serde(serialize_with = "four_places")]
+    pub held: f64,
+    #[serde(serialize_with = "four_places")]
+    pub total: f64,
+    pub locked: bool,
+}
+
+impl TryFrom<&ClientAccount> for Balances {
+    type Error = KrakenError;
+
+    /// The balances of `account`, failing as `ClientAccount::total` does.
+    fn try_from(account: &ClientAccount) -> Result<Self, Self::Error> {
+        Ok(Self { available: account.available, held: account.held, total: account.total()?, locked: account.locked })
+    }
+}
+
+/// Decimal places kept in every reported amount.
+const SCALE: u32 = 4;
+
+/// Amounts are reported to four decimal places, as in the CSV report.
+pub(crate) fn four_places<S: Serializer>(amount: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    let factor = 10f64.powi(SCALE as i32);
+    serializer.serialize_f64((amount * factor).round() / factor)
+}
+
+fn four_places_if_any<S: Serializer>(amount: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => four_places(amount, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Write the report for `accounts` to `writer`.
+pub fn write_accounts<W: Write>(
+    mut writer: W,
+    accounts: &HashMap<u32, ClientAccount>,
+    format: OutputFormat,
+) -> Result<(), KrakenError> {
+    let summaries = accounts.iter().map(|(client, account)| AccountSummary::new(*client, account));
+    match format {
+        OutputFormat::Csv if pending_shown() => {
+            writeln!(writer, "client, available, held, pending, total, locked").map_err(|_| KrakenError::IO)?;
+            for (client, account) in accounts {
+                let (available, held, pending, total) = (account.available, account.held, account.pending()?, account.total()?);
+                writeln!(writer, "{client}, {available:.4}, {held:.4}, {pending:.4}, {total:.4}, {}", account.locked)
+                    .map_err(|_| KrakenError::IO)?;
+            }
+        }
+        OutputFormat::Csv => {
+            writeln!(writer, "client, available, held, total, locked").map_err(|_| KrakenError::IO)?;
+            for (client, account) in accounts {
+                writeln!(writer, "{}", account.to_str_row(*client)?).map_err(|_| KrakenError::IO)?;
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut writer, &summaries.collect::<Result<Vec<_>, KrakenError>>()?)
+                .map_err(|e| KrakenError::Parse(e.to_string()))?;
+            writeln!(writer).map_err(|_| KrakenError::IO)?;
+        }
+        OutputFormat::JsonLines => {
+            for summary in summaries {
+                serde_json::to_writer(&mut writer, &summary?).map_err(|e| KrakenError::Parse(e.to_string()))?;
+                writeln!(writer).map_err(|_| KrakenError::IO)?;
+            }
+        }
+        OutputFormat::Table => write_table(&mut writer, accounts, false)?,
+        #[cfg(feature = "polars")]
+        OutputFormat::Parquet => write_parquet(&mut writer, summaries.collect::<Result<_, KrakenError>>()?)?,
+    }
+    writer.flush().map_err(|_| KrakenError::IO)
+}
+
+/// One row of a report covering several tenants.
+#[derive(Serialize)]
+struct TenantSummary<'a> {
+    tenant: &'a str,
+    #[serde(flatten)]
+    summary: AccountSummary,
+}
+
+/// Write one report for the accounts of every tenant, by tenant, to `writer`: `tenant, client, available, held,
+/// total, locked` rows for CSV, and account objects with a `tenant` field for JSON. Tables and Parquet only
+/// report a single tenant.
+pub fn write_tenant_accounts<W: Write>(
+    mut writer: W,
+    books: &BTreeMap<String, HashMap<u32, ClientAccount>>,
+    format: OutputFormat,
+) -> Result<(), KrakenError> {
+    let summaries = books.iter().flat_map(|(tenant, accounts)| {
+        accounts.iter().map(|(client, account)| Ok(TenantSummary { tenant, summary: AccountSummary::new(*client, account)? }))
+    });
+    match format {
+        OutputFormat::Csv => {
+            writeln!(writer, "tenant, client, available, held, total, locked").map_err(|_| KrakenError::IO)?;
+            for (tenant, accounts) in books {
+                for (client, account) in accounts {
+                    writeln!(writer, "{tenant}, {}", account.to_str_row(*client)?).map_err(|_| KrakenError::IO)?;
+                }
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut writer, &summaries.collect::<Result<Vec<_>, KrakenError>>()?)
+                .map_err(|e| KrakenError::Parse(e.to_string()))?;
+            writeln!(writer).map_err(|_| KrakenError::IO)?;
+        }
+        OutputFormat::JsonLines => {
+            for summary in summaries {
+                serde_json::to_writer(&mut writer, &summary?).map_err(|e| KrakenError::Parse(e.to_string()))?;
+                writeln!(writer).map_err(|_| KrakenError::IO)?;
+            }
+        }
+        format => {
+            return Err(KrakenError::InvalidArgument(format!("A report of several tenants cannot be written as {format:?}")));
+        }
+    }
+    writer.flush().map_err(|_| KrakenError::IO)
+}
+
+/// One row of a report whose clients have textual ids.
+#[derive(Serialize)]
+struct NamedSummary {
+    client: String,
+    #[serde(serialize_with = "four_places")]
+    available: f64,
+    #[serde(serialize_with = "four_places")]
+    held: f64,
+    #[serde(serialize_with = "four_places")]
+    total: f64,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock: Option<LockEvent>,
+}
+
+/// Write the report for `accounts`, whose client ids were interned into `names`, with each client's textual id
+/// in place of its number. CSV fields are quoted where the id needs it. Tables and Parquet only have numeric ids.
+pub fn write_named_accounts<W: Write>(
+    mut writer: W,
+    accounts: &HashMap<u32, ClientAccount>,
+    format: OutputFormat,
+    names: &Interner,
+) -> Result<(), KrakenError> {
+    let summaries = accounts.iter().map(|(client, account)| {
+        Ok(NamedSummary {
+            client: names.name(*client).unwrap_or_else(|| client.to_string()),
+            available: account.available,
+            held: account.held,
+            total: account.total()?,
+            locked: account.locked,
+            lock: account.lock_event().cloned(),
+        })
+    });
+    match format {
+        OutputFormat::Csv => {
+            writeln!(writer, "client, available, held, total, locked").map_err(|_| KrakenError::IO)?;
+            for summary in summaries {
+                let summary: NamedSummary = summary?;
+                let client = match summary.client.contains([',', '"', '\n', '\r']) || summary.client.trim() != summary.client {
+                    true => format!("\"{}\"", summary.client.replace('"', "\"\"")),
+                    false => summary.client,
+                };
+                let (available, held, total) = (summary.available, summary.held, summary.total);
+                writeln!(writer, "{client}, {available:.4}, {held:.4}, {total:.4}, {}", summary.locked).map_err(|_| KrakenError::IO)?;
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut writer, &summaries.collect::<Result<Vec<_>, KrakenError>>()?)
+                .map_err(|e| KrakenError::Parse(e.to_string()))?;
+            writeln!(writer).map_err(|_| KrakenError::IO)?;
+        }
+        OutputFormat::JsonLines => {
+            for summary in summaries {
+                serde_json::to_writer(&mut writer, &summary?).map_err(|e| KrakenError::Parse(e.to_string()))?;
+                writeln!(writer).map_err(|_| KrakenError::IO)?;
+            }
+        }
+        format => {
+            return Err(KrakenError::InvalidArgument(format!("A report of string ids cannot be written as {format:?}")));
+        }
+    }
+    writer.flush().map_err(|_| KrakenError::IO)
+}
+
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// Write the report as a table sorted by client, with the sums of every column in a final row.
+/// With `color`, the header and totals are bold, and locked accounts and negative amounts are red.
+pub fn write_table<W: Write>(
+    mut writer: W,
+    accounts: &HashMap<u32, ClientAccount>,
+    color: bool,
+) -> Result<(), KrakenError> {
+    let mut summaries: Vec<AccountSummary> =
+        accounts.iter().map(|(client, account)| AccountSummary::new(*client, account)).collect::<Result<_, _>>()?;
+    summaries.sort_by_key(|summary| summary.client);
+
+    // The pending column sits after held, when shown
+    let pending = pending_shown();
+    let with_pending = |mut row: Vec<String>, cell: String| {
+        if pending {
+            row.insert(3, cell);
+        }
+        row
+    };
+    let header = with_pending(["client", "available", "held", "total", "locked"].map(String::from).to_vec(), "pending".into());
+    let mut rows: Vec<Vec<String>> = summaries
+        .iter()
+        .map(|summary| {
+            let row = vec![
+                summary.client.to_string(),
+                format!("{:.4}", summary.available),
+                format!("{:.4}", summary.held),
+                format!("{:.4}", summary.total),
+                summary.locked.to_string(),
+            ];
+            with_pending(row, format!("{:.4}", summary.pending.unwrap_or_default()))
+        })
+        .collect();
+    let sum = |amount: fn(&AccountSummary) -> f64| format!("{:.4}", summaries.iter().map(amount).sum::<f64>());
+    let footer = vec![
+        format!("{} clients", summaries.len()),
+        sum(|summary| summary.available),
+        sum(|summary| summary.held),
+        sum(|summary| summary.total),
+        format!("{} locked", summaries.iter().filter(|summary| summary.locked).count()),
+    ];
+    let footer = with_pending(footer, sum(|summary| summary.pending.unwrap_or_default()));
+    let columns = header.len();
+    rows.insert(0, header);
+    rows.push(footer);
+
+    let mut widths = vec![0; columns];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let last = rows.len() - 1;
+    for (index, row) in rows.iter().enumerate() {
+        if index == last {
+            let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+            writeln!(writer, "{}", rule.join("  ")).map_err(|_| KrakenError::IO)?;
+        }
+
+        let mut line = String::new();
+        for (column, (cell, &width)) in row.iter().zip(&widths).enumerate() {
+            if column > 0 {
+                line.push_str("  ");
+            }
+            let style = if !color {
+                ""
+            } else if index == 0 || index == last {
+                BOLD
+            } else if summaries[index - 1].locked || cell.starts_with('-') {
+                RED
+            } else {
+                ""
+            };
+            // Numbers are right-aligned, the rest left-aligned
+            let cell = if column == columns - 1 { format!("{cell:<width$}") } else { format!("{cell:>width$}") };
+            match style {
+                "" => line.push_str(&cell),
+                style => line.push_str(&format!("{style}{cell}{RESET}")),
+            }
+        }
+        writeln!(writer, "{}", line.trim_end()).map_err(|_| KrakenError::IO)?;
+    }
+    Ok(())
+}
+
+/// Precision of the Parquet amount columns. Up to 18 digits fit the decimal in an `INT64`.
+#[cfg(feature = "polars")]
+const PRECISION: usize = 18;
+
+#[cfg(feature = "polars")]
+fn write_parquet<W: Write>(writer: W, summaries: Vec<AccountSummary>) -> Result<(), KrakenError> {
+    use polars::prelude::*;
+
+    let polars_error = |e: PolarsError| KrakenError::Parse(e.to_string());
+    let decimal = |name: &str, amount: fn(&AccountSummary) -> f64| {
+        let factor = 10f64.powi(SCALE as i32);
+        let units: Vec<i128> = summaries.iter().map(|summary| (amount(summary) * factor).round() as i128).collect();
+        Int128Chunked::from_vec(name.into(), units)
+            .into_decimal(Some(PRECISION), SCALE as usize)
+            .map(|amounts| amounts.into_series().into_column())
+            .map_err(polars_error)
+    };
+
+    let mut columns = vec![
+        Column::new("client".into(), summaries.iter().map(|summary| summary.client).collect::<Vec<_>>()),
+        decimal("available", |summary| summary.available)?,
+        decimal("held", |summary| summary.held)?,
+        decimal("total", |summary| summary.total)?,
+        Column::new("locked".into(), summaries.iter().map(|summary| summary.locked).collect::<Vec<_>>()),
+    ];
+    if pending_shown() {
+        columns.insert(3, decimal("pending", |summary| summary.pending.unwrap_or_default())?);
+    }
+    let mut df = DataFrame::new(columns).map_err(polars_error)?;
+    ParquetWriter::new(writer).finish(&mut df).map_err(polars_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::{write_accounts, write_tenant_accounts, OutputFormat};
+    use crate::processor::tests::TEST_DIR;
+    use crate::processor::{compute_account_totals, ProcessorConfig};
+    use std::collections::BTreeMap;
+
+    #[cfg(feature = "polars")]
+    #[test]
+    fn test_parquet_output() {
+        use polars::prelude::*;
+
+        let path = String::from(TEST_DIR) + "1-dispute-after-withdraw.csv";
+        let totals = compute_account_totals(&path, &ProcessorConfig::default()).unwrap();
+        let mut parquet = Vec::new();
+        write_accounts(&mut parquet, &totals, OutputFormat::Parquet).unwrap();
+
+        let df = ParquetReader::new(std::io::Cursor::new(parquet)).finish().unwrap();
+        assert_eq!(&DataType::Decimal(Some(18), Some(4)), df.column("held").unwrap().dtype());
+        let available = df.column("available").unwrap().cast(&DataType::Float64).unwrap();
+        assert_eq!(Some(-9.5), available.f64().unwrap().get(0));
+    }
+
+    #[test]
+    fn test_table_output() {
+        let path = String::from(TEST_DIR) + "0-trivial.csv";
+        let totals = compute_account_totals(&path, &ProcessorConfig::default()).unwrap();
+
+        let mut table = Vec::new();
+        write_accounts(&mut table, &totals, OutputFormat::Table).unwrap();
+        assert_eq!(
+            "   client  available    held   total  locked
+        1     1.5000  0.0000  1.5000  false
+        2     2.0000  0.0000  2.0000  false
+---------  ---------  ------  ------  --------
+2 clients     3.5000  0.0000  3.5000  0 locked
+",
+            String::from_utf8(table).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_json_output() {
+        let path = String::from(TEST_DIR) + "1-dispute-after-withdraw.csv";
+        let totals = compute_account_totals(&path, &ProcessorConfig::default()).unwrap();
+
+        let mut json = Vec::new();
+        write_accounts(&mut json, &totals, OutputFormat::Json).unwrap();
+        assert_eq!(
+            "[{\"client\":1,\"available\":-9.5,\"held\":10.0,\"total\":0.5,\"locked\":false}]\n",
+            String::from_utf8(json).unwrap()
+        );
+
+        let mut lines = Vec::new();
+        write_accounts(&mut lines, &totals, OutputFormat::JsonLines).unwrap();
+        assert_eq!(
+            "{\"client\":1,\"available\":-9.5,\"held\":10.0,\"total\":0.5,\"locked\":false}\n",
+            String::from_utf8(lines).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tenant_output() {
+        let path = String::from(TEST_DIR) + "1-dispute-after-withdraw.csv";
+        let totals = || compute_account_totals(&path, &ProcessorConfig::default()).unwrap();
+        // The same client is a separate account in each book
+        let books = BTreeMap::from([(String::from("beta"), totals()), (String::from("acme"), totals())]);
+
+        let mut csv = Vec::new();
+        write_tenant_accounts(&mut csv, &books, OutputFormat::Csv).unwrap();
+        assert_eq!(
+            "tenant, client, available, held, total, locked\nacme, 1, -9.5000, 10.0000, 0.5000, false\nbeta, 1, -9.5000, 10.0000, 0.5000, false\n",
+            String::from_utf8(csv).unwrap()
+        );
+
+        let mut lines = Vec::new();
+        write_tenant_accounts(&mut lines, &books, OutputFormat::JsonLines).unwrap();
+        let first = String::from_utf8(lines).unwrap().lines().next().unwrap().to_string();
+        assert_eq!("{\"tenant\":\"acme\",\"client\":1,\"available\":-9.5,\"held\":10.0,\"total\":0.5,\"locked\":false}", first);
+        assert!(write_tenant_accounts(Vec::new(), &books, OutputFormat::Table).is_err());
+    }
+}
@@ -0,0 +1,468 @@
+use crate::actor::ActorRouter;
+use crate::engine::{Engine, EngineObserver};
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::{self, InputOptions, InputSource, MultiSource, DEFAULT_BATCH_ROWS};
+use crate::metrics::{Metered, Metrics, Tally};
+use crate::rules::Rules;
+use crate::structures::{ClientAccount, Transaction};
+use anyhow::{anyhow, Result};
+use crossbeam_utils::thread;
+use dashmap::DashMap;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::time::Instant;
+use tracing::{info_span, Instrument, Span};
+
+/// Chunks each threaded worker may have queued before the reader waits for it.
+const CHUNKS_IN_FLIGHT: usize = 2;
+
+/// Strategy used to fan client partitions out across cores.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ParallelMode {
+    /// Single-threaded: rows are applied in file order on the calling thread. Used as the reference by `--verify`.
+    Serial,
+    /// A fixed set of scoped worker threads, each owning a shard of the clients and fed chunks over a channel.
+    #[default]
+    Threads,
+    /// Each chunk is split by client shard and the shards are applied in parallel on a Rayon pool.
+    Rayon,
+    /// Rows are streamed in order, without partitioning, to one tokio task per client.
+    Actors,
+}
+
+impl TryFrom<&str> for ParallelMode {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "serial" => Ok(ParallelMode::Serial),
+            "threads" => Ok(ParallelMode::Threads),
+            "rayon" => Ok(ParallelMode::Rayon),
+            "actors" => Ok(ParallelMode::Actors),
+            _ => Err(KrakenError::Enum(format!(
+                "Invalid String for ParallelMode: {value}"
+            ))),
+        }
+    }
+}
+
+/// Knobs controlling how `compute_account_totals` spreads work across cores.
+#[derive(Debug, Clone)]
+pub struct ProcessorConfig {
+    pub parallel: ParallelMode,
+    /// Degree of parallelism: worker threads, Rayon pool size, or tokio workers depending on `parallel`.
+    pub threads: usize,
+    /// Estimated byte budget for account histories. Histories spill to a temporary file beyond it.
+    pub max_memory: Option<usize>,
+    pub input: InputOptions,
+    /// Rows dispatched to the parallel workers at a time. Bounds in-flight memory for `Threads` and `Rayon`.
+    pub chunk_rows: usize,
+    /// Where to count rows and time the stages of the run, if anywhere.
+    pub metrics: Option<Arc<Metrics>>,
+    /// Policies for disputes and chargebacks, instead of the installed ones.
+    pub rules: Option<Arc<Rules>>,
+}
+
+impl Default for ProcessorConfig {
+    fn default() -> Self {
+        Self {
+            parallel: ParallelMode::default(),
+            threads: default_threads(),
+            max_memory: None,
+            input: InputOptions::default(),
+            chunk_rows: DEFAULT_BATCH_ROWS,
+            metrics: None,
+            rules: None,
+        }
+    }
+}
+
+/// Number of cores available to this process, falling back to 1 if it can't be determined.
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+}
+
+pub fn compute_account_totals(path: &str, config: &ProcessorConfig) -> Result<HashMap<u32, ClientAccount>> {
+    compute_combined_totals(&[path], config)
+}
+
+/// Process several files, in order, as one logical stream and return the combined final accounts.
+pub fn compute_combined_totals<P: AsRef<Path>>(
+    paths: &[P],
+    config: &ProcessorConfig,
+) -> Result<HashMap<u32, ClientAccount>> {
+    let budget = config.max_memory.map(MemoryBudget::new).transpose()?;
+    apply_source(&mut MultiSource::new(paths, config.input.clone()), config, budget.as_ref())
+}
+
+/// Apply every transaction from a generic `InputSource` using the configured strategy.
+pub fn apply_source<S: InputSource>(
+    source: &mut S,
+    config: &ProcessorConfig,
+    budget: Option<&MemoryBudget>,
+) -> Result<HashMap<u32, ClientAccount>> {
+    let _span = info_span!("process", mode = ?config.parallel).entered();
+    let mut source = Metered::new(source, config.metrics.as_deref());
+    let rows = input::rows(&mut source);
+
+    match config.parallel {
+        ParallelMode::Threads | ParallelMode::Rayon => apply_sharded(rows, config, budget),
+        ParallelMode::Serial | ParallelMode::Actors => apply_stream(rows, config, budget),
+    }
+}
+
+/// `apply_source`, calling `observer` back for every transaction. Observers see transactions in input order, so they
+/// are applied serially, whatever the configured strategy.
+pub fn apply_source_observed<S: InputSource>(
+    source: &mut S,
+    config: &ProcessorConfig,
+    budget: Option<&MemoryBudget>,
+    observer: impl EngineObserver + 'static,
+) -> Result<HashMap<u32, ClientAccount>> {
+    let _span = info_span!("process", mode = ?ParallelMode::Serial).entered();
+    let mut source = Metered::new(source, config.metrics.as_deref());
+    let engine = Engine::with_budget(budget.cloned()).with_rules(config.rules.clone()).with_observer(observer);
+    let _apply = info_span!("apply").entered();
+    apply_serial(input::rows(&mut source), engine, config.metrics.as_deref())
+}
+
+/// Apply rows in parallel by sharding clients across `threads` engines.
+/// Rows are dispatched in chunks of `chunk_rows`, and each shard applies its chunks in arrival order, so
+/// per-thread memory stays flat however skewed the clients are.
+fn apply_sharded(
+    rows: impl Iterator<Item = Result<Transaction, KrakenError>>,
+    config: &ProcessorConfig,
+    budget: Option<&MemoryBudget>,
+) -> Result<HashMap<u32, ClientAccount>> {
+    let shards = config.threads.max(1);
+    let metrics = config.metrics.as_deref();
+    let chunks = input::batched(rows, config.chunk_rows);
+    // Workers run outside the calling thread, so they join the run's span explicitly
+    let span = Span::current();
+
+    match config.parallel {
+        ParallelMode::Rayon => {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(shards).build()?;
+            let mut engines: Vec<Engine> = (0..shards).map(|_| Engine::with_budget(budget.cloned()).with_rules(config.rules.clone())).collect();
+
+            // Every shard owns its clients outright, so the shards of a chunk are applied in parallel
+            // without any lock.
+            for chunk in chunks {
+                let split = split_by_shard(chunk?, shards, metrics);
+                pool.install(|| {
+                    engines.par_iter_mut().zip(split).for_each(|(engine, rows)| {
+                        span.in_scope(|| apply_rows(engine, rows, metrics))
+                    })
+                });
+            }
+
+            Ok(engines.into_iter().flat_map(Engine::into_accounts).collect())
+        }
+        _ => {
+            // Master collection of accounts. DashMap shards its storage, so workers finishing at the same
+            // time only contend when their clients hash to the same shard.
+            let client_accounts: DashMap<u32, ClientAccount> = DashMap::new();
+
+            // The scope joins every worker before returning, so no thread outlives the borrowed state
+            thread::scope(|s| {
+                let (sinks, handles): (Vec<_>, Vec<_>) = (0..shards)
+                    .map(|_| {
+                        let (sink, source) = mpsc::sync_channel::<Vec<Transaction>>(CHUNKS_IN_FLIGHT);
+                        let (accounts, span) = (&client_accounts, &span);
+                        let handle = s.spawn(move |_| {
+                            let _span = span.enter();
+                            let mut engine = Engine::with_budget(budget.cloned()).with_rules(config.rules.clone());
+                            for rows in source {
+                                apply_rows(&mut engine, rows, metrics);
+                            }
+                            for (client_id, account) in engine.into_accounts() {
+                                accounts.insert(client_id, account);
+                            }
+                        });
+                        (sink, handle)
+                    })
+                    .unzip();
+
+                let dispatched = chunks.into_iter().try_for_each(|chunk| {
+                    for (sink, rows) in sinks.iter().zip(split_by_shard(chunk?, shards, metrics)) {
+                        // A worker only hangs up by panicking, which joining it reports below
+                        if !rows.is_empty() && sink.send(rows).is_err() {
+                            return Err(KrakenError::Error);
+                        }
+                    }
+                    Ok::<_, KrakenError>(())
+                });
+
+                // Closing the channels lets the workers drain and exit
+                drop(sinks);
+                let panicked = handles.into_iter().map(|handle| handle.join()).filter(Result::is_err).count();
+                if panicked > 0 {
+                    return Err(anyhow!("{panicked} worker thread(s) panicked"));
+                }
+                Ok(dispatched?)
+            })
+            .map_err(|_| anyhow!("Worker thread panicked"))??;
+
+            Ok(client_accounts.into_iter().collect())
+        }
+    }
+}
+
+/// Split a chunk into one list per shard, keeping each client's rows in their original order.
+fn split_by_shard(chunk: Vec<Transaction>, shards: usize, metrics: Option<&Metrics>) -> Vec<Vec<Transaction>> {
+    let _span = info_span!("partition", rows = chunk.len()).entered();
+    let start = Instant::now();
+    let mut split: Vec<Vec<Transaction>> = (0..shards).map(|_| Vec::new()).collect();
+    for transaction in chunk {
+        split[transaction.client as usize % shards].push(transaction);
+    }
+    if let Some(metrics) = metrics {
+        metrics.add_partition(start.elapsed());
+    }
+    split
+}
+
+fn apply_rows(engine: &mut Engine, rows: Vec<Transaction>, metrics: Option<&Metrics>) {
+    let _span = info_span!("apply", rows = rows.len()).entered();
+    let Some(metrics) = metrics else {
+        // Results are only counted, and only when metrics are kept
+        rows.into_iter().for_each(|transaction| {
+            let _ = engine.apply(transaction);
+        });
+        return;
+    };
+
+    let (start, mut tally) = (Instant::now(), Tally::default());
+    for transaction in rows {
+        let (kind, applying) = (transaction.kind.clone(), Instant::now());
+        let result = engine.apply(transaction);
+        tally.record(kind, &result, applying.elapsed());
+    }
+    metrics.add_tally(&tally);
+    metrics.add_apply(start.elapsed());
+}
+
+/// Apply rows in stream order, either on the calling thread or by routing them to per-client actors.
+fn apply_stream(
+    rows: impl Iterator<Item = Result<Transaction, KrakenError>>,
+    config: &ProcessorConfig,
+    budget: Option<&MemoryBudget>,
+) -> Result<HashMap<u32, ClientAccount>> {
+    // Reading is interleaved with applying, so parsing is traced within the apply stage
+    match config.parallel {
+        ParallelMode::Actors => runtime(config.threads)?.block_on(async {
+            let mut router = ActorRouter::default()
+                .with_budget(budget.cloned())
+                .with_metrics(config.metrics.clone())
+                .with_rules(config.rules.clone());
+            for transaction in rows {
+                router.send(transaction?).await?;
+            }
+            router.shutdown().await
+        }.instrument(info_span!("apply"))),
+        _ => {
+            let _span = info_span!("apply").entered();
+            let engine = Engine::with_budget(budget.cloned()).with_rules(config.rules.clone());
+            apply_serial(rows, engine, config.metrics.as_deref())
+        }
+    }
+}
+
+/// Apply rows in stream order with `engine`, on the calling thread.
+fn apply_serial(
+    rows: impl Iterator<Item = Result<Transaction, KrakenError>>,
+    mut engine: Engine,
+    metrics: Option<&Metrics>,
+) -> Result<HashMap<u32, ClientAccount>> {
+    // Reading is interleaved with applying, so the time spent applying is what's left once parsing is taken out
+    let (start, parsed) = (Instant::now(), metrics.map(Metrics::parse).unwrap_or_default());
+    let mut tally = Tally::default();
+    for transaction in rows {
+        let transaction = transaction?;
+        match metrics {
+            Some(_) => {
+                let (kind, applying) = (transaction.kind.clone(), Instant::now());
+                let result = engine.apply(transaction);
+                tally.record(kind, &result, applying.elapsed());
+            }
+            // Results are only counted, and only when metrics are kept
+            None => {
+                let _ = engine.apply(transaction);
+            }
+        }
+    }
+    if let Some(metrics) = metrics {
+        metrics.add_tally(&tally);
+        metrics.add_apply(start.elapsed().saturating_sub(metrics.parse() - parsed));
+    }
+    Ok(engine.into_accounts())
+}
+
+/// Multi-threaded tokio runtime with `threads` workers.
+pub fn runtime(threads: usize) -> Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads.max(1))
+        .enable_all()
+        .build()?)
+}
+
+/// Compare two sets of final accounts, describing every client whose balances or lock state differ.
+/// Balances are compared exactly: each client's transactions are applied in the same order regardless of
+/// the strategy, so any difference at all points to an ordering bug.
+pub fn diff_accounts(
+    expected: &HashMap<u32, ClientAccount>,
+    actual: &HashMap<u32, ClientAccount>,
+) -> Vec<String> {
+    let mut clients: Vec<&u32> = expected.keys().chain(actual.keys()).collect();
+    clients.sort();
+    clients.dedup();
+
+    clients
+        .into_iter()
+        .filter_map(|client_id| match (expected.get(client_id), actual.get(client_id)) {
+            (Some(e), Some(a))
+                if e.available == a.available && e.held == a.held && e.locked == a.locked =>
+            {
+                None
+            }
+            (Some(e), Some(a)) => Some(format!(
+                "client {client_id}: expected `{}`, got `{}`",
+                e.to_str_row(*client_id).unwrap_or_else(|error| error.to_string()),
+                a.to_str_row(*client_id).unwrap_or_else(|error| error.to_string())
+            )),
+            (Some(_), None) => Some(format!("client {client_id}: missing from result")),
+            (None, _) => Some(format!("client {client_id}: unexpected in result")),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::errors::{KrakenError, EXIT_SCHEMA};
+    use crate::input::{InputOptions, ReaderKind};
+    use crate::processor::{compute_account_totals, compute_combined_totals, diff_accounts, ParallelMode, ProcessorConfig};
+    use std::io::Write;
+
+    pub(crate) const TEST_DIR: &str = "./test/";
+    pub(crate) const TEST_CASES: [(&str, &str); 7] = [
+        ("0-trivial.csv", "1, 1.5000, 0.0000, 1.5000, false"),
+        ("1-dispute-after-withdraw.csv", "1, -9.5000, 10.0000, 0.5000, false"),
+        ("2-chargeback-after-withdraw.csv", "1, -9.5000, 0.0000, -9.5000, true"),
+        ("3-resolve-without-dispute.csv", "1, 11.0000, 0.0000, 11.0000, false"),
+        ("4-oversized-withdrawal.csv", "1, 100.0000, 0.0000, 100.0000, false"),
+        ("5-very-parallel.csv", "1, 10.0000, 0.0000, 10.0000, false"),
+        ("9-assert-balance.csv", "1, 7.5000, 0.0000, 7.5000, false")
+    ];
+    #[test]
+    fn test_csv() {
+        let readers = [
+            #[cfg(feature = "polars")]
+            ReaderKind::Polars,
+            ReaderKind::Fast,
+            ReaderKind::Csv,
+        ];
+
+        for reader in readers {
+            for mode in [ParallelMode::Serial, ParallelMode::Threads, ParallelMode::Rayon, ParallelMode::Actors] {
+                for (file_name, expected) in TEST_CASES {
+                    let config = ProcessorConfig {
+                        parallel: mode,
+                        threads: 2,
+                        input: InputOptions { reader, ..Default::default() },
+                        ..Default::default()
+                    };
+                    let totals = compute_account_totals((String::from(TEST_DIR) + file_name).as_str(), &config).unwrap();
+                    assert_eq!(String::from(expected), totals.get(&1).expect("").to_str_row(1).unwrap())
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_delimiters() {
+        let readers = [
+            #[cfg(feature = "polars")]
+            ReaderKind::Polars,
+            ReaderKind::Fast,
+            ReaderKind::Csv,
+        ];
+
+        for (file_name, expected) in TEST_CASES {
+            let csv = std::fs::read_to_string(String::from(TEST_DIR) + file_name).unwrap();
+            // Tabs are picked up from the extension, semicolons need the explicit delimiter
+            for (suffix, delimiter, explicit) in [(".tsv", "\t", None), (".txt", ";", Some(b';'))] {
+                let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+                file.write_all(csv.replace(',', delimiter).as_bytes()).unwrap();
+
+                for reader in readers {
+                    let config = ProcessorConfig {
+                        input: InputOptions { reader, delimiter: explicit, ..Default::default() },
+                        ..Default::default()
+                    };
+                    let totals = compute_account_totals(file.path().to_str().unwrap(), &config).unwrap();
+                    assert_eq!(String::from(expected), totals.get(&1).expect("").to_str_row(1).unwrap())
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        for (file_name, _) in TEST_CASES {
+            let path = String::from(TEST_DIR) + file_name;
+            let serial = ProcessorConfig { parallel: ParallelMode::Serial, threads: 1, ..Default::default() };
+            let serial = compute_account_totals(&path, &serial).unwrap();
+            for mode in [ParallelMode::Threads, ParallelMode::Rayon, ParallelMode::Actors] {
+                let config = ProcessorConfig { parallel: mode, max_memory: Some(1), chunk_rows: 2, ..Default::default() };
+                let parallel = compute_account_totals(&path, &config).unwrap();
+                assert_eq!(Vec::<String>::new(), diff_accounts(&serial, &parallel));
+            }
+        }
+    }
+
+    #[test]
+    fn test_multiple_files() {
+        // Client 1 ends the first file at 11.0 and gains 1.5 in the second
+        let paths = [
+            String::from(TEST_DIR) + "3-resolve-without-dispute.csv",
+            String::from(TEST_DIR) + "0-trivial.csv",
+        ];
+        let totals = compute_combined_totals(&paths, &ProcessorConfig::default()).unwrap();
+        assert_eq!("1, 12.5000, 0.0000, 12.5000, false", totals.get(&1).expect("").to_str_row(1).unwrap());
+        assert_eq!("2, 2.0000, 0.0000, 2.0000, false", totals.get(&2).expect("").to_str_row(2).unwrap());
+
+        let missing = [String::from(TEST_DIR) + "0-trivial.csv", String::from(TEST_DIR) + "missing.csv"];
+        let error = compute_combined_totals(&missing, &ProcessorConfig::default()).unwrap_err();
+        assert!(error.to_string().contains("missing.csv"));
+    }
+
+    #[test]
+    fn test_skip_malformed() {
+        let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        let rows = "deposit, 1, 10, 2.0\ndeposit, 1, eleven, 3.0\nrefund, 1, 11, 3.0\ndeposit, 1, 12, 4.0\n";
+        file.write_all(format!("type, client, tx, amount\n{rows}").as_bytes()).unwrap();
+        let paths = [String::from(TEST_DIR) + "0-trivial.csv", file.path().to_str().unwrap().to_string()];
+
+        let error = compute_combined_totals(&paths, &ProcessorConfig::default()).unwrap_err();
+        assert_eq!(EXIT_SCHEMA, error.downcast_ref::<KrakenError>().unwrap().exit_code());
+        // Only the malformed rows are skipped, not the valid ones around them in the same batch
+        #[cfg(feature = "polars")]
+        let readers = [ReaderKind::Polars, ReaderKind::Fast, ReaderKind::Csv];
+        #[cfg(not(feature = "polars"))]
+        let readers = [ReaderKind::Fast, ReaderKind::Csv];
+        for reader in readers {
+            for mode in [ParallelMode::Serial, ParallelMode::Threads, ParallelMode::Rayon, ParallelMode::Actors] {
+                let input = InputOptions { reader, skip_malformed: true, ..Default::default() };
+                let config = ProcessorConfig { parallel: mode, input, ..Default::default() };
+                let totals = compute_combined_totals(&paths, &config).unwrap();
+                assert_eq!("1, 7.5000, 0.0000, 7.5000, false", totals.get(&1).expect("").to_str_row(1).unwrap());
+            }
+        }
+    }
+}
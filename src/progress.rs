@@ -0,0 +1,155 @@
+use crate::compression::Compression;
+use crate::errors::KrakenError;
+use crate::input::{InputFormat, InputOptions, InputSource, STDIN};
+use crate::structures::Transaction;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Bytes read from the start of each file to estimate its average row length.
+const SAMPLE_BYTES: u64 = 1 << 16;
+/// Minimum time between two redraws of the bar.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+/// Width of the bar itself, in characters.
+const BAR_WIDTH: usize = 30;
+
+/// Estimate the rows of `paths` from their sizes and the rows in their first `SAMPLE_BYTES`, or `None` if
+/// any of them can't be estimated: stdin, remote URLs, compressed files, and formats other than CSV and
+/// JSON Lines, whose rows aren't lines.
+pub fn estimate_rows<P: AsRef<Path>>(paths: &[P], options: &InputOptions) -> Option<u64> {
+    paths.iter().map(|path| estimate_file_rows(path.as_ref(), options)).sum()
+}
+
+fn estimate_file_rows(path: &Path, options: &InputOptions) -> Option<u64> {
+    let format = options.format.unwrap_or_else(|| InputFormat::detect(path));
+    if path == Path::new(STDIN)
+        || !matches!(format, InputFormat::Csv | InputFormat::JsonLines)
+        || Compression::detect(path).ok()? != Compression::None
+    {
+        return None;
+    }
+
+    let file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let mut sample = Vec::new();
+    file.take(SAMPLE_BYTES).read_to_end(&mut sample).ok()?;
+    if sample.len() as u64 == size {
+        // The whole file was sampled, so its lines can be counted exactly
+        return Some(sample.split(|&byte| byte == b'\n').filter(|line| !line.trim_ascii().is_empty()).count() as u64);
+    }
+    let lines = sample.iter().filter(|&&byte| byte == b'\n').count() as u64;
+    Some(size * lines.max(1) / sample.len() as u64)
+}
+
+/// Progress bar drawn on stderr: the percentage done and an ETA when the total is known, and the rows read
+/// and rate either way. The line is cleared again once the input is exhausted or the bar is dropped.
+pub struct ProgressBar {
+    /// Estimated rows of the whole input.
+    total: Option<u64>,
+    rows: u64,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl ProgressBar {
+    pub fn new(total: Option<u64>) -> Self {
+        Self { total, rows: 0, started: Instant::now(), drawn: None }
+    }
+
+    /// Count `rows` more rows read, redrawing the bar if it hasn't been for a while.
+    pub fn advance(&mut self, rows: u64) {
+        self.rows += rows;
+        if self.drawn.is_some_and(|drawn| drawn.elapsed() < REDRAW_INTERVAL) {
+            return;
+        }
+        self.drawn = Some(Instant::now());
+        // Progress is best effort: a closed stderr mustn't fail the run
+        let _ = write!(std::io::stderr().lock(), "\r{}\x1b[K", self.line(self.started.elapsed()));
+    }
+
+    /// Clear the bar, if it was ever drawn.
+    pub fn finish(&mut self) {
+        if self.drawn.take().is_some() {
+            let _ = write!(std::io::stderr().lock(), "\r\x1b[K");
+        }
+    }
+
+    /// Text of the bar after `elapsed`.
+    fn line(&self, elapsed: Duration) -> String {
+        let rate = self.rows as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        let counts = format!("{} rows, {rate:.0} rows/s", self.rows);
+        let Some(total) = self.total.filter(|&total| total > 0) else {
+            return counts;
+        };
+
+        // The total is an estimate, so the bar stops just short of full until the input runs out
+        let done = (self.rows as f64 / total as f64).min(0.99);
+        let filled = (done * BAR_WIDTH as f64) as usize;
+        let eta = if self.rows == 0 { 0 } else { (elapsed.as_secs_f64() * (1.0 - done) / done) as u64 };
+        format!(
+            "{:>3.0}% [{}{}] {counts}, ETA {}:{:02}:{:02}",
+            done * 100.0,
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            eta / 3600,
+            eta / 60 % 60,
+            eta % 60,
+        )
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Source wrapper advancing `bar` by every batch read from `source`.
+pub struct ProgressSource<S: InputSource> {
+    source: S,
+    bar: ProgressBar,
+}
+
+impl<S: InputSource> ProgressSource<S> {
+    pub fn new(source: S, bar: ProgressBar) -> Self {
+        Self { source, bar }
+    }
+}
+
+impl<S: InputSource> InputSource for ProgressSource<S> {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        let batch = self.source.next_batch();
+        match &batch {
+            Some(Ok(batch)) => self.bar.advance(batch.len() as u64),
+            Some(Err(_)) => {}
+            None => self.bar.finish(),
+        }
+        batch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::InputOptions;
+    use crate::processor::tests::TEST_DIR;
+    use crate::progress::{estimate_rows, ProgressBar};
+    use std::time::Duration;
+
+    #[test]
+    fn test_progress() {
+        // Small files fit in the sample, so their lines are counted exactly, header included
+        let paths = [String::from(TEST_DIR) + "4-oversized-withdrawal.csv"];
+        assert_eq!(Some(3), estimate_rows(&paths, &InputOptions::default()));
+        assert_eq!(None, estimate_rows(&["-"], &InputOptions::default()));
+
+        let mut bar = ProgressBar::new(Some(200));
+        bar.rows = 50;
+        assert_eq!(
+            " 25% [#######-----------------------] 50 rows, 5 rows/s, ETA 0:00:30",
+            bar.line(Duration::from_secs(10))
+        );
+        bar.total = None;
+        assert_eq!("50 rows, 5 rows/s", bar.line(Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,122 @@
+use crate::errors::KrakenError;
+use crate::input::STDIN;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// An input a run read, identified by its content, so whatever the run wrote can be traced back to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputProvenance {
+    /// The path or URL the input was read from, as given.
+    pub path: String,
+    /// SHA-256 of the file's bytes, as stored, compressed or not. Left out for stdin and URLs, which can't be read
+    /// again to hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// Rows read from the input, malformed rows ending it excepted. Left out when the run didn't count them, or
+    /// was interrupted before opening the input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<u64>,
+}
+
+/// The inputs of a run, in order, and how many rows have been read from each, shared with the `MultiSource`
+/// reading them, which counts the rows.
+#[derive(Debug, Clone)]
+pub struct Provenance {
+    inputs: Arc<Mutex<Vec<InputProvenance>>>,
+}
+
+impl Provenance {
+    pub fn new<P: AsRef<Path>>(paths: &[P]) -> Self {
+        let inputs = paths
+            .iter()
+            .map(|path| InputProvenance { path: path.as_ref().display().to_string(), sha256: None, bytes: None, rows: None })
+            .collect();
+        Self { inputs: Arc::new(Mutex::new(inputs)) }
+    }
+
+    /// Count `rows` more rows read from the input at `index`.
+    pub fn count(&self, index: usize, rows: u64) {
+        if let Some(input) = self.inputs.lock().unwrap().get_mut(index) {
+            input.rows = Some(input.rows.unwrap_or_default() + rows);
+        }
+    }
+
+    /// Every input, with its hash and size if it's a local file, and the rows counted so far. Files are hashed as
+    /// they are now, so this is best called right after they were read.
+    pub fn inputs(&self) -> Result<Vec<InputProvenance>, KrakenError> {
+        let inputs = self.inputs.lock().unwrap().clone();
+        inputs
+            .into_iter()
+            .map(|mut input| {
+                if is_file(&input.path) {
+                    let (sha256, bytes) = hash_file(Path::new(&input.path))
+                        .map_err(|e| KrakenError::InFile(input.path.clone(), Box::new(e)))?;
+                    (input.sha256, input.bytes) = (Some(sha256), Some(bytes));
+                }
+                Ok(input)
+            })
+            .collect()
+    }
+}
+
+fn is_file(path: &str) -> bool {
+    #[cfg(feature = "remote")]
+    if crate::remote::is_url(path) {
+        return false;
+    }
+    path != STDIN
+}
+
+/// The SHA-256 of the file at `path`, in hex, and its size in bytes, read in blocks.
+pub fn hash_file(path: &Path) -> Result<(String, u64), KrakenError> {
+    let mut file = File::open(path).map_err(|_| KrakenError::IO)?;
+    let (mut hasher, mut bytes) = (Sha256::new(), 0);
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        match file.read(&mut buffer).map_err(|_| KrakenError::IO)? {
+            0 => break,
+            read => {
+                hasher.update(&buffer[..read]);
+                bytes += read as u64;
+            }
+        }
+    }
+    Ok((hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect(), bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{rows, InputOptions, MultiSource, STDIN};
+    use crate::processor::tests::TEST_DIR;
+    use crate::provenance::{hash_file, InputProvenance, Provenance};
+    use std::path::Path;
+
+    #[test]
+    fn test_provenance() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("empty.csv");
+        std::fs::write(&path, "").unwrap();
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!((String::from(empty), 0), hash_file(&path).unwrap());
+
+        let paths = [String::from(TEST_DIR) + "0-trivial.csv", String::from(TEST_DIR) + "1-dispute-after-withdraw.csv"];
+        let provenance = Provenance::new(&paths);
+        let mut source = MultiSource::new(&paths, InputOptions::default()).with_provenance(provenance.clone());
+        let read = rows(&mut source).count() as u64;
+        let inputs = provenance.inputs().unwrap();
+        assert_eq!(vec![Some(5), Some(read - 5)], inputs.iter().map(|input| input.rows).collect::<Vec<_>>());
+        let (sha256, bytes) = hash_file(Path::new(&paths[0])).unwrap();
+        assert_eq!((Some(sha256), Some(bytes)), (inputs[0].sha256.clone(), inputs[0].bytes));
+
+        // Stdin can't be hashed, and inputs never read have no rows
+        let inputs = Provenance::new(&[STDIN]).inputs().unwrap();
+        assert_eq!(vec![InputProvenance { path: String::from(STDIN), sha256: None, bytes: None, rows: None }], inputs);
+        assert_eq!(r#"{"path":"-"}"#, serde_json::to_string(&inputs[0]).unwrap());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::engine::Engine;
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::parse_message;
+use crate::snapshot::Snapshot;
+use crate::structures::ClientAccount;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Default time between two checkpoints while consuming.
+pub const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Message brokers the `consume` subcommand can read from.
+#[cfg(feature = "queue")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Broker {
+    #[cfg(feature = "kafka")]
+    Kafka,
+    #[cfg(feature = "amqp")]
+    Amqp,
+    #[cfg(feature = "nats")]
+    Nats,
+}
+
+/// Broker used when none is named: Kafka, when it is compiled in.
+#[cfg(feature = "kafka")]
+pub const DEFAULT_BROKER: Option<Broker> = Some(Broker::Kafka);
+#[cfg(all(feature = "queue", not(feature = "kafka")))]
+pub const DEFAULT_BROKER: Option<Broker> = None;
+
+#[cfg(feature = "queue")]
+impl TryFrom<&str> for Broker {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            #[cfg(feature = "kafka")]
+            "kafka" => Ok(Broker::Kafka),
+            #[cfg(feature = "amqp")]
+            "amqp" | "rabbitmq" => Ok(Broker::Amqp),
+            #[cfg(feature = "nats")]
+            "nats" | "jetstream" => Ok(Broker::Nats),
+            _ => Err(KrakenError::InvalidArgument(format!("Unknown broker: {value}"))),
+        }
+    }
+}
+
+/// Where to consume from, and where to keep the state that makes consuming restartable.
+#[derive(Debug, Clone)]
+pub struct ConsumeConfig {
+    /// Brokers to connect to: `host:port` for Kafka, an `amqp://` URI, or NATS server URLs.
+    pub brokers: Vec<String>,
+    /// Kafka topic, AMQP queue, or JetStream stream to read.
+    pub topic: String,
+    /// Kafka consumer group, AMQP consumer tag, or durable JetStream consumer.
+    pub group: String,
+    /// Snapshot file holding the balances and the positions they reflect.
+    pub state: PathBuf,
+    pub checkpoint_interval: Duration,
+}
+
+/// A message taken from a queue, holding one transaction.
+#[derive(Debug)]
+pub struct QueueMessage {
+    /// A JSON object or a `type, client, tx, amount` row, as accepted by `parse_message`.
+    pub payload: Vec<u8>,
+    /// Name of the partition or stream the message came from and its position there, for brokers that
+    /// number their messages. Messages a snapshot already reflects are recognized by it and skipped.
+    pub position: Option<(String, i64)>,
+}
+
+/// A broker delivering transactions asynchronously, one message at a time.
+///
+/// Messages must stay unacknowledged, and so eligible for redelivery, until `ack` is called. `consume_async`
+/// only calls it once every message received so far has been applied and checkpointed.
+pub trait AsyncTransactionSource {
+    /// Wait for the next message, or `None` once the source is closed.
+    fn recv(&mut self) -> impl Future<Output = Option<Result<QueueMessage, KrakenError>>>;
+
+    /// Acknowledge every message received so far.
+    fn ack(&mut self) -> impl Future<Output = Result<(), KrakenError>>;
+}
+
+/// Resume from the snapshot at `state`, or start empty if there is none yet.
+pub fn restore_state(
+    state: &Path,
+    budget: Option<MemoryBudget>,
+) -> Result<(Engine, BTreeMap<String, i64>), KrakenError> {
+    if !state.exists() {
+        return Ok((Engine::with_budget(budget), BTreeMap::new()));
+    }
+
+    let snapshot = Snapshot::load(state)?;
+    let offsets = snapshot.offsets.clone();
+    Ok((Engine::from_accounts(snapshot.restore(budget.as_ref())?, budget), offsets))
+}
+
+/// Durably write the accounts and the positions they reflect to `state`.
+pub fn checkpoint(engine: &Engine, offsets: &BTreeMap<String, i64>, state: &Path) -> Result<(), KrakenError> {
+    let mut snapshot = Snapshot::capture(engine.accounts())?;
+    snapshot.offsets = offsets.clone();
+    snapshot.save(state)
+}
+
+/// Apply transactions from `source` until it closes or an error occurs.
+///
+/// Every `config.checkpoint_interval`, if anything has been received, the accounts are checkpointed to
+/// `config.state`, then the messages are acknowledged and `on_checkpoint` is called with the balances.
+/// Malformed messages are reported on `stderr` and skipped.
+pub async fn consume_async<S, F>(
+    source: &mut S,
+    config: &ConsumeConfig,
+    budget: Option<MemoryBudget>,
+    mut on_checkpoint: F,
+) -> Result<(), KrakenError>
+where
+    S: AsyncTransactionSource,
+    F: FnMut(&HashMap<u32, ClientAccount>),
+{
+    let (mut engine, mut offsets) = restore_state(&config.state, budget)?;
+
+    let mut last_checkpoint = Instant::now();
+    let mut pending = false;
+    loop {
+        // Only wait as long as the next checkpoint allows, so a quiet queue doesn't hold acknowledgements back
+        let received = if pending {
+            let wait = config.checkpoint_interval.saturating_sub(last_checkpoint.elapsed());
+            tokio::time::timeout(wait, source.recv()).await.ok()
+        } else {
+            Some(source.recv().await)
+        };
+
+        let closed = matches!(received, Some(None));
+        if let Some(Some(message)) = received {
+            let message = message?;
+            pending = true;
+            if let Some((key, offset)) = message.position {
+                if offset < offsets.get(&key).copied().unwrap_or(0) {
+                    continue;
+                }
+                offsets.insert(key, offset + 1);
+            }
+
+            match parse_message(&message.payload) {
+                // Swallow results since we aren't tracking them
+                Ok(transaction) => {
+                    let _ = engine.apply(transaction);
+                }
+                Err(e) => warn!(code = e.code(), error = %e, "Skipping malformed message"),
+            }
+        }
+
+        if pending && (closed || last_checkpoint.elapsed() >= config.checkpoint_interval) {
+            checkpoint(&engine, &offsets, &config.state)?;
+            source.ack().await?;
+
+            on_checkpoint(engine.accounts());
+            last_checkpoint = Instant::now();
+            pending = false;
+        }
+        if closed {
+            return Ok(());
+        }
+    }
+}
+
+/// Consume from `broker` until an error occurs. See `consume_async` and `kafka::consume`.
+#[cfg(feature = "queue")]
+pub fn consume<F>(
+    broker: Broker,
+    config: &ConsumeConfig,
+    budget: Option<MemoryBudget>,
+    on_checkpoint: F,
+) -> Result<(), KrakenError>
+where
+    F: FnMut(&HashMap<u32, ClientAccount>),
+{
+    #[cfg(any(feature = "amqp", feature = "nats"))]
+    let runtime = || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|_| KrakenError::IO)
+    };
+
+    match broker {
+        #[cfg(feature = "kafka")]
+        Broker::Kafka => crate::kafka::consume(config, budget, on_checkpoint),
+        #[cfg(feature = "amqp")]
+        Broker::Amqp => runtime()?.block_on(async {
+            let mut source = crate::amqp::AmqpSource::connect(&config.brokers[0], &config.topic, &config.group).await?;
+            consume_async(&mut source, config, budget, on_checkpoint).await
+        }),
+        #[cfg(feature = "nats")]
+        Broker::Nats => runtime()?.block_on(async {
+            let mut source = crate::nats::NatsSource::connect(&config.brokers, &config.topic, &config.group).await?;
+            consume_async(&mut source, config, budget, on_checkpoint).await
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::KrakenError;
+    use crate::queue::{consume_async, AsyncTransactionSource, ConsumeConfig, QueueMessage};
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    /// In-memory queue that keeps every message until it is acknowledged, like a broker would.
+    struct TestQueue {
+        messages: VecDeque<&'static str>,
+        received: usize,
+        acked: usize,
+    }
+
+    impl AsyncTransactionSource for TestQueue {
+        async fn recv(&mut self) -> Option<Result<QueueMessage, KrakenError>> {
+            let payload = self.messages.get(self.received)?;
+            self.received += 1;
+            Some(Ok(QueueMessage {
+                payload: payload.as_bytes().to_vec(),
+                position: Some((String::from("test"), self.received as i64 - 1)),
+            }))
+        }
+
+        async fn ack(&mut self) -> Result<(), KrakenError> {
+            self.acked = self.received;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_consume_resumes_from_checkpoint() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = ConsumeConfig {
+            brokers: Vec::new(),
+            topic: String::from("transactions"),
+            group: String::from("test"),
+            state: directory.path().join("state.json"),
+            checkpoint_interval: Duration::from_secs(60),
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        let mut queue = TestQueue {
+            messages: VecDeque::from(["deposit, 1, 1, 2.0", r#"{"type":"deposit","client":1,"tx":2,"amount":3.0}"#, "bogus"]),
+            received: 0,
+            acked: 0,
+        };
+        runtime.block_on(consume_async(&mut queue, &config, None, |_| {})).unwrap();
+        assert_eq!(3, queue.acked);
+
+        // Redelivering everything, as a broker would after a crash before acking, must not apply anything twice
+        queue.messages.push_back("withdrawal, 1, 3, 1.0");
+        queue.received = 0;
+        let mut balances = String::new();
+        runtime
+            .block_on(consume_async(&mut queue, &config, None, |accounts| balances = accounts[&1].to_str_row(1).unwrap()))
+            .unwrap();
+        assert_eq!("1, 4.0000, 0.0000, 4.0000, false", balances);
+    }
+}
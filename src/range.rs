@@ -0,0 +1,116 @@
+use crate::dates::parse_timestamp;
+use crate::errors::KrakenError;
+use crate::structures::Transaction;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// One end of a `Range`: a row of the input, counting from 1 across every input, a tx id, or a time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bound {
+    Row(u64),
+    Tx(u32),
+    /// The first and last second, since 1970-01-01 UTC, of the time given: the whole day for a date, or a single
+    /// second for a time.
+    Time(i64, i64),
+}
+
+impl TryFrom<&str> for Bound {
+    type Error = KrakenError;
+
+    /// Parse `row:N`, `tx:N`, or a `YYYY-MM-DD` date or RFC 3339 time as `dates::parse_timestamp` reads them. Bare
+    /// numbers are refused, as they could be any of the three.
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let invalid = || KrakenError::InvalidArgument(format!("Expected row:N, tx:N, or a date or time: {value}"));
+        let value = value.trim();
+        if let Some(row) = value.strip_prefix("row:") {
+            return row.parse().map(Bound::Row).map_err(|_| invalid());
+        }
+        if let Some(tx) = value.strip_prefix("tx:") {
+            return tx.parse().map(Bound::Tx).map_err(|_| invalid());
+        }
+        if value.parse::<i64>().is_ok() {
+            return Err(invalid());
+        }
+        let first = parse_timestamp(value).map_err(|_| invalid())?;
+        match value.len() {
+            10 => Ok(Bound::Time(first, first + SECONDS_PER_DAY - 1)),
+            _ => Ok(Bound::Time(first, first)),
+        }
+    }
+}
+
+/// The part of the input a run covers, from the `from` bound to the `to` bound, both included. The bounds are
+/// independent, so a range may start at a row and end at a time. There's no telling where transactions without a time
+/// fall, so a range bounded by one refuses them.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Range {
+    pub from: Option<Bound>,
+    pub to: Option<Bound>,
+}
+
+impl Range {
+    /// Whether `transaction`, read at `row`, is in the range. Disputes, resolves, and chargebacks are placed by the tx
+    /// id they refer to. A transaction without a time is an error if either bound is a time.
+    pub fn contains(&self, row: u64, transaction: &Transaction) -> Result<bool, KrakenError> {
+        let time = || {
+            transaction
+                .timestamp
+                .ok_or_else(|| KrakenError::Parse(format!("Row {row} has no timestamp to place it in a range bounded by a time")))
+        };
+        let after_from = match self.from {
+            None => true,
+            Some(Bound::Row(from)) => row >= from,
+            Some(Bound::Tx(from)) => transaction.tx >= from,
+            Some(Bound::Time(from, _)) => time()? >= from,
+        };
+        let before_to = match self.to {
+            None => true,
+            Some(Bound::Row(to)) => row <= to,
+            Some(Bound::Tx(to)) => transaction.tx <= to,
+            Some(Bound::Time(_, to)) => time()? <= to,
+        };
+        Ok(after_from && before_to)
+    }
+
+    /// Whether every row after `row` is past the end of the range, so the input needn't be read any further.
+    pub fn ends_by(&self, row: u64) -> bool {
+        matches!(self.to, Some(Bound::Row(to)) if row >= to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dates::parse_timestamp;
+    use crate::range::{Bound, Range};
+    use crate::structures::Transaction;
+
+    #[test]
+    fn test_range() {
+        assert_eq!(Bound::Row(10), Bound::try_from("row:10").unwrap());
+        assert_eq!(Bound::Tx(7), Bound::try_from("tx:7").unwrap());
+        let january = parse_timestamp("2024-01-01").unwrap();
+        assert_eq!(Bound::Time(january, january + 86_399), Bound::try_from("2024-01-01").unwrap());
+        assert_eq!(Bound::Time(january + 60, january + 60), Bound::try_from("2024-01-01T00:01:00Z").unwrap());
+        for invalid in ["10", "row:x", "tx:-1", "January"] {
+            assert!(Bound::try_from(invalid).is_err(), "{invalid}");
+        }
+
+        // Only January, with the whole of its last day
+        let range = Range { from: Some(Bound::try_from("2024-01-01").unwrap()), to: Some(Bound::try_from("2024-01-31").unwrap()) };
+        let at = |time: Option<&str>| {
+            let mut transaction = Transaction::try_from("deposit, 1, 5, 1.0").unwrap();
+            transaction.timestamp = time.map(|time| parse_timestamp(time).unwrap());
+            transaction
+        };
+        assert!(range.contains(1, &at(Some("2024-01-31T23:59:59Z"))).unwrap());
+        assert!(!range.contains(1, &at(Some("2024-02-01"))).unwrap());
+        assert!(!range.contains(1, &at(Some("2023-12-31T23:59:59Z"))).unwrap());
+        let error = range.contains(4, &at(None)).unwrap_err();
+        assert!(error.to_string().contains("Row 4 has no timestamp"), "{error}");
+
+        let range = Range { from: Some(Bound::Tx(5)), to: Some(Bound::Row(3)) };
+        assert!(range.contains(3, &at(None)).unwrap() && !range.contains(4, &at(None)).unwrap());
+        assert!(!Range { from: Some(Bound::Tx(6)), to: None }.contains(1, &at(None)).unwrap());
+        assert!(range.ends_by(3) && !range.ends_by(2));
+    }
+}
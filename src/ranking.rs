@@ -0,0 +1,128 @@
+use crate::errors::KrakenError;
+use crate::structures::ClientAccount;
+use std::collections::HashMap;
+use std::fmt;
+
+pub const HEADER: &str = "rank, client, available, held, total, volume, locked";
+
+/// What accounts are ranked by.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RankBy {
+    /// Total funds, available and held.
+    #[default]
+    Total,
+    /// Funds held by open disputes.
+    Held,
+    /// Amount deposited and withdrawn.
+    Volume,
+}
+
+impl TryFrom<&str> for RankBy {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "total" => Ok(RankBy::Total),
+            "held" => Ok(RankBy::Held),
+            "volume" => Ok(RankBy::Volume),
+            _ => Err(KrakenError::Enum(format!("Invalid String for RankBy: {value}"))),
+        }
+    }
+}
+
+impl RankBy {
+    fn measure(self, account: &ClientAccount) -> Result<f64, KrakenError> {
+        match self {
+            RankBy::Total => account.total(),
+            RankBy::Held => Ok(account.held),
+            RankBy::Volume => Ok(account.volume),
+        }
+    }
+}
+
+/// One of the largest accounts, and its place among them, counting from 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankedAccount {
+    pub rank: usize,
+    pub client: u32,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub volume: f64,
+    pub locked: bool,
+}
+
+impl fmt::Display for RankedAccount {
+    /// A `rank, client, available, held, total, volume, locked` row.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}, {:.4}, {:.4}, {:.4}, {:.4}, {}", self.rank, self.client, self.available, self.held,
+               self.total, self.volume, self.locked)
+    }
+}
+
+/// The `n` largest of `accounts` by `by`, largest first, ties going to the lower client id. Only the `n` are sorted,
+/// so ranking a few of millions of accounts takes little more than a pass over them. Fails as `ClientAccount::total`.
+pub fn top_accounts(accounts: &HashMap<u32, ClientAccount>, n: usize, by: RankBy) -> Result<Vec<RankedAccount>, KrakenError> {
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let mut ranked: Vec<(f64, u32)> =
+        accounts.iter().map(|(client, account)| Ok((by.measure(account)?, *client))).collect::<Result<_, KrakenError>>()?;
+    let order = |a: &(f64, u32), b: &(f64, u32)| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1));
+    if n < ranked.len() {
+        ranked.select_nth_unstable_by(n - 1, order);
+        ranked.truncate(n);
+    }
+    ranked.sort_unstable_by(|a, b| order(a, b));
+    ranked
+        .into_iter()
+        .enumerate()
+        .map(|(index, (_, client))| {
+            let account = &accounts[&client];
+            Ok(RankedAccount {
+                rank: index + 1,
+                client,
+                available: account.available,
+                held: account.held,
+                total: account.total()?,
+                volume: account.volume,
+                locked: account.locked,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::ranking::{top_accounts, RankBy};
+    use crate::structures::Transaction;
+
+    #[test]
+    fn test_top_accounts() {
+        let rows = [
+            "deposit, 1, 1, 10.0",
+            "withdrawal, 1, 2, 9.0",
+            "deposit, 2, 3, 4.0",
+            "deposit, 3, 4, 3.0",
+            "dispute, 3, 4,",
+            "deposit, 4, 5, 4.0",
+            // Refused, so not counted in the volume
+            "withdrawal, 4, 6, 100.0",
+        ];
+        let mut engine = Engine::new();
+        engine.process(rows.iter().map(|row| Transaction::try_from(*row).unwrap()));
+        let clients = |by, n| top_accounts(engine.accounts(), n, by).unwrap().iter().map(|a| a.client).collect::<Vec<_>>();
+
+        // Ties go to the lower client id
+        assert_eq!(vec![2, 4, 3], clients(RankBy::Total, 3));
+        assert_eq!(vec![3], clients(RankBy::Held, 1));
+        assert_eq!(vec![1, 2, 4, 3], clients(RankBy::Volume, 10));
+        assert!(clients(RankBy::Total, 0).is_empty());
+
+        let top = top_accounts(engine.accounts(), 1, RankBy::Volume).unwrap();
+        assert_eq!("1, 1, 1.0000, 0.0000, 1.0000, 19.0000, false", top[0].to_string());
+        assert_eq!(RankBy::Held, RankBy::try_from("held").unwrap());
+        assert!(RankBy::try_from("largest").is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::diff::same_amount;
+use crate::errors::KrakenError;
+use crate::output::AccountSummary;
+use crate::redact::{Amount, Client};
+use crate::structures::ClientAccount;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// A client whose computed balances don't match the expected ones, or who is only found on one side.
+#[derive(Debug, Clone)]
+pub struct Discrepancy {
+    pub client: u32,
+    pub expected: Option<AccountSummary>,
+    pub actual: Option<AccountSummary>,
+}
+
+impl Discrepancy {
+    /// Names of the fields that differ, when the client is found on both sides.
+    pub fn fields(&self) -> Vec<&'static str> {
+        let (Some(expected), Some(actual)) = (&self.expected, &self.actual) else {
+            return Vec::new();
+        };
+        let amounts = [
+            ("available", expected.available, actual.available),
+            ("held", expected.held, actual.held),
+            ("total", expected.total, actual.total),
+        ];
+        let mut fields: Vec<_> = amounts.into_iter().filter(|(_, e, a)| !same_amount(*e, *a)).map(|(name, ..)| name).collect();
+        if expected.locked != actual.locked {
+            fields.push("locked");
+        }
+        fields
+    }
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.expected, &self.actual) {
+            (Some(expected), Some(actual)) => {
+                let amounts = |actual, expected| format!("{:.4}, expected {:.4}", Amount(actual), Amount(expected));
+                let fields = self.fields().into_iter().map(|field| match field {
+                    "available" => format!("available {}", amounts(actual.available, expected.available)),
+                    "held" => format!("held {}", amounts(actual.held, expected.held)),
+                    "total" => format!("total {}", amounts(actual.total, expected.total)),
+                    _ => format!("locked {}, expected {}", actual.locked, expected.locked),
+                });
+                write!(f, "client {}: {}", Client(self.client), fields.collect::<Vec<_>>().join("; "))
+            }
+            (Some(_), None) => write!(f, "client {}: expected, but has no account", Client(self.client)),
+            _ => write!(f, "client {}: has an account, but isn't expected", Client(self.client)),
+        }
+    }
+}
+
+/// Compare the computed `accounts` with the `expected` balances, client by client. Amounts are compared to the
+/// four places they are reported to, so a report read back as the expected balances always matches.
+pub fn reconcile(
+    expected: &BTreeMap<u32, AccountSummary>,
+    accounts: &HashMap<u32, ClientAccount>,
+) -> Result<Vec<Discrepancy>, KrakenError> {
+    let mut clients: Vec<u32> = expected.keys().chain(accounts.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+
+    let mut discrepancies = Vec::new();
+    for client in clients {
+        let discrepancy = Discrepancy {
+            client,
+            expected: expected.get(&client).cloned(),
+            actual: accounts.get(&client).map(|account| AccountSummary::new(client, account)).transpose()?,
+        };
+        if !matches!((&discrepancy.expected, &discrepancy.actual), (Some(_), Some(_))) || !discrepancy.fields().is_empty() {
+            discrepancies.push(discrepancy);
+        }
+    }
+    Ok(discrepancies)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::read_balances;
+    use crate::reconcile::reconcile;
+    use crate::structures::ClientAccount;
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    #[test]
+    fn test_reconcile() {
+        let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        file.write_all(b"client, available, held, total, locked\n1, 1.5, 0.0, 1.5, false\n2, 3.0, 1.0, , true\n4, 0, 0, 0, false\n")
+            .unwrap();
+        let expected = read_balances(file.path()).unwrap();
+
+        let account = |available, held, locked| ClientAccount { available, held, locked, ..Default::default() };
+        let accounts = HashMap::from([
+            (1, account(1.50001, 0.0, false)),
+            (2, account(2.0, 2.0, true)),
+            (3, account(5.0, 0.0, false)),
+        ]);
+        let discrepancies: Vec<String> = reconcile(&expected, &accounts).unwrap().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            vec![
+                "client 2: available 2.0000, expected 3.0000; held 2.0000, expected 1.0000",
+                "client 3: has an account, but isn't expected",
+                "client 4: expected, but has no account",
+            ],
+            discrepancies
+        );
+    }
+}
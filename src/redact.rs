@@ -0,0 +1,131 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// Fields of log records holding an amount, bucketed when redacting.
+const AMOUNT_FIELDS: [&str; 5] = ["amount", "available", "held", "total", "asserted"];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SALT: OnceLock<[u8; 32]> = OnceLock::new();
+
+/// Redact client ids and amounts from everything the process logs, the errors it reports, and the audit log it
+/// writes from now on, for environments where they mustn't be kept. Client ids become pseudonyms, the same for a
+/// client throughout the process but not from one run to the next, and amounts their order of magnitude.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The pseudonym of `client`: the start of the SHA-256 of the client id salted with `salt`, which can't be
+/// reversed by hashing every id without the salt.
+pub fn pseudonym(salt: &[u8], client: u64) -> String {
+    let hash = Sha256::new().chain_update(salt).chain_update(client.to_le_bytes()).finalize();
+    format!("c_{}", hash[..6].iter().map(|byte| format!("{byte:02x}")).collect::<String>())
+}
+
+/// The order of magnitude of `amount`, as the range holding it, such as `10..100` or `-1..0`. Zero, and amounts
+/// that aren't finite, are left as they are.
+pub fn bucket(amount: f64) -> String {
+    if amount == 0.0 || !amount.is_finite() {
+        return format!("{}", amount.abs());
+    }
+    let magnitude = amount.abs();
+    let low = match magnitude < 1.0 {
+        true => 0.0,
+        false => {
+            let low = 10f64.powi(magnitude.log10().floor() as i32);
+            // log10 may round down an exact power of ten
+            if magnitude >= low * 10.0 { low * 10.0 } else { low }
+        }
+    };
+    let high = if low == 0.0 { 1.0 } else { low * 10.0 };
+    match amount > 0.0 {
+        true => format!("{low}..{high}"),
+        false if low == 0.0 => format!("-{high}..0"),
+        false => format!("-{high}..-{low}"),
+    }
+}
+
+/// `client`, or its pseudonym for the process when redacting.
+pub fn client(client: u64) -> String {
+    match enabled() {
+        true => pseudonym(SALT.get_or_init(salt), client),
+        false => client.to_string(),
+    }
+}
+
+/// `value` of the log field `name`, redacted if it's a client id, an amount, or a memo, which is free text, and
+/// redacting is enabled. Values that don't parse as what the field should hold are left out, as there's no telling
+/// what they hold.
+pub fn field(name: &str, value: &str) -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    match name {
+        "client" => Some(value.parse().map_or_else(|_| String::from("redacted"), client)),
+        "memo" => Some(String::from("redacted")),
+        name if AMOUNT_FIELDS.contains(&name) => Some(value.parse().map_or_else(|_| String::from("redacted"), bucket)),
+        _ => None,
+    }
+}
+
+/// Displays a client id, or its pseudonym when redacting.
+#[derive(Debug, Clone, Copy)]
+pub struct Client(pub u32);
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&client(u64::from(self.0)))
+    }
+}
+
+/// Displays an amount, to the precision asked for, or its order of magnitude when redacting.
+#[derive(Debug, Clone, Copy)]
+pub struct Amount(pub f64);
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (enabled(), f.precision()) {
+            (true, _) => f.write_str(&bucket(self.0)),
+            (false, Some(precision)) => write!(f, "{:.*}", precision, self.0),
+            (false, None) => write!(f, "{}", self.0),
+        }
+    }
+}
+
+fn salt() -> [u8; 32] {
+    let mut salt = [0; 32];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::redact::{bucket, pseudonym, Amount, Client};
+
+    #[test]
+    fn test_redact() {
+        let buckets = [(0.0, "0"), (0.5, "0..1"), (1.0, "1..10"), (99.99, "10..100"), (1000.0, "1000..10000"), (-0.25, "-1..0")];
+        for (amount, expected) in buckets {
+            assert_eq!(expected, bucket(amount), "{amount}");
+        }
+        assert_eq!("-100..-10", bucket(-42.0));
+        assert_eq!("NaN", bucket(f64::NAN));
+
+        // Pseudonyms are stable for a salt, and differ with the client or the salt
+        let client = pseudonym(b"salt", 7);
+        assert_eq!(client, pseudonym(b"salt", 7));
+        assert!(client.starts_with("c_") && client.len() == 14);
+        assert_ne!(client, pseudonym(b"salt", 8));
+        assert_ne!(client, pseudonym(b"pepper", 7));
+
+        // Unless redacting, the values are displayed as they are
+        assert_eq!("7 1.5000 1.5", format!("{} {:.4} {}", Client(7), Amount(1.5), Amount(1.5)));
+    }
+}
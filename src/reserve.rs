@@ -0,0 +1,85 @@
+use crate::rules::Rules;
+use crate::structures::ClientAccount;
+use std::collections::HashMap;
+use std::fmt;
+
+pub const HEADER: &str = "client, available, reserve, headroom, locked";
+
+/// An account whose available funds are within the margin of its reserve, or already below it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NearReserve {
+    pub client: u32,
+    pub available: f64,
+    pub reserve: f64,
+    /// Available funds above the reserve, negative when below it.
+    pub headroom: f64,
+    pub locked: bool,
+}
+
+impl fmt::Display for NearReserve {
+    /// A `client, available, reserve, headroom, locked` row.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {:.4}, {:.4}, {:.4}, {}", self.client, self.available, self.reserve, self.headroom, self.locked)
+    }
+}
+
+/// The accounts of `accounts` with a reserve under `rules` and no more than `margin` available above it, closest to
+/// or furthest below it first, ties going to the lower client id. Accounts can end up below their reserve, as
+/// disputes and chargebacks are never refused for it.
+pub fn near_reserve(accounts: &HashMap<u32, ClientAccount>, rules: &Rules, margin: f64) -> Vec<NearReserve> {
+    let mut near: Vec<NearReserve> = accounts
+        .iter()
+        .filter_map(|(client, account)| {
+            let reserve = rules.reserve_of(*client)?;
+            let headroom = account.available - reserve;
+            (headroom <= margin).then_some(NearReserve {
+                client: *client,
+                available: account.available,
+                reserve,
+                headroom,
+                locked: account.locked,
+            })
+        })
+        .collect();
+    near.sort_unstable_by(|a, b| a.headroom.total_cmp(&b.headroom).then(a.client.cmp(&b.client)));
+    near
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::errors::KrakenError;
+    use crate::reserve::near_reserve;
+    use crate::rules::Rules;
+    use crate::structures::Transaction;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_reserve() {
+        let rules = Rules { reserve: Some(5.0), reserves: BTreeMap::from([(2, 0.0), (3, 20.0)]), ..Default::default() };
+        let mut engine = Engine::new().with_rules(Some(Arc::new(rules.clone())));
+        let mut apply = |row: &str| engine.apply(Transaction::try_from(row).unwrap());
+        apply("deposit, 1, 1, 10.0").unwrap();
+        let result = apply("withdrawal, 1, 2, 6.0");
+        assert!(matches!(result, Err(KrakenError::BelowReserve(1, 5.0))), "{result:?}");
+        // Down to the reserve, but no further, and short funds are still short
+        apply("withdrawal, 1, 3, 5.0").unwrap();
+        assert!(matches!(apply("authorize, 1, 4, 0.5"), Err(KrakenError::BelowReserve(1, _))));
+        assert!(matches!(apply("withdrawal, 1, 5, 11.0"), Err(KrakenError::InsufficientFunds(1))));
+        // Client 2 has no reserve to keep, and client 3 a larger one
+        apply("deposit, 2, 6, 3.0").unwrap();
+        apply("withdrawal, 2, 7, 3.0").unwrap();
+        apply("deposit, 3, 8, 30.0").unwrap();
+        apply("deposit, 3, 9, 4.0").unwrap();
+        assert!(apply("withdrawal, 3, 10, 15.0").is_err());
+        // Disputes aren't refused for it
+        apply("dispute, 3, 8,").unwrap();
+
+        let near = near_reserve(engine.accounts(), &rules, 1.0);
+        let rows: Vec<String> = near.iter().map(|account| account.to_string()).collect();
+        let expected = ["3, 4.0000, 20.0000, -16.0000, false", "1, 5.0000, 5.0000, 0.0000, false", "2, 0.0000, 0.0000, 0.0000, false"];
+        assert_eq!(expected.to_vec(), rows);
+        assert!(near_reserve(engine.accounts(), &Rules::default(), 100.0).is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::errors::KrakenError;
+use crate::minor_units::AmountBackend;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Policies deciding what disputes, chargebacks, authorizations, and withdrawals may do, read from the `[rules]`
+/// section of the config file rather than hard-coded in `ClientAccount::apply_transaction`. The defaults are the
+/// processor's usual behavior.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    /// Whether withdrawals can be disputed as well as deposits. A disputed withdrawal holds its amount without
+    /// taking it from the available funds; a chargeback then returns it to them, and a resolve lets the
+    /// withdrawal stand.
+    pub withdrawals_disputable: bool,
+    /// Whether a chargeback locks the account.
+    pub chargeback_locks: bool,
+    /// Whether reversing a chargeback unlocks the account, once no other chargeback of it stands.
+    pub reversal_unlocks: bool,
+    /// How many disputes each client may have open at once. Unlimited when `None`.
+    pub max_open_disputes: Option<u32>,
+    /// Days after which an authorization not captured expires, releasing its hold, checked against the time of each
+    /// later transaction of its client. Authorizations never expire when `None`, nor do those without a time.
+    pub authorization_expiry_days: Option<u32>,
+    /// Later transactions of the same client, applied or refused, after which a deposit becomes available: the last
+    /// of them already sees it available. Pending until then.
+    pub deposit_delay_rows: Option<u32>,
+    /// Days after which a deposit becomes available, pending until then, checked against the time of each later
+    /// transaction of its client. With both delays, a deposit becomes available at the first of them.
+    pub deposit_delay_days: Option<u32>,
+    /// Available funds every client must keep: a withdrawal, or an authorization, that would leave less is refused.
+    /// No reserve when `None`.
+    pub reserve: Option<f64>,
+    /// Reserves of particular clients, in place of `reserve`.
+    pub reserves: BTreeMap<u32, f64>,
+    /// What becomes of deposits into locked accounts.
+    pub locked_deposits: LockedPolicy,
+    /// What becomes of withdrawals from locked accounts. Authorizations are only accepted under `Accept`, as they
+    /// would otherwise be captured while the account is locked.
+    pub locked_withdrawals: LockedPolicy,
+    /// How amounts are added up and compared, set by `--amounts` rather than this section.
+    #[serde(skip)]
+    pub amounts: AmountBackend,
+}
+
+/// What a locked account does with a transaction of a type it may refuse.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockedPolicy {
+    /// Refused as the account is locked.
+    #[default]
+    Reject,
+    /// Accepted, with its amount held until the account is unlocked: a deposit then becomes available, and a
+    /// withdrawal is paid out.
+    Hold,
+    /// Accepted as if the account weren't locked.
+    Accept,
+}
+
+impl TryFrom<&str> for LockedPolicy {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "reject" => Ok(LockedPolicy::Reject),
+            "hold" => Ok(LockedPolicy::Hold),
+            "accept" => Ok(LockedPolicy::Accept),
+            _ => Err(KrakenError::Enum(format!("Invalid String for LockedPolicy: {value}"))),
+        }
+    }
+}
+
+static DEFAULT: Rules = Rules {
+    withdrawals_disputable: false,
+    chargeback_locks: true,
+    reversal_unlocks: false,
+    max_open_disputes: None,
+    authorization_expiry_days: None,
+    deposit_delay_rows: None,
+    deposit_delay_days: None,
+    reserve: None,
+    reserves: BTreeMap::new(),
+    locked_deposits: LockedPolicy::Reject,
+    locked_withdrawals: LockedPolicy::Reject,
+    amounts: AmountBackend::Float,
+};
+
+impl Default for Rules {
+    fn default() -> Self {
+        DEFAULT.clone()
+    }
+}
+
+static RULES: OnceLock<Rules> = OnceLock::new();
+
+/// Apply `rules` to every transaction of the process from now on. They can only be installed once, before any
+/// transaction is applied; installing the same rules again does nothing.
+pub fn install(rules: Rules) -> Result<(), KrakenError> {
+    let installed = RULES.get_or_init(|| rules.clone());
+    match *installed == rules {
+        true => Ok(()),
+        false => Err(KrakenError::InvalidArgument(String::from("Different rules are already installed"))),
+    }
+}
+
+impl Rules {
+    /// The reserve `client` must keep, if any.
+    pub fn reserve_of(&self, client: u32) -> Option<f64> {
+        self.reserves.get(&client).copied().or(self.reserve)
+    }
+}
+
+/// The rules installed, or the defaults.
+pub fn current() -> &'static Rules {
+    RULES.get().unwrap_or(&DEFAULT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::KrakenError;
+    use crate::rules::{LockedPolicy, Rules};
+    use crate::structures::{ClientAccount, LockReason, Transaction};
+
+    fn apply(account: &mut ClientAccount, row: &str, rules: &Rules) -> Result<(), KrakenError> {
+        account.apply_with_rules(Transaction::try_from(row).unwrap(), rules)
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = Rules { withdrawals_disputable: true, chargeback_locks: false, max_open_disputes: Some(1), ..Default::default() };
+        let mut account = ClientAccount::default();
+        apply(&mut account, "deposit, 1, 1, 10.0", &rules).unwrap();
+        apply(&mut account, "deposit, 1, 2, 5.0", &rules).unwrap();
+        apply(&mut account, "withdrawal, 1, 3, 4.0", &rules).unwrap();
+        assert!(apply(&mut account, "dispute, 1, 3, ", &Rules::default()).is_err());
+
+        // A disputed withdrawal holds its amount, and a chargeback returns it
+        apply(&mut account, "dispute, 1, 3, ", &rules).unwrap();
+        assert_eq!((11.0, 4.0), (account.available, account.held));
+        assert!(matches!(apply(&mut account, "dispute, 1, 1, ", &rules), Err(KrakenError::DisputeStateError(_))));
+        apply(&mut account, "chargeback, 1, 3, ", &rules).unwrap();
+        assert_eq!((15.0, 0.0, false), (account.available, account.held, account.locked));
+
+        // With the dispute closed, another may open
+        apply(&mut account, "dispute, 1, 1, ", &rules).unwrap();
+        apply(&mut account, "chargeback, 1, 1, ", &Rules::default()).unwrap();
+        assert_eq!((5.0, 0.0, true), (account.available, account.held, account.locked));
+    }
+
+    #[test]
+    fn test_locked_policies() {
+        let rules = Rules {
+            reversal_unlocks: true,
+            locked_deposits: LockedPolicy::Hold,
+            locked_withdrawals: LockedPolicy::Accept,
+            ..Default::default()
+        };
+        let mut account = ClientAccount::default();
+        for row in ["deposit, 1, 1, 10.0", "deposit, 1, 2, 5.0", "dispute, 1, 2,", "chargeback, 1, 2,"] {
+            apply(&mut account, row, &rules).unwrap();
+        }
+        assert!(matches!(apply(&mut account, "deposit, 1, 3, 4.0", &Rules::default()), Err(KrakenError::AccountLocked(1))));
+
+        // A held deposit can't be disputed, while withdrawals and authorizations go through
+        apply(&mut account, "deposit, 1, 3, 4.0", &rules).unwrap();
+        assert!(matches!(apply(&mut account, "dispute, 1, 3,", &rules), Err(KrakenError::DisputeStateError(_))));
+        apply(&mut account, "withdrawal, 1, 4, 3.0", &rules).unwrap();
+        apply(&mut account, "authorize, 1, 5, 1.0", &rules).unwrap();
+        assert_eq!((6.0, 5.0, true), (account.available, account.held, account.locked));
+
+        // Unlocking makes the held deposit available
+        apply(&mut account, "chargeback_reversal, 1, 2,", &rules).unwrap();
+        assert_eq!((15.0, 1.0, false), (account.available, account.held, account.locked));
+        assert!(account.lock_holds.is_empty());
+
+        // Held withdrawals leave the funds, and are paid out once unlocked, while authorizations are refused
+        let rules = Rules { locked_withdrawals: LockedPolicy::Hold, ..rules };
+        account.lock(LockReason::Admin { note: None }, None);
+        apply(&mut account, "withdrawal, 1, 6, 2.0", &rules).unwrap();
+        assert!(matches!(apply(&mut account, "authorize, 1, 7, 1.0", &rules), Err(KrakenError::AccountLocked(1))));
+        assert_eq!((13.0, 3.0), (account.available, account.held));
+        account.unlock(LockReason::Admin { note: None }, None).unwrap();
+        assert_eq!((13.0, 1.0), (account.available, account.held));
+    }
+}
@@ -0,0 +1,708 @@
+use crate::engine::Engine;
+use crate::errors::KrakenError;
+use crate::history::{HistoryFilter, MemoryBudget};
+use crate::input::parse_message;
+use crate::metrics::{Metrics, Tally};
+use crate::output::{write_accounts, AccountSummary, OutputFormat};
+use crate::queue::{checkpoint, restore_state};
+use crate::snapshot::HistoryEntry;
+use crate::structures::{DisputeState, LockEvent, LockReason, Transaction, TransactionType};
+use crate::webhook::{deliver, Notifier, WebhookConfig};
+use axum::body::Bytes;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, info_span, Instrument};
+
+/// Account updates a slow subscriber may fall behind by before it starts missing some.
+const UPDATE_CAPACITY: usize = 1024;
+
+/// Where to listen, and where to keep the balances between runs.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    /// Address to serve the gRPC API on as well, if any.
+    #[cfg(feature = "grpc")]
+    pub grpc: Option<SocketAddr>,
+    /// Address to accept newline-delimited transactions on, if any. See `tcp::ingest`.
+    pub tcp: Option<SocketAddr>,
+    /// Snapshot restored on startup, if it exists, and saved on shutdown.
+    pub state: Option<PathBuf>,
+    pub max_memory: Option<usize>,
+    /// Endpoints notified of disputes opened and accounts locked.
+    pub webhooks: WebhookConfig,
+}
+
+/// What a transaction did to an account.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateEvent {
+    /// The balances changed.
+    Balance,
+    /// A chargeback, an operator or a fraud rule locked the account.
+    Locked,
+    /// A chargeback reversal or an operator unlocked the account.
+    Unlocked,
+}
+
+/// An account right after a transaction changed it, as published to subscribers.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountUpdate {
+    pub event: UpdateEvent,
+    #[serde(flatten)]
+    pub account: AccountSummary,
+}
+
+/// The engine every request applies to, whichever API it comes through, and the account updates it publishes.
+/// Requests are short, so a lock around the whole engine is simpler and no slower than routing them to
+/// per-client workers.
+pub struct Ledger {
+    engine: Mutex<Engine>,
+    updates: broadcast::Sender<AccountUpdate>,
+    /// Snapshot the balances are saved to, if any.
+    snapshot: Option<PathBuf>,
+    /// Requests waiting for the engine or applying to it.
+    pending: AtomicUsize,
+    /// Transactions applied since the snapshot was last saved or restored.
+    unsaved: AtomicU64,
+    /// The engine's memory budget, reachable without waiting for the engine.
+    budget: Option<MemoryBudget>,
+    /// Counters of every transaction applied or refused, as exported on `/metrics`.
+    metrics: Metrics,
+}
+
+impl Ledger {
+    pub fn new(engine: Engine) -> Self {
+        Self {
+            budget: engine.budget().cloned(),
+            engine: Mutex::new(engine),
+            updates: broadcast::channel(UPDATE_CAPACITY).0,
+            snapshot: None,
+            pending: AtomicUsize::new(0),
+            unsaved: AtomicU64::new(0),
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Save the balances to `snapshot` with `save`.
+    pub fn with_snapshot(mut self, snapshot: Option<PathBuf>) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Engine> {
+        // A panicking request can't leave an account half-updated, so a poisoned lock is still usable
+        self.engine.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Apply `transactions` in order, publishing the account of each one applied, and return the outcome of each.
+    pub fn apply<I>(&self, transactions: I) -> Vec<Result<AccountSummary, KrakenError>>
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        let span = info_span!("apply", transactions = tracing::field::Empty).entered();
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let mut engine = self.lock();
+        let mut updates = Vec::new();
+        let mut tally = Tally::default();
+        let results: Vec<_> = transactions
+            .into_iter()
+            .map(|transaction| {
+                let (client, kind) = (transaction.client, transaction.kind.clone());
+                let was_locked = engine.accounts().get(&client).is_some_and(|account| account.locked);
+                let start = Instant::now();
+                let result = engine.apply(transaction);
+                tally.record(kind, &result, start.elapsed());
+                result?;
+
+                let account = AccountSummary::new(client, &engine.accounts()[&client])?;
+                let event = match (was_locked, account.locked) {
+                    (false, true) => UpdateEvent::Locked,
+                    (true, false) => UpdateEvent::Unlocked,
+                    _ => UpdateEvent::Balance,
+                };
+                updates.push(AccountUpdate { event, account: account.clone() });
+                Ok(account)
+            })
+            .collect();
+        drop(engine);
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+        self.metrics.add_rows(results.len() as u64);
+        self.metrics.add_tally(&tally);
+        span.record("transactions", results.len());
+        drop(span);
+        self.unsaved.fetch_add(updates.len() as u64, Ordering::Relaxed);
+
+        for update in updates {
+            // Sending only fails when nobody is subscribed
+            let _ = self.updates.send(update);
+        }
+        results
+    }
+
+    pub fn account(&self, client: u32) -> Result<Option<AccountSummary>, KrakenError> {
+        self.lock().accounts().get(&client).map(|account| AccountSummary::new(client, account)).transpose()
+    }
+
+    /// Lock or unlock the account of `client` for `reason`, now, publishing it if that changed anything. `None` if
+    /// there's no such client.
+    pub fn set_locked(&self, client: u32, locked: bool, reason: LockReason) -> Result<Option<AccountSummary>, KrakenError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let mut engine = self.lock();
+        if engine.account(client).is_none() {
+            return Ok(None);
+        }
+        let changed = match locked {
+            true => engine.lock_account(client, reason, Some(now)),
+            false => engine.unlock_account(client, reason, Some(now))?,
+        };
+        let account = AccountSummary::new(client, &engine.accounts()[&client])?;
+        drop(engine);
+        if changed {
+            self.unsaved.fetch_add(1, Ordering::Relaxed);
+            let event = if locked { UpdateEvent::Locked } else { UpdateEvent::Unlocked };
+            let _ = self.updates.send(AccountUpdate { event, account: account.clone() });
+        }
+        Ok(Some(account))
+    }
+
+    /// Every time the account of `client` was locked or unlocked, oldest first.
+    pub fn lock_events(&self, client: u32) -> Option<Vec<LockEvent>> {
+        self.lock().account(client).map(|account| account.lock_events.clone())
+    }
+
+    /// The deposit or withdrawal `tx` of `client`, with its dispute state.
+    pub fn transaction(&self, client: u32, tx: u32) -> Result<Option<HistoryEntry>, KrakenError> {
+        Ok(self.lock().transaction(client, tx)?.map(HistoryEntry::from))
+    }
+
+    /// The deposits and withdrawals of `client` that `filter` matches, in tx order.
+    pub fn history(&self, client: u32, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>, KrakenError> {
+        Ok(self.lock().history(client, filter)?.into_iter().map(HistoryEntry::from).collect())
+    }
+
+    /// Receive the account of every transaction applied from now on. A subscriber falling more than
+    /// `UPDATE_CAPACITY` updates behind skips the oldest.
+    pub fn subscribe(&self) -> broadcast::Receiver<AccountUpdate> {
+        self.updates.subscribe()
+    }
+
+    /// Save the balances to the snapshot, if there is one.
+    pub fn save(&self) -> Result<(), KrakenError> {
+        let Some(snapshot) = &self.snapshot else { return Ok(()) };
+        let engine = self.lock();
+        checkpoint(&engine, &BTreeMap::new(), snapshot)?;
+        self.unsaved.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Current state of the service, for `/healthz` and `/readyz`.
+    pub fn health(&self) -> Health {
+        // Creating a file is the only reliable way to know the snapshot can be replaced
+        let storage = self.snapshot.as_ref().map(|snapshot| {
+            let directory = snapshot.parent().filter(|parent| !parent.as_os_str().is_empty());
+            let writable = tempfile::NamedTempFile::new_in(directory.unwrap_or(std::path::Path::new("."))).is_ok();
+            Storage { snapshot: snapshot.display().to_string(), writable }
+        });
+        let memory = self.budget.as_ref().map(|budget| Memory { used: budget.used(), limit: budget.limit() });
+        Health {
+            ready: storage.as_ref().is_none_or(|storage| storage.writable),
+            queue_depth: self.pending.load(Ordering::Relaxed),
+            unsaved_transactions: self.unsaved.load(Ordering::Relaxed),
+            storage,
+            memory,
+        }
+    }
+}
+
+/// Health of the service, as reported by `/healthz` and `/readyz`.
+#[derive(Debug, Serialize)]
+pub struct Health {
+    /// Whether the service can do everything asked of it: false when the snapshot can't be saved.
+    pub ready: bool,
+    /// Requests waiting for the engine or applying to it.
+    pub queue_depth: usize,
+    /// Transactions applied since the snapshot was last saved or restored, which a crash would lose.
+    pub unsaved_transactions: u64,
+    pub storage: Option<Storage>,
+    /// Memory budget of transaction histories, if one was set.
+    pub memory: Option<Memory>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Storage {
+    pub snapshot: String,
+    /// Whether the snapshot's directory accepts new files.
+    pub writable: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Memory {
+    pub used: usize,
+    pub limit: usize,
+}
+
+type Shared = Arc<Ledger>;
+
+/// An error response: a status and a message, sent as `{"error": message}`.
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<KrakenError> for ApiError {
+    fn from(e: KrakenError) -> Self {
+        let status = match e {
+            KrakenError::Parse(_) | KrakenError::Enum(_) | KrakenError::MissingAmount(_) => StatusCode::BAD_REQUEST,
+            KrakenError::IO => StatusCode::INTERNAL_SERVER_ERROR,
+            // Well-formed transactions the engine refuses, such as withdrawals exceeding the funds
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        };
+        ApiError(status, e.to_string())
+    }
+}
+
+/// Outcome of `POST /transactions/batch`.
+#[derive(Debug, Default, Serialize)]
+struct BatchResult {
+    applied: usize,
+    /// Transactions the engine refused, by their position in the batch.
+    rejected: Vec<Rejection>,
+}
+
+#[derive(Debug, Serialize)]
+struct Rejection {
+    index: usize,
+    error: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReportQuery {
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    /// A dispute state as spelled in snapshots, or `none` for transactions never disputed.
+    state: Option<String>,
+    min_amount: Option<f64>,
+    max_amount: Option<f64>,
+}
+
+impl TryFrom<HistoryQuery> for HistoryFilter {
+    type Error = KrakenError;
+
+    fn try_from(query: HistoryQuery) -> Result<Self, Self::Error> {
+        let mut filter = HistoryFilter::default().with_amount_range(query.min_amount, query.max_amount);
+        if let Some(kind) = query.kind {
+            filter = filter.with_kind(TransactionType::try_from(kind.as_str())?);
+        }
+        match query.state.as_deref() {
+            None => {}
+            Some("none") => filter = filter.with_state(None),
+            Some(state) => filter = filter.with_state(Some(DisputeState::try_from(state)?)),
+        }
+        Ok(filter)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchQuery {
+    client: Option<u32>,
+}
+
+/// Routes of the REST API, applying to `ledger`:
+///
+/// - `POST /transactions`: apply one transaction, a JSON object or a `type, client, tx, amount` row, and
+///   return its client's account
+/// - `POST /transactions/batch`: apply a JSON array of transactions, or one per line, and return how many were
+///   applied and which were refused. Nothing is applied if any of them is malformed.
+/// - `GET /accounts/{client}`: one account
+/// - `GET /accounts/{client}/transactions`: the client's deposits and withdrawals with their dispute state, only
+///   those of `?type=`, `?state=` (`dispute`, `resolve`, `chargeback` or `none`), at least `?min_amount=` or at
+///   most `?max_amount=` if given
+/// - `GET /accounts/{client}/transactions/{tx}`: one of them
+/// - `GET /accounts`: the full report, as JSON, or in any `?format=` the command line accepts
+/// - `GET /healthz`: always `200` while the service responds, with its `Health`
+/// - `GET /readyz`: the same, but `503` when it isn't ready
+/// - `GET /metrics`: counters and the apply latency histogram, in the Prometheus text format
+/// - `GET /accounts/updates`: a WebSocket receiving an `AccountUpdate` as JSON whenever a transaction changes
+///   an account, or only `?client=`'s
+pub fn router(ledger: Arc<Ledger>) -> Router {
+    Router::new()
+        .route("/transactions", post(submit))
+        .route("/transactions/batch", post(submit_batch))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .route("/accounts", get(report))
+        .route("/accounts/updates", get(watch))
+        .route("/accounts/{client}", get(account))
+        .route("/accounts/{client}/transactions", get(history))
+        .route("/accounts/{client}/transactions/{tx}", get(transaction))
+        .route("/accounts/{client}/locks", get(lock_events))
+        .route("/accounts/{client}/lock", post(lock))
+        .route("/accounts/{client}/unlock", post(unlock))
+        .layer(middleware::from_fn(trace))
+        .with_state(ledger)
+}
+
+/// Handle each request in its own span, continuing the caller's trace when it sent a `traceparent` header.
+async fn trace(request: Request, next: Next) -> Response {
+    let span = info_span!("request", method = %request.method(), path = request.uri().path());
+    #[cfg(feature = "otel")]
+    crate::telemetry::set_remote_parent(&span, request.headers().iter().map(|(name, value)| (name.as_str(), value.as_bytes())));
+    next.run(request).instrument(span).await
+}
+
+async fn submit(State(ledger): State<Shared>, body: Bytes) -> Result<Json<AccountSummary>, ApiError> {
+    let transaction = parse_message(&body)?;
+    let mut results = ledger.apply([transaction]);
+    Ok(Json(results.remove(0)?))
+}
+
+async fn submit_batch(State(ledger): State<Shared>, body: Bytes) -> Result<Json<BatchResult>, ApiError> {
+    let transactions: Vec<Transaction> = match body.trim_ascii_start().starts_with(b"[") {
+        true => serde_json::from_slice(&body).map_err(|e| KrakenError::Parse(e.to_string()))?,
+        false => body
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .map(parse_message)
+            .collect::<Result<_, _>>()?,
+    };
+
+    let mut result = BatchResult::default();
+    for (index, outcome) in ledger.apply(transactions).into_iter().enumerate() {
+        match outcome {
+            Ok(_) => result.applied += 1,
+            Err(e) => result.rejected.push(Rejection { index, error: e.to_string() }),
+        }
+    }
+    Ok(Json(result))
+}
+
+async fn account(State(ledger): State<Shared>, Path(client): Path<u32>) -> Result<Json<AccountSummary>, ApiError> {
+    match ledger.account(client)? {
+        Some(summary) => Ok(Json(summary)),
+        None => Err(ApiError(StatusCode::NOT_FOUND, format!("No such client: {client}"))),
+    }
+}
+
+/// Body of a lock or unlock request, which may be left empty: a fraud rule's lock names the `rule`, and an
+/// operator may leave a `note`.
+#[derive(Debug, Default, Deserialize)]
+struct LockRequest {
+    note: Option<String>,
+    rule: Option<String>,
+}
+
+impl LockRequest {
+    fn parse(body: &[u8]) -> Result<Self, KrakenError> {
+        match body.trim_ascii().is_empty() {
+            true => Ok(Self::default()),
+            false => serde_json::from_slice(body).map_err(|e| KrakenError::Parse(e.to_string())),
+        }
+    }
+}
+
+async fn lock(State(ledger): State<Shared>, Path(client): Path<u32>, body: Bytes) -> Result<Json<AccountSummary>, ApiError> {
+    let request = LockRequest::parse(&body)?;
+    let reason = match request.rule {
+        Some(rule) => LockReason::Fraud { rule },
+        None => LockReason::Admin { note: request.note },
+    };
+    match ledger.set_locked(client, true, reason)? {
+        Some(summary) => Ok(Json(summary)),
+        None => Err(ApiError(StatusCode::NOT_FOUND, format!("No such client: {client}"))),
+    }
+}
+
+async fn unlock(State(ledger): State<Shared>, Path(client): Path<u32>, body: Bytes) -> Result<Json<AccountSummary>, ApiError> {
+    let request = LockRequest::parse(&body)?;
+    match ledger.set_locked(client, false, LockReason::Admin { note: request.note })? {
+        Some(summary) => Ok(Json(summary)),
+        None => Err(ApiError(StatusCode::NOT_FOUND, format!("No such client: {client}"))),
+    }
+}
+
+async fn lock_events(State(ledger): State<Shared>, Path(client): Path<u32>) -> Result<Json<Vec<LockEvent>>, ApiError> {
+    match ledger.lock_events(client) {
+        Some(events) => Ok(Json(events)),
+        None => Err(ApiError(StatusCode::NOT_FOUND, format!("No such client: {client}"))),
+    }
+}
+
+async fn history(
+    State(ledger): State<Shared>,
+    Path(client): Path<u32>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<HistoryEntry>>, ApiError> {
+    let filter = HistoryFilter::try_from(query)?;
+    match ledger.account(client)? {
+        Some(_) => Ok(Json(ledger.history(client, &filter)?)),
+        None => Err(ApiError(StatusCode::NOT_FOUND, format!("No such client: {client}"))),
+    }
+}
+
+async fn transaction(
+    State(ledger): State<Shared>,
+    Path((client, tx)): Path<(u32, u32)>,
+) -> Result<Json<HistoryEntry>, ApiError> {
+    match ledger.transaction(client, tx)? {
+        Some(entry) => Ok(Json(entry)),
+        None => Err(ApiError(StatusCode::NOT_FOUND, format!("No such transaction of client {client}: {tx}"))),
+    }
+}
+
+async fn report(State(ledger): State<Shared>, Query(query): Query<ReportQuery>) -> Result<Response, ApiError> {
+    let format = query.format.as_deref().map(OutputFormat::try_from).transpose()?.unwrap_or(OutputFormat::Json);
+    let mut body = Vec::new();
+    write_accounts(&mut body, ledger.lock().accounts(), format)?;
+
+    let content_type = match format {
+        OutputFormat::Json => "application/json",
+        OutputFormat::JsonLines => "application/jsonl",
+        OutputFormat::Csv => "text/csv",
+        OutputFormat::Table => "text/plain",
+        #[cfg(feature = "polars")]
+        OutputFormat::Parquet => "application/vnd.apache.parquet",
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+async fn healthz(State(ledger): State<Shared>) -> Json<Health> {
+    Json(ledger.health())
+}
+
+async fn readyz(State(ledger): State<Shared>) -> (StatusCode, Json<Health>) {
+    let health = ledger.health();
+    let status = if health.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(health))
+}
+
+async fn metrics(State(ledger): State<Shared>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], ledger.metrics.prometheus())
+}
+
+async fn watch(ws: WebSocketUpgrade, State(ledger): State<Shared>, Query(query): Query<WatchQuery>) -> Response {
+    // Subscribe before upgrading, so nothing applied once the client sees the upgrade is missed
+    let updates = ledger.subscribe();
+    ws.on_upgrade(move |socket| push_updates(socket, updates, query.client))
+}
+
+/// Send `updates` to `socket` until either side closes. Anything the client sends is ignored.
+async fn push_updates(mut socket: WebSocket, mut updates: broadcast::Receiver<AccountUpdate>, client: Option<u32>) {
+    loop {
+        let update = tokio::select! {
+            update = updates.recv() => update,
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let update = match update {
+            Ok(update) if client.is_none_or(|client| client == update.account.client) => update,
+            // Lagging subscribers skip ahead, as `Ledger::subscribe` describes
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        // An update always serializes
+        let text = serde_json::to_string(&update).unwrap_or_default();
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Serve the REST API, and the gRPC API and TCP line protocol on the addresses given for them, until
+/// interrupted with Ctrl-C, then save the balances to `config.state`, if given. Disputes opened and accounts
+/// locked through any of them are posted to the webhooks as they happen.
+pub fn serve(config: &ServerConfig) -> Result<(), KrakenError> {
+    let budget = config.max_memory.map(MemoryBudget::new).transpose()?;
+    let mut engine = match &config.state {
+        Some(state) => restore_state(state, budget)?.0,
+        None => Engine::with_budget(budget),
+    };
+    let mut notifications = None;
+    if !config.webhooks.urls.is_empty() {
+        let (notifier, receiver) = Notifier::new();
+        engine = engine.with_observer(notifier);
+        notifications = Some(receiver);
+    }
+    let ledger = Arc::new(Ledger::new(engine).with_snapshot(config.state.clone()));
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().map_err(|_| KrakenError::IO)?;
+    let shutdown = || async {
+        // Without a handler, stopping now beats running with no way to save the state on the way out
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!(code = "signal", error = %e, "Failed to listen for Ctrl-C, shutting down");
+        }
+    };
+    runtime.block_on(async {
+        if let Some(notifications) = notifications {
+            tokio::spawn(deliver(config.webhooks.clone(), notifications));
+        }
+        let listener = tokio::net::TcpListener::bind(config.listen).await.map_err(|e| {
+            error!(code = "io", address = %config.listen, error = %e, "Failed to listen for HTTP requests");
+            KrakenError::IO
+        })?;
+        info!("Listening on http://{}", listener.local_addr().map_err(|_| KrakenError::IO)?);
+        let rest = async {
+            axum::serve(listener, router(ledger.clone()))
+                .with_graceful_shutdown(shutdown())
+                .await
+                .map_err(|e| {
+                    error!(code = "io", error = %e, "HTTP server failed");
+                    KrakenError::IO
+                })
+        };
+
+        #[cfg(feature = "grpc")]
+        let grpc = async {
+            let Some(address) = config.grpc else { return Ok(()) };
+            info!("Serving gRPC on {address}");
+            crate::grpc::serve(address, ledger.clone(), shutdown()).await
+        };
+        #[cfg(not(feature = "grpc"))]
+        let grpc = async { Ok(()) };
+        let tcp = async {
+            let Some(address) = config.tcp else { return Ok(()) };
+            info!("Accepting transaction lines on {address}");
+            crate::tcp::serve(address, ledger.clone(), shutdown()).await
+        };
+        tokio::try_join!(rest, grpc, tcp).map(|_| ())
+    })?;
+
+    ledger.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::server::{router, Ledger};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Send one HTTP/1.1 request and return the status line and body of the response.
+    async fn request(address: std::net::SocketAddr, method: &str, path: &str, body: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: test\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    #[test]
+    fn test_server() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio::spawn(axum::serve(listener, router(Arc::new(Ledger::new(Engine::new())))).into_future());
+
+            let (status, body) = request(address, "POST", "/transactions", "deposit, 1, 1, 5.0").await;
+            assert_eq!("HTTP/1.1 200 OK", status);
+            assert_eq!(r#"{"client":1,"available":5.0,"held":0.0,"total":5.0,"locked":false}"#, body);
+
+            let batch = r#"[{"type":"withdrawal","client":1,"tx":2,"amount":9.0},{"type":"deposit","client":2,"tx":3,"amount":1.5}]"#;
+            let (_, body) = request(address, "POST", "/transactions/batch", batch).await;
+            assert_eq!(r#"{"applied":1,"rejected":[{"index":0,"error":"Insufficient Funds for account: 1"}]}"#, body);
+
+            let (status, _) = request(address, "POST", "/transactions", "refund, 1, 4, 1.0").await;
+            assert_eq!("HTTP/1.1 400 Bad Request", status);
+            let (status, _) = request(address, "GET", "/accounts/7", "").await;
+            assert_eq!("HTTP/1.1 404 Not Found", status);
+            let (_, body) = request(address, "GET", "/accounts/2", "").await;
+            assert!(body.contains(r#""available":1.5"#), "{body}");
+            let (_, body) = request(address, "GET", "/accounts/2/transactions?type=deposit&max_amount=2", "").await;
+            assert_eq!(r#"[{"tx":3,"kind":"deposit","amount":1.5,"state":null}]"#, body);
+            let (status, _) = request(address, "GET", "/accounts/2/transactions?state=open", "").await;
+            assert_eq!("HTTP/1.1 400 Bad Request", status);
+            let (status, _) = request(address, "GET", "/accounts/1/transactions/2", "").await;
+            assert_eq!("HTTP/1.1 404 Not Found", status);
+            let (_, body) = request(address, "GET", "/accounts?format=csv", "").await;
+            assert_eq!(3, body.lines().count(), "{body}");
+            let (status, body) = request(address, "GET", "/readyz", "").await;
+            assert_eq!("HTTP/1.1 200 OK", status);
+            let expected = r#"{"ready":true,"queue_depth":0,"unsaved_transactions":2,"storage":null,"memory":null}"#;
+            assert_eq!(expected, body);
+            let (_, body) = request(address, "GET", "/metrics", "").await;
+            assert!(body.contains("paymentprocessor_transactions_applied_total{type=\"deposit\"} 2\n"), "{body}");
+            assert!(body.contains("paymentprocessor_rows_total 3\n"), "{body}");
+
+            // Watch client 1 over a WebSocket, while client 2's deposit goes unreported
+            let mut socket = TcpStream::connect(address).await.unwrap();
+            let upgrade = "GET /accounts/updates?client=1 HTTP/1.1\r\nHost: test\r\nConnection: Upgrade\r\n\
+                           Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGVzdHRlc3R0ZXN0dGVzdA==\r\n\r\n";
+            socket.write_all(upgrade.as_bytes()).await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(socket.read_u8().await.unwrap());
+            }
+            assert!(head.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&head));
+
+            for transaction in ["deposit, 2, 5, 1.0", "dispute, 1, 1,", "chargeback, 1, 1,"] {
+                request(address, "POST", "/transactions", transaction).await;
+            }
+            let mut messages = Vec::new();
+            for _ in 0..2 {
+                // Unmasked text frames, short enough for a one-byte or two-byte length
+                let (opcode, length) = (socket.read_u8().await.unwrap(), socket.read_u8().await.unwrap());
+                assert_eq!(0x81, opcode);
+                let length = match length {
+                    126 => socket.read_u16().await.unwrap() as usize,
+                    length => length as usize,
+                };
+                let mut text = vec![0; length];
+                socket.read_exact(&mut text).await.unwrap();
+                messages.push(String::from_utf8(text).unwrap());
+            }
+            assert!(messages[0].starts_with(r#"{"event":"balance","client":1,"available":0.0,"held":5.0"#), "{messages:?}");
+            assert!(messages[1].starts_with(r#"{"event":"locked","client":1"#), "{messages:?}");
+
+            // Operators unlock accounts, and lock them again for a fraud rule
+            let (_, body) = request(address, "POST", "/accounts/1/unlock", r#"{"note":"refunded"}"#).await;
+            assert!(body.ends_with(r#""locked":false}"#), "{body}");
+            let (_, body) = request(address, "POST", "/accounts/1/lock", r#"{"rule":"velocity"}"#).await;
+            assert!(body.contains(r#""locked":true,"lock":{"locked":true,"reason":"fraud","rule":"velocity","at":"#), "{body}");
+            let (status, _) = request(address, "POST", "/accounts/7/lock", "").await;
+            assert_eq!("HTTP/1.1 404 Not Found", status);
+            let (_, body) = request(address, "GET", "/accounts/1/locks", "").await;
+            let events: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+            let reasons: Vec<_> = events.iter().map(|event| (event["locked"].clone(), event["reason"].clone())).collect();
+            let expected = [(true, "chargeback"), (false, "admin"), (true, "fraud")].map(|(locked, reason)| (locked.into(), reason.into()));
+            assert_eq!(expected.to_vec(), reasons);
+            assert_eq!(Some("refunded"), events[1]["note"].as_str());
+        });
+
+        // A snapshot that can't be saved makes the service unready
+        let ledger = Ledger::new(Engine::new()).with_snapshot(Some(PathBuf::from("/nonexistent/state.json")));
+        assert!(!ledger.health().ready);
+
+    }
+}
@@ -0,0 +1,187 @@
+use crate::dates::format_timestamp;
+use crate::engine::{replay, Outcome};
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::InputSource;
+use crate::output::Balances;
+use crate::structures::TransactionType;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+const HEADER: &str = "cycle, date, client, transactions, deposited, withdrawn, available, held, total, locked";
+
+/// Where a settlement cycle ends.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Cycle {
+    /// After every this many rows.
+    Rows(u64),
+    /// At the end of every day, by the time of the transactions.
+    #[default]
+    Day,
+}
+
+impl TryFrom<&str> for Cycle {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "day" => Ok(Cycle::Day),
+            rows => match rows.parse() {
+                Ok(rows) if rows > 0 => Ok(Cycle::Rows(rows)),
+                _ => Err(KrakenError::Enum(format!("Invalid String for Cycle: {value}"))),
+            },
+        }
+    }
+}
+
+/// What a client did in the cycle so far, and its balances after its latest transaction in it.
+#[derive(Debug, Default)]
+struct Activity {
+    transactions: u64,
+    deposited: f64,
+    withdrawn: f64,
+    balances: Balances,
+}
+
+/// Replay `source` serially, writing its settlement cycles to `writer` as `SettlementWriter` does. Returns how many
+/// cycles were settled.
+pub fn write_settlement<S: InputSource, W: Write>(
+    source: S,
+    writer: W,
+    cycle: Cycle,
+    budget: Option<MemoryBudget>,
+) -> Result<u64, KrakenError> {
+    let mut settlement = SettlementWriter::new(writer, cycle)?;
+    replay(source, budget, |outcome| settlement.record(outcome))?;
+    Ok(settlement.finish()?.0)
+}
+
+/// Settles at the end of every cycle: each client with transactions in the cycle gets a `cycle, date, client,
+/// transactions, deposited, withdrawn, available, held, total, locked` row, with what it did in the cycle and its
+/// balances at the end of it, and its counters start again from zero. Cycles are numbered from 1, and daily ones
+/// dated; a row without a time stays in the cycle of the row before it, as does one dated before it. The last cycle
+/// is settled by `finish`.
+pub struct SettlementWriter<W: Write> {
+    writer: W,
+    cycle: Cycle,
+    activity: BTreeMap<u32, Activity>,
+    /// Cycles settled so far.
+    settled: u64,
+    rows: u64,
+    /// Latest day of the input so far, by the time of the transactions.
+    day: Option<i64>,
+}
+
+impl<W: Write> SettlementWriter<W> {
+    pub fn new(mut writer: W, cycle: Cycle) -> Result<Self, KrakenError> {
+        writeln!(writer, "{HEADER}").map_err(|_| KrakenError::IO)?;
+        Ok(Self { writer, cycle, activity: BTreeMap::new(), settled: 0, rows: 0, day: None })
+    }
+
+    pub fn record(&mut self, outcome: &Outcome) -> Result<(), KrakenError> {
+        self.rows += 1;
+        if self.cycle == Cycle::Day
+            && let Some(today) = outcome.timestamp.map(|time| time.div_euclid(SECONDS_PER_DAY))
+        {
+            // A new day closes the cycle before its first transaction
+            if self.day.is_some_and(|day| today > day) {
+                settle(&mut self.writer, &mut self.settled, self.day, &mut self.activity)?;
+            }
+            self.day = self.day.max(Some(today));
+        }
+
+        let client = self.activity.entry(outcome.client).or_default();
+        client.transactions += 1;
+        if outcome.result.is_ok() {
+            match outcome.kind {
+                TransactionType::Deposit => client.deposited += outcome.amount.unwrap_or_default(),
+                TransactionType::Withdrawal => client.withdrawn += outcome.amount.unwrap_or_default(),
+                _ => {}
+            }
+        }
+        client.balances = Balances::try_from(outcome.account)?;
+
+        if let Cycle::Rows(every) = self.cycle
+            && self.rows.is_multiple_of(every)
+        {
+            settle(&mut self.writer, &mut self.settled, None, &mut self.activity)?;
+        }
+        Ok(())
+    }
+
+    /// Settle the last cycle and flush, returning how many cycles were settled, and the writer.
+    pub fn finish(mut self) -> Result<(u64, W), KrakenError> {
+        settle(&mut self.writer, &mut self.settled, self.day, &mut self.activity)?;
+        self.writer.flush().map_err(|_| KrakenError::IO)?;
+        Ok((self.settled, self.writer))
+    }
+}
+
+/// Write a row for every client in `activity`, if any, as cycle `settled + 1` of `day`, and clear it.
+fn settle<W: Write>(
+    writer: &mut W,
+    settled: &mut u64,
+    day: Option<i64>,
+    activity: &mut BTreeMap<u32, Activity>,
+) -> Result<(), KrakenError> {
+    if activity.is_empty() {
+        return Ok(());
+    }
+    *settled += 1;
+    let date = day.map(|day| format_timestamp(day * SECONDS_PER_DAY)[..10].to_string()).unwrap_or_default();
+    for (client, activity) in std::mem::take(activity) {
+        let Activity { transactions, deposited, withdrawn, balances } = activity;
+        writeln!(
+            writer,
+            "{settled}, {date}, {client}, {transactions}, {deposited:.4}, {withdrawn:.4}, {:.4}, {:.4}, {:.4}, {}",
+            balances.available, balances.held, balances.total, balances.locked
+        )
+        .map_err(|_| KrakenError::IO)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::JsonLinesSource;
+    use crate::settlement::{write_settlement, Cycle};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_settlement() {
+        let rows = [
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 5.0, "timestamp": "2024-01-01T09:00:00Z"}"#,
+            r#"{"type": "deposit", "client": 2, "tx": 2, "amount": 3.0, "timestamp": "2024-01-01T10:00:00Z"}"#,
+            r#"{"type": "withdrawal", "client": 1, "tx": 3, "amount": 1.0}"#,
+            r#"{"type": "dispute", "client": 2, "tx": 2, "timestamp": "2024-01-02T08:00:00Z"}"#,
+            // Refused, so not counted as withdrawn
+            r#"{"type": "withdrawal", "client": 1, "tx": 4, "amount": 10.0, "timestamp": "2024-01-01T23:00:00Z"}"#,
+            r#"{"type": "deposit", "client": 1, "tx": 5, "amount": 2.0, "timestamp": "2024-01-04"}"#,
+        ];
+        let settle = |cycle| {
+            let mut report = Vec::new();
+            let source = JsonLinesSource::from_reader(Cursor::new(rows.join("\n")));
+            let settled = write_settlement(source, &mut report, cycle, None).unwrap();
+            (settled, String::from_utf8(report).unwrap())
+        };
+
+        let expected = "cycle, date, client, transactions, deposited, withdrawn, available, held, total, locked\n\
+                        1, 2024-01-01, 1, 2, 5.0000, 1.0000, 4.0000, 0.0000, 4.0000, false\n\
+                        1, 2024-01-01, 2, 1, 3.0000, 0.0000, 3.0000, 0.0000, 3.0000, false\n\
+                        2, 2024-01-02, 1, 1, 0.0000, 0.0000, 4.0000, 0.0000, 4.0000, false\n\
+                        2, 2024-01-02, 2, 1, 0.0000, 0.0000, 0.0000, 3.0000, 3.0000, false\n\
+                        3, 2024-01-04, 1, 1, 2.0000, 0.0000, 6.0000, 0.0000, 6.0000, false\n";
+        assert_eq!((3, expected.to_string()), settle(Cycle::Day));
+
+        let (settled, report) = settle(Cycle::Rows(4));
+        assert_eq!(2, settled);
+        assert!(report.ends_with("\n2, , 1, 2, 2.0000, 0.0000, 6.0000, 0.0000, 6.0000, false\n"), "{report}");
+        assert_eq!(Cycle::Rows(100), Cycle::try_from("100").unwrap());
+        assert_eq!(Cycle::Day, Cycle::try_from("day").unwrap());
+        for invalid in ["0", "week", "-1"] {
+            assert!(Cycle::try_from(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use crate::errors::KrakenError;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Prefix of a signature, naming how it was computed.
+const ALGORITHM: &str = "hmac-sha256";
+
+/// Where the signature of the report at `report` is written: next to it, with `.sig` appended to its name.
+pub fn sidecar(report: &Path) -> PathBuf {
+    let mut name = report.as_os_str().to_owned();
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+/// A secret shared with the consumers of the reports, who check with it that a report is the one signed. Its
+/// `Debug` leaves the secret out, so it never ends up in a log.
+#[derive(Clone)]
+pub struct SigningKey {
+    key: Vec<u8>,
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SigningKey(..)")
+    }
+}
+
+impl SigningKey {
+    pub fn new(key: impl Into<Vec<u8>>) -> Result<Self, KrakenError> {
+        let key = key.into();
+        if key.is_empty() {
+            return Err(KrakenError::InvalidArgument(String::from("The signing key is empty")));
+        }
+        Ok(Self { key })
+    }
+
+    /// Read the key from the file at `path`, without the line ending it was likely saved with.
+    pub fn load(path: &Path) -> Result<Self, KrakenError> {
+        let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+        let mut key = std::fs::read(path).map_err(|_| in_file(KrakenError::IO))?;
+        while key.last().is_some_and(|byte| matches!(byte, b'\n' | b'\r')) {
+            key.pop();
+        }
+        Self::new(key).map_err(in_file)
+    }
+
+    /// The signature of the file at `path`, as `hmac-sha256:` and the HMAC-SHA256 of its bytes in hex.
+    pub fn sign(&self, path: &Path) -> Result<String, KrakenError> {
+        let mac = self.mac(path)?.finalize().into_bytes();
+        Ok(format!("{ALGORITHM}:{}", mac.iter().map(|byte| format!("{byte:02x}")).collect::<String>()))
+    }
+
+    /// Sign the report at `report`, writing the signature and a line ending to its `sidecar`, which is returned.
+    pub fn write_signature(&self, report: &Path) -> Result<PathBuf, KrakenError> {
+        let path = sidecar(report);
+        let signature = self.sign(report)?;
+        std::fs::write(&path, signature + "\n")
+            .map_err(|_| KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO)))?;
+        Ok(path)
+    }
+
+    /// Check that the file at `signature` holds the signature of the report at `report`, comparing them in constant
+    /// time.
+    pub fn verify(&self, report: &Path, signature: &Path) -> Result<(), KrakenError> {
+        let bad = |reason: &str| KrakenError::BadSignature(format!("{}: {reason}", report.display()));
+        let text = std::fs::read_to_string(signature)
+            .map_err(|_| KrakenError::InFile(signature.display().to_string(), Box::new(KrakenError::IO)))?;
+        let expected = text
+            .trim_end()
+            .strip_prefix(ALGORITHM)
+            .and_then(|rest| rest.strip_prefix(':'))
+            .and_then(decode_hex)
+            .ok_or_else(|| bad(&format!("{} isn't an {ALGORITHM} signature", signature.display())))?;
+        self.mac(report)?.verify_slice(&expected).map_err(|_| bad("doesn't match its signature"))
+    }
+
+    fn mac(&self, path: &Path) -> Result<Hmac<Sha256>, KrakenError> {
+        let in_file = || KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO));
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        let mut file = File::open(path).map_err(|_| in_file())?;
+        let mut buffer = vec![0; 1 << 16];
+        loop {
+            match file.read(&mut buffer).map_err(|_| in_file())? {
+                0 => return Ok(mac),
+                read => mac.update(&buffer[..read]),
+            }
+        }
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signature::{sidecar, SigningKey};
+
+    #[test]
+    fn test_signature() {
+        let directory = tempfile::tempdir().unwrap();
+        let report = directory.path().join("accounts.csv");
+        std::fs::write(&report, "client, available, held, total, locked\n1, 1.5000, 0.0000, 1.5000, false\n").unwrap();
+        let key_file = directory.path().join("key");
+        std::fs::write(&key_file, "secret\n").unwrap();
+        let key = SigningKey::load(&key_file).unwrap();
+
+        let signature = key.write_signature(&report).unwrap();
+        assert_eq!(directory.path().join("accounts.csv.sig"), signature);
+        assert_eq!(sidecar(&report), signature);
+        // The line ending of the key file isn't part of the key
+        assert_eq!(SigningKey::new("secret").unwrap().sign(&report).unwrap(), key.sign(&report).unwrap());
+        assert!(key.sign(&report).unwrap().starts_with("hmac-sha256:"));
+        key.verify(&report, &signature).unwrap();
+
+        // Another key, or a report changed in transit, doesn't match
+        let error = SigningKey::new("other").unwrap().verify(&report, &signature).unwrap_err();
+        assert!(error.to_string().ends_with("accounts.csv: doesn't match its signature"), "{error}");
+        std::fs::write(&report, "client, available, held, total, locked\n1, 9.5000, 0.0000, 9.5000, false\n").unwrap();
+        assert_eq!("bad_signature", key.verify(&report, &signature).unwrap_err().code());
+        std::fs::write(&signature, "sha256:00\n").unwrap();
+        assert!(key.verify(&report, &signature).unwrap_err().to_string().contains("isn't an hmac-sha256 signature"));
+        assert!(SigningKey::new("").is_err());
+    }
+}
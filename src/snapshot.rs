@@ -0,0 +1,685 @@
+use crate::aliases::Aliases;
+use crate::encryption::{self, EncryptionKey};
+use crate::engine::Engine;
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::idempotency::{IdempotencyKey, IdempotencyKeys};
+use crate::input::{self, InputSource};
+use crate::interrupt::Interruptible;
+use crate::provenance::{InputProvenance, Provenance};
+use crate::redact::Client;
+use crate::structures::{ClientAccount, DisputeState, LockEvent, LockHold, PendingDeposit, Transaction, TransactionType};
+use crate::tx_index::TxIndex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Serializable copy of every account, including the transaction history later disputes depend on.
+/// Long-running modes write one as a checkpoint, recording how far into each input it got alongside the
+/// balances, so a restart can resume without reapplying anything.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub accounts: Vec<AccountSnapshot>,
+    /// Position reached in each input, keyed by a source-specific name, e.g. `topic/partition` for Kafka.
+    /// Each value is the first position not yet reflected in `accounts`.
+    #[serde(default)]
+    pub offsets: BTreeMap<String, i64>,
+    /// Keys of every transaction given to the accounts, when a replay tracked them, so replaying the same
+    /// transactions again doesn't apply them twice.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub idempotency_keys: Vec<IdempotencyKey>,
+    /// The inputs the accounts were computed from, in the order they were read, by every run saving to the
+    /// snapshot.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<InputProvenance>,
+    /// What is left of the clients purged from the snapshot, in the order they were purged.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tombstones: Vec<Tombstone>,
+}
+
+/// Offset recorded by an interrupted run: how many rows of its inputs are reflected in the snapshot, for a replay of
+/// the same inputs to skip.
+pub const ROWS_OFFSET: &str = "rows";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountSnapshot {
+    pub client: u32,
+    pub available: f64,
+    pub held: f64,
+    pub locked: bool,
+    pub history: Vec<HistoryEntry>,
+    /// Time of the account's latest transaction, in seconds since 1970-01-01 UTC, if any had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_activity: Option<i64>,
+    /// Deposits not yet available.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending: Vec<PendingEntry>,
+    /// Every time the account was locked or unlocked, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub locks: Vec<LockEvent>,
+    /// Deposits and withdrawals whose amounts are held until the account is unlocked.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub holds: Vec<LockHold>,
+}
+
+impl AccountSnapshot {
+    /// Funds of the deposits not yet available, apart from those disputed, which are held.
+    pub fn pending(&self) -> f64 {
+        self.pending.iter().filter(|entry| !entry.disputed).fold(0.0, |sum, entry| sum + entry.amount)
+    }
+}
+
+/// A deposit not yet available, with what's left of its delay.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingEntry {
+    pub tx: u32,
+    pub amount: f64,
+    /// Transactions of the client left before the deposit is available.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<u64>,
+    /// Time from which the deposit is available, in seconds since 1970-01-01 UTC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due_at: Option<i64>,
+    /// Whether the deposit is disputed, so held rather than pending.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub disputed: bool,
+}
+
+/// A stored deposit, withdrawal, or authorization, with its dispute state.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub tx: u32,
+    pub kind: TransactionType,
+    pub amount: Option<f64>,
+    pub state: Option<DisputeState>,
+    /// Time of an authorization still holding funds, for it to expire, if it had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authorized_at: Option<i64>,
+}
+
+/// What `Snapshot::purge` leaves of a client: the balances it held, so the snapshot's totals are unchanged, and how
+/// many transactions of its history were forgotten. It doesn't say which client it was.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub available: f64,
+    pub held: f64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub pending: f64,
+    pub transactions: usize,
+}
+
+fn is_zero(amount: &f64) -> bool {
+    *amount == 0.0
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl From<Transaction> for HistoryEntry {
+    fn from(transaction: Transaction) -> Self {
+        Self {
+            tx: transaction.tx,
+            kind: transaction.kind,
+            amount: transaction.amount,
+            state: transaction.state,
+            authorized_at: None,
+        }
+    }
+}
+
+impl Snapshot {
+    /// Copy the accounts, reading back any history that was spilled to disk.
+    pub fn capture(accounts: &HashMap<u32, ClientAccount>) -> Result<Self, KrakenError> {
+        let mut snapshots = Vec::with_capacity(accounts.len());
+        for (client, account) in accounts {
+            let mut history: Vec<HistoryEntry> = account
+                .history
+                .transactions()?
+                .into_iter()
+                .map(|transaction| HistoryEntry {
+                    authorized_at: account.authorizations.get(&transaction.tx).copied(),
+                    ..HistoryEntry::from(transaction)
+                })
+                .collect();
+            history.sort_by_key(|entry| entry.tx);
+
+            snapshots.push(AccountSnapshot {
+                client: *client,
+                available: account.available,
+                held: account.held,
+                locked: account.locked,
+                history,
+                last_activity: account.last_activity,
+                pending: account
+                    .pending_deposits
+                    .iter()
+                    .map(|deposit| PendingEntry {
+                        tx: deposit.tx,
+                        amount: deposit.amount,
+                        rows: deposit.due_row.map(|due| due.saturating_sub(account.rows)),
+                        due_at: deposit.due_at,
+                        disputed: deposit.disputed,
+                    })
+                    .collect(),
+                locks: account.lock_events.clone(),
+                holds: account.lock_holds.clone(),
+            });
+        }
+        snapshots.sort_by_key(|account| account.client);
+
+        Ok(Self {
+            accounts: snapshots,
+            offsets: BTreeMap::new(),
+            idempotency_keys: Vec::new(),
+            inputs: Vec::new(),
+            tombstones: Vec::new(),
+        })
+    }
+
+    /// Remove `client`'s account and history, as a deletion request asks, leaving a `Tombstone` of its balances in
+    /// their place, which is returned. Nothing changes if the client isn't in the snapshot. The idempotency keys of
+    /// its transactions are kept, as they hold no more than a type and a tx id, so replaying them is still refused.
+    pub fn purge(&mut self, client: u32) -> Option<Tombstone> {
+        let position = self.accounts.iter().position(|account| account.client == client)?;
+        let account = self.accounts.remove(position);
+        let tombstone = Tombstone {
+            available: account.available,
+            held: account.held,
+            pending: account.pending(),
+            transactions: account.history.len(),
+        };
+        self.tombstones.push(tombstone.clone());
+        Some(tombstone)
+    }
+
+    /// Rebuild the accounts, with histories counting against `budget` if one is given. Tombstones aren't accounts, so
+    /// a purged client transacting again starts afresh.
+    pub fn restore(self, budget: Option<&MemoryBudget>) -> Result<HashMap<u32, ClientAccount>, KrakenError> {
+        let mut accounts = HashMap::with_capacity(self.accounts.len());
+        for snapshot in self.accounts {
+            let mut account = ClientAccount::new(budget);
+            account.available = snapshot.available;
+            account.held = snapshot.held;
+            account.locked = snapshot.locked;
+            account.lock_events = snapshot.locks;
+            account.lock_holds = snapshot.holds;
+            account.last_activity = snapshot.last_activity;
+            // Restored accounts count transactions from 0
+            account.pending_deposits = snapshot
+                .pending
+                .into_iter()
+                .map(|entry| PendingDeposit {
+                    tx: entry.tx,
+                    amount: entry.amount,
+                    due_row: entry.rows,
+                    due_at: entry.due_at,
+                    disputed: entry.disputed,
+                })
+                .collect();
+            for entry in snapshot.history {
+                account.open_disputes += u32::from(entry.state == Some(DisputeState::Open));
+                account.chargebacks += u32::from(entry.state == Some(DisputeState::ChargedBack));
+                if let Some(at) = entry.authorized_at {
+                    account.authorizations.insert(entry.tx, at);
+                }
+                account.history.insert(Transaction {
+                    kind: entry.kind,
+                    client: snapshot.client,
+                    amount: entry.amount,
+                    tx: entry.tx,
+                    memo: None,
+                    idempotency_key: None,
+                    timestamp: None,
+                    state: entry.state,
+                })?;
+            }
+            accounts.insert(snapshot.client, account);
+        }
+        Ok(accounts)
+    }
+
+    /// Combine snapshots of disjoint work, such as those of sharded workers or separate regions, into one. The
+    /// balances of a client found in several are summed, its account is locked if any of them locks it, and its
+    /// histories are joined, as are the offsets, idempotency keys, inputs, and tombstones.
+    ///
+    /// Transactions are identified by tx id across every client, so a tx id found in more than one snapshot, which
+    /// merging the same work twice would also lead to, is a conflict, as is an offset or idempotency key recorded by
+    /// more than one.
+    /// Every conflict is named, each with the names of the snapshots involved, and nothing is merged if there is any.
+    pub fn merge<'a>(snapshots: impl IntoIterator<Item = (&'a str, Snapshot)>) -> Result<Snapshot, KrakenError> {
+        let mut accounts: BTreeMap<u32, AccountSnapshot> = BTreeMap::new();
+        let mut offsets = BTreeMap::new();
+        // Where each tx id and offset was first seen
+        let mut txs: HashMap<u32, (&str, u32)> = HashMap::new();
+        let mut offset_sources: HashMap<String, &str> = HashMap::new();
+        let mut keys: HashMap<IdempotencyKey, &str> = HashMap::new();
+        let mut conflicts = Vec::new();
+        let mut inputs = Vec::new();
+        let mut tombstones = Vec::new();
+
+        for (name, snapshot) in snapshots {
+            inputs.extend(snapshot.inputs);
+            tombstones.extend(snapshot.tombstones);
+            for key in snapshot.idempotency_keys {
+                if let Some(first) = keys.get(&key) {
+                    conflicts.push(format!("{key} was processed by both {first} and {name}"));
+                } else {
+                    keys.insert(key, name);
+                }
+            }
+            for (key, offset) in snapshot.offsets {
+                match offset_sources.get(&key) {
+                    Some(first) => conflicts.push(format!("offset {key} is in both {first} and {name}")),
+                    None => {
+                        offset_sources.insert(key.clone(), name);
+                        offsets.insert(key, offset);
+                    }
+                }
+            }
+            for account in snapshot.accounts {
+                let merged = accounts.entry(account.client).or_insert_with(|| AccountSnapshot {
+                    client: account.client,
+                    available: 0.0,
+                    held: 0.0,
+                    locked: false,
+                    history: Vec::new(),
+                    last_activity: None,
+                    pending: Vec::new(),
+                    locks: Vec::new(),
+                    holds: Vec::new(),
+                });
+                merged.available += account.available;
+                merged.held += account.held;
+                merged.locked |= account.locked;
+                merged.last_activity = merged.last_activity.max(account.last_activity);
+                merged.pending.extend(account.pending);
+                merged.locks.extend(account.locks);
+                merged.holds.extend(account.holds);
+                for entry in account.history {
+                    if let Some((first, client)) = txs.insert(entry.tx, (name, account.client)) {
+                        conflicts.push(format!(
+                            "tx {} is in both {first} (client {}) and {name} (client {})",
+                            entry.tx,
+                            Client(client),
+                            Client(account.client)
+                        ));
+                    }
+                    merged.history.push(entry);
+                }
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(KrakenError::MergeConflicts(conflicts));
+        }
+
+        let mut accounts: Vec<AccountSnapshot> = accounts.into_values().collect();
+        for account in &mut accounts {
+            account.history.sort_by_key(|entry| entry.tx);
+        }
+        let mut idempotency_keys: Vec<IdempotencyKey> = keys.into_keys().collect();
+        idempotency_keys.sort_unstable();
+        Ok(Snapshot { accounts, offsets, idempotency_keys, inputs, tombstones })
+    }
+
+    /// Merge the account of every client `aliases` declares an alias into that of its canonical client, as `merge`
+    /// merges the accounts of a client found in several snapshots: balances summed, locked if any is, and histories
+    /// joined. A tx id in the histories of more than one of the accounts merged is a conflict, each named, and nothing
+    /// is merged if there is any.
+    pub fn alias(mut self, aliases: &Aliases) -> Result<Snapshot, KrakenError> {
+        let mut accounts: BTreeMap<u32, AccountSnapshot> = BTreeMap::new();
+        // The client each tx id of a canonical client's merged history came from
+        let mut txs: HashMap<(u32, u32), u32> = HashMap::new();
+        let mut conflicts = Vec::new();
+        for account in std::mem::take(&mut self.accounts) {
+            let canonical = aliases.canonical(account.client);
+            let merged = accounts.entry(canonical).or_insert_with(|| AccountSnapshot {
+                client: canonical,
+                available: 0.0,
+                held: 0.0,
+                locked: false,
+                history: Vec::new(),
+                last_activity: None,
+                pending: Vec::new(),
+                locks: Vec::new(),
+                holds: Vec::new(),
+            });
+            merged.available += account.available;
+            merged.held += account.held;
+            merged.locked |= account.locked;
+            merged.last_activity = merged.last_activity.max(account.last_activity);
+            merged.pending.extend(account.pending);
+            merged.locks.extend(account.locks);
+            merged.holds.extend(account.holds);
+            for entry in account.history {
+                if let Some(client) = txs.insert((canonical, entry.tx), account.client) {
+                    conflicts.push(format!(
+                        "tx {} is in both client {} and client {}, merged as client {}",
+                        entry.tx,
+                        Client(client),
+                        Client(account.client),
+                        Client(canonical)
+                    ));
+                }
+                merged.history.push(entry);
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(KrakenError::MergeConflicts(conflicts));
+        }
+
+        self.accounts = accounts.into_values().collect();
+        for account in &mut self.accounts {
+            account.history.sort_by_key(|entry| entry.tx);
+        }
+        Ok(self)
+    }
+
+    /// Read a snapshot written by `save`, decrypting it with the installed encryption key if it's encrypted.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KrakenError> {
+        Self::load_with(path, encryption::installed())
+    }
+
+    /// Read a snapshot written by `save_with`, decrypting it with `key` if it's encrypted.
+    pub fn load_with(path: impl AsRef<Path>, key: Option<&EncryptionKey>) -> Result<Self, KrakenError> {
+        let bytes = encryption::read_file(path.as_ref(), key)?;
+        serde_json::from_slice(&bytes).map_err(|e| KrakenError::Parse(e.to_string()))
+    }
+
+    /// Write the snapshot to `path` atomically, encrypted with the installed encryption key if there is one.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), KrakenError> {
+        self.save_with(path, encryption::installed())
+    }
+
+    /// Write the snapshot to `path` atomically, encrypted with `key` if there is one: it is written and synced to
+    /// a sibling temporary file, which then replaces `path`. A crash mid-write leaves the previous snapshot intact.
+    pub fn save_with(&self, path: impl AsRef<Path>, key: Option<&EncryptionKey>) -> Result<(), KrakenError> {
+        let path = path.as_ref();
+        let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let mut temporary = tempfile::NamedTempFile::new_in(directory).map_err(|_| KrakenError::IO)?;
+
+        let mut writer = BufWriter::new(temporary.as_file_mut());
+        match key {
+            // Encrypted whole, as the tag authenticates everything before it
+            Some(key) => {
+                let json = serde_json::to_vec(self).map_err(|e| KrakenError::Parse(e.to_string()))?;
+                writer.write_all(&key.encrypt(&json)?).map_err(|_| KrakenError::IO)?;
+            }
+            None => serde_json::to_writer(&mut writer, self).map_err(|e| KrakenError::Parse(e.to_string()))?,
+        }
+        writer.flush().map_err(|_| KrakenError::IO)?;
+        drop(writer);
+
+        temporary.as_file().sync_all().map_err(|_| KrakenError::IO)?;
+        temporary.persist(path).map_err(|_| KrakenError::IO)?;
+        Ok(())
+    }
+}
+
+/// How `replay_onto` applies its input, besides the snapshot it starts from.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayConfig {
+    /// Budget the account histories count against, and may spill under.
+    pub budget: Option<MemoryBudget>,
+    /// Refuse transactions whose idempotency key was seen before.
+    pub idempotent: bool,
+    /// Index of the tx ids used, refusing transactions that reuse one, loaded if it exists and saved back.
+    pub tx_index: Option<PathBuf>,
+    /// The inputs `source` reads, to add to those the snapshot records once read.
+    pub provenance: Option<Provenance>,
+    /// Aliases whose accounts in the snapshot are merged into those of their canonical clients before `source` is
+    /// applied, as `source` reports them under the canonical ids too.
+    pub aliases: Option<Arc<Aliases>>,
+}
+
+/// Apply `source` serially on top of the accounts saved at `state`, starting empty if there is no snapshot
+/// there yet, then save them back to `state` along with any input positions it recorded.
+/// Nothing is saved if the input can't be read to the end. A snapshot left by an interrupted run resumes it: the
+/// rows it got through are skipped, as `source` is taken to be the same inputs. If `source` is interrupted in
+/// turn, the snapshot records how far it got.
+///
+/// With `config.idempotent`, or onto a snapshot holding idempotency keys, a transaction whose key was seen before is
+/// refused, so an input overlapping those already replayed is only applied where it's new, and the keys are saved
+/// along with the accounts. A snapshot saved without keys starts from those `IdempotencyKeys::derive` finds.
+///
+/// With a `config.tx_index` path, a transaction reusing a tx id its client used before, as recorded in the `TxIndex` there
+/// or earlier in `source`, is refused as a duplicate, and the index is saved back after the snapshot.
+pub fn replay_onto<S: InputSource>(
+    source: &mut Interruptible<S>,
+    state: &Path,
+    config: ReplayConfig,
+) -> Result<HashMap<u32, ClientAccount>, KrakenError> {
+    let ReplayConfig { budget, idempotent, tx_index, provenance, aliases } = config;
+    let (mut engine, mut offsets, mut inputs, tombstones) = match state.exists() {
+        true => {
+            let mut snapshot = Snapshot::load(state)?;
+            if let Some(aliases) = &aliases {
+                snapshot = snapshot.alias(aliases)?;
+            }
+            let keys = match std::mem::take(&mut snapshot.idempotency_keys) {
+                keys if !keys.is_empty() => Some(keys.into_iter().collect()),
+                _ => idempotent.then(|| IdempotencyKeys::derive(&snapshot.accounts)),
+            };
+            let (offsets, inputs) = (std::mem::take(&mut snapshot.offsets), std::mem::take(&mut snapshot.inputs));
+            let tombstones = std::mem::take(&mut snapshot.tombstones);
+            let engine = Engine::from_accounts(snapshot.restore(budget.as_ref())?, budget).with_idempotency(keys);
+            (engine, offsets, inputs, tombstones)
+        }
+        false => {
+            let engine = Engine::with_budget(budget).with_idempotency(idempotent.then(IdempotencyKeys::new));
+            (engine, BTreeMap::new(), Vec::new(), Vec::new())
+        }
+    };
+    let index = match &tx_index {
+        Some(path) if path.exists() => Some(TxIndex::load(path)?),
+        Some(_) => Some(TxIndex::new()),
+        None => None,
+    };
+    engine = engine.with_tx_index(index);
+
+    let skip = offsets.remove(ROWS_OFFSET).unwrap_or_default().max(0) as u64;
+    for transaction in input::rows(source).skip(skip as usize) {
+        // Swallow results since we aren't tracking them
+        let _ = engine.apply(transaction?);
+    }
+    if source.interrupted() {
+        offsets.insert(String::from(ROWS_OFFSET), source.rows().max(skip) as i64);
+    }
+
+    let mut snapshot = Snapshot::capture(engine.accounts())?;
+    snapshot.offsets = offsets;
+    snapshot.idempotency_keys = engine.idempotency().map(IdempotencyKeys::sorted).unwrap_or_default();
+    if let Some(provenance) = provenance {
+        inputs.extend(provenance.inputs()?);
+    }
+    snapshot.inputs = inputs;
+    snapshot.tombstones = tombstones;
+    snapshot.save(state)?;
+    // Saved second, so a crash in between leaves the index missing the last ids rather than holding ids the
+    // snapshot doesn't reflect
+    if let (Some(path), Some(index)) = (tx_index, engine.tx_index()) {
+        index.save(path)?;
+    }
+    Ok(engine.into_accounts())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Aliases;
+    use crate::encryption::EncryptionKey;
+    use crate::engine::Engine;
+    use crate::history::MemoryBudget;
+    use crate::input::{rows, CsvSource, InputOptions, MultiSource};
+    use crate::interrupt::{Interrupt, Interruptible};
+    use crate::processor::tests::TEST_DIR;
+    use crate::provenance::Provenance;
+    use crate::snapshot::{replay_onto, ReplayConfig, Snapshot, Tombstone, ROWS_OFFSET};
+    use crate::structures::{Transaction, TransactionType};
+    use crate::tx_index::TxIndex;
+
+    fn transaction(kind: TransactionType, tx: u32, amount: Option<f64>) -> Transaction {
+        Transaction {
+            kind,
+            client: 1,
+            amount,
+            tx,
+            memo: None,
+            idempotency_key: None,
+            timestamp: None,
+            state: None,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let budget = MemoryBudget::new(1).unwrap();
+        let mut engine = Engine::with_budget(Some(budget.clone()));
+        engine.apply(transaction(TransactionType::Deposit, 1, Some(10.0))).unwrap();
+        engine.apply(transaction(TransactionType::Deposit, 2, Some(5.0))).unwrap();
+        engine.apply(transaction(TransactionType::Dispute, 1, None)).unwrap();
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state.json");
+        let mut snapshot = Snapshot::capture(engine.accounts()).unwrap();
+        snapshot.offsets.insert(String::from("transactions/0"), 3);
+        snapshot.save(&path).unwrap();
+
+        // The dispute on tx 1 must survive the round trip for the chargeback to apply
+        let snapshot = Snapshot::load(&path).unwrap();
+        assert_eq!(Some(&3), snapshot.offsets.get("transactions/0"));
+        let mut engine = Engine::from_accounts(snapshot.restore(None).unwrap(), None);
+        engine.apply(transaction(TransactionType::Chargeback, 1, None)).unwrap();
+        assert_eq!("1, 5.0000, 0.0000, 5.0000, true", engine.accounts()[&1].to_str_row(1).unwrap());
+
+        // An encrypted snapshot reads back the same, only with its key
+        let key = EncryptionKey::new([7; 32]);
+        Snapshot::capture(engine.accounts()).unwrap().save_with(&path, Some(&key)).unwrap();
+        assert!(!std::fs::read(&path).unwrap().windows(8).any(|window| window == b"accounts"));
+        assert_eq!(engine.accounts().len(), Snapshot::load_with(&path, Some(&key)).unwrap().accounts.len());
+        assert_eq!("encryption", Snapshot::load_with(&path, None).unwrap_err().code());
+    }
+
+    #[test]
+    fn test_replay_onto() {
+        let directory = tempfile::tempdir().unwrap();
+        let state = directory.path().join("state.json");
+        let open = || Interruptible::new(CsvSource::open(String::from(TEST_DIR) + "0-trivial.csv", b',').unwrap(), Interrupt::default());
+        let idempotent = || ReplayConfig { idempotent: true, ..Default::default() };
+
+        // The first replay starts empty, the second adds the same rows to the saved balances
+        let accounts = replay_onto(&mut open(), &state, ReplayConfig::default()).unwrap();
+        assert_eq!("1, 1.5000, 0.0000, 1.5000, false", accounts[&1].to_str_row(1).unwrap());
+        let accounts = replay_onto(&mut open(), &state, ReplayConfig::default()).unwrap();
+        assert_eq!("1, 3.0000, 0.0000, 3.0000, false", accounts[&1].to_str_row(1).unwrap());
+        assert_eq!(2, Snapshot::load(&state).unwrap().accounts.len());
+
+        // Replayed idempotently, the same rows are only applied once, starting from the keys the saved histories show
+        let accounts = replay_onto(&mut open(), &state, idempotent()).unwrap();
+        assert_eq!("1, 3.0000, 0.0000, 3.0000, false", accounts[&1].to_str_row(1).unwrap());
+        let keyed = directory.path().join("keyed.json");
+        for _ in 0..2 {
+            let accounts = replay_onto(&mut open(), &keyed, idempotent()).unwrap();
+            assert_eq!("1, 1.5000, 0.0000, 1.5000, false", accounts[&1].to_str_row(1).unwrap());
+        }
+        // The keys are kept, so replays go on refusing the rows without being asked to
+        assert!(!Snapshot::load(&keyed).unwrap().idempotency_keys.is_empty());
+        let accounts = replay_onto(&mut open(), &keyed, ReplayConfig::default()).unwrap();
+        assert_eq!("1, 1.5000, 0.0000, 1.5000, false", accounts[&1].to_str_row(1).unwrap());
+
+        // The tx index survives from one replay to the next, so the second finds every tx id already used
+        let (indexed, index) = (directory.path().join("indexed.json"), directory.path().join("index.bin"));
+        for _ in 0..2 {
+            let config = ReplayConfig { tx_index: Some(index.clone()), ..Default::default() };
+            let accounts = replay_onto(&mut open(), &indexed, config).unwrap();
+            assert_eq!("1, 1.5000, 0.0000, 1.5000, false", accounts[&1].to_str_row(1).unwrap());
+        }
+        assert_eq!(5, TxIndex::load(&index).unwrap().len());
+
+        // The inputs read are added to those the snapshot records
+        let paths = [String::from(TEST_DIR) + "0-trivial.csv"];
+        let provenance = Provenance::new(&paths);
+        let source = MultiSource::new(&paths, InputOptions::default()).with_provenance(provenance.clone());
+        let config = ReplayConfig { provenance: Some(provenance), ..Default::default() };
+        replay_onto(&mut Interruptible::new(source, Interrupt::default()), &indexed, config).unwrap();
+        let inputs = Snapshot::load(&indexed).unwrap().inputs;
+        assert_eq!(vec![(paths[0].clone(), Some(5))], inputs.into_iter().map(|input| (input.path, input.rows)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_replay_resumes() {
+        let directory = tempfile::tempdir().unwrap();
+        let state = directory.path().join("state.json");
+        let open = || CsvSource::open(String::from(TEST_DIR) + "0-trivial.csv", b',').unwrap();
+
+        // A run interrupted after its first row leaves the rest for the replay
+        let mut engine = Engine::with_budget(None);
+        engine.apply(rows(&mut open()).next().unwrap().unwrap()).unwrap();
+        let mut snapshot = Snapshot::capture(engine.accounts()).unwrap();
+        snapshot.offsets.insert(String::from(ROWS_OFFSET), 1);
+        snapshot.save(&state).unwrap();
+
+        let accounts = replay_onto(&mut Interruptible::new(open(), Interrupt::default()), &state, ReplayConfig::default()).unwrap();
+        assert_eq!("1, 1.5000, 0.0000, 1.5000, false", accounts[&1].to_str_row(1).unwrap());
+        assert!(Snapshot::load(&state).unwrap().offsets.is_empty());
+
+        let interrupt = Interrupt::default();
+        interrupt.request();
+        replay_onto(&mut Interruptible::new(open(), interrupt), &state, ReplayConfig::default()).unwrap();
+        assert_eq!(Some(&0), Snapshot::load(&state).unwrap().offsets.get(ROWS_OFFSET));
+    }
+
+    #[test]
+    fn test_merge() {
+        let shard = |client, deposits: &[u32]| {
+            let mut engine = Engine::new();
+            for &tx in deposits {
+                engine.apply(Transaction { client, ..transaction(TransactionType::Deposit, tx, Some(1.0)) }).unwrap();
+            }
+            Snapshot::capture(engine.accounts()).unwrap()
+        };
+        let merged = Snapshot::merge([("a", shard(1, &[1, 2])), ("b", shard(1, &[3])), ("c", shard(2, &[4]))]).unwrap();
+        assert_eq!(2, merged.accounts.len());
+        assert_eq!(3.0, merged.accounts[0].available);
+        assert_eq!(vec![1, 2, 3], merged.accounts[0].history.iter().map(|entry| entry.tx).collect::<Vec<_>>());
+
+        // A tx id in two snapshots is a conflict, whichever clients they belong to
+        let error = Snapshot::merge([("a", shard(1, &[1, 2])), ("b", shard(2, &[2]))]).unwrap_err();
+        assert_eq!(
+            "1 conflict(s) merging the snapshots, the first: tx 2 is in both a (client 1) and b (client 2)",
+            error.to_string()
+        );
+
+        // Aliases merge into their canonical client's account, unless their histories share a tx id
+        let aliases = Aliases::new([(2, 1)]).unwrap();
+        let aliased = merged.alias(&aliases).unwrap();
+        assert_eq!(1, aliased.accounts.len());
+        assert_eq!((1, 4.0), (aliased.accounts[0].client, aliased.accounts[0].available));
+        assert_eq!(vec![1, 2, 3, 4], aliased.accounts[0].history.iter().map(|entry| entry.tx).collect::<Vec<_>>());
+        let mut clashing = shard(1, &[1]);
+        clashing.accounts.extend(shard(2, &[1]).accounts);
+        let error = clashing.alias(&aliases).unwrap_err();
+        assert!(error.to_string().ends_with("tx 1 is in both client 1 and client 2, merged as client 1"), "{error}");
+    }
+
+    #[test]
+    fn test_purge() {
+        let directory = tempfile::tempdir().unwrap();
+        let state = directory.path().join("state.json");
+        let open = || Interruptible::new(CsvSource::open(String::from(TEST_DIR) + "0-trivial.csv", b',').unwrap(), Interrupt::default());
+        replay_onto(&mut open(), &state, ReplayConfig::default()).unwrap();
+
+        let mut snapshot = Snapshot::load(&state).unwrap();
+        let tombstone = snapshot.purge(1).unwrap();
+        assert_eq!(Tombstone { available: 1.5, held: 0.0, pending: 0.0, transactions: 3 }, tombstone);
+        assert!(snapshot.accounts.iter().all(|account| account.client != 1));
+        assert!(snapshot.purge(1).is_none());
+        snapshot.save(&state).unwrap();
+
+        // The tombstone outlives replays, which open the client a new account
+        let accounts = replay_onto(&mut open(), &state, ReplayConfig::default()).unwrap();
+        assert_eq!("1, 1.5000, 0.0000, 1.5000, false", accounts[&1].to_str_row(1).unwrap());
+        assert_eq!(vec![tombstone], Snapshot::load(&state).unwrap().tombstones);
+    }
+}
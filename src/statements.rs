@@ -0,0 +1,209 @@
+use crate::engine::{replay, Outcome};
+use crate::errors::KrakenError;
+use crate::history::MemoryBudget;
+use crate::input::InputSource;
+use crate::output::csv_row;
+use crate::structures::TransactionType;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Buffered statement text, across all clients, at which the buffers are appended to their files.
+const FLUSH_BYTES: usize = 64 << 20;
+
+const HEADER: &str = "tx,type,amount,status,available,held,total,locked,note,memo";
+
+/// Replay `source` serially, writing one statement per client to `directory/client-<id>.csv`, as `Statements`
+/// does. Returns the number of statements written.
+pub fn write_statements<S: InputSource>(
+    source: S,
+    directory: impl AsRef<Path>,
+    budget: Option<MemoryBudget>,
+) -> Result<usize, KrakenError> {
+    let mut statements = Statements::create(directory.as_ref())?;
+    replay(source, budget, |outcome| statements.record(outcome))?;
+    statements.finish()
+}
+
+/// One statement per client, in `directory/client-<id>.csv`.
+///
+/// Each statement lists every transaction naming the client, in input order, with whether it was applied,
+/// the balances right after it, and a note: what a dispute, resolve, or chargeback did to the referenced
+/// transaction, or why the transaction was rejected, and its memo, quoted where they hold a comma or a quote. Rows are
+/// buffered per client and appended to files
+/// in bulk, so only one file is open at a time, in a staging directory inside `directory`; `finish` moves them in,
+/// replacing the existing statements of the same clients.
+pub struct Statements {
+    directory: PathBuf,
+    staging: TempDir,
+    buffers: HashMap<u32, String>,
+    buffered: usize,
+    /// Clients whose statement has been started by this run.
+    created: HashSet<u32>,
+}
+
+impl Statements {
+    pub fn create(directory: &Path) -> Result<Self, KrakenError> {
+        let in_directory = || KrakenError::InFile(directory.display().to_string(), Box::new(KrakenError::IO));
+        fs::create_dir_all(directory).map_err(|_| in_directory())?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            staging: tempfile::tempdir_in(directory).map_err(|_| in_directory())?,
+            buffers: HashMap::new(),
+            buffered: 0,
+            created: HashSet::new(),
+        })
+    }
+
+    /// Add the row of `outcome` to its client's statement.
+    pub fn record(&mut self, outcome: &Outcome) -> Result<(), KrakenError> {
+        let (tx, held, account) = (outcome.tx, outcome.held_change, outcome.account);
+        let (status, note) = match outcome.result {
+            Ok(()) => (
+                "applied",
+                match outcome.kind {
+                    TransactionType::Deposit if outcome.pending_change > 0.0 => String::from("pending"),
+                    TransactionType::Deposit | TransactionType::Withdrawal if held > 0.0 => {
+                        String::from("held until the account is unlocked")
+                    }
+                    TransactionType::Deposit | TransactionType::Withdrawal => String::new(),
+                    TransactionType::Dispute => format!("holds {held:.4} of tx {tx}"),
+                    TransactionType::Resolve => format!("releases {held:.4} of tx {tx}"),
+                    TransactionType::Chargeback => format!("reverses {held:.4} of tx {tx} and locks the account"),
+                    TransactionType::ChargebackReversal => {
+                        let unheld: f64 = outcome
+                            .unheld
+                            .iter()
+                            .filter(|hold| hold.kind == TransactionType::Deposit)
+                            .map(|hold| hold.amount)
+                            .sum();
+                        let restored = outcome.available_change - unheld;
+                        match outcome.before.locked && !account.locked {
+                            true => format!("restores {restored:.4} of tx {tx} and unlocks the account"),
+                            false => format!("restores {restored:.4} of tx {tx}"),
+                        }
+                    }
+                    TransactionType::AssertBalance => String::from("balance as asserted"),
+                    TransactionType::Authorize => format!("holds {held:.4} until captured"),
+                    TransactionType::Capture => {
+                        let released = outcome.available_change;
+                        format!("takes {:.4} of tx {tx} and releases {released:.4}", held - released)
+                    }
+                    TransactionType::Custom(_) => String::new(),
+                },
+            ),
+            Err(e) => ("rejected", e.to_string()),
+        };
+        let note = match outcome.expired > 0.0 {
+            true => format!("releases {:.4} of expired authorizations; {note}", outcome.expired),
+            false => note,
+        };
+        let note = match outcome.unheld.is_empty() {
+            true => note,
+            false => {
+                let txs: Vec<String> = outcome.unheld.iter().map(|hold| hold.tx.to_string()).collect();
+                format!("{note}; releases tx {} held by the lock", txs.join(" "))
+            }
+        };
+        let note = match outcome.released > 0.0 {
+            true => format!("makes {:.4} of pending deposits available; {note}", outcome.released),
+            false => note,
+        };
+
+        let amount = outcome.amount.map(|amount| format!("{amount:.4}")).unwrap_or_default();
+        let row = csv_row(&[
+            &tx.to_string(),
+            outcome.kind.name(),
+            &amount,
+            status,
+            &format!("{:.4}", account.available),
+            &format!("{:.4}", account.held),
+            &format!("{:.4}", account.total()?),
+            &account.locked.to_string(),
+            &note,
+            outcome.memo.unwrap_or_default(),
+        ]);
+        self.push(outcome.client, row + "\n")
+    }
+
+    /// Write out what's buffered, and move the statements of this run into the directory. Returns how many there are.
+    pub fn finish(mut self) -> Result<usize, KrakenError> {
+        self.flush()?;
+        for client in &self.created {
+            let name = format!("client-{client}.csv");
+            let path = self.directory.join(&name);
+            fs::rename(self.staging.path().join(&name), &path)
+                .map_err(|_| KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO)))?;
+        }
+        Ok(self.created.len())
+    }
+
+    fn push(&mut self, client: u32, row: String) -> Result<(), KrakenError> {
+        self.buffered += row.len();
+        self.buffers.entry(client).or_default().push_str(&row);
+        if self.buffered >= FLUSH_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KrakenError> {
+        for (client, rows) in self.buffers.drain() {
+            let path = self.staging.path().join(format!("client-{client}.csv"));
+            // The first write of a statement starts it with its header
+            let first = self.created.insert(client);
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(!first)
+                .truncate(first)
+                .open(&path)
+                .map_err(|_| KrakenError::InFile(path.display().to_string(), Box::new(KrakenError::IO)))?;
+            if first {
+                writeln!(file, "{HEADER}").map_err(|_| KrakenError::IO)?;
+            }
+            file.write_all(rows.as_bytes()).map_err(|_| KrakenError::IO)?;
+        }
+        self.buffered = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{CsvSource, InputOptions, MultiSource, ReaderKind};
+    use crate::processor::tests::TEST_DIR;
+    use crate::statements::write_statements;
+
+    #[test]
+    fn test_statements() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = CsvSource::open(String::from(TEST_DIR) + "1-dispute-after-withdraw.csv", b',').unwrap();
+        assert_eq!(1, write_statements(source, directory.path(), None).unwrap());
+
+        let statement = std::fs::read_to_string(directory.path().join("client-1.csv")).unwrap();
+        let rows: Vec<&str> = statement.lines().collect();
+        assert_eq!("tx,type,amount,status,available,held,total,locked,note,memo", rows[0]);
+        assert_eq!("0,deposit,10.0000,applied,10.0000,0.0000,10.0000,false,,", rows[1]);
+        assert_eq!("0,dispute,,applied,-9.5000,10.0000,0.5000,false,holds 10.0000 of tx 0,", rows[rows.len() - 1]);
+    }
+
+    #[test]
+    fn test_statement_memos() {
+        let paths = [String::from(TEST_DIR) + "10-memo.csv"];
+        for reader in [ReaderKind::Fast, ReaderKind::Csv] {
+            let directory = tempfile::tempdir().unwrap();
+            let source = MultiSource::new(&paths, InputOptions { reader, ..Default::default() });
+            write_statements(source, directory.path(), None).unwrap();
+
+            let statement = std::fs::read_to_string(directory.path().join("client-1.csv")).unwrap();
+            let rows: Vec<_> = csv::Reader::from_reader(statement.as_bytes()).into_records().map(Result::unwrap).collect();
+            let memos: Vec<&str> = rows.iter().map(|row| &row[9]).collect();
+            assert_eq!(vec!["order-17", "", "refund to card", "ticket 4411", "order 5, gift"], memos);
+            assert!(rows.iter().all(|row| row.len() == 10), "{statement}");
+            assert!(statement.contains("rejected"), "{statement}");
+        }
+    }
+}
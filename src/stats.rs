@@ -0,0 +1,191 @@
+use crate::errors::KrakenError;
+use crate::input::{open_rows, open_source, InputOptions, RowSource};
+use crate::output::four_places;
+use crate::structures::{Transaction, TransactionType};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+/// Malformed rows quoted in the summary. The rest are only counted.
+pub const MAX_ERROR_SAMPLES: usize = 10;
+
+/// Profile of a dataset, gathered without applying any of it.
+#[derive(Debug, Default, Serialize)]
+pub struct DatasetStats {
+    /// Rows read, malformed ones included.
+    pub rows: usize,
+    pub deposits: usize,
+    pub withdrawals: usize,
+    pub disputes: usize,
+    pub resolves: usize,
+    pub chargebacks: usize,
+    pub reversals: usize,
+    pub assertions: usize,
+    pub authorizations: usize,
+    pub captures: usize,
+    /// Distinct clients named by any well-formed row.
+    pub clients: usize,
+    /// Sum of every deposit amount.
+    #[serde(serialize_with = "four_places")]
+    pub deposited: f64,
+    /// Sum of every withdrawal amount.
+    #[serde(serialize_with = "four_places")]
+    pub withdrawn: f64,
+    /// Disputes per deposit.
+    pub dispute_rate: f64,
+    /// Chargebacks per deposit.
+    pub chargeback_rate: f64,
+    /// Smallest and largest amount on a deposit or withdrawal.
+    pub min_amount: Option<f64>,
+    pub max_amount: Option<f64>,
+    /// Rows that couldn't be decoded.
+    pub errors: usize,
+    /// The first `MAX_ERROR_SAMPLES` decoding errors, each naming its file.
+    pub error_samples: Vec<String>,
+}
+
+impl DatasetStats {
+    /// Total amount moved by deposits and withdrawals.
+    pub fn volume(&self) -> f64 {
+        self.deposited + self.withdrawn
+    }
+
+    fn record(&mut self, transaction: &Transaction, clients: &mut HashSet<u32>) {
+        self.rows += 1;
+        clients.insert(transaction.client);
+        match transaction.kind {
+            TransactionType::Deposit => self.deposits += 1,
+            TransactionType::Withdrawal => self.withdrawals += 1,
+            TransactionType::Dispute => self.disputes += 1,
+            TransactionType::Resolve => self.resolves += 1,
+            TransactionType::Chargeback => self.chargebacks += 1,
+            TransactionType::ChargebackReversal => self.reversals += 1,
+            TransactionType::AssertBalance => self.assertions += 1,
+            TransactionType::Authorize => self.authorizations += 1,
+            TransactionType::Capture => self.captures += 1,
+            // Counted among the rows only
+            TransactionType::Custom(_) => {}
+        }
+
+        let Some(amount) = transaction.amount else {
+            return;
+        };
+        match transaction.kind {
+            TransactionType::Deposit => self.deposited += amount,
+            TransactionType::Withdrawal => self.withdrawn += amount,
+            _ => return,
+        }
+        self.min_amount = Some(self.min_amount.map_or(amount, |min| min.min(amount)));
+        self.max_amount = Some(self.max_amount.map_or(amount, |max| max.max(amount)));
+    }
+
+    fn record_error(&mut self, path: &Path, error: KrakenError) {
+        self.rows += 1;
+        self.errors += 1;
+        if self.error_samples.len() < MAX_ERROR_SAMPLES {
+            self.error_samples.push(format!("{}: {error}", path.display()));
+        }
+    }
+}
+
+/// Read every row of `paths`, in order, and summarize them. Nothing is applied, so disputes and the like are
+/// counted whether or not they would succeed.
+///
+/// Malformed rows are counted and skipped. Formats only read a batch at a time (Parquet, Arrow IPC, ISO 20022,
+/// and remote URLs) can't skip a single row, so the first malformed batch ends their file and counts as one
+/// error. An input that can't be read at all is an error.
+pub fn collect_stats<P: AsRef<Path>>(paths: &[P], options: &InputOptions) -> Result<DatasetStats, KrakenError> {
+    let mut stats = DatasetStats::default();
+    let mut clients = HashSet::new();
+
+    for path in paths {
+        let path = path.as_ref();
+        let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+
+        match open_rows(path, options).map_err(in_file)? {
+            Some(mut source) => {
+                while let Some(row) = source.next_row() {
+                    match row {
+                        Ok(transaction) => stats.record(&transaction, &mut clients),
+                        Err(e @ (KrakenError::Parse(_) | KrakenError::Enum(_))) => stats.record_error(path, e),
+                        Err(e) => return Err(in_file(e)),
+                    }
+                }
+            }
+            None => {
+                let mut source = open_source(path, options).map_err(in_file)?;
+                while let Some(batch) = source.next_batch() {
+                    match batch {
+                        Ok(batch) => batch.iter().for_each(|transaction| stats.record(transaction, &mut clients)),
+                        Err(e) => {
+                            stats.record_error(path, e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    stats.clients = clients.len();
+    if stats.deposits > 0 {
+        stats.dispute_rate = stats.disputes as f64 / stats.deposits as f64;
+        stats.chargeback_rate = stats.chargebacks as f64 / stats.deposits as f64;
+    }
+    Ok(stats)
+}
+
+impl fmt::Display for DatasetStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let amount = |amount: Option<f64>| amount.map_or(String::from("-"), |amount| format!("{amount:.4}"));
+        writeln!(f, "rows:            {}", self.rows)?;
+        writeln!(f, "  deposits:      {}", self.deposits)?;
+        writeln!(f, "  withdrawals:   {}", self.withdrawals)?;
+        writeln!(f, "  disputes:      {}", self.disputes)?;
+        writeln!(f, "  resolves:      {}", self.resolves)?;
+        writeln!(f, "  chargebacks:   {}", self.chargebacks)?;
+        writeln!(f, "  reversals:     {}", self.reversals)?;
+        writeln!(f, "  assertions:    {}", self.assertions)?;
+        writeln!(f, "  authorized:    {}", self.authorizations)?;
+        writeln!(f, "  captured:      {}", self.captures)?;
+        writeln!(f, "  errors:        {}", self.errors)?;
+        writeln!(f, "clients:         {}", self.clients)?;
+        writeln!(f, "volume:          {:.4}", self.volume())?;
+        writeln!(f, "  deposited:     {:.4}", self.deposited)?;
+        writeln!(f, "  withdrawn:     {:.4}", self.withdrawn)?;
+        writeln!(f, "min amount:      {}", amount(self.min_amount))?;
+        writeln!(f, "max amount:      {}", amount(self.max_amount))?;
+        writeln!(f, "dispute rate:    {:.4}%", self.dispute_rate * 100.0)?;
+        writeln!(f, "chargeback rate: {:.4}%", self.chargeback_rate * 100.0)?;
+        for sample in &self.error_samples {
+            writeln!(f, "error: {sample}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::InputOptions;
+    use crate::processor::tests::TEST_DIR;
+    use crate::stats::collect_stats;
+    use std::io::Write;
+
+    #[test]
+    fn test_collect_stats() {
+        let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        // One well-formed row and two malformed ones
+        let rows = "type, client, tx, amount\ndeposit, 3, 10, 4.0\nrefund, 3, 11, 1.0\nwithdrawal, 3, x, 1.0\n";
+        write!(file, "{rows}").unwrap();
+
+        let paths = [String::from(TEST_DIR) + "2-chargeback-after-withdraw.csv", file.path().display().to_string()];
+        let stats = collect_stats(&paths, &InputOptions::default()).unwrap();
+        let counts = (stats.deposits, stats.withdrawals, stats.disputes, stats.resolves, stats.chargebacks);
+        assert_eq!((3, 1, 1, 0, 1), counts);
+        assert_eq!((8, 2, 2), (stats.rows, stats.clients, stats.errors));
+        assert_eq!((Some(1.0), Some(10.5)), (stats.min_amount, stats.max_amount));
+        assert_eq!(25.5, stats.volume());
+        assert!(stats.error_samples.iter().all(|sample| sample.starts_with(&paths[1])));
+    }
+}
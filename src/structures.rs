@@ -1,78 +1,379 @@
+use crate::dates::parse_timestamp;
 use crate::errors::KrakenError;
 use crate::errors::KrakenError::{
-    AccountLocked, DisputeStateError, InsufficientFunds, NoSuchTransactionError,
+    AccountLocked, BalanceAssertion, BelowReserve, DisputeStateError, InsufficientFunds, MissingAmount,
+    NoSuchTransactionError, Parse,
 };
-use std::collections::HashMap;
+use crate::handlers;
+use crate::history::{History, MemoryBudget};
+use crate::ids::{ClientId, TxId};
+use crate::minor_units::AmountBackend;
+use crate::rules::{self, LockedPolicy, Rules};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+
+const SECONDS_PER_DAY: i64 = 86_400;
 
 /// Running stats for a Client's account.
 /// Does not store individual transactions, just the overall state of the account.
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct ClientAccount {
     pub available: f64,
     pub held: f64,
     pub locked: bool,
-    pub history: HashMap<u32, Transaction>, // A map of TX to Transaction. Only Deposits and Withdrawals are stored.
+    pub history: History, // A map of TX to Transaction. Only Deposits and Withdrawals are stored.
+    /// Transactions in the history currently disputed.
+    pub open_disputes: u32,
+    /// Transactions in the history charged back, and not reversed since.
+    pub chargebacks: u32,
+    /// Time of the latest transaction applied to the account, of those that had one. Balance assertions don't count.
+    pub last_activity: Option<i64>,
+    /// Sum of the amounts of the deposits and withdrawals applied to the account since it was created or restored.
+    pub volume: f64,
+    /// Time of each authorization still holding funds, by tx id, of those that had one, so they can expire.
+    pub authorizations: BTreeMap<TxId, i64>,
+    /// Deposits not yet available, under a deposit delay in the rules.
+    pub pending_deposits: Vec<PendingDeposit>,
+    /// Transactions that reached the account, applied or refused, since it was created or restored.
+    pub rows: u64,
+    /// Times the available funds turned negative since the account was created or restored, as a dispute of funds
+    /// already withdrawn makes them.
+    pub negative_events: u32,
+    /// Lowest the available funds have been since the account was created or restored, if below zero.
+    pub lowest_available: f64,
+    /// Every time the account was locked or unlocked, oldest first.
+    pub lock_events: Vec<LockEvent>,
+    /// Deposits and withdrawals accepted while the account was locked whose amounts are held until it's unlocked,
+    /// under a `LockedPolicy::Hold` in the rules.
+    pub lock_holds: Vec<LockHold>,
+    /// How the account's amounts are added up and compared, as the rules it was last applied with have it.
+    pub amounts: AmountBackend,
+}
+
+/// A deposit or withdrawal accepted by a locked account, its amount held until the account is unlocked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockHold {
+    pub tx: TxId,
+    pub kind: TransactionType,
+    pub amount: f64,
+}
+
+/// Why an account was locked or unlocked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum LockReason {
+    /// Chargeback `tx` locked the account, as the rules have chargebacks do.
+    Chargeback { tx: TxId },
+    /// The reversal of chargeback `tx` unlocked the account, as the rules allow.
+    ChargebackReversal { tx: TxId },
+    /// An operator locked or unlocked the account.
+    Admin {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+    },
+    /// A fraud rule outside the engine flagged the account.
+    Fraud { rule: String },
+}
+
+impl LockReason {
+    /// The reason as it's tagged in JSON.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LockReason::Chargeback { .. } => "chargeback",
+            LockReason::ChargebackReversal { .. } => "chargeback_reversal",
+            LockReason::Admin { .. } => "admin",
+            LockReason::Fraud { .. } => "fraud",
+        }
+    }
+}
+
+/// An account being locked or unlocked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockEvent {
+    /// Whether the account was locked, rather than unlocked.
+    pub locked: bool,
+    #[serde(flatten)]
+    pub reason: LockReason,
+    /// Time of the event, in seconds since 1970-01-01 UTC, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<i64>,
+}
+
+/// A deposit waiting to become available, which it does at the first of its due row or time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingDeposit {
+    pub tx: TxId,
+    pub amount: f64,
+    /// Value of `ClientAccount::rows` from which the deposit is available.
+    pub due_row: Option<u64>,
+    /// Time from which the deposit is available, in seconds since 1970-01-01 UTC.
+    pub due_at: Option<i64>,
+    /// Whether the deposit is disputed, its amount held rather than pending until a resolve makes it pending again.
+    pub disputed: bool,
 }
 
 impl ClientAccount {
-    pub fn total(&self) -> f64 {
-        self.available + self.held
+    /// An empty account whose history counts against (and may spill under) `budget`.
+    pub fn with_budget(budget: &MemoryBudget) -> Self {
+        Self {
+            history: History::with_budget(budget),
+            ..Default::default()
+        }
+    }
+
+    /// An empty account, budgeted if a budget is supplied.
+    pub fn new(budget: Option<&MemoryBudget>) -> Self {
+        budget.map_or_else(Default::default, Self::with_budget)
+    }
+
+    /// Funds of deposits not yet available, apart from those disputed, which are held. Under `AmountBackend::Minor`,
+    /// fails for funds beyond `minor_units::LIMIT`.
+    pub fn pending(&self) -> Result<f64, KrakenError> {
+        pending_funds(self.amounts, &self.pending_deposits)
+    }
+
+    /// Funds available, held and pending, failing as `pending` does.
+    pub fn total(&self) -> Result<f64, KrakenError> {
+        self.amounts.sum([self.available, self.held, self.pending()?])
+    }
+
+    /// Lock the account for `reason`, recording the event. Returns whether it wasn't locked already.
+    pub fn lock(&mut self, reason: LockReason, at: Option<i64>) -> bool {
+        self.set_locked(true, reason, at)
+    }
+
+    /// Unlock the account for `reason`, recording the event. Returns whether it was locked. Fails, leaving it locked,
+    /// if releasing what the lock held would take a balance beyond `minor_units::LIMIT` under `AmountBackend::Minor`.
+    pub fn unlock(&mut self, reason: LockReason, at: Option<i64>) -> Result<bool, KrakenError> {
+        if !self.locked {
+            return Ok(false);
+        }
+        (self.available, self.held) = release_holds(self.amounts, &self.lock_holds, self.available, self.held)?;
+        self.lock_holds.clear();
+        Ok(self.set_locked(false, reason, at))
     }
 
-    pub fn to_str_row(&self, client_id: u32) -> String {
-        format!("{}, {:.4}, {:.4}, {:.4}, {}",
+    fn set_locked(&mut self, locked: bool, reason: LockReason, at: Option<i64>) -> bool {
+        if self.locked == locked {
+            return false;
+        }
+        self.locked = locked;
+        self.lock_events.push(LockEvent { locked, reason, at });
+        true
+    }
+
+    /// What the locked account does with a transaction of `kind` under `rules`, `Accept` if it isn't locked.
+    fn locked_policy(&self, kind: &TransactionType, rules: &Rules) -> LockedPolicy {
+        match (self.locked, kind) {
+            (false, _) => LockedPolicy::Accept,
+            (true, TransactionType::Deposit) => rules.locked_deposits,
+            (true, TransactionType::Withdrawal) => rules.locked_withdrawals,
+            // Authorizations are held already, but would be captured while the account is locked
+            (true, TransactionType::Authorize) => match rules.locked_withdrawals {
+                LockedPolicy::Accept => LockedPolicy::Accept,
+                _ => LockedPolicy::Reject,
+            },
+            (true, _) => LockedPolicy::Reject,
+        }
+    }
+
+    /// The event that locked the account, while it's locked. Accounts restored from snapshots predating lock events
+    /// are locked without one.
+    pub fn lock_event(&self) -> Option<&LockEvent> {
+        self.lock_events.last().filter(|event| self.locked && event.locked)
+    }
+
+    pub fn to_str_row(&self, client_id: ClientId) -> Result<String, KrakenError> {
+        Ok(format!("{}, {:.4}, {:.4}, {:.4}, {}",
                 client_id,
                 self.available,
                 self.held,
-                self.total(),
-                self.locked)
+                self.total()?,
+                self.locked))
     }
 
     /// Move a Transaction object into the `history` field and then apply logic to the account.
-    /// Invalid transactions are dropped.
+    /// Invalid transactions are dropped. Disputes and chargebacks follow the installed `Rules`.
     pub fn apply_transaction(&mut self, transaction: Transaction) -> Result<(), KrakenError> {
+        self.apply_with_rules(transaction, rules::current())
+    }
+
+    /// `apply_transaction`, with disputes and chargebacks following `rules`. Authorizations that have expired and
+    /// deposits that are due by the time of the transaction are released first, whether it's applied or not.
+    pub fn apply_with_rules(&mut self, transaction: Transaction, rules: &Rules) -> Result<(), KrakenError> {
+        self.amounts = rules.amounts;
+        self.expire_authorizations(transaction.timestamp, rules)?;
+        self.release_pending(transaction.timestamp)?;
+        let activity = transaction.timestamp.filter(|_| transaction.kind != TransactionType::AssertBalance);
+        let volume = match transaction.kind {
+            TransactionType::Deposit | TransactionType::Withdrawal => transaction.amount.unwrap_or_default(),
+            _ => 0.0,
+        };
+        let was_negative = self.available < 0.0;
+        let result = self.apply_kind(transaction, rules);
+        self.rows += 1;
+        result?;
+        if self.available < 0.0 && !was_negative {
+            self.negative_events += 1;
+        }
+        self.lowest_available = self.lowest_available.min(self.available);
+        self.last_activity = self.last_activity.max(activity);
+        self.volume += volume;
+        Ok(())
+    }
+
+    /// Release the funds held by every authorization that `rules` has expiring by `now`, in seconds since 1970-01-01
+    /// UTC, returning how much was released. Nothing expires without a time, or without an expiry in the rules.
+    pub fn expire_authorizations(&mut self, now: Option<i64>, rules: &Rules) -> Result<f64, KrakenError> {
+        self.amounts = rules.amounts;
+        let (Some(now), Some(days)) = (now, rules.authorization_expiry_days) else {
+            return Ok(0.0);
+        };
+        let cutoff = now.saturating_sub(i64::from(days) * SECONDS_PER_DAY);
+        let expired: Vec<TxId> = self.authorizations.iter().filter(|(_, at)| **at <= cutoff).map(|(tx, _)| *tx).collect();
+        let mut released = 0.0;
+        for tx in expired {
+            self.authorizations.remove(&tx);
+            if let Some(authorization) = self.history.get_mut(tx)?
+                && authorization.state == Some(DisputeState::Authorized)
+            {
+                let amount = authorization.amount.ok_or(MissingAmount(tx))?;
+                let available = self.amounts.add(self.available, amount)?;
+                let held = self.amounts.sub(self.held, amount)?;
+                authorization.state = Some(DisputeState::Expired);
+                (self.available, self.held) = (available, held);
+                released += amount;
+            }
+        }
+        Ok(released)
+    }
+
+    /// Make every pending deposit due by the next transaction, at time `now` if it has one, available, returning how
+    /// much was released. Releasing again before that transaction is applied releases nothing more. Disputed deposits
+    /// wait for their dispute to be resolved. Fails, releasing nothing, if the available funds would go beyond
+    /// `minor_units::LIMIT` under `AmountBackend::Minor`.
+    pub fn release_pending(&mut self, now: Option<i64>) -> Result<f64, KrakenError> {
+        let rows = self.rows;
+        let due = |deposit: &PendingDeposit| {
+            !deposit.disputed
+                && (deposit.due_row.is_some_and(|due| rows >= due)
+                    || deposit.due_at.zip(now).is_some_and(|(due, now)| now >= due))
+        };
+        let released = self.amounts.sum(self.pending_deposits.iter().filter(|deposit| due(deposit)).map(|deposit| deposit.amount))?;
+        self.available = self.amounts.add(self.available, released)?;
+        self.pending_deposits.retain(|deposit| !due(deposit));
+        Ok(released)
+    }
+
+    fn apply_kind(&mut self, mut transaction: Transaction, rules: &Rules) -> Result<(), KrakenError> {
+        transaction.amount = transaction.amount.map(|amount| self.amounts.round(amount)).transpose()?;
         match &transaction.kind {
             TransactionType::Deposit => {
-                if self.locked {
+                let policy = self.locked_policy(&transaction.kind, rules);
+                if policy == LockedPolicy::Reject {
                     return Err(AccountLocked(transaction.client));
                 }
 
-                self.available += transaction.amount.expect("Amount may not be null for Deposits!");
+                let amount = transaction.amount.ok_or(MissingAmount(transaction.tx))?;
+                if policy == LockedPolicy::Hold {
+                    self.held = self.amounts.add(self.held, amount)?;
+                    self.lock_holds.push(LockHold { tx: transaction.tx, kind: TransactionType::Deposit, amount });
+                    self.history.insert(transaction)?;
+                    return Ok(());
+                }
+                // Due after this many more transactions of the client, or days, whichever comes first
+                let due_row = rules.deposit_delay_rows.filter(|rows| *rows > 0).map(|rows| self.rows + u64::from(rows));
+                let due_at = rules
+                    .deposit_delay_days
+                    .filter(|days| *days > 0)
+                    .zip(transaction.timestamp)
+                    .map(|(days, at)| at.saturating_add(i64::from(days) * SECONDS_PER_DAY));
+                match due_row.is_some() || due_at.is_some() {
+                    true => {
+                        // Refused if the pending funds would go beyond the limit, as the other balances are
+                        self.amounts.add(pending_funds(self.amounts, &self.pending_deposits)?, amount)?;
+                        let pending = PendingDeposit { tx: transaction.tx, amount, due_row, due_at, disputed: false };
+                        self.pending_deposits.push(pending);
+                    }
+                    false => self.available = self.amounts.add(self.available, amount)?,
+                }
 
-                self.history.insert(transaction.tx, transaction); // Move to history
+                self.history.insert(transaction)?; // Move to history
                 Ok(())
             }
             TransactionType::Withdrawal => {
-                if self.locked {
+                let policy = self.locked_policy(&transaction.kind, rules);
+                if policy == LockedPolicy::Reject {
                     return Err(AccountLocked(transaction.client));
                 }
 
-                if self.available < transaction.amount.expect("Amount may not be null for Withdrawals!") {
+                let amount = transaction.amount.ok_or(MissingAmount(transaction.tx))?;
+                if self.available < amount {
                     return Err(InsufficientFunds(transaction.client));
                 }
+                let available = self.amounts.sub(self.available, amount)?;
+                if let Some(reserve) = rules.reserve_of(transaction.client)
+                    && available < self.amounts.round(reserve)?
+                {
+                    return Err(BelowReserve(transaction.client, reserve));
+                }
 
-                self.available -= transaction.amount.expect("Amount may not be null for Withdrawals!");
+                // Paid out once the account is unlocked
+                if policy == LockedPolicy::Hold {
+                    self.held = self.amounts.add(self.held, amount)?;
+                    self.lock_holds.push(LockHold { tx: transaction.tx, kind: TransactionType::Withdrawal, amount });
+                }
+                self.available = available;
 
-                self.history.insert(transaction.tx, transaction); // Move to history
+                self.history.insert(transaction)?; // Move to history
                 Ok(())
             }
             TransactionType::Dispute => {
                 // Allow locked accounts to still dispute.
-                if let Some(transaction) = self.history.get_mut(&transaction.tx) {
+                if let Some(transaction) = self.history.get_mut(transaction.tx)? {
                     if transaction.state.is_some() {
                         return Err(DisputeStateError(String::from(
                             "Transaction already disputed",
                         )));
                     }
+                    if self.lock_holds.iter().any(|hold| hold.tx == transaction.tx) {
+                        return Err(DisputeStateError(String::from(
+                            "Cannot dispute transaction held by a lock",
+                        )));
+                    }
 
-                    if transaction.kind != TransactionType::Deposit {
+                    let disputable = match transaction.kind {
+                        TransactionType::Deposit => true,
+                        TransactionType::Withdrawal => rules.withdrawals_disputable,
+                        _ => false,
+                    };
+                    if !disputable {
                         return Err(KrakenError::Error)
                     }
+                    if rules.max_open_disputes.is_some_and(|max| self.open_disputes >= max) {
+                        return Err(DisputeStateError(String::from(
+                            "Too many open disputes",
+                        )));
+                    }
 
-                    transaction.state = Some(TransactionType::Dispute);
-                    self.available -= transaction.amount.expect("Amount may not be null for Deposits!");
-                    self.held += transaction.amount.expect("Amount may not be null for Disputes!");
+                    // Only deposits and withdrawals with an amount make it into the history
+                    let amount = transaction.amount.ok_or(MissingAmount(transaction.tx))?;
+                    let available = self.amounts.sub(self.available, amount)?;
+                    let held = self.amounts.add(self.held, amount)?;
+                    transaction.state = Some(DisputeState::Open);
+                    // A disputed withdrawal's amount is held pending its return, rather than taken from the funds, and
+                    // a pending deposit's is taken from the pending funds, keeping its delay for a resolve
+                    if transaction.kind == TransactionType::Deposit {
+                        match self.pending_deposits.iter_mut().find(|deposit| deposit.tx == transaction.tx) {
+                            Some(deposit) => deposit.disputed = true,
+                            None => self.available = available,
+                        }
+                    }
+                    self.held = held;
+                    self.open_disputes += 1;
 
                     Ok(())
                 } else {
@@ -80,12 +381,26 @@ impl ClientAccount {
                 }
             }
             TransactionType::Resolve => {
-                if let Some(transaction) = self.history.get_mut(&transaction.tx) {
+                if let Some(transaction) = self.history.get_mut(transaction.tx)? {
                     match transaction.state {
-                        Some(TransactionType::Dispute) => {
-                            transaction.state = Some(TransactionType::Resolve);
-                            self.available += transaction.amount.expect("Amount may not be null for Deposits");
-                            self.held -= transaction.amount.expect("Amount may not be null for Deposits!");
+                        Some(DisputeState::Open) => {
+                            let amount = transaction.amount.ok_or(MissingAmount(transaction.tx))?;
+                            let available = self.amounts.add(self.available, amount)?;
+                            let held = self.amounts.sub(self.held, amount)?;
+                            // A resolved withdrawal stands, and a resolved pending deposit is pending again, due as it
+                            // was before the dispute, refused if the pending funds would go beyond the limit
+                            if transaction.kind == TransactionType::Deposit {
+                                match self.pending_deposits.iter().position(|deposit| deposit.tx == transaction.tx) {
+                                    Some(index) => {
+                                        self.amounts.add(pending_funds(self.amounts, &self.pending_deposits)?, amount)?;
+                                        self.pending_deposits[index].disputed = false;
+                                    }
+                                    None => self.available = available,
+                                }
+                            }
+                            transaction.state = Some(DisputeState::Resolved);
+                            self.held = held;
+                            self.open_disputes = self.open_disputes.saturating_sub(1);
                             Ok(())
                         }
                         _ => Err(DisputeStateError(String::from(
@@ -97,12 +412,25 @@ impl ClientAccount {
                 }
             }
             TransactionType::Chargeback => {
-                if let Some(transaction) = self.history.get_mut(&transaction.tx) {
+                let (tx, at) = (transaction.tx, transaction.timestamp);
+                if let Some(transaction) = self.history.get_mut(transaction.tx)? {
                     match transaction.state {
-                        Some(TransactionType::Dispute) => {
-                            transaction.state = Some(TransactionType::Chargeback);
-                            self.held -= transaction.amount.expect("Amount may not be null for deposits");
-                            self.locked = true;
+                        Some(DisputeState::Open) => {
+                            let amount = transaction.amount.ok_or(MissingAmount(transaction.tx))?;
+                            let available = self.amounts.add(self.available, amount)?;
+                            let held = self.amounts.sub(self.held, amount)?;
+                            transaction.state = Some(DisputeState::ChargedBack);
+                            // A charged back withdrawal is returned to the funds, and a pending deposit never will be
+                            if transaction.kind == TransactionType::Withdrawal {
+                                self.available = available;
+                            }
+                            self.pending_deposits.retain(|deposit| deposit.tx != transaction.tx);
+                            self.held = held;
+                            self.open_disputes = self.open_disputes.saturating_sub(1);
+                            self.chargebacks += 1;
+                            if rules.chargeback_locks {
+                                self.lock(LockReason::Chargeback { tx }, at);
+                            }
                             Ok(())
                         }
                         _ => Err(DisputeStateError(String::from(
@@ -113,17 +441,247 @@ impl ClientAccount {
                     Err(NoSuchTransactionError(transaction.tx))
                 }
             }
+            TransactionType::ChargebackReversal => {
+                // Allow locked accounts to still reverse, as a reversal may be what unlocks them.
+                let (tx, at) = (transaction.tx, transaction.timestamp);
+                // Only once no other chargeback stands against the account, and only a lock by a chargeback: an
+                // operator's or a fraud rule's lock stays
+                let by_chargeback =
+                    self.lock_event().is_none_or(|event| matches!(event.reason, LockReason::Chargeback { .. }));
+                let unlocks = rules.reversal_unlocks && self.chargebacks <= 1 && by_chargeback && self.locked;
+                if let Some(transaction) = self.history.get_mut(transaction.tx)? {
+                    match transaction.state {
+                        Some(DisputeState::ChargedBack) => {
+                            let amount = transaction.amount.ok_or(MissingAmount(transaction.tx))?;
+                            // The chargeback is undone: a deposit's funds come back, and a withdrawal stands again
+                            let available = match transaction.kind {
+                                TransactionType::Withdrawal => self.amounts.sub(self.available, amount)?,
+                                _ => self.amounts.add(self.available, amount)?,
+                            };
+                            // What the lock held is released with it
+                            let (available, held) = match unlocks {
+                                true => release_holds(self.amounts, &self.lock_holds, available, self.held)?,
+                                false => (available, self.held),
+                            };
+                            transaction.state = Some(DisputeState::Reversed);
+                            (self.available, self.held) = (available, held);
+                            self.chargebacks = self.chargebacks.saturating_sub(1);
+                            if unlocks {
+                                self.lock_holds.clear();
+                                self.set_locked(false, LockReason::ChargebackReversal { tx }, at);
+                            }
+                            Ok(())
+                        }
+                        _ => Err(DisputeStateError(String::from(
+                            "Cannot reverse transaction not charged back",
+                        ))),
+                    }
+                } else {
+                    Err(NoSuchTransactionError(transaction.tx))
+                }
+            }
+            TransactionType::Authorize => {
+                if self.locked_policy(&transaction.kind, rules) != LockedPolicy::Accept {
+                    return Err(AccountLocked(transaction.client));
+                }
+
+                let amount = transaction.amount.ok_or(MissingAmount(transaction.tx))?;
+                if self.available < amount {
+                    return Err(InsufficientFunds(transaction.client));
+                }
+                let available = self.amounts.sub(self.available, amount)?;
+                // As it's to be captured like a withdrawal
+                if let Some(reserve) = rules.reserve_of(transaction.client)
+                    && available < self.amounts.round(reserve)?
+                {
+                    return Err(BelowReserve(transaction.client, reserve));
+                }
+
+                // Held like a disputed deposit, until captured or expired
+                self.held = self.amounts.add(self.held, amount)?;
+                self.available = available;
+                transaction.state = Some(DisputeState::Authorized);
+                if let Some(at) = transaction.timestamp {
+                    self.authorizations.insert(transaction.tx, at);
+                }
+
+                self.history.insert(transaction)?; // Move to history
+                Ok(())
+            }
+            TransactionType::Capture => {
+                // Allow locked accounts to still capture, as the funds are held already.
+                if let Some(authorization) = self.history.get_mut(transaction.tx)? {
+                    if authorization.state != Some(DisputeState::Authorized) {
+                        return Err(DisputeStateError(String::from(
+                            "Cannot capture transaction not authorized",
+                        )));
+                    }
+
+                    let authorized = authorization.amount.ok_or(MissingAmount(authorization.tx))?;
+                    // The whole authorization by default, releasing the rest of it when less is captured
+                    let captured = transaction.amount.unwrap_or(authorized);
+                    if !(0.0..=authorized).contains(&captured) {
+                        return Err(DisputeStateError(String::from(
+                            "Cannot capture more than was authorized",
+                        )));
+                    }
+                    let held = self.amounts.sub(self.held, authorized)?;
+                    let available = self.amounts.add(self.available, self.amounts.sub(authorized, captured)?)?;
+                    authorization.state = Some(DisputeState::Captured);
+                    (self.available, self.held) = (available, held);
+                    self.authorizations.remove(&transaction.tx);
+
+                    Ok(())
+                } else {
+                    Err(NoSuchTransactionError(transaction.tx))
+                }
+            }
+            TransactionType::AssertBalance => {
+                // Only checks the account, so it isn't kept, and locked accounts can be checked too.
+                // Balances are compared to the four places they are reported to.
+                let asserted = transaction.amount.ok_or(MissingAmount(transaction.tx))?;
+                if (asserted * 1e4).round() != (self.available * 1e4).round() {
+                    return Err(BalanceAssertion(transaction.client, transaction.tx, asserted, self.available));
+                }
+                Ok(())
+            }
+            TransactionType::Custom(index) => {
+                let handler = handlers::handler(*index).ok_or(KrakenError::Error)?;
+                handler.apply(self, &transaction)?;
+                // Handlers add up amounts as floats, so their balances are brought back to four places
+                (self.available, self.held) = (self.amounts.round(self.available)?, self.amounts.round(self.held)?);
+                Ok(())
+            }
         }
     }
 }
 
+/// The sum of the `pending` deposits that aren't disputed, added up by `amounts`.
+fn pending_funds(amounts: AmountBackend, pending: &[PendingDeposit]) -> Result<f64, KrakenError> {
+    amounts.sum(pending.iter().filter(|deposit| !deposit.disputed).map(|deposit| deposit.amount))
+}
+
+/// `available` and `held` once what a lock held in `holds` is released: deposits become available, and withdrawals
+/// are paid out.
+fn release_holds(amounts: AmountBackend, holds: &[LockHold], available: f64, held: f64) -> Result<(f64, f64), KrakenError> {
+    holds.iter().try_fold((available, held), |(available, held), hold| {
+        let available = match hold.kind {
+            TransactionType::Deposit => amounts.add(available, hold.amount)?,
+            _ => available,
+        };
+        Ok((available, amounts.sub(held, hold.amount)?))
+    })
+}
+
+/// Codes from which `TransactionType::Custom` types are numbered, as stored in account histories.
+const CUSTOM_CODES: u8 = 0x80;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum TransactionType {
-    Deposit = 0,
-    Withdrawal = 1,
-    Dispute = 2,
-    Resolve = 3,
-    Chargeback = 4,
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Checks the available balance instead of moving money: `assert_balance, client, tx, amount` is refused
+    /// unless the client's available funds are `amount` at that point in the input.
+    AssertBalance,
+    /// Holds funds for a later capture: `authorize, client, tx, amount` moves `amount` from the available funds to
+    /// the held ones, until a capture of the same tx takes it or the authorization expires.
+    Authorize,
+    /// Takes the funds of an authorization, or `amount` of them, releasing the rest: `capture, client, tx[, amount]`.
+    Capture,
+    /// Overturns the chargeback of `tx`, as when the merchant wins the representment, restoring what it took:
+    /// `chargeback_reversal, client, tx`.
+    ChargebackReversal,
+    /// A type registered with `handlers::register`, by its index, applied by its `TransactionHandler`.
+    Custom(u8),
+}
+
+impl TransactionType {
+    /// The type as spelled in input files.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::AssertBalance => "assert_balance",
+            TransactionType::Authorize => "authorize",
+            TransactionType::Capture => "capture",
+            TransactionType::ChargebackReversal => "chargeback_reversal",
+            TransactionType::Custom(index) => handlers::handler(*index).map_or("custom", |handler| handler.name()),
+        }
+    }
+
+    /// The type as a single byte, which `TryFrom<u8>` reads back.
+    pub fn code(&self) -> u8 {
+        match self {
+            TransactionType::Deposit => 0,
+            TransactionType::Withdrawal => 1,
+            TransactionType::Dispute => 2,
+            TransactionType::Resolve => 3,
+            TransactionType::Chargeback => 4,
+            TransactionType::AssertBalance => 5,
+            TransactionType::Authorize => 6,
+            TransactionType::Capture => 7,
+            TransactionType::ChargebackReversal => 8,
+            TransactionType::Custom(index) => CUSTOM_CODES + index,
+        }
+    }
+}
+
+impl Serialize for TransactionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TypeVisitor;
+
+        impl Visitor<'_> for TypeVisitor {
+            type Value = TransactionType;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a transaction type")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                TransactionType::try_from(value).map_err(|_| E::custom(format!("unknown transaction type `{value}`")))
+            }
+
+            fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+                TransactionType::try_from(value).map_err(|_| E::invalid_value(de::Unexpected::Bytes(value), &self))
+            }
+        }
+
+        deserializer.deserialize_str(TypeVisitor)
+    }
+}
+
+impl TryFrom<u8> for TransactionType {
+    type Error = KrakenError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TransactionType::Deposit),
+            1 => Ok(TransactionType::Withdrawal),
+            2 => Ok(TransactionType::Dispute),
+            3 => Ok(TransactionType::Resolve),
+            4 => Ok(TransactionType::Chargeback),
+            5 => Ok(TransactionType::AssertBalance),
+            6 => Ok(TransactionType::Authorize),
+            7 => Ok(TransactionType::Capture),
+            8 => Ok(TransactionType::ChargebackReversal),
+            code if code >= CUSTOM_CODES => Ok(TransactionType::Custom(code - CUSTOM_CODES)),
+            _ => Err(KrakenError::Enum(format!(
+                "Invalid discriminant for TransactionType: {value}"
+            ))),
+        }
+    }
 }
 
 impl TryFrom<String> for TransactionType {
@@ -136,7 +694,11 @@ impl TryFrom<String> for TransactionType {
             "dispute" => Ok(TransactionType::Dispute),
             "resolve" => Ok(TransactionType::Resolve),
             "chargeback" => Ok(TransactionType::Chargeback),
-            _ => Err(KrakenError::Enum(String::from(
+            "assert_balance" => Ok(TransactionType::AssertBalance),
+            "authorize" => Ok(TransactionType::Authorize),
+            "capture" => Ok(TransactionType::Capture),
+            "chargeback_reversal" => Ok(TransactionType::ChargebackReversal),
+            value => handlers::find(value.as_bytes()).ok_or_else(|| KrakenError::Enum(String::from(
                 "Invalid String for TransactionType",
             ))),
         }
@@ -146,24 +708,318 @@ impl TryFrom<String> for TransactionType {
 impl TryFrom<&str> for TransactionType {
     type Error = KrakenError;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
+        TransactionType::try_from(value.as_bytes())
+    }
+}
+
+impl TryFrom<&[u8]> for TransactionType {
+    type Error = KrakenError;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         match value {
-            "deposit" => Ok(TransactionType::Deposit),
-            "withdrawal" => Ok(TransactionType::Withdrawal),
-            "dispute" => Ok(TransactionType::Dispute),
-            "resolve" => Ok(TransactionType::Resolve),
-            "chargeback" => Ok(TransactionType::Chargeback),
-            _ => Err(KrakenError::Enum(String::from(
+            b"deposit" => Ok(TransactionType::Deposit),
+            b"withdrawal" => Ok(TransactionType::Withdrawal),
+            b"dispute" => Ok(TransactionType::Dispute),
+            b"resolve" => Ok(TransactionType::Resolve),
+            b"chargeback" => Ok(TransactionType::Chargeback),
+            b"assert_balance" => Ok(TransactionType::AssertBalance),
+            b"authorize" => Ok(TransactionType::Authorize),
+            b"capture" => Ok(TransactionType::Capture),
+            b"chargeback_reversal" => Ok(TransactionType::ChargebackReversal),
+            value => handlers::find(value).ok_or_else(|| KrakenError::Enum(String::from(
                 "Invalid String for TransactionType",
             ))),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Transaction {
+    #[serde(rename = "type")]
     pub kind: TransactionType,
-    pub client: u32,
+    pub client: ClientId,
+    #[serde(default)]
     pub amount: Option<f64>,
-    pub tx: u32,
-    pub state: Option<TransactionType>,
+    pub tx: TxId,
+    /// Free text passed through from the input, such as an order id, for the audit log and statements. Account
+    /// histories don't keep it.
+    #[serde(default)]
+    pub memo: Option<String>,
+    /// Identifies the transaction across inputs, such as a producer's message id, so one delivered twice is only
+    /// applied once. Engines tracking keys derive one from the type and tx id of transactions without it.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// When the transaction happened, in seconds since 1970-01-01 UTC, if the input says: a number of seconds, or a
+    /// date or time as `dates::parse_timestamp` reads them. Account histories don't keep it.
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub timestamp: Option<i64>,
+    #[serde(skip)]
+    pub state: Option<DisputeState>,
+}
+
+fn deserialize_timestamp<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        Seconds(i64),
+        Text(String),
+    }
+    match Option::<Timestamp>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Timestamp::Seconds(seconds)) => Ok(Some(seconds)),
+        Some(Timestamp::Text(text)) if text.trim().is_empty() => Ok(None),
+        Some(Timestamp::Text(text)) => parse_timestamp(&text).map(Some).map_err(de::Error::custom),
+    }
+}
+
+/// Where a deposit or withdrawal in an account's history stands in the dispute process, once disputed, or where an
+/// authorization stands in its hold. Written as the type of the row that put it there, so state snapshots read
+/// `"dispute"`, `"resolve"`, `"chargeback"`, `"authorize"`, and `"capture"`, or `"expire"` for an expired hold.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DisputeState {
+    /// Disputed, with its amount held until the dispute is resolved or charged back.
+    #[serde(rename = "dispute")]
+    Open,
+    /// The dispute was resolved, releasing the hold.
+    #[serde(rename = "resolve")]
+    Resolved,
+    /// The dispute ended in a chargeback.
+    #[serde(rename = "chargeback")]
+    ChargedBack,
+    /// Authorized, with its amount held until it's captured or expires.
+    #[serde(rename = "authorize")]
+    Authorized,
+    /// The authorization was captured, taking the funds.
+    #[serde(rename = "capture")]
+    Captured,
+    /// The authorization expired, releasing the hold.
+    #[serde(rename = "expire")]
+    Expired,
+    /// The chargeback was reversed, restoring its funds.
+    #[serde(rename = "chargeback_reversal")]
+    Reversed,
+}
+
+impl DisputeState {
+    /// The state as a single byte, which `TryFrom<u8>` reads back.
+    pub fn code(&self) -> u8 {
+        match self {
+            DisputeState::Open => 0,
+            DisputeState::Resolved => 1,
+            DisputeState::ChargedBack => 2,
+            DisputeState::Authorized => 3,
+            DisputeState::Captured => 4,
+            DisputeState::Expired => 5,
+            DisputeState::Reversed => 6,
+        }
+    }
+}
+
+impl TryFrom<&str> for DisputeState {
+    type Error = KrakenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "dispute" => Ok(DisputeState::Open),
+            "resolve" => Ok(DisputeState::Resolved),
+            "chargeback" => Ok(DisputeState::ChargedBack),
+            "authorize" => Ok(DisputeState::Authorized),
+            "capture" => Ok(DisputeState::Captured),
+            "expire" => Ok(DisputeState::Expired),
+            "chargeback_reversal" => Ok(DisputeState::Reversed),
+            _ => Err(KrakenError::Enum(format!("Invalid String for DisputeState: {value}"))),
+        }
+    }
+}
+
+impl TryFrom<u8> for DisputeState {
+    type Error = KrakenError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DisputeState::Open),
+            1 => Ok(DisputeState::Resolved),
+            2 => Ok(DisputeState::ChargedBack),
+            3 => Ok(DisputeState::Authorized),
+            4 => Ok(DisputeState::Captured),
+            5 => Ok(DisputeState::Expired),
+            6 => Ok(DisputeState::Reversed),
+            _ => Err(KrakenError::Enum(format!("Invalid discriminant for DisputeState: {value}"))),
+        }
+    }
+}
+
+impl TryFrom<&str> for Transaction {
+    type Error = KrakenError;
+
+    /// Parse a single `type, client, tx, amount` CSV row.
+    fn try_from(line: &str) -> Result<Self, Self::Error> {
+        Transaction::try_from(line.as_bytes())
+    }
+}
+
+impl TryFrom<&[u8]> for Transaction {
+    type Error = KrakenError;
+
+    /// Parse a single `type, client, tx, amount` CSV row straight from borrowed bytes, without allocating.
+    /// Whitespace around fields is ignored and the amount may be left empty for dispute-flow rows.
+    fn try_from(line: &[u8]) -> Result<Self, Self::Error> {
+        Transaction::parse_delimited(line, b',')
+    }
+}
+
+/// Where the fields of a delimited row are, by position, as the header of the input names them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Columns {
+    pub kind: usize,
+    pub client: usize,
+    pub tx: usize,
+    pub amount: usize,
+    pub memo: Option<usize>,
+    pub idempotency_key: Option<usize>,
+    pub timestamp: Option<usize>,
+}
+
+impl Default for Columns {
+    /// `type, client, tx, amount`, with an optional trailing `memo`.
+    fn default() -> Self {
+        Columns { kind: 0, client: 1, tx: 2, amount: 3, memo: Some(4), idempotency_key: None, timestamp: None }
+    }
+}
+
+impl Columns {
+    /// The columns `header` names, with its fields separated by `delimiter`. A header that doesn't name all of
+    /// `type`, `client`, `tx`, and `amount` is taken to have the usual layout, and one of only those four columns to
+    /// be followed by an unnamed memo, as usual.
+    pub fn from_header(header: &[u8], delimiter: u8) -> Self {
+        let names: Vec<&[u8]> = split_fields(header, delimiter).collect();
+        let find = |name: &str| names.iter().position(|field| *field == name.as_bytes());
+        let (Some(kind), Some(client), Some(tx), Some(amount)) = (find("type"), find("client"), find("tx"), find("amount"))
+        else {
+            return Columns::default();
+        };
+        Columns {
+            kind,
+            client,
+            tx,
+            amount,
+            memo: find("memo").or((names.len() == 4).then_some(4)),
+            idempotency_key: find("idempotency_key"),
+            timestamp: find("timestamp"),
+        }
+    }
+}
+
+impl Transaction {
+    /// Parse a single `type, client, tx, amount` row, with an optional trailing `memo`, whose fields are separated
+    /// by `delimiter`. Fields may be quoted, to hold the delimiter, with any quote inside doubled.
+    pub fn parse_delimited(line: &[u8], delimiter: u8) -> Result<Self, KrakenError> {
+        Transaction::parse_columns(line, delimiter, &Columns::default())
+    }
+
+    /// Parse a single row whose fields are separated by `delimiter` and laid out as `columns` say, quoted as for
+    /// `parse_delimited`. Empty optional fields are left out.
+    pub fn parse_columns(line: &[u8], delimiter: u8, columns: &Columns) -> Result<Self, KrakenError> {
+        let invalid = |field: &str| Parse(format!("Invalid {field} in row: {}", String::from_utf8_lossy(line)));
+        let (mut kind, mut client, mut tx, mut amount) = (None, None, None, None);
+        let (mut memo, mut idempotency_key, mut timestamp) = (None, None, None);
+        for (index, field) in split_fields(line, delimiter).enumerate() {
+            match Some(index) {
+                index if index == Some(columns.kind) => kind = Some(field),
+                index if index == Some(columns.client) => client = Some(field),
+                index if index == Some(columns.tx) => tx = Some(field),
+                index if index == Some(columns.amount) => amount = Some(field),
+                index if index == columns.memo => memo = Some(field),
+                index if index == columns.idempotency_key => idempotency_key = Some(field),
+                index if index == columns.timestamp => timestamp = Some(field),
+                _ => {}
+            }
+        }
+        let text = |field: Option<&[u8]>| {
+            field.filter(|field| !field.is_empty()).map(|field| String::from_utf8_lossy(field).replace("\"\"", "\""))
+        };
+
+        let kind = TransactionType::try_from(kind.unwrap_or_default())?;
+        let client = client.and_then(parse_u32).ok_or_else(|| invalid("client"))?;
+        let tx = tx.and_then(parse_u32).ok_or_else(|| invalid("tx"))?;
+        let amount = match amount {
+            None | Some(b"") => None,
+            Some(field) => Some(
+                std::str::from_utf8(field)
+                    .ok()
+                    .and_then(|field| field.parse::<f64>().ok())
+                    .ok_or_else(|| invalid("amount"))?,
+            ),
+        };
+        let timestamp = text(timestamp).map(|timestamp| parse_timestamp(&timestamp)).transpose()?;
+
+        Ok(Transaction {
+            kind,
+            client,
+            amount,
+            tx,
+            memo: text(memo),
+            idempotency_key: text(idempotency_key),
+            timestamp,
+            state: None,
+        })
+    }
+}
+
+/// Offset of the first byte of `bytes` `stop` holds for, outside quoted fields: those starting with a double quote,
+/// after any whitespace, and running to the next quote that isn't doubled. Quotes anywhere else are plain text.
+pub(crate) fn unquoted_position(bytes: &[u8], delimiter: u8, stop: impl Fn(u8) -> bool) -> Option<usize> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Field {
+        Start,
+        Unquoted,
+        Quoted,
+        Closed,
+    }
+    let mut field = Field::Start;
+    for (offset, byte) in bytes.iter().copied().enumerate() {
+        field = match field {
+            Field::Quoted if byte == b'"' => Field::Closed,
+            Field::Quoted => Field::Quoted,
+            _ if stop(byte) => return Some(offset),
+            _ if byte == delimiter => Field::Start,
+            Field::Closed if byte == b'"' => Field::Quoted,
+            Field::Start if byte == b'"' => Field::Quoted,
+            Field::Start if byte.is_ascii_whitespace() => Field::Start,
+            Field::Closed => Field::Closed,
+            _ => Field::Unquoted,
+        };
+    }
+    None
+}
+
+/// The fields of `line` split at every `delimiter` outside quoted fields, trimmed of whitespace and of the quotes
+/// around them. Doubled quotes inside a field are left as they are.
+fn split_fields(line: &[u8], delimiter: u8) -> impl Iterator<Item = &[u8]> {
+    let mut rest = Some(line);
+    std::iter::from_fn(move || {
+        let line = rest?;
+        let end = unquoted_position(line, delimiter, |byte| byte == delimiter);
+        let field = match end {
+            Some(end) => {
+                rest = Some(&line[end + 1..]);
+                &line[..end]
+            }
+            None => {
+                rest = None;
+                line
+            }
+        };
+        let field = field.trim_ascii();
+        Some(field.strip_prefix(b"\"").and_then(|field| field.strip_suffix(b"\"")).unwrap_or(field))
+    })
+}
+
+fn parse_u32(field: &[u8]) -> Option<u32> {
+    if field.is_empty() {
+        return None;
+    }
+    field.iter().try_fold(0u32, |value, byte| {
+        let digit = (*byte as char).to_digit(10)?;
+        value.checked_mul(10)?.checked_add(digit)
+    })
 }
@@ -0,0 +1,105 @@
+use crate::errors::KrakenError;
+use crate::input::parse_message;
+use crate::server::Ledger;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::TcpListener;
+use tracing::{error, warn, Instrument};
+
+/// Longest line accepted. Anything longer is reported and skipped, rather than buffered without bound.
+const MAX_LINE: u64 = 64 * 1024;
+
+/// Apply every line read from `reader` until it closes, and return how many transactions were applied.
+///
+/// Each line holds one transaction, as a JSON object or a `type, client, tx, amount` row, so a CSV file can be
+/// piped in as is: its header is skipped. Nothing is sent back, since the systems pushing this way often never
+/// read their socket. Malformed lines are logged as warnings, naming `peer` and the line number, and skipped.
+pub async fn ingest<R: AsyncRead + Unpin>(reader: R, ledger: &Ledger, peer: &str) -> usize {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    let (mut applied, mut number) = (0, 0u64);
+    loop {
+        line.clear();
+        match (&mut reader).take(MAX_LINE).read_until(b'\n', &mut line).await {
+            Ok(0) => return applied,
+            Ok(_) => number += 1,
+            Err(e) => {
+                warn!(code = "io", peer, error = %e, "Closing connection");
+                return applied;
+            }
+        }
+
+        if !line.ends_with(b"\n") && line.len() as u64 == MAX_LINE {
+            warn!(code = "line_too_long", peer, line = number, "Skipping line longer than {MAX_LINE} bytes");
+            // Discard the rest of the line
+            let mut rest = Vec::new();
+            if reader.read_until(b'\n', &mut rest).await.is_err() {
+                return applied;
+            }
+            continue;
+        }
+        let trimmed = line.trim_ascii();
+        let header = trimmed.split(|&byte| byte == b',').next().is_some_and(|first| first.trim_ascii() == b"type");
+        if trimmed.is_empty() || header {
+            continue;
+        }
+
+        match parse_message(trimmed) {
+            // Refused transactions are part of normal traffic, as in batch processing
+            Ok(transaction) => applied += ledger.apply([transaction]).iter().filter(|result| result.is_ok()).count(),
+            Err(e) => warn!(code = e.code(), peer, line = number, error = %e, "Skipping malformed line"),
+        }
+    }
+}
+
+/// Accept connections on `address` until `shutdown` completes, applying each one's lines to `ledger` as they
+/// arrive. Connections are read concurrently, so lines from different connections interleave.
+pub async fn serve<F>(address: SocketAddr, ledger: Arc<Ledger>, shutdown: F) -> Result<(), KrakenError>
+where
+    F: Future<Output = ()>,
+{
+    let listener = TcpListener::bind(address).await.map_err(|e| {
+        error!(code = "io", %address, error = %e, "Failed to listen for transaction lines");
+        KrakenError::IO
+    })?;
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => return Ok(()),
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let ledger = ledger.clone();
+                    let span = tracing::info_span!("connection", %peer);
+                    tokio::spawn(async move { ingest(stream, &ledger, &peer.to_string()).await }.instrument(span));
+                }
+                Err(e) => warn!(code = "io", error = %e, "Failed to accept a connection"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::server::Ledger;
+    use crate::tcp::ingest;
+
+    #[test]
+    fn test_ingest() {
+        let ledger = Ledger::new(Engine::new());
+        let lines = "type, client, tx, amount\r\n\
+                     deposit, 1, 1, 5.0\r\n\
+                     \n\
+                     {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":2.0}\n\
+                     refund, 1, 3, 1.0\n\
+                     withdrawal, 1, 4, 9.0\n\
+                     deposit, 2, 5, 1.0";
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        // The header, blank line, malformed refund, and refused withdrawal are skipped; the unterminated last line isn't
+        assert_eq!(3, runtime.block_on(ingest(lines.as_bytes(), &ledger, "test")));
+        assert_eq!(3.0, ledger.account(1).unwrap().unwrap().available);
+        assert!(ledger.account(2).unwrap().is_some());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::errors::KrakenError;
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use std::env;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Exports spans until dropped, then flushes those still buffered.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            tracing::warn!(code = "telemetry", error = %e, "Failed to export the last spans");
+        }
+    }
+}
+
+/// A layer exporting every span over OTLP/HTTP, if `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set. The exporter reads the rest of its configuration, such as headers
+/// and timeouts, from the standard `OTEL_*` variables too. Spans are exported in the background, and the last ones
+/// when the returned `Telemetry` is dropped. Debug and trace events, such as per-transaction refusals, are left
+/// out of the export, whatever the log level.
+pub fn layer<S>() -> Result<Option<(impl Layer<S>, Telemetry)>, KrakenError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let configured = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"];
+    if configured.iter().all(|name| env::var_os(name).is_none()) {
+        return Ok(None);
+    }
+
+    let exporter = SpanExporter::builder().with_http().build().map_err(|e| KrakenError::Telemetry(e.to_string()))?;
+    let mut resource = Resource::builder();
+    if env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+    }
+    let provider = SdkTracerProvider::builder().with_batch_exporter(exporter).with_resource(resource.build()).build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")));
+    Ok(Some((layer.with_filter(LevelFilter::INFO), Telemetry { provider })))
+}
+
+/// Make `span` part of the trace a request came from, given the request's headers, when they carry a W3C
+/// `traceparent`. Headers that aren't valid UTF-8 are ignored.
+pub fn set_remote_parent<'a>(span: &Span, headers: impl IntoIterator<Item = (&'a str, &'a [u8])>) {
+    let headers: HashMap<String, String> = headers
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.to_ascii_lowercase(), String::from_utf8(value.to_vec()).ok()?)))
+        .collect();
+    let context = TraceContextPropagator::new().extract(&headers);
+    // Only fails when no OpenTelemetry layer is installed, in which case there's no trace to join
+    let _ = span.set_parent(context);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::telemetry::set_remote_parent;
+    use opentelemetry::trace::{TraceContextExt, TraceId, TracerProvider};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_remote_parent() {
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request");
+            let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+            set_remote_parent(&span, [("Traceparent", traceparent.as_bytes())]);
+            let trace = span.context().span().span_context().trace_id();
+            assert_eq!(TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(), trace);
+        });
+    }
+}
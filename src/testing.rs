@@ -0,0 +1,240 @@
+use crate::structures::{Transaction, TransactionType};
+use proptest::collection::{vec, SizeRange};
+use proptest::prelude::*;
+
+/// Largest amount generated, in ten-thousandths, so sums of many of them stay exact to four places in an `f64`.
+const MAX_UNITS: u64 = 10_000_000_000;
+
+impl Arbitrary for TransactionType {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    /// Any of the built-in types. Custom types depend on the handlers registered, so are never generated.
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            Just(TransactionType::Deposit),
+            Just(TransactionType::Withdrawal),
+            Just(TransactionType::Dispute),
+            Just(TransactionType::Resolve),
+            Just(TransactionType::Chargeback),
+            Just(TransactionType::AssertBalance),
+            Just(TransactionType::Authorize),
+            Just(TransactionType::Capture),
+            Just(TransactionType::ChargebackReversal),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for Transaction {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    /// A well-formed transaction on its own: an amount when its type takes one, and none otherwise. Nothing
+    /// relates it to any other, so disputes mostly refer to transactions that don't exist; see `transactions`.
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (any::<TransactionType>(), any::<u32>(), any::<u32>(), amount())
+            .prop_map(|(kind, client, tx, amount)| {
+                let amount = matches!(
+                    kind,
+                    TransactionType::Deposit
+                        | TransactionType::Withdrawal
+                        | TransactionType::AssertBalance
+                        | TransactionType::Authorize
+                )
+                .then_some(amount);
+                Transaction { kind, client, tx, amount, memo: None, idempotency_key: None, timestamp: None, state: None }
+            })
+            .boxed()
+    }
+}
+
+/// A positive amount, to four places, as input files hold them.
+pub fn amount() -> impl Strategy<Value = f64> {
+    (1..=MAX_UNITS).prop_map(|units| units as f64 / 10_000.0)
+}
+
+/// One transaction of a generated sequence, before its tx id and what it refers to are known.
+#[derive(Debug, Clone)]
+enum Step {
+    Deposit { client: u32, amount: f64 },
+    Withdrawal { client: u32, amount: f64 },
+    /// A dispute, resolve, chargeback, or chargeback reversal of the earlier deposit picked by `index`, by its own client.
+    Refer { kind: TransactionType, index: usize },
+    /// A deposit or withdrawal reusing the tx id of the earlier deposit picked by `index`.
+    Reuse { kind: TransactionType, index: usize, amount: Option<f64> },
+    /// A dispute, resolve, chargeback, or chargeback reversal of the earlier deposit picked by `index`, by another client.
+    Misdirect { kind: TransactionType, index: usize, client: u32 },
+    /// Any transaction at all, taken as is.
+    Raw(Transaction),
+}
+
+fn step(clients: u32) -> impl Strategy<Value = Step> {
+    let client = 1..=clients.max(1);
+    let reference = prop_oneof![
+        2 => Just(TransactionType::Dispute),
+        1 => Just(TransactionType::Resolve),
+        1 => Just(TransactionType::Chargeback),
+        1 => Just(TransactionType::ChargebackReversal),
+    ];
+    prop_oneof![
+        4 => (client.clone(), amount()).prop_map(|(client, amount)| Step::Deposit { client, amount }),
+        3 => (client, amount()).prop_map(|(client, amount)| Step::Withdrawal { client, amount }),
+        4 => (reference, any::<usize>()).prop_map(|(kind, index)| Step::Refer { kind, index }),
+    ]
+}
+
+/// Amounts no sensible input holds, but some input will: none, zero, negative, far too large, or too precise.
+fn odd_amount() -> impl Strategy<Value = Option<f64>> {
+    prop_oneof![
+        Just(None),
+        Just(Some(0.0)),
+        amount().prop_map(|amount| Some(-amount)),
+        Just(Some(1e15)),
+        Just(Some(1e-9)),
+    ]
+}
+
+fn adversarial_step(clients: u32) -> impl Strategy<Value = Step> {
+    let deposit_or_withdrawal = prop_oneof![Just(TransactionType::Deposit), Just(TransactionType::Withdrawal)];
+    let reference = prop_oneof![
+        Just(TransactionType::Dispute),
+        Just(TransactionType::Resolve),
+        Just(TransactionType::Chargeback),
+        Just(TransactionType::ChargebackReversal),
+    ];
+    prop_oneof![
+        6 => step(clients),
+        1 => (deposit_or_withdrawal.clone(), any::<usize>(), amount())
+            .prop_map(|(kind, index, amount)| Step::Reuse { kind, index, amount: Some(amount) }),
+        1 => (deposit_or_withdrawal, any::<usize>(), odd_amount())
+            .prop_map(|(kind, index, amount)| Step::Reuse { kind, index, amount }),
+        1 => (reference, any::<usize>(), 1..=clients.max(1) + 1)
+            .prop_map(|(kind, index, client)| Step::Misdirect { kind, index, client }),
+        1 => any::<Transaction>().prop_map(Step::Raw),
+    ]
+}
+
+/// Number the steps' transactions from tx 1, and point each reference at an earlier deposit. References made
+/// before any deposit are dropped.
+fn resolve(steps: Vec<Step>) -> Vec<Transaction> {
+    let mut deposits: Vec<(u32, u32)> = Vec::new();
+    let mut transactions = Vec::with_capacity(steps.len());
+    let transaction = |kind, client, tx, amount| Transaction { kind, client, tx, amount, memo: None, idempotency_key: None, timestamp: None, state: None };
+    for step in steps {
+        let tx = transactions.len() as u32 + 1;
+        let picked = |index: usize| deposits.get(index % deposits.len().max(1)).copied();
+        transactions.push(match step {
+            Step::Deposit { client, amount } => {
+                deposits.push((client, tx));
+                transaction(TransactionType::Deposit, client, tx, Some(amount))
+            }
+            Step::Withdrawal { client, amount } => transaction(TransactionType::Withdrawal, client, tx, Some(amount)),
+            Step::Refer { kind, index } => match picked(index) {
+                Some((client, deposit)) => transaction(kind, client, deposit, None),
+                None => continue,
+            },
+            Step::Reuse { kind, index, amount } => match picked(index) {
+                Some((client, deposit)) => transaction(kind, client, deposit, amount),
+                None => continue,
+            },
+            Step::Misdirect { kind, index, client } => match picked(index) {
+                Some((owner, deposit)) if owner != client => transaction(kind, client, deposit, None),
+                _ => continue,
+            },
+            Step::Raw(transaction) => transaction,
+        });
+    }
+    transactions
+}
+
+/// Sequences of well-formed transactions between clients `1..=clients`, such as input files hold: tx ids are
+/// unique, and every dispute, resolve, chargeback, and reversal refers to an earlier deposit of its own client. The
+/// engine may still refuse some, such as withdrawals exceeding the funds or a second chargeback of a deposit.
+/// Shrinking removes transactions, renumbering the rest.
+pub fn transactions(clients: u32, len: impl Into<SizeRange>) -> impl Strategy<Value = Vec<Transaction>> {
+    vec(step(clients), len).prop_map(resolve)
+}
+
+/// Sequences mixing the well-formed transactions of `transactions` with those a hostile or broken producer
+/// sends: reused tx ids, references to unknown transactions and to other clients' deposits, missing, zero,
+/// negative, huge, and overly precise amounts, and unrelated transactions of any type.
+pub fn adversarial_transactions(clients: u32, len: impl Into<SizeRange>) -> impl Strategy<Value = Vec<Transaction>> {
+    vec(adversarial_step(clients), len).prop_map(resolve)
+}
+
+/// Shrink a sequence of transactions `fails` holds for, such as a production input that trips a bug, by
+/// removing ever smaller runs of transactions for as long as `fails` still holds without them. Returns a sequence
+/// `fails` holds for, from which no single transaction can be removed, or the input as is if `fails` doesn't hold
+/// for it.
+pub fn minimize<F>(mut transactions: Vec<Transaction>, mut fails: F) -> Vec<Transaction>
+where
+    F: FnMut(&[Transaction]) -> bool,
+{
+    let mut chunk = transactions.len().div_ceil(2);
+    while chunk > 0 {
+        let (mut start, mut removed) = (0, false);
+        while start < transactions.len() {
+            let end = (start + chunk).min(transactions.len());
+            let candidate: Vec<Transaction> = transactions[..start].iter().chain(&transactions[end..]).cloned().collect();
+            if fails(&candidate) {
+                transactions = candidate;
+                removed = true;
+            } else {
+                start += chunk;
+            }
+        }
+        if !removed {
+            chunk /= 2;
+        }
+    }
+    transactions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::structures::{Transaction, TransactionType};
+    use crate::testing::{adversarial_transactions, minimize, transactions};
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+
+    proptest! {
+        #[test]
+        fn test_transactions(transactions in transactions(4, 0..200)) {
+            let mut deposits = HashSet::new();
+            let mut engine = Engine::new();
+            for transaction in transactions {
+                match transaction.kind {
+                    TransactionType::Deposit => prop_assert!(deposits.insert((transaction.client, transaction.tx))),
+                    TransactionType::Withdrawal => {}
+                    _ => prop_assert!(deposits.contains(&(transaction.client, transaction.tx))),
+                }
+                let _ = engine.apply(transaction);
+            }
+            for account in engine.accounts().values() {
+                prop_assert!(account.held >= -1e-9, "{account:?}");
+            }
+        }
+
+        #[test]
+        fn test_adversarial_transactions(transactions in adversarial_transactions(4, 0..200)) {
+            // Whatever arrives, the engine refuses or applies it without panicking
+            Engine::new().process(transactions);
+        }
+    }
+
+    #[test]
+    fn test_minimize() {
+        let rows = ["deposit, 1, 1, 5.0", "deposit, 2, 2, 1.0", "withdrawal, 1, 3, 2.0", "dispute, 1, 1, ", "deposit, 1, 4, 1.0"];
+        let transactions: Vec<Transaction> = rows.iter().map(|row| Transaction::try_from(*row).unwrap()).collect();
+        // Stands in for a bug: client 1 ending with negative available funds
+        let fails = |transactions: &[Transaction]| {
+            let mut engine = Engine::new();
+            engine.process(transactions.to_vec());
+            engine.account(1).is_some_and(|account| account.available < 0.0)
+        };
+        let minimal: Vec<u32> = minimize(transactions, fails).iter().map(|transaction| transaction.tx).collect();
+        assert_eq!(vec![1, 3, 1], minimal);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::errors::KrakenError;
+use crate::output::Balances;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+pub const HEADER: &str = "client, available, held, total, locked";
+
+/// A point of an event stream written by `write_events`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AsOf {
+    /// Right after the event numbered `seq`.
+    Seq(u64),
+    /// Right after the first event of the tx id, that of the transaction bringing it.
+    Tx(u32),
+}
+
+impl TryFrom<&str> for AsOf {
+    type Error = KrakenError;
+
+    /// Parse `seq:N` or `tx:N`.
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let invalid = || KrakenError::InvalidArgument(format!("Expected seq:N or tx:N: {value}"));
+        let value = value.trim();
+        if let Some(seq) = value.strip_prefix("seq:") {
+            return seq.parse().map(AsOf::Seq).map_err(|_| invalid());
+        }
+        match value.strip_prefix("tx:") {
+            Some(tx) => tx.parse().map(AsOf::Tx).map_err(|_| invalid()),
+            None => Err(invalid()),
+        }
+    }
+}
+
+/// The balances of every client as of an event of the stream, and where the events after it start, so queries of a
+/// later point replay from there rather than from the start.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Number of the event the balances are as of.
+    pub seq: u64,
+    /// Byte offset of the event after it in the stream.
+    pub offset: u64,
+    /// Highest tx id of the events up to it, so queries of a higher one know it came after.
+    pub max_tx: u32,
+    /// SHA-256 of the stream up to `offset`, in hex, so checkpoints of other events aren't trusted.
+    #[serde(default)]
+    pub sha256: String,
+    pub balances: BTreeMap<u32, Balances>,
+}
+
+/// A client's balances as of a point of the stream.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientBalances {
+    pub client: u32,
+    #[serde(flatten)]
+    pub balances: Balances,
+}
+
+impl fmt::Display for ClientBalances {
+    /// A `client, available, held, total, locked` row, as in the report.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let balances = &self.balances;
+        write!(f, "{}, {:.4}, {:.4}, {:.4}, {}", self.client, balances.available, balances.held, balances.total,
+               balances.locked)
+    }
+}
+
+/// What a query reads of a `ChangeEvent`.
+#[derive(Deserialize)]
+struct Event {
+    seq: u64,
+    tx: u32,
+    client: u32,
+    after: Balances,
+}
+
+fn hex(hasher: Sha256) -> String {
+    hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Read the events of `events` one line at a time, from the checkpoint `start`, adding every line read to `hasher`
+/// and calling `apply` with each event and the offset of the next until it returns `false`.
+fn scan<R: BufRead>(
+    mut events: R,
+    start: &Checkpoint,
+    hasher: &mut Sha256,
+    mut apply: impl FnMut(&Event, &Sha256, u64) -> Result<bool, KrakenError>,
+) -> Result<(), KrakenError> {
+    let (mut line, mut offset, mut expected) = (String::new(), start.offset, start.seq + 1);
+    loop {
+        line.clear();
+        let read = events.read_line(&mut line).map_err(|_| KrakenError::IO)?;
+        if read == 0 {
+            return Ok(());
+        }
+        offset += read as u64;
+        hasher.update(line.as_bytes());
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Event =
+            serde_json::from_str(&line).map_err(|e| KrakenError::Parse(format!("event {expected}: {e}")))?;
+        if event.seq != expected {
+            let stale = match start.seq > 0 && expected == start.seq + 1 {
+                true => ", as when the checkpoints are older than the events",
+                false => "",
+            };
+            return Err(KrakenError::Parse(format!("expected event {expected}, found {}{stale}", event.seq)));
+        }
+        expected += 1;
+        if !apply(&event, hasher, offset)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Replay the event stream `events`, writing a `Checkpoint` of every client's balances as a line of JSON to `writer`
+/// after every `every` events. Returns how many were written.
+pub fn write_checkpoints<R: BufRead, W: Write>(events: R, mut writer: W, every: u64) -> Result<u64, KrakenError> {
+    let mut checkpoint = Checkpoint::default();
+    let mut written = 0;
+    scan(events, &Checkpoint::default(), &mut Sha256::new(), |event, hasher, offset| {
+        checkpoint.balances.insert(event.client, event.after.clone());
+        checkpoint.max_tx = checkpoint.max_tx.max(event.tx);
+        (checkpoint.seq, checkpoint.offset) = (event.seq, offset);
+        if event.seq % every.max(1) == 0 {
+            checkpoint.sha256 = hex(hasher.clone());
+            serde_json::to_writer(&mut writer, &checkpoint).map_err(|_| KrakenError::IO)?;
+            writeln!(writer).map_err(|_| KrakenError::IO)?;
+            written += 1;
+        }
+        Ok(true)
+    })?;
+    writer.flush().map_err(|_| KrakenError::IO)?;
+    Ok(written)
+}
+
+/// The balances of every client with events up to `as_of` in the event stream `events`, as they were then, by
+/// client. The stream is replayed from the start, or from the latest of `checkpoints`, as written by
+/// `write_checkpoints` for the same stream, known to come before `as_of`. The checkpoint is only trusted if the
+/// stream up to it hashes to what it was written from, which reads those events but doesn't parse them. A `Seq` past
+/// the end is the end; a `Tx` without an event is refused.
+pub fn balances_as_of<E, C>(
+    mut events: E,
+    checkpoints: Option<C>,
+    as_of: AsOf,
+) -> Result<BTreeMap<u32, Balances>, KrakenError>
+where
+    E: Read + Seek,
+    C: BufRead,
+{
+    let mut start = Checkpoint::default();
+    for line in checkpoints.into_iter().flat_map(BufRead::lines) {
+        let line = line.map_err(|_| KrakenError::IO)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let checkpoint: Checkpoint =
+            serde_json::from_str(&line).map_err(|e| KrakenError::Parse(format!("checkpoint: {e}")))?;
+        let before = match as_of {
+            AsOf::Seq(seq) => checkpoint.seq <= seq,
+            AsOf::Tx(tx) => checkpoint.max_tx < tx,
+        };
+        if !before {
+            break;
+        }
+        start = checkpoint;
+    }
+
+    if start.seq > 0 {
+        let mut covered = (&mut events).take(start.offset);
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 1 << 16];
+        loop {
+            match covered.read(&mut buffer).map_err(|_| KrakenError::IO)? {
+                0 => break,
+                read => hasher.update(&buffer[..read]),
+            }
+        }
+        if hex(hasher) != start.sha256 {
+            let detail = format!("checkpoint of event {} is of other events, as when the checkpoints are older than the \
+                                  events", start.seq);
+            return Err(KrakenError::Parse(detail));
+        }
+    }
+    events.seek(SeekFrom::Start(start.offset)).map_err(|_| KrakenError::IO)?;
+    let mut balances = start.balances.clone();
+    let mut found = false;
+    scan(BufReader::new(events), &start, &mut Sha256::new(), |event, _, _| {
+        if let AsOf::Seq(seq) = as_of
+            && event.seq > seq
+        {
+            return Ok(false);
+        }
+        balances.insert(event.client, event.after.clone());
+        found = as_of == AsOf::Tx(event.tx);
+        Ok(!found)
+    })?;
+    match as_of {
+        AsOf::Tx(tx) if !found => Err(KrakenError::NoSuchTransactionError(tx)),
+        _ => Ok(balances),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::write_events;
+    use crate::input::CsvSource;
+    use crate::output::Balances;
+    use crate::processor::tests::TEST_DIR;
+    use crate::time_travel::{balances_as_of, write_checkpoints, AsOf};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_balances_as_of() {
+        let source = CsvSource::open(String::from(TEST_DIR) + "2-chargeback-after-withdraw.csv", b',').unwrap();
+        let mut events = Vec::new();
+        assert_eq!(5, write_events(source, &mut events, None).unwrap());
+        let mut checkpoints = Vec::new();
+        assert_eq!(2, write_checkpoints(Cursor::new(&events), &mut checkpoints, 2).unwrap());
+
+        let available = |as_of, checkpoints: Option<&[u8]>| {
+            let balances = balances_as_of(Cursor::new(&events), checkpoints, as_of).unwrap();
+            balances.get(&1).map(|balances: &Balances| balances.available)
+        };
+        // From the start and from a checkpoint alike
+        for checkpoints in [None, Some(checkpoints.as_slice())] {
+            assert_eq!(None, available(AsOf::Seq(0), checkpoints));
+            assert_eq!(Some(10.0), available(AsOf::Tx(0), checkpoints));
+            assert_eq!(Some(0.5), available(AsOf::Seq(3), checkpoints));
+            assert_eq!(Some(-9.5), available(AsOf::Seq(99), checkpoints));
+        }
+        assert!(balances_as_of(Cursor::new(&events), None::<&[u8]>, AsOf::Tx(99)).is_err());
+
+        // Checkpoints of other events are told apart, here events missing the one after the first checkpoint, and
+        // events of the same shape but other amounts
+        let lines: Vec<&str> = std::str::from_utf8(&events).unwrap().split_inclusive('\n').collect();
+        let other = [lines[0], lines[1], lines[3], lines[4]].concat();
+        let error = balances_as_of(Cursor::new(other), Some(checkpoints.as_slice()), AsOf::Seq(3)).unwrap_err();
+        assert!(error.to_string().contains("older than the events"), "{error}");
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("doubled.csv");
+        let doubled = "type, client, tx, amount\ndeposit, 1, 0, 20\ndeposit, 1, 1, 2\nwithdrawal, 1, 2, 20.5\n";
+        std::fs::write(&path, doubled).unwrap();
+        let mut other = Vec::new();
+        write_events(CsvSource::open(path.display().to_string(), b',').unwrap(), &mut other, None).unwrap();
+        let error = balances_as_of(Cursor::new(&other), Some(checkpoints.as_slice()), AsOf::Seq(2)).unwrap_err();
+        assert!(error.to_string().contains("checkpoint of event 2 is of other events"), "{error}");
+        let balances = balances_as_of(Cursor::new(&other), None::<&[u8]>, AsOf::Seq(2)).unwrap();
+        assert_eq!(22.0, balances[&1].available);
+        assert_eq!(Ok(AsOf::Tx(10_000)), AsOf::try_from("tx:10000").map_err(|e| e.to_string()));
+        assert!(AsOf::try_from("10000").is_err());
+    }
+}
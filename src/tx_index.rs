@@ -0,0 +1,221 @@
+use crate::errors::KrakenError;
+use crate::structures::TransactionType;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// First bytes of an index file, with the format's version.
+const MAGIC: &[u8; 8] = b"PPTXIDX1";
+
+/// Tx ids of one client, as inclusive runs of consecutive ids keyed by their first, so ids numbered in sequence,
+/// as most inputs number them, take a single run however many there are.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Runs {
+    runs: BTreeMap<u32, u32>,
+}
+
+impl Runs {
+    fn contains(&self, tx: u32) -> bool {
+        self.runs.range(..=tx).next_back().is_some_and(|(_, &end)| tx <= end)
+    }
+
+    /// Add `tx`, joining the runs on either side of it, returning `false` if it was already there.
+    fn insert(&mut self, tx: u32) -> bool {
+        let before = self.runs.range(..=tx).next_back().map(|(&start, &end)| (start, end));
+        if before.is_some_and(|(_, end)| tx <= end) {
+            return false;
+        }
+        let start = match before {
+            Some((start, end)) if end.checked_add(1) == Some(tx) => start,
+            _ => tx,
+        };
+        let end = tx.checked_add(1).and_then(|next| self.runs.remove(&next)).unwrap_or(tx);
+        self.runs.insert(start, end);
+        true
+    }
+
+    /// Take `tx` out, splitting the run holding it, returning `false` if it wasn't there.
+    fn remove(&mut self, tx: u32) -> bool {
+        let Some((start, end)) = self.runs.range(..=tx).next_back().map(|(&start, &end)| (start, end)) else {
+            return false;
+        };
+        if tx > end {
+            return false;
+        }
+        self.runs.remove(&start);
+        if start < tx {
+            self.runs.insert(start, tx - 1);
+        }
+        if tx < end {
+            self.runs.insert(tx + 1, end);
+        }
+        true
+    }
+
+    fn len(&self) -> u64 {
+        self.runs.iter().map(|(&start, &end)| u64::from(end - start) + 1).sum()
+    }
+}
+
+/// Every tx id each client has used, kept across files and restarts in a compact file of its own, so a transaction
+/// reusing one, such as a deposit sent again in the next day's file, is detected as a duplicate. Only transactions
+/// that bring their own tx id count: disputes, resolves, chargebacks, and captures refer to one already used.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TxIndex {
+    clients: BTreeMap<u32, Runs>,
+}
+
+impl TxIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether transactions of type `kind` bring a tx id of their own, rather than referring to one.
+    pub fn indexes(kind: &TransactionType) -> bool {
+        !matches!(
+            kind,
+            TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::ChargebackReversal
+                | TransactionType::Capture
+        )
+    }
+
+    pub fn contains(&self, client: u32, tx: u32) -> bool {
+        self.clients.get(&client).is_some_and(|runs| runs.contains(tx))
+    }
+
+    /// Record that `client` used `tx`, returning `false` if it had before.
+    pub fn insert(&mut self, client: u32, tx: u32) -> bool {
+        self.clients.entry(client).or_default().insert(tx)
+    }
+
+    /// Forget that `client` used `tx`, returning `false` if it hadn't.
+    pub fn remove(&mut self, client: u32, tx: u32) -> bool {
+        let Some(runs) = self.clients.get_mut(&client) else {
+            return false;
+        };
+        let removed = runs.remove(tx);
+        if runs.runs.is_empty() {
+            self.clients.remove(&client);
+        }
+        removed
+    }
+
+    /// Tx ids recorded, across every client.
+    pub fn len(&self) -> u64 {
+        self.clients.values().map(Runs::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Read an index written by `save`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KrakenError> {
+        let mut bytes = Vec::new();
+        File::open(path).and_then(|file| BufReader::new(file).read_to_end(&mut bytes)).map_err(|_| KrakenError::IO)?;
+        Self::decode(&bytes)
+    }
+
+    /// Write the index to `path` atomically, as `Snapshot::save` does: a crash mid-write leaves the previous index
+    /// intact.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), KrakenError> {
+        let path = path.as_ref();
+        let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let mut temporary = tempfile::NamedTempFile::new_in(directory).map_err(|_| KrakenError::IO)?;
+
+        let mut writer = BufWriter::new(temporary.as_file_mut());
+        writer.write_all(&self.encode()).and_then(|_| writer.flush()).map_err(|_| KrakenError::IO)?;
+        drop(writer);
+
+        temporary.as_file().sync_all().map_err(|_| KrakenError::IO)?;
+        temporary.persist(path).map_err(|_| KrakenError::IO)?;
+        Ok(())
+    }
+
+    /// `MAGIC`, the number of clients, then each client with its number of runs and the first and last tx of each,
+    /// every number a little-endian `u32`.
+    fn encode(&self) -> Vec<u8> {
+        let runs: usize = self.clients.values().map(|runs| runs.runs.len()).sum();
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + self.clients.len() * 8 + runs * 8);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(self.clients.len() as u32).to_le_bytes());
+        for (client, runs) in &self.clients {
+            bytes.extend_from_slice(&client.to_le_bytes());
+            bytes.extend_from_slice(&(runs.runs.len() as u32).to_le_bytes());
+            for (start, end) in &runs.runs {
+                bytes.extend_from_slice(&start.to_le_bytes());
+                bytes.extend_from_slice(&end.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, KrakenError> {
+        let invalid = |reason: &str| KrakenError::Parse(format!("Invalid tx index: {reason}"));
+        let rest = bytes.strip_prefix(MAGIC.as_slice()).ok_or_else(|| invalid("not an index file"))?;
+        let mut numbers = rest.chunks(4).map(|chunk| chunk.try_into().map(u32::from_le_bytes));
+        let mut next = || numbers.next().ok_or_else(|| invalid("truncated"))?.map_err(|_| invalid("truncated"));
+
+        let mut clients = BTreeMap::new();
+        for _ in 0..next()? {
+            let client = next()?;
+            let mut runs = BTreeMap::new();
+            for _ in 0..next()? {
+                let (start, end) = (next()?, next()?);
+                if start > end {
+                    return Err(invalid("run ending before it starts"));
+                }
+                runs.insert(start, end);
+            }
+            clients.insert(client, Runs { runs });
+        }
+        match next() {
+            Err(_) => Ok(Self { clients }),
+            Ok(_) => Err(invalid("trailing bytes")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::structures::Transaction;
+    use crate::tx_index::TxIndex;
+
+    #[test]
+    fn test_tx_index() {
+        let mut index = TxIndex::new();
+        for tx in [1, 2, 4, 3, 10, 9] {
+            assert!(index.insert(1, tx));
+        }
+        assert!(!index.insert(1, 3));
+        assert!(index.insert(2, 3));
+        assert!(index.contains(1, 4) && !index.contains(1, 5) && !index.contains(3, 1));
+        assert_eq!(7, index.len());
+        // Consecutive ids are kept as one run
+        assert_eq!(vec![(1, 4), (9, 10)], index.clients[&1].runs.iter().map(|(&s, &e)| (s, e)).collect::<Vec<_>>());
+        // Removing an id splits its run
+        assert!(index.remove(1, 2) && !index.remove(1, 2) && !index.remove(1, 5) && index.insert(1, 2));
+        assert!(index.remove(2, 3) && !index.contains(2, 3) && index.insert(2, 3));
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("index.bin");
+        index.save(&path).unwrap();
+        assert_eq!(8 + 4 + (8 + 2 * 8) + (8 + 8), std::fs::metadata(&path).unwrap().len());
+        let index = TxIndex::load(&path).unwrap();
+        assert!(index.contains(1, 2) && index.contains(2, 3));
+        std::fs::write(&path, &std::fs::read(&path).unwrap()[..20]).unwrap();
+        assert_eq!("Parse Error: Invalid tx index: truncated", TxIndex::load(&path).unwrap_err().to_string());
+
+        // A deposit reusing a tx id of its client is a duplicate, while a dispute refers to one
+        let mut engine = Engine::new().with_tx_index(Some(index));
+        let rows = ["deposit, 1, 5, 2.0", "deposit, 1, 4, 2.0", "dispute, 1, 5, ", "deposit, 3, 4, 1.0"];
+        let results: Vec<_> = rows.map(|row| engine.apply(Transaction::try_from(row).unwrap()).map_err(|e| e.to_string())).into();
+        assert_eq!(vec![Ok(()), Err(String::from("Duplicate transaction: 4")), Ok(()), Ok(())], results);
+        assert!(engine.tx_index().unwrap().contains(1, 5));
+    }
+}
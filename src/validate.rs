@@ -0,0 +1,208 @@
+use crate::errors::KrakenError;
+use crate::input::{open_rows, open_source, InputOptions, RowSource};
+use crate::rules;
+use crate::structures::{Transaction, TransactionType};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// Something wrong with one row of the input.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub path: String,
+    /// Position of the row among its file's rows, counting from 1 and not counting headers.
+    pub row: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: row {}: {}", self.path, self.row, self.message)
+    }
+}
+
+/// Outcome of `validate`.
+#[derive(Debug, Default, Serialize)]
+pub struct Validation {
+    /// Rows read, malformed ones included.
+    pub rows: usize,
+    pub problems: usize,
+}
+
+/// Deposits and withdrawals seen so far, by tx id, with the client they belong to.
+#[derive(Default)]
+struct Checker {
+    transactions: HashMap<u32, (u32, TransactionType)>,
+}
+
+impl Checker {
+    /// Check a well-formed row against the rows before it, without applying anything: balances aren't
+    /// computed, so a withdrawal exceeding the funds, for example, isn't a problem.
+    fn check(&mut self, transaction: &Transaction) -> Option<String> {
+        let (client, tx) = (transaction.client, transaction.tx);
+        match transaction.kind {
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Authorize => {
+                let Some(amount) = transaction.amount else {
+                    return Some(format!("{:?} tx {tx} has no amount", transaction.kind));
+                };
+                if !amount.is_finite() || amount < 0.0 {
+                    return Some(format!("{:?} tx {tx} has an invalid amount: {amount}", transaction.kind));
+                }
+                if let Some((first, _)) = self.transactions.get(&tx) {
+                    return Some(format!("tx {tx} was already used by client {first}"));
+                }
+                self.transactions.insert(tx, (client, transaction.kind.clone()));
+                None
+            }
+            // What a custom type's amount and tx id mean is up to its handler
+            TransactionType::Custom(_) => None,
+            // Assertions don't name a transaction, so their tx ids may be anything
+            TransactionType::AssertBalance => match transaction.amount {
+                Some(amount) if amount.is_finite() => None,
+                Some(amount) => Some(format!("AssertBalance tx {tx} has an invalid amount: {amount}")),
+                None => Some(format!("AssertBalance tx {tx} has no amount")),
+            },
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::ChargebackReversal
+            | TransactionType::Capture => {
+                match self.transactions.get(&tx) {
+                    None => Some(format!("{:?} references missing tx {tx}", transaction.kind)),
+                    Some((owner, _)) if *owner != client => {
+                        Some(format!("{:?} by client {client} references tx {tx} of client {owner}", transaction.kind))
+                    }
+                    Some((_, TransactionType::Authorize)) if transaction.kind != TransactionType::Capture => {
+                        Some(format!("{:?} references authorization tx {tx}; authorizations are captured", transaction.kind))
+                    }
+                    Some((_, kind)) if transaction.kind == TransactionType::Capture && *kind != TransactionType::Authorize => {
+                        Some(format!("Capture references {kind:?} tx {tx}; only authorizations can be captured"))
+                    }
+                    Some(_) if transaction.kind == TransactionType::Capture => match transaction.amount {
+                        Some(amount) if !amount.is_finite() || amount < 0.0 => {
+                            Some(format!("Capture tx {tx} has an invalid amount: {amount}"))
+                        }
+                        _ => None,
+                    },
+                    Some((_, TransactionType::Withdrawal))
+                        if transaction.kind == TransactionType::Dispute && !rules::current().withdrawals_disputable =>
+                    {
+                        Some(format!("Dispute references withdrawal tx {tx}; only deposits can be disputed"))
+                    }
+                    Some(_) => None,
+                }
+            }
+        }
+    }
+}
+
+/// Read every row of `paths`, in order, checking each for problems without computing any balances, and pass
+/// every problem found to `report` as it is found.
+///
+/// Rows that can't be decoded (missing columns, malformed amounts, unknown transaction types) are problems,
+/// as are deposits, withdrawals, and authorizations without a valid amount or reusing a tx id, disputes, resolves,
+/// chargebacks, their reversals, and captures referencing a tx that no earlier row of the same client created, and captures of
+/// anything but an authorization, or disputes of one. Formats only read a batch at a
+/// time (Parquet, Arrow IPC, ISO 20022, and remote URLs) can't skip a malformed row, so the first one ends
+/// their file. An input that can't be read at all is an error.
+pub fn validate<P, F>(paths: &[P], options: &InputOptions, mut report: F) -> Result<Validation, KrakenError>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Problem),
+{
+    let mut validation = Validation::default();
+    let mut checker = Checker::default();
+
+    for path in paths {
+        let path = path.as_ref();
+        let in_file = |e| KrakenError::InFile(path.display().to_string(), Box::new(e));
+        let mut row = 0;
+        let mut problem = |row: usize, message: String| {
+            validation.problems += 1;
+            report(&Problem { path: path.display().to_string(), row, message });
+        };
+
+        match open_rows(path, options).map_err(in_file)? {
+            Some(mut source) => {
+                while let Some(result) = source.next_row() {
+                    row += 1;
+                    match result {
+                        Ok(transaction) => {
+                            if let Some(message) = checker.check(&transaction) {
+                                problem(row, message);
+                            }
+                        }
+                        Err(e @ (KrakenError::Parse(_) | KrakenError::Enum(_))) => problem(row, e.to_string()),
+                        Err(e) => return Err(in_file(e)),
+                    }
+                }
+            }
+            None => {
+                let mut source = open_source(path, options).map_err(in_file)?;
+                while let Some(batch) = source.next_batch() {
+                    match batch {
+                        Ok(batch) => {
+                            for transaction in &batch {
+                                row += 1;
+                                if let Some(message) = checker.check(transaction) {
+                                    problem(row, message);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            row += 1;
+                            problem(row, e.to_string());
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        validation.rows += row;
+    }
+
+    Ok(validation)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::InputOptions;
+    use crate::processor::tests::{TEST_CASES, TEST_DIR};
+    use crate::validate::validate;
+    use std::io::Write;
+
+    #[test]
+    fn test_validate() {
+        // The bundled cases exercise failing transactions, but none of them is malformed
+        let paths: Vec<String> = TEST_CASES.iter().map(|(file, _)| String::from(TEST_DIR) + file).collect();
+        for path in &paths {
+            let validation = validate(&[path], &InputOptions::default(), |problem| panic!("{problem}")).unwrap();
+            assert!(validation.rows > 0);
+        }
+
+        let mut file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        let rows = "type, client, tx, amount\n\
+                    deposit, 1, 1, 5.0\n\
+                    refund, 1, 2, 1.0\n\
+                    deposit, 1, 3, abc\n\
+                    dispute, 1, 9,\n\
+                    deposit, 2, 1, 3.0\n\
+                    withdrawal, 2, 4, -1.0\n\
+                    chargeback, 2, 1,\n\
+                    resolve, 1, 1,\n\
+                    authorize, 1, 5, 2.0\n\
+                    capture, 1, 5, 1.0\n\
+                    capture, 1, 1,\n\
+                    dispute, 1, 5,\n";
+        write!(file, "{rows}").unwrap();
+
+        let mut problems = Vec::new();
+        let validation = validate(&[file.path()], &InputOptions::default(), |problem| problems.push(problem.row)).unwrap();
+        assert_eq!(12, validation.rows);
+        assert_eq!(8, validation.problems);
+        // The chargeback names a tx of client 1, and the resolve a tx that was never disputed, which is fine. Only
+        // authorizations are captured, and they aren't disputed.
+        assert_eq!(vec![2, 3, 4, 5, 6, 7, 11, 12], problems);
+    }
+}
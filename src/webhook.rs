@@ -0,0 +1,183 @@
+use crate::engine::EngineObserver;
+use crate::errors::KrakenError;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Longest wait between two attempts at a delivery.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long an endpoint has to answer one attempt.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where notifications are posted, and how hard to try.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    /// `http://` URLs, each sent every notification.
+    pub urls: Vec<String>,
+    /// Attempts after the first before a notification is given up on.
+    pub retries: u32,
+    /// Wait before the first retry, doubled before each one after, up to `MAX_BACKOFF`.
+    pub backoff: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self { urls: Vec::new(), retries: 5, backoff: Duration::from_secs(1) }
+    }
+}
+
+/// Something a risk team wants to hear about as it happens, posted as JSON with its `event` name.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Notification {
+    /// A dispute started holding `amount` of transaction `tx`.
+    DisputeOpened { client: u32, tx: u32, amount: f64 },
+    /// Chargeback `tx` locked the account.
+    AccountLocked { client: u32, tx: u32 },
+    /// Transaction `tx` turned the available funds negative, leaving them at `available`.
+    NegativeBalance { client: u32, tx: u32, available: f64 },
+}
+
+/// Observer queueing a notification of every dispute opened, account locked, and balance turned negative, for `deliver` to post. Queueing
+/// never blocks, so a slow endpoint doesn't hold up the engine.
+pub struct Notifier {
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl Notifier {
+    /// The observer, and the queue to hand to `deliver`.
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self { sender }, receiver)
+    }
+
+    fn send(&self, notification: Notification) {
+        // A notification always serializes, and sending only fails once delivery has stopped, on shutdown
+        let _ = self.sender.send(serde_json::to_string(&notification).unwrap_or_default());
+    }
+}
+
+impl EngineObserver for Notifier {
+    fn on_account_locked(&mut self, client: u32, tx: u32) {
+        self.send(Notification::AccountLocked { client, tx });
+    }
+
+    fn on_dispute_opened(&mut self, client: u32, tx: u32, amount: f64) {
+        self.send(Notification::DisputeOpened { client, tx, amount });
+    }
+
+    fn on_negative_balance(&mut self, client: u32, tx: u32, available: f64) {
+        self.send(Notification::NegativeBalance { client, tx, available });
+    }
+}
+
+/// Post every JSON body received to each of `config.urls`, until its sender, such as a `Notifier`, is dropped. Each
+/// delivery is retried on its own task, so one endpoint being down delays neither the others nor later bodies,
+/// which may then arrive out of order. Deliveries still retrying when the runtime shuts down are lost.
+pub async fn deliver(config: WebhookConfig, mut bodies: mpsc::UnboundedReceiver<String>) {
+    while let Some(body) = bodies.recv().await {
+        for url in &config.urls {
+            tokio::spawn(post_with_retries(url.clone(), body.clone(), config.retries, config.backoff));
+        }
+    }
+}
+
+/// Post `body` to `url`, retrying up to `retries` times with exponential backoff, and log it if all attempts fail.
+async fn post_with_retries(url: String, body: String, retries: u32, mut backoff: Duration) {
+    for attempt in 0..=retries {
+        let error = match tokio::time::timeout(TIMEOUT, post(&url, &body)).await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e,
+            Err(_) => KrakenError::Webhook(format!("{url}: timed out")),
+        };
+        if attempt == retries {
+            warn!(code = error.code(), attempts = attempt + 1, %body, "{error}, giving up");
+            return;
+        }
+        debug!(code = error.code(), attempt = attempt + 1, "{error}, retrying in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Post `body` to `url` as JSON, failing unless the response has a `2xx` status. Only plain `http://` URLs are
+/// supported.
+async fn post(url: &str, body: &str) -> Result<(), KrakenError> {
+    let failed = |e: std::io::Error| KrakenError::Webhook(format!("{url}: {e}"));
+    let rest = url.strip_prefix("http://").ok_or_else(|| KrakenError::InvalidArgument(format!("Not an http:// URL: {url}")))?;
+    let (host, path) = rest.split_once('/').map_or((rest, String::from("/")), |(host, path)| (host, format!("/{path}")));
+    let address = match host.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+        _ => format!("{host}:80"),
+    };
+
+    let mut stream = TcpStream::connect(&address).await.map_err(failed)?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await.map_err(failed)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(failed)?;
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(KrakenError::Webhook(format!("{url}: {status}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::Engine;
+    use crate::structures::Transaction;
+    use crate::webhook::{deliver, Notifier, WebhookConfig};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_webhook() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let config = WebhookConfig {
+                urls: vec![format!("http://{}/hooks/risk", listener.local_addr().unwrap())],
+                retries: 1,
+                backoff: Duration::from_millis(10),
+            };
+            let (notifier, notifications) = Notifier::new();
+            let mut engine = Engine::new().with_observer(notifier);
+            for row in ["deposit, 1, 1, 5.0", "dispute, 1, 1, ", "chargeback, 1, 1, "] {
+                engine.apply(Transaction::try_from(row).unwrap()).unwrap();
+            }
+            drop(engine);
+            tokio::spawn(deliver(config, notifications));
+
+            // Fail the first attempt at each, so both are retried
+            let mut received = Vec::new();
+            for attempt in 0..4 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"}") {
+                    request.push(socket.read_u8().await.unwrap());
+                }
+                let request = String::from_utf8(request).unwrap();
+                assert!(request.starts_with("POST /hooks/risk HTTP/1.1\r\n"), "{request}");
+                let status = if attempt < 2 { "500 Internal Server Error" } else { "204 No Content" };
+                socket.write_all(format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").as_bytes()).await.unwrap();
+                received.push(request.split_once("\r\n\r\n").unwrap().1.to_string());
+            }
+            received.sort();
+            let dispute = r#"{"event":"dispute_opened","client":1,"tx":1,"amount":5.0}"#;
+            let locked = r#"{"event":"account_locked","client":1,"tx":1}"#;
+            assert_eq!(vec![locked, locked, dispute, dispute], received);
+        });
+    }
+}
@@ -0,0 +1,148 @@
+use crate::errors::KrakenError;
+use crate::errors::KrakenError::Parse;
+use crate::input::{next_chunk, InputSource, RowSource, DEFAULT_BATCH_ROWS};
+use crate::structures::{Transaction, TransactionType};
+use calamine::{open_workbook_auto, Data, Range, Reader};
+use std::path::Path;
+
+/// Columns every sheet must provide. They may appear in any order.
+const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Spreadsheet reader for `.xlsx`, `.xlsm`, `.xls`, and `.ods` workbooks.
+/// Workbooks are zipped XML, so the whole sheet is decoded up front; rows are then converted a batch at a time.
+pub struct XlsxSource {
+    range: Range<Data>,
+    /// Position of each of `COLUMNS` in the sheet.
+    columns: [usize; 4],
+    /// Position of the optional `memo` column.
+    memo: Option<usize>,
+    /// Index of the next row to convert. Row 0 is the header.
+    row: usize,
+}
+
+impl XlsxSource {
+    /// Open `sheet`, or the first sheet in the workbook when `None`.
+    /// The header row is matched case-insensitively and must name every expected column; a `memo` column is optional.
+    pub fn open(path: impl AsRef<Path>, sheet: Option<&str>) -> Result<Self, KrakenError> {
+        let mut workbook = open_workbook_auto(path).map_err(|e| Parse(e.to_string()))?;
+        let range = match sheet {
+            Some(sheet) => workbook.worksheet_range(sheet),
+            None => workbook
+                .worksheet_range_at(0)
+                .ok_or_else(|| Parse(String::from("Workbook has no sheets")))?,
+        }
+        .map_err(|e| Parse(e.to_string()))?;
+
+        let header: Vec<String> = range
+            .rows()
+            .next()
+            .unwrap_or_default()
+            .iter()
+            .map(|cell| cell.to_string().trim().to_ascii_lowercase())
+            .collect();
+        let mut columns = [0; 4];
+        for (index, name) in COLUMNS.iter().enumerate() {
+            columns[index] = header
+                .iter()
+                .position(|column| column == name)
+                .ok_or_else(|| Parse(format!("Missing column: {name}")))?;
+        }
+
+        let memo = header.iter().position(|column| column == "memo");
+        Ok(Self { range, columns, memo, row: 1 })
+    }
+}
+
+impl RowSource for XlsxSource {
+    fn next_row(&mut self) -> Option<Result<Transaction, KrakenError>> {
+        loop {
+            let cells = self.range.rows().nth(self.row)?;
+            self.row += 1;
+
+            let [kind, client, tx, amount] = self.columns.map(|column| cells.get(column).unwrap_or(&Data::Empty));
+            if [kind, client, tx, amount].iter().all(|cell| matches!(cell, Data::Empty)) {
+                continue;
+            }
+
+            let memo = self.memo.and_then(|column| cells.get(column)).map(|cell| cell.to_string().trim().to_string());
+            return Some(parse_row([kind, client, tx, amount], self.row).map(|transaction| Transaction {
+                memo: memo.filter(|memo| !memo.is_empty()),
+                ..transaction
+            }));
+        }
+    }
+}
+
+/// Convert the `type, client, tx, amount` cells of spreadsheet row `row` (counting from 1, as displayed).
+fn parse_row([kind, client, tx, amount]: [&Data; 4], row: usize) -> Result<Transaction, KrakenError> {
+    let invalid = |field: &str| Parse(format!("Invalid {field} in row {row}"));
+    Ok(Transaction {
+        kind: TransactionType::try_from(kind.to_string().trim())?,
+        client: to_u32(client).ok_or_else(|| invalid("client"))?,
+        amount: match amount {
+            Data::Empty => None,
+            amount => Some(to_f64(amount).ok_or_else(|| invalid("amount"))?),
+        },
+        tx: to_u32(tx).ok_or_else(|| invalid("tx"))?,
+        memo: None,
+        idempotency_key: None,
+        timestamp: None,
+        state: None,
+    })
+}
+
+impl InputSource for XlsxSource {
+    fn next_batch(&mut self) -> Option<Result<Vec<Transaction>, KrakenError>> {
+        next_chunk(&mut std::iter::from_fn(|| self.next_row()), DEFAULT_BATCH_ROWS)
+    }
+}
+
+/// Spreadsheets store every number as a float, so ids are accepted from whole floats as well as integers
+/// and text.
+fn to_u32(cell: &Data) -> Option<u32> {
+    match cell {
+        Data::Int(value) => u32::try_from(*value).ok(),
+        Data::Float(value) if value.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(value) => Some(*value as u32),
+        Data::String(value) => value.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn to_f64(cell: &Data) -> Option<f64> {
+    match cell {
+        Data::Int(value) => Some(*value as f64),
+        Data::Float(value) => Some(*value),
+        Data::String(value) => value.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{InputFormat, InputOptions};
+    use crate::processor::tests::TEST_DIR;
+    use crate::processor::{compute_account_totals, ProcessorConfig};
+
+    #[test]
+    fn test_xlsx() {
+        let path = String::from(TEST_DIR) + "6-workbook.xlsx";
+        assert_eq!(InputFormat::Xlsx, InputFormat::detect(&path));
+
+        // The first sheet holds 0-trivial.csv and the "Disputes" sheet 1-dispute-after-withdraw.csv
+        let totals = compute_account_totals(&path, &ProcessorConfig::default()).unwrap();
+        assert_eq!("1, 1.5000, 0.0000, 1.5000, false", totals.get(&1).expect("").to_str_row(1).unwrap());
+
+        let config = ProcessorConfig {
+            input: InputOptions { sheet: Some(String::from("Disputes")), ..Default::default() },
+            ..Default::default()
+        };
+        let totals = compute_account_totals(&path, &config).unwrap();
+        assert_eq!("1, -9.5000, 10.0000, 0.5000, false", totals.get(&1).expect("").to_str_row(1).unwrap());
+
+        let config = ProcessorConfig {
+            input: InputOptions { sheet: Some(String::from("Missing")), ..Default::default() },
+            ..Default::default()
+        };
+        assert!(compute_account_totals(&path, &config).is_err());
+    }
+}